@@ -0,0 +1,171 @@
+use std::rc::Rc;
+
+use real::Real;
+use span::Span;
+use types::Type;
+
+/// A parsed script is a list of statements.
+pub type Program = Vec<Stmt>;
+
+/// A list of statements enclosed in `{` and `}`.
+pub type Block = Vec<Stmt>;
+
+/// A statement together with where it starts in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    /// An expression evaluated for its side effects.
+    Expr(Expr),
+    /// `target = value`, or `target op= value` if there is an operator.
+    Assign(Expr, Option<BinaryOp>, Expr),
+    /// `if cond { .. } else { .. }`. An `else if` is stored as an
+    /// else block containing a single `If` statement.
+    If(Expr, Block, Option<Block>),
+    /// `while cond { .. }`
+    While(Expr, Block),
+    /// `for name in iterable { .. }`
+    For(String, Expr, Block),
+    Break,
+    /// `return` with an optional value.
+    Return(Option<Expr>),
+    /// A named function declaration, `fn name(a, b) { .. }`, where the
+    /// parameters and the return value can have a type, see `types::Type`.
+    Function(Rc<Function>),
+    /// `every 10ms { .. }` registers a handler run periodically, the
+    /// period is in milliseconds. The body is a function without
+    /// parameters, see `scheduler::Scheduler`.
+    Every(u32, Rc<Function>),
+    /// `on @accel { .. }` registers a handler run when the host
+    /// signals the device.
+    On(String, Rc<Function>),
+}
+
+/// A function declaration or an anonymous function expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// `None` for anonymous functions.
+    pub name: Option<String>,
+    pub params: Vec<Param>,
+    /// The annotated return type, `fn f() -> int { .. }`.
+    pub ret: Option<Type>,
+    pub body: Block,
+    /// Where the `fn` keyword is.
+    pub span: Span,
+}
+
+/// A parameter of a function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    /// The annotated type, `x: real`.
+    pub ty: Option<Type>,
+    pub span: Span,
+}
+
+/// An expression together with where it is in the source.
+/// For operators the span points at the operator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Int(i32),
+    Real(Real),
+    Str(String),
+    Bool(bool),
+    Nil,
+    Identity(String),
+    /// A reference to a host device or channel, `@gyro` or `@accel.x`.
+    Device(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// `cond ? then : otherwise`
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `[a, b, c]`
+    List(Vec<Expr>),
+    /// `target[index]`, the index can also be a range to take a slice.
+    Index(Box<Expr>, Box<Expr>),
+    /// `{x: 1, "y": 2}`
+    Map(Vec<(String, Expr)>),
+    /// `target.name`
+    Field(Box<Expr>, String),
+    /// `callee(a, b)`
+    Call(Box<Expr>, Vec<Expr>),
+    /// `fn (a, b) { .. }`
+    Function(Rc<Function>),
+    /// `start..end by step` or `start...end by step` where the `bool`
+    /// tells if the range is inclusive. The step is optional.
+    Range(Box<Expr>, Box<Expr>, Option<Box<Expr>>, bool),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Equal,
+    NotEqual,
+    GreaterThan,
+    LessThan,
+    GreaterEqual,
+    LessEqual,
+    And,
+    Or,
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Stmt { kind, span }
+    }
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
+
+impl UnaryOp {
+    /// The operator as written in the source.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "!",
+        }
+    }
+}
+
+impl BinaryOp {
+    /// The operator as written in the source.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::GreaterThan => ">",
+            BinaryOp::LessThan => "<",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::And => "&",
+            BinaryOp::Or => "|",
+        }
+    }
+}
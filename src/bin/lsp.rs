@@ -0,0 +1,18 @@
+extern crate interpreter;
+
+use interpreter::lsp::Server;
+
+use std::io;
+use std::process;
+
+/// Serves the Language Server Protocol over stdin and stdout.
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut server = Server::new();
+    if let Err(e) = server.run(stdin.lock(), stdout.lock()) {
+        eprintln!("interpreter_lsp: {}", e);
+        process::exit(2);
+    }
+    process::exit(server.exit_code());
+}
@@ -0,0 +1,87 @@
+use std::time::Instant;
+
+use span::Span;
+use error::Error;
+use error::Error::*;
+
+/// How often the clock is read when there is a deadline, in steps.
+/// Reading it on every step would slow the scripts down.
+const CLOCK_INTERVAL: u32 = 1024;
+
+/// Limits how long a script may run, so a script looping forever
+/// cannot hang the host. There are no limits by default.
+///
+/// The fuel is the number of steps the script may take. A step is an
+/// instruction for the `Vm`, or a statement or expression evaluated for
+/// the `Interpreter`. The deadline is a point in time after which the
+/// script is stopped.
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    /// Steps left until the clock is read again.
+    until_clock: u32,
+}
+
+/// Why a script was stopped by its `Budget`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Exhausted {
+    Fuel,
+    Deadline,
+}
+
+impl Exhausted {
+    /// The error reported when the script was executing the loop at `span`.
+    pub fn error(self, span: Span) -> Error {
+        match self {
+            Exhausted::Fuel => OutOfFuel(span),
+            Exhausted::Deadline => DeadlineExceeded(span),
+        }
+    }
+}
+
+impl Budget {
+    /// Limits the number of steps to `fuel`, or removes the limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Allows `fuel` more steps. Does nothing if there is no limit.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(ref mut left) = self.fuel {
+            *left = left.saturating_add(fuel);
+        }
+    }
+
+    /// The number of steps left, or `None` if there is no limit.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Stops the script at `deadline`, or removes the deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        self.until_clock = 0;
+    }
+
+    /// Takes one step, failing if there is no fuel left or the
+    /// deadline has passed. A failed step takes no fuel.
+    pub fn step(&mut self) -> Result<(), Exhausted> {
+        if self.fuel == Some(0) {
+            return Err(Exhausted::Fuel);
+        }
+        if let Some(deadline) = self.deadline {
+            if self.until_clock == 0 {
+                if Instant::now() >= deadline {
+                    return Err(Exhausted::Deadline);
+                }
+                self.until_clock = CLOCK_INTERVAL;
+            }
+            self.until_clock -= 1;
+        }
+        if let Some(ref mut fuel) = self.fuel {
+            *fuel -= 1;
+        }
+        Ok(())
+    }
+}
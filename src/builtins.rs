@@ -0,0 +1,138 @@
+use std::io::{self, Write};
+
+use span::Span;
+use value::Value;
+use error;
+use error::Error::*;
+
+/// A function provided by the interpreter. They are available in every
+/// script unless a variable with the same name hides them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Builtin {
+    /// `print(a, b, ..)` writes the values separated by spaces and a newline.
+    Print,
+    /// `len(x)` is the number of items in a list or map, or characters in a string.
+    Len,
+    /// `str(x)` converts a value to a string.
+    Str,
+    /// `push(list, x)` appends `x` to the end of `list`.
+    Push,
+}
+
+/// What the builtin functions need from the engine running them.
+pub trait Host {
+    /// Writes a line of output.
+    fn print(&mut self, line: &str);
+
+    /// Tells the engine an item was added to a list, which now has
+    /// `len` items, so it can enforce its `Limits`.
+    fn grow_list(&mut self, len: usize, span: Span) -> error::Result<()>;
+}
+
+/// Every builtin function.
+pub const BUILTINS: [Builtin; 4] = [Builtin::Print, Builtin::Len, Builtin::Str, Builtin::Push];
+
+impl Builtin {
+    /// Looks up the builtin function called `name`.
+    pub fn lookup(name: &str) -> Option<Builtin> {
+        BUILTINS.iter().cloned().find(|b| b.name() == name)
+    }
+
+    /// The name of the function in the scripts.
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Print => "print",
+            Builtin::Len => "len",
+            Builtin::Str => "str",
+            Builtin::Push => "push",
+        }
+    }
+
+    /// The number of arguments the function takes,
+    /// or `None` if it takes any number of arguments.
+    pub fn arity(self) -> Option<usize> {
+        match self {
+            Builtin::Print => None,
+            Builtin::Len | Builtin::Str => Some(1),
+            Builtin::Push => Some(2),
+        }
+    }
+
+    /// How the function is called and what it does, for an editor to
+    /// show. Like the documentation of `Builtin`.
+    pub fn help(self) -> &'static str {
+        match self {
+            Builtin::Print => "`print(a, b, ..)` writes the values separated by spaces and a newline.",
+            Builtin::Len => "`len(x)` is the number of items in a list or map, or characters in a string.",
+            Builtin::Str => "`str(x)` converts a value to a string.",
+            Builtin::Push => "`push(list, x)` appends `x` to the end of `list`.",
+        }
+    }
+
+    /// Calls the function with `args`. The `span` is where it is called from.
+    pub fn call(self, args: Vec<Value>, host: &mut dyn Host, span: Span) -> error::Result<Value> {
+        if let Some(arity) = self.arity() {
+            if args.len() != arity {
+                return Err(ArityMismatch(arity, args.len(), span));
+            }
+        }
+        match self {
+            Builtin::Print => {
+                let line: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                host.print(&line.join(" "));
+                Ok(Value::Nil)
+            }
+            Builtin::Len => {
+                let len = match args[0] {
+                    Value::Str(ref s) => s.chars().count(),
+                    Value::List(ref list) => list.borrow().len(),
+                    Value::Map(ref map) => map.borrow().len(),
+                    ref value => {
+                        return Err(TypeMismatch(format!("{} has no length", value.type_name()),
+                                                span))
+                    }
+                };
+                Ok(Value::Int(len as i32))
+            }
+            Builtin::Str => Ok(Value::Str(args[0].to_string())),
+            Builtin::Push => {
+                match args[0] {
+                    Value::List(ref list) => {
+                        let len = list.borrow().len() + 1;
+                        host.grow_list(len, span)?;
+                        list.borrow_mut().push(args[1].clone());
+                    }
+                    ref value => {
+                        return Err(TypeMismatch(format!("cannot push to {}", value.type_name()),
+                                                span))
+                    }
+                }
+                Ok(Value::Nil)
+            }
+        }
+    }
+}
+
+/// Where the output of `print` goes.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Output {
+    #[default]
+    Stdout,
+    /// The output is collected, so the host can inspect it.
+    Buffer(String),
+}
+
+impl Output {
+    /// Writes `line` followed by a newline.
+    pub fn write_line(&mut self, line: &str) {
+        match *self {
+            Output::Stdout => {
+                let _ = writeln!(io::stdout(), "{}", line);
+            }
+            Output::Buffer(ref mut buf) => {
+                buf.push_str(line);
+                buf.push('\n');
+            }
+        }
+    }
+}
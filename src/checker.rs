@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use ast::*;
+use builtins::Builtin;
+use diagnostic::Diagnostic;
+use types::{Signature, Type};
+use span::Span;
+use error;
+use error::Error::*;
+
+/// A variable as known to the checker.
+#[derive(Debug, Clone)]
+struct Var {
+    /// The type of its value at this point of the script.
+    ty: Type,
+    /// The type of every value assigned to it so far.
+    all: Type,
+    /// The annotated type of a parameter, which every value must fit.
+    declared: Option<Type>,
+}
+
+/// A scope of the script, see `eval::Interpreter`.
+#[derive(Default)]
+struct Scope {
+    vars: HashMap<String, Var>,
+    /// Functions created in the scope, checked when the scope ends.
+    functions: Vec<Rc<Function>>,
+}
+
+/// The types of the variables of every scope at a point of the script.
+type State = Vec<HashMap<String, Var>>;
+
+/// Checks the types of a script without running it, using the annotated
+/// types of parameters and return values, see `types::Type`. It reports
+/// values of the wrong type given to functions, returned, or used with
+/// an operator, values which may be nil used where nil is not allowed,
+/// and calls with the wrong number of arguments.
+///
+/// The type of a variable is inferred from the values assigned to it,
+/// following the branches and loops of the script, so it is only known
+/// inside a block. A value which is not annotated and cannot be inferred
+/// can be of any type and is not checked, so scripts without annotations
+/// run as dynamically as before. `x != nil` and `x` as the condition of
+/// an `if` or `while` make `x` not nil inside the block.
+#[derive(Default)]
+pub struct Checker {
+    /// The number of arguments of the native functions of the host.
+    natives: HashMap<String, usize>,
+    scopes: Vec<Scope>,
+    /// The name and annotated return type of the functions around the
+    /// statement, the innermost last.
+    functions: Vec<(Option<String>, Option<Type>)>,
+    /// Errors are not reported while this is not zero, used for the
+    /// first pass over a loop which finds the types a variable takes.
+    quiet: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        Checker::default()
+    }
+
+    /// Tells the checker the host has a native function `name` taking
+    /// `arity` arguments.
+    pub fn declare_native(&mut self, name: &str, arity: usize) {
+        self.natives.insert(name.to_string(), arity);
+    }
+
+    pub fn check(mut self, program: &[Stmt]) -> Vec<Diagnostic> {
+        self.block(program);
+        self.diagnostics.sort_by_key(Diagnostic::span);
+        self.diagnostics
+    }
+
+    fn error(&mut self, error: error::Error) {
+        if self.quiet == 0 {
+            self.diagnostics.push(Diagnostic::error(error));
+        }
+    }
+
+    /// Reports a value of type `found` used where `expected` is needed.
+    fn expect(&mut self, found: &Type, expected: &Type, what: &str, span: Span) {
+        if !found.fits(expected) {
+            self.error(TypeMismatch(format!("{} must be {}, found {}", what, expected, found), span));
+        }
+    }
+
+    /// Checks a block in a new scope.
+    fn block(&mut self, block: &[Stmt]) {
+        self.scopes.push(Scope::default());
+        for stmt in block {
+            self.stmt(stmt);
+        }
+        self.end_scope();
+    }
+
+    /// Checks the functions created in the innermost scope, then drops it.
+    /// The functions can be called later, so they see every type their
+    /// variables take.
+    fn end_scope(&mut self) {
+        let state = self.state();
+        for scope in &mut self.scopes {
+            for var in scope.vars.values_mut() {
+                var.ty = var.all.clone();
+            }
+        }
+        loop {
+            let functions = ::std::mem::take(&mut self.scopes.last_mut().unwrap().functions);
+            if functions.is_empty() {
+                break;
+            }
+            for function in functions {
+                self.function_body(&function);
+            }
+        }
+        self.restore(state);
+        self.scopes.pop();
+    }
+
+    fn state(&self) -> State {
+        self.scopes.iter().map(|scope| scope.vars.clone()).collect()
+    }
+
+    /// Goes back to the types of `state`, taken in the same scope.
+    fn restore(&mut self, state: State) {
+        for (scope, vars) in self.scopes.iter_mut().zip(state) {
+            scope.vars = vars;
+        }
+    }
+
+    /// Joins the types of `state` into the current ones, after a branch
+    /// which may or may not have run.
+    fn join(&mut self, state: &State) {
+        for (scope, vars) in self.scopes.iter_mut().zip(state) {
+            for (name, var) in &mut scope.vars {
+                if let Some(other) = vars.get(name) {
+                    var.ty = var.ty.join(&other.ty);
+                }
+            }
+        }
+    }
+
+    fn lookup(&mut self, name: &str) -> Option<&mut Var> {
+        self.scopes.iter_mut().rev().filter_map(|scope| scope.vars.get_mut(name)).next()
+    }
+
+    /// The type of the variable or function `name`.
+    fn name_type(&mut self, name: &str) -> Type {
+        if let Some(var) = self.lookup(name) {
+            return var.ty.clone();
+        }
+        if let Some(&arity) = self.natives.get(name) {
+            return Type::Fn(Some(Rc::new(Signature { params: vec![Type::Any; arity], ret: Type::Any })));
+        }
+        match Builtin::lookup(name) {
+            Some(builtin) => builtin_type(builtin),
+            // Undefined names are reported by the `Resolver`, the host may define them
+            None => Type::Any,
+        }
+    }
+
+    /// Gives `name` a value of type `ty`, declaring it in the innermost
+    /// scope if it is not visible.
+    fn assign(&mut self, name: &str, ty: Type, span: Span) {
+        let declared = match self.lookup(name) {
+            Some(var) => {
+                var.ty = ty.clone();
+                var.all = var.all.join(&ty);
+                var.declared.clone()
+            }
+            None => {
+                let var = Var { ty: ty.clone(), all: ty, declared: None };
+                self.scopes.last_mut().unwrap().vars.insert(name.to_string(), var);
+                return;
+            }
+        };
+        if let Some(declared) = declared {
+            if !ty.fits(&declared) {
+                self.error(TypeMismatch(format!("{} is declared as {}, found {}", name, declared, ty), span));
+                // Keep checking as if it had the right type
+                let var = self.lookup(name).unwrap();
+                var.ty = declared.clone();
+                var.all = declared;
+            }
+        }
+    }
+
+    /// Makes the variables a condition checks for nil not nil if it is
+    /// `truthy`.
+    fn narrow(&mut self, cond: &Expr, truthy: bool) {
+        let name = match cond.kind {
+            ExprKind::Identity(ref name) if truthy => name,
+            ExprKind::Binary(op, ref left, ref right) => {
+                let not_nil = match op {
+                    BinaryOp::NotEqual => truthy,
+                    BinaryOp::Equal => !truthy,
+                    _ => return,
+                };
+                match (&left.kind, &right.kind) {
+                    (&ExprKind::Identity(ref name), &ExprKind::Nil) |
+                    (&ExprKind::Nil, &ExprKind::Identity(ref name)) if not_nil => name,
+                    _ => return,
+                }
+            }
+            _ => return,
+        };
+        if let Some(var) = self.lookup(name) {
+            var.ty = var.ty.non_nil();
+        }
+    }
+
+    /// Checks the body of a function in a scope holding its parameters.
+    fn function_body(&mut self, function: &Function) {
+        self.scopes.push(Scope::default());
+        for param in &function.params {
+            let ty = param.ty.clone().unwrap_or(Type::Any);
+            let var = Var { ty: ty.clone(), all: ty, declared: param.ty.clone() };
+            self.scopes.last_mut().unwrap().vars.insert(param.name.clone(), var);
+        }
+        self.functions.push((function.name.clone(), function.ret.clone()));
+        self.block(&function.body);
+        if let Some(ref ret) = function.ret {
+            if !Type::Nil.fits(ret) && !ends(&function.body, false) {
+                let name = function.name.as_ref().map_or("the function", String::as_str);
+                let message = format!("{} can end without a return, but must return {}", name, ret);
+                self.error(TypeMismatch(message, function.span));
+            }
+        }
+        self.functions.pop();
+        self.end_scope();
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt.kind {
+            StmtKind::Expr(ref expr) => {
+                self.expr(expr);
+            }
+            StmtKind::Assign(ref target, op, ref value) => {
+                let value_type = self.expr(value);
+                match target.kind {
+                    ExprKind::Identity(ref name) => {
+                        let ty = match op {
+                            Some(op) => {
+                                let current = self.name_type(name);
+                                self.binary(op, &current, &value_type, target.span)
+                            }
+                            None => value_type,
+                        };
+                        self.assign(name, ty, target.span);
+                    }
+                    _ => {
+                        self.expr(target);
+                    }
+                }
+            }
+            StmtKind::If(ref cond, ref then, ref otherwise) => {
+                self.expr(cond);
+                let before = self.state();
+                self.narrow(cond, true);
+                self.block(then);
+                let after_then = self.state();
+                self.restore(before);
+                self.narrow(cond, false);
+                if let Some(ref otherwise) = *otherwise {
+                    self.block(otherwise);
+                }
+                // A branch which returns or breaks doesn't reach the code after
+                let then_ends = ends(then, true);
+                let otherwise_ends = otherwise.as_ref().is_some_and(|b| ends(b, true));
+                if then_ends && !otherwise_ends {
+                    return;
+                }
+                if otherwise_ends && !then_ends {
+                    self.restore(after_then);
+                } else {
+                    self.join(&after_then);
+                }
+            }
+            StmtKind::While(ref cond, ref body) => {
+                self.expr(cond);
+                self.loop_body(body, |checker| checker.narrow(cond, true));
+            }
+            StmtKind::For(ref name, ref iterable, ref body) => {
+                let iterable_type = self.expr(iterable);
+                let item = match iterable_type {
+                    Type::Any | Type::Range | Type::List => Type::Any,
+                    Type::Str | Type::Map => Type::Str,
+                    ref ty => {
+                        self.error(NotIterable(ty.to_string(), iterable.span));
+                        Type::Any
+                    }
+                };
+                self.loop_body(body, |checker| {
+                    let var = Var { ty: item.clone(), all: item.clone(), declared: None };
+                    checker.scopes.last_mut().unwrap().vars.insert(name.clone(), var);
+                });
+            }
+            StmtKind::Break => {}
+            StmtKind::Return(ref value) => {
+                let ty = match *value {
+                    Some(ref value) => self.expr(value),
+                    None => Type::Nil,
+                };
+                let (name, ret) = match self.functions.last() {
+                    Some(&(ref name, Some(ref ret))) => (name.clone(), ret.clone()),
+                    _ => return,
+                };
+                let what = format!("the return value of {}", name.as_ref().map_or("the function", String::as_str));
+                let span = value.as_ref().map_or(stmt.span, |value| value.span);
+                self.expect(&ty, &ret, &what, span);
+            }
+            StmtKind::Function(ref function) => {
+                let name = function.name.clone().unwrap_or_default();
+                let ty = function_type(function);
+                self.scopes.last_mut().unwrap().vars.insert(name, Var { ty: ty.clone(), all: ty, declared: None });
+                self.scopes.last_mut().unwrap().functions.push(function.clone());
+            }
+            StmtKind::Every(_, ref function) |
+            StmtKind::On(_, ref function) => {
+                self.scopes.last_mut().unwrap().functions.push(function.clone());
+            }
+        }
+    }
+
+    /// Checks the body of a loop in a scope set up by `enter`. The body
+    /// is first checked quietly to find the types its variables take
+    /// after running once, which it may run with the next time.
+    fn loop_body<F: Fn(&mut Checker)>(&mut self, body: &[Stmt], enter: F) {
+        let before = self.state();
+        self.quiet += 1;
+        self.scopes.push(Scope::default());
+        enter(self);
+        self.block(body);
+        self.scopes.pop();
+        self.quiet -= 1;
+        self.join(&before);
+
+        let entry = self.state();
+        self.scopes.push(Scope::default());
+        enter(self);
+        self.block(body);
+        self.scopes.pop();
+        self.join(&entry);
+    }
+
+    /// Checks an expression and returns its type.
+    fn expr(&mut self, expr: &Expr) -> Type {
+        match expr.kind {
+            ExprKind::Int(_) => Type::Int,
+            ExprKind::Real(_) => Type::Real,
+            ExprKind::Str(_) => Type::Str,
+            ExprKind::Bool(_) => Type::Bool,
+            ExprKind::Nil => Type::Nil,
+            ExprKind::Identity(ref name) => self.name_type(name),
+            ExprKind::Device(_) => Type::Any,
+            ExprKind::Unary(op, ref operand) => {
+                let ty = self.expr(operand);
+                match (op, ty) {
+                    (UnaryOp::Not, _) => Type::Bool,
+                    (UnaryOp::Neg, ty @ Type::Any) |
+                    (UnaryOp::Neg, ty @ Type::Int) |
+                    (UnaryOp::Neg, ty @ Type::Real) => ty,
+                    (op, ty) => {
+                        self.error(TypeMismatch(format!("cannot apply `{}` to {}", op.symbol(), ty), expr.span));
+                        Type::Any
+                    }
+                }
+            }
+            ExprKind::Binary(op, ref left, ref right) => {
+                let left = self.expr(left);
+                let right = self.expr(right);
+                self.binary(op, &left, &right, expr.span)
+            }
+            ExprKind::Ternary(ref cond, ref then, ref otherwise) => {
+                self.expr(cond);
+                let then = self.expr(then);
+                then.join(&self.expr(otherwise))
+            }
+            ExprKind::List(ref items) => {
+                for item in items {
+                    self.expr(item);
+                }
+                Type::List
+            }
+            ExprKind::Map(ref entries) => {
+                for entry in entries {
+                    self.expr(&entry.1);
+                }
+                Type::Map
+            }
+            ExprKind::Index(ref target, ref index) => {
+                let target = self.expr(target);
+                self.expr(index);
+                match target {
+                    Type::Str => Type::Str,
+                    _ => Type::Any,
+                }
+            }
+            ExprKind::Field(ref object, _) => {
+                self.expr(object);
+                Type::Any
+            }
+            ExprKind::Call(ref callee, ref args) => {
+                let callee_type = self.expr(callee);
+                let arg_types: Vec<Type> = args.iter().map(|arg| self.expr(arg)).collect();
+                let signature = match callee_type {
+                    Type::Fn(Some(signature)) => signature,
+                    Type::Fn(None) | Type::Any => return Type::Any,
+                    ty => {
+                        self.error(NotCallable(ty.to_string(), expr.span));
+                        return Type::Any;
+                    }
+                };
+                if signature.params.len() != args.len() {
+                    self.error(ArityMismatch(signature.params.len(), args.len(), expr.span));
+                    return signature.ret.clone();
+                }
+                let name = match callee.kind {
+                    ExprKind::Identity(ref name) => name.as_str(),
+                    _ => "the function",
+                };
+                for (i, (arg, expected)) in args.iter().zip(&signature.params).enumerate() {
+                    let what = format!("argument {} of {}", i + 1, name);
+                    self.expect(&arg_types[i], expected, &what, arg.span);
+                }
+                signature.ret.clone()
+            }
+            ExprKind::Function(ref function) => {
+                self.scopes.last_mut().unwrap().functions.push(function.clone());
+                function_type(function)
+            }
+            ExprKind::Range(ref start, ref end, ref step, _) => {
+                let bounds = Some(start).into_iter().chain(Some(end)).chain(step.as_ref());
+                for bound in bounds {
+                    let ty = self.expr(bound);
+                    if !matches!(ty, Type::Any | Type::Int | Type::Real) {
+                        self.error(TypeMismatch(format!("cannot make a range of {}", ty), bound.span));
+                    }
+                }
+                Type::Range
+            }
+        }
+    }
+
+    /// The type of `lhs op rhs`, see `ops::binary`.
+    fn binary(&mut self, op: BinaryOp, lhs: &Type, rhs: &Type, span: Span) -> Type {
+        let number = |ty: &Type| matches!(*ty, Type::Int | Type::Real);
+        let arithmetic = matches!(op, BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div);
+        match op {
+            BinaryOp::Equal | BinaryOp::NotEqual | BinaryOp::And | BinaryOp::Or => Type::Bool,
+            _ if *lhs == Type::Any || *rhs == Type::Any => {
+                if arithmetic { Type::Any } else { Type::Bool }
+            }
+            BinaryOp::Add if *lhs == Type::Str && *rhs == Type::Str => Type::Str,
+            _ if arithmetic && *lhs == Type::Int && *rhs == Type::Int => Type::Int,
+            _ if arithmetic && number(lhs) && number(rhs) => Type::Real,
+            _ if !arithmetic && (number(lhs) && number(rhs) || *lhs == Type::Str && *rhs == Type::Str) => {
+                Type::Bool
+            }
+            _ => {
+                let message = format!("cannot apply `{}` to {} and {}", op.symbol(), lhs, rhs);
+                self.error(TypeMismatch(message, span));
+                if arithmetic { Type::Any } else { Type::Bool }
+            }
+        }
+    }
+}
+
+/// The type of a function from its annotations.
+fn function_type(function: &Function) -> Type {
+    let params = function.params.iter().map(|p| p.ty.clone().unwrap_or(Type::Any)).collect();
+    let ret = function.ret.clone().unwrap_or(Type::Any);
+    Type::Fn(Some(Rc::new(Signature { params, ret })))
+}
+
+fn builtin_type(builtin: Builtin) -> Type {
+    let (params, ret) = match builtin {
+        // Takes any number of arguments
+        Builtin::Print => return Type::Fn(None),
+        Builtin::Len => (vec![Type::Any], Type::Int),
+        Builtin::Str => (vec![Type::Any], Type::Str),
+        Builtin::Push => (vec![Type::List, Type::Any], Type::Nil),
+    };
+    Type::Fn(Some(Rc::new(Signature { params, ret })))
+}
+
+/// Whether a block always ends with a `return`, or a `break` too if
+/// `breaks` is `true`, so the code after it is never reached. A
+/// `while true` loop without a `break` never ends but by a `return`.
+fn ends(block: &[Stmt], breaks: bool) -> bool {
+    block.iter().any(|stmt| match stmt.kind {
+        StmtKind::Return(_) => true,
+        StmtKind::Break => breaks,
+        StmtKind::If(_, ref then, Some(ref otherwise)) => ends(then, breaks) && ends(otherwise, breaks),
+        StmtKind::While(ref cond, ref body) => {
+            matches!(cond.kind, ExprKind::Bool(true)) && !breaks_out(body)
+        }
+        _ => false,
+    })
+}
+
+/// Whether a block of a loop may `break` out of it. The breaks of the
+/// loops and functions inside of it are their own.
+fn breaks_out(block: &[Stmt]) -> bool {
+    block.iter().any(|stmt| match stmt.kind {
+        StmtKind::Break => true,
+        StmtKind::If(_, ref then, ref otherwise) => {
+            breaks_out(then) || otherwise.as_ref().is_some_and(|otherwise| breaks_out(otherwise))
+        }
+        _ => false,
+    })
+}
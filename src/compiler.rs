@@ -0,0 +1,743 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt::Write;
+
+use ast::*;
+use span::Span;
+use value::Value;
+use error;
+use error::Error::*;
+
+/// An instruction of the virtual machine, see `Vm`.
+/// The instructions work on a stack of values. Indexes into the pools
+/// of a `Module` or the tables of a `Proto` are `u32`, slots are `u16`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Op {
+    /// Pushes a constant from the constants pool.
+    Const(u32),
+    Nil,
+    True,
+    False,
+    Pop,
+    Dup,
+    /// Duplicates the top two values.
+    Dup2,
+    Swap,
+    /// Moves the value under the top two to the top.
+    Rotate,
+    /// Pushes the value of a variable, see `Var`.
+    GetVar(u32),
+    /// Pops a value and assigns it to a variable.
+    SetVar(u32),
+    /// Pops a value and declares a variable in the innermost scope.
+    Declare(u32),
+    /// Clears the variables of a block, so every time the block is
+    /// entered it starts with fresh variables.
+    EnterBlock(u32),
+    Unary(UnaryOp),
+    /// Applies an operator other than `&` and `|`, which are made of jumps.
+    Binary(BinaryOp),
+    Jump(u32),
+    /// Pops a value and jumps if it is false.
+    JumpIfFalse(u32),
+    /// Pops a value and jumps if it is true.
+    JumpIfTrue(u32),
+    /// Pops a number of values into a new list.
+    List(u32),
+    /// Pops a number of key and value pairs into a new map.
+    Map(u32),
+    Index,
+    /// Pops a list, an index and a value and stores the value.
+    SetIndex,
+    Field(u32),
+    /// Pops an object and a value and stores the value in a field.
+    SetField(u32),
+    Range { inclusive: bool, step: bool },
+    /// Reads a device, the operand is the name.
+    Device(u32),
+    /// Calls the value under the number of arguments given.
+    Call(u16),
+    /// Creates a closure from a function in the module.
+    Closure(u32),
+    Return,
+    /// Pops a value and starts iterating over it. The iteration is kept
+    /// in the two slots starting at the operand.
+    IterStart(u16),
+    /// Pushes the next item of an iteration or jumps if there is none.
+    IterNext(u16, u32),
+    BreakOutsideLoop,
+    ReturnOutsideFunction,
+    /// Pops a closure and registers it to run every number of milliseconds.
+    Every(u32),
+    /// Pops a closure and registers it to run when the device is signaled.
+    On(u32),
+}
+
+/// Where a variable may live.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Place {
+    /// A slot in the frame of the function.
+    Local(u16),
+    /// A variable of an enclosing function captured by the closure.
+    Capture(u16),
+    /// A global, the operand is the name.
+    Global(u32),
+}
+
+/// A variable as seen from one place in a function.
+///
+/// A variable is declared in a scope when it is first assigned, so
+/// which scope a name refers to is only known when running. The
+/// places are the scopes which may hold the name, innermost first,
+/// and the first place where it is declared is the one used. When
+/// assigning to a variable which is not declared anywhere it is
+/// declared in the first place, which is the innermost scope.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Var {
+    /// An index into the names of the module.
+    pub name: u32,
+    pub places: Vec<Place>,
+}
+
+/// A variable captured by a closure when it is created.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Capture {
+    /// A slot in the frame creating the closure.
+    Local(u16),
+    /// A variable captured by the closure creating the closure.
+    Capture(u16),
+}
+
+/// A compiled function.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Proto {
+    pub name: Option<String>,
+    pub arity: usize,
+    /// Where the function is defined.
+    pub span: Span,
+    pub code: Vec<Op>,
+    /// Where in the source each instruction comes from.
+    pub spans: Vec<Span>,
+    /// Tells for each slot if it is captured by a closure, in which case
+    /// the variable lives in a cell shared with the closure.
+    pub captured: Vec<bool>,
+    /// The slots of the variables declared in each block.
+    pub blocks: Vec<Vec<u16>>,
+    pub vars: Vec<Var>,
+    pub captures: Vec<Capture>,
+    pub loops: Vec<Loop>,
+    /// The first instruction of each statement and where the statement
+    /// is, in order, where a debugger can stop.
+    pub statements: Vec<(u32, Span)>,
+    /// The variable in each slot, for debuggers. The slots holding
+    /// iterations have none.
+    pub locals: Vec<Option<Local>>,
+}
+
+/// A variable living in a slot of a function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Local {
+    pub name: String,
+    /// The first instruction where the variable is in scope.
+    pub start: u32,
+    /// The instruction after the last one where it is in scope.
+    pub end: u32,
+}
+
+/// The instructions of a loop, used to tell which loop was running.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Loop {
+    pub start: u32,
+    /// The instruction after the loop.
+    pub end: u32,
+    pub span: Span,
+}
+
+/// A compiled program. The first function is the program itself.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Module {
+    pub constants: Vec<Value>,
+    /// The names of variables, fields and devices.
+    pub names: Vec<String>,
+    pub protos: Vec<Proto>,
+}
+
+impl Module {
+    /// Lists the instructions of every function, for debugging.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        for (i, proto) in self.protos.iter().enumerate() {
+            let name = match proto.name {
+                Some(ref name) => name,
+                None if i == 0 => "<main>",
+                None => "<anonymous>",
+            };
+            let _ = writeln!(out,
+                             "fn #{} {} ({} params, {} slots)",
+                             i,
+                             name,
+                             proto.arity,
+                             proto.captured.len());
+            for (ip, (op, span)) in proto.code.iter().zip(&proto.spans).enumerate() {
+                let _ = write!(out, "{:04} {:>7}  {:?}", ip, span.to_string(), op);
+                match *op {
+                    Op::Const(index) => {
+                        let _ = match self.constants[index as usize] {
+                            Value::Str(ref s) => write!(out, "  ; {:?}", s),
+                            ref value => write!(out, "  ; {}", value),
+                        };
+                    }
+                    Op::GetVar(index) | Op::SetVar(index) | Op::Declare(index) => {
+                        let var = &proto.vars[index as usize];
+                        let _ = write!(out, "  ; {} {:?}", self.names[var.name as usize], var.places);
+                    }
+                    Op::Field(index) | Op::SetField(index) | Op::Device(index) | Op::On(index) => {
+                        let _ = write!(out, "  ; {}", self.names[index as usize]);
+                    }
+                    _ => {}
+                }
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+/// Compiles a program to bytecode. Fails with `TooMany` if a function
+/// has more locals, captures or arguments in a call than a slot holds.
+pub fn compile(program: &[Stmt]) -> error::Result<Module> {
+    let mut compiler = Compiler {
+        module: Module::default(),
+        fns: vec![FnState::default()],
+        error: None,
+    };
+    compiler.module.protos.push(Proto::default());
+
+    let mut returned = false;
+    for (i, stmt) in program.iter().enumerate() {
+        match stmt.kind {
+            // The value of the last statement is the result of the program
+            StmtKind::Expr(ref expr) if i + 1 == program.len() => {
+                compiler.statement(stmt.span);
+                compiler.expr(expr);
+                compiler.emit(Op::Return, stmt.span);
+                returned = true;
+            }
+            _ => compiler.stmt(stmt),
+        }
+    }
+    if !returned {
+        let span = program.last().map_or_else(Span::default, |stmt| stmt.span);
+        compiler.emit(Op::Nil, span);
+        compiler.emit(Op::Return, span);
+    }
+    compiler.module.protos[0] = compiler.fns.pop().unwrap().proto;
+    match compiler.error {
+        Some(e) => Err(e),
+        None => Ok(compiler.module),
+    }
+}
+
+/// The state of a function being compiled.
+#[derive(Default)]
+struct FnState {
+    proto: Proto,
+    /// The names declared in each scope and their slots. The outermost
+    /// scope of the program holds the globals and is not listed.
+    scopes: Vec<HashMap<String, u16>>,
+    /// The jumps of the `break` statements in each loop, to be
+    /// patched when the end of the loop is known.
+    loops: Vec<Vec<usize>>,
+}
+
+struct Compiler {
+    module: Module,
+    /// The function being compiled and those enclosing it.
+    fns: Vec<FnState>,
+    /// The first error found. The compiling goes on, so it need not
+    /// be checked everywhere.
+    error: Option<error::Error>,
+}
+
+impl Compiler {
+    fn current(&mut self) -> &mut FnState {
+        self.fns.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        let proto = &mut self.current().proto;
+        proto.code.push(op);
+        proto.spans.push(span);
+        proto.code.len() - 1
+    }
+
+    /// Where the next instruction will be.
+    fn here(&mut self) -> u32 {
+        self.current().proto.code.len() as u32
+    }
+
+    /// Makes the jump at `at` jump to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.here();
+        match self.current().proto.code[at] {
+            Op::Jump(ref mut to) |
+            Op::JumpIfFalse(ref mut to) |
+            Op::JumpIfTrue(ref mut to) |
+            Op::IterNext(_, ref mut to) => *to = target,
+            ref op => panic!("cannot patch {:?}", op),
+        }
+    }
+
+    fn constant(&mut self, value: Value) -> u32 {
+        let constants = &mut self.module.constants;
+        match constants.iter().position(|c| *c == value) {
+            Some(index) => index as u32,
+            None => {
+                constants.push(value);
+                constants.len() as u32 - 1
+            }
+        }
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        let names = &mut self.module.names;
+        match names.iter().position(|n| n == name) {
+            Some(index) => index as u32,
+            None => {
+                names.push(name.to_string());
+                names.len() as u32 - 1
+            }
+        }
+    }
+
+    /// Converts `index`, of a slot or a capture or a number of arguments
+    /// as `what` says, to a `u16`. Records `TooMany` if it does not fit.
+    fn slot(&mut self, index: usize, what: &str, span: Span) -> u16 {
+        if index > u16::MAX as usize && self.error.is_none() {
+            self.error = Some(TooMany(what.to_string(), span));
+        }
+        index as u16
+    }
+
+    /// Records that the statement at `span` starts at the next instruction.
+    fn statement(&mut self, span: Span) {
+        let here = self.here();
+        self.current().proto.statements.push((here, span));
+    }
+
+    /// Adds a slot for the variable `name`, which is in scope from the
+    /// next instruction until its scope ends, or for an iteration.
+    fn new_slot(&mut self, name: Option<String>, span: Span) -> u16 {
+        let start = self.here();
+        let proto = &mut self.current().proto;
+        proto.captured.push(false);
+        proto.locals.push(name.map(|name| Local { name, start, end: u32::MAX }));
+        let index = proto.captured.len() - 1;
+        self.slot(index, "locals", span)
+    }
+
+    /// Opens a scope declaring `names`.
+    fn begin_scope(&mut self, names: Vec<String>, span: Span) {
+        let mut scope = HashMap::new();
+        let mut slots = Vec::new();
+        for name in names {
+            if let Entry::Vacant(entry) = scope.entry(name) {
+                let slot = self.new_slot(Some(entry.key().clone()), span);
+                entry.insert(slot);
+                slots.push(slot);
+            }
+        }
+        let state = self.current();
+        state.scopes.push(scope);
+        if !slots.is_empty() {
+            state.proto.blocks.push(slots);
+            let block = state.proto.blocks.len() as u32 - 1;
+            self.emit(Op::EnterBlock(block), span);
+        }
+    }
+
+    fn end_scope(&mut self) {
+        let end = self.here();
+        let state = self.current();
+        let scope = state.scopes.pop().unwrap_or_default();
+        for &slot in scope.values() {
+            if let Some(ref mut local) = state.proto.locals[slot as usize] {
+                local.end = end;
+            }
+        }
+    }
+
+    /// Compiles a block in a new scope.
+    fn block(&mut self, block: &[Stmt], span: Span) {
+        self.begin_scope(declared_names(block), span);
+        for stmt in block {
+            self.stmt(stmt);
+        }
+        self.end_scope();
+    }
+
+    /// Looks up the variable `name` used at `span` as seen from the
+    /// current scope.
+    fn var(&mut self, name: &str, span: Span) -> u32 {
+        let depth = self.fns.len() - 1;
+        let mut places = self.places(depth, name, span);
+        let name = self.name(name);
+        places.push(Place::Global(name));
+
+        let var = Var { name, places };
+        let vars = &mut self.current().proto.vars;
+        match vars.iter().position(|v| *v == var) {
+            Some(index) => index as u32,
+            None => {
+                vars.push(var);
+                vars.len() as u32 - 1
+            }
+        }
+    }
+
+    /// The places where `name` may live in the function at `depth`,
+    /// leaving out the globals.
+    fn places(&mut self, depth: usize, name: &str, span: Span) -> Vec<Place> {
+        let mut places: Vec<Place> = self.fns[depth].scopes.iter().rev()
+            .filter_map(|scope| scope.get(name))
+            .map(|&slot| Place::Local(slot))
+            .collect();
+        if depth > 0 {
+            for outer in self.places(depth - 1, name, span) {
+                let capture = match outer {
+                    Place::Local(slot) => {
+                        self.fns[depth - 1].proto.captured[slot as usize] = true;
+                        Capture::Local(slot)
+                    }
+                    Place::Capture(index) => Capture::Capture(index),
+                    Place::Global(_) => continue,
+                };
+                let captures = &mut self.fns[depth].proto.captures;
+                let index = match captures.iter().position(|c| *c == capture) {
+                    Some(index) => index,
+                    None => {
+                        captures.push(capture);
+                        captures.len() - 1
+                    }
+                };
+                let index = self.slot(index, "captures", span);
+                places.push(Place::Capture(index));
+            }
+        }
+        places
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        let span = stmt.span;
+        self.statement(span);
+        match stmt.kind {
+            StmtKind::Expr(ref expr) => {
+                self.expr(expr);
+                self.emit(Op::Pop, span);
+            }
+            StmtKind::Assign(ref target, None, ref value) => {
+                self.expr(value);
+                match target.kind {
+                    ExprKind::Identity(ref name) => {
+                        let var = self.var(name, target.span);
+                        self.emit(Op::SetVar(var), target.span);
+                    }
+                    ExprKind::Index(ref list, ref index) => {
+                        self.expr(list);
+                        self.expr(index);
+                        self.emit(Op::Rotate, target.span);
+                        self.emit(Op::SetIndex, target.span);
+                    }
+                    ExprKind::Field(ref object, ref name) => {
+                        self.expr(object);
+                        self.emit(Op::Swap, target.span);
+                        let name = self.name(name);
+                        self.emit(Op::SetField(name), target.span);
+                    }
+                    _ => unreachable!("the parser only allows assignments to variables, elements and fields"),
+                }
+            }
+            StmtKind::Assign(ref target, Some(op), ref value) => {
+                let span = target.span;
+                match target.kind {
+                    ExprKind::Identity(ref name) => {
+                        let var = self.var(name, span);
+                        self.emit(Op::GetVar(var), span);
+                        self.expr(value);
+                        self.emit(Op::Binary(op), span);
+                        self.emit(Op::SetVar(var), span);
+                    }
+                    ExprKind::Index(ref list, ref index) => {
+                        self.expr(list);
+                        self.expr(index);
+                        self.emit(Op::Dup2, span);
+                        self.emit(Op::Index, span);
+                        self.expr(value);
+                        self.emit(Op::Binary(op), span);
+                        self.emit(Op::SetIndex, span);
+                    }
+                    ExprKind::Field(ref object, ref name) => {
+                        let name = self.name(name);
+                        self.expr(object);
+                        self.emit(Op::Dup, span);
+                        self.emit(Op::Field(name), span);
+                        self.expr(value);
+                        self.emit(Op::Binary(op), span);
+                        self.emit(Op::SetField(name), span);
+                    }
+                    _ => unreachable!("the parser only allows assignments to variables, elements and fields"),
+                }
+            }
+            StmtKind::If(ref cond, ref then, ref otherwise) => {
+                self.expr(cond);
+                let to_else = self.emit(Op::JumpIfFalse(0), span);
+                self.block(then, span);
+                match *otherwise {
+                    Some(ref otherwise) => {
+                        let to_end = self.emit(Op::Jump(0), span);
+                        self.patch(to_else);
+                        self.block(otherwise, span);
+                        self.patch(to_end);
+                    }
+                    None => self.patch(to_else),
+                }
+            }
+            StmtKind::While(ref cond, ref body) => {
+                let start = self.here();
+                self.expr(cond);
+                let to_end = self.emit(Op::JumpIfFalse(0), span);
+                self.current().loops.push(Vec::new());
+                self.block(body, span);
+                self.emit(Op::Jump(start), span);
+                self.patch(to_end);
+                self.end_loop(start, span);
+            }
+            StmtKind::For(ref name, ref iterable, ref body) => {
+                self.expr(iterable);
+                let iter = self.new_slot(None, span);
+                self.new_slot(None, span);
+                self.emit(Op::IterStart(iter), iterable.span);
+                let start = self.here();
+                let to_end = self.emit(Op::IterNext(iter, 0), span);
+                self.current().loops.push(Vec::new());
+                // Every iteration gets its own scope holding the loop variable
+                self.begin_scope(vec![name.clone()], span);
+                let var = self.var(name, span);
+                self.emit(Op::Declare(var), span);
+                self.block(body, span);
+                self.end_scope();
+                self.emit(Op::Jump(start), span);
+                self.patch(to_end);
+                self.end_loop(start, span);
+            }
+            StmtKind::Break => {
+                if self.current().loops.is_empty() {
+                    self.emit(Op::BreakOutsideLoop, span);
+                } else {
+                    let jump = self.emit(Op::Jump(0), span);
+                    self.current().loops.last_mut().unwrap().push(jump);
+                }
+            }
+            StmtKind::Return(ref value) => {
+                match *value {
+                    Some(ref value) => self.expr(value),
+                    None => {
+                        self.emit(Op::Nil, span);
+                    }
+                }
+                if self.fns.len() > 1 {
+                    self.emit(Op::Return, span);
+                } else {
+                    self.emit(Op::ReturnOutsideFunction, span);
+                }
+            }
+            StmtKind::Function(ref function) => {
+                self.function(function);
+                let var = self.var(function.name.as_ref().map_or("", |name| name), span);
+                self.emit(Op::Declare(var), span);
+            }
+            StmtKind::Every(period, ref function) => {
+                self.function(function);
+                self.emit(Op::Every(period), span);
+            }
+            StmtKind::On(ref device, ref function) => {
+                self.function(function);
+                let name = self.name(device);
+                self.emit(Op::On(name), span);
+            }
+        }
+    }
+
+    /// Makes the `break` statements of the innermost loop jump here
+    /// and records the loop, which started at `start`.
+    fn end_loop(&mut self, start: u32, span: Span) {
+        for jump in self.current().loops.pop().unwrap() {
+            self.patch(jump);
+        }
+        let end = self.here();
+        self.current().proto.loops.push(Loop { start, end, span });
+    }
+
+    /// Compiles a function and emits the instruction creating a closure of it.
+    fn function(&mut self, function: &Function) {
+        let index = self.module.protos.len();
+        self.module.protos.push(Proto::default());
+
+        let mut state = FnState::default();
+        state.proto.name = function.name.clone();
+        state.proto.arity = function.params.len();
+        state.proto.span = function.span;
+        let mut params = HashMap::new();
+        for param in &function.params {
+            // A repeated parameter uses the slot of the last one
+            state.proto.captured.push(false);
+            state.proto.locals.push(Some(Local {
+                name: param.name.clone(),
+                start: 0,
+                end: u32::MAX,
+            }));
+            let slot = self.slot(state.proto.captured.len() - 1, "locals", param.span);
+            params.insert(param.name.clone(), slot);
+        }
+        state.scopes.push(params);
+        self.fns.push(state);
+
+        self.block(&function.body, function.span);
+        self.emit(Op::Nil, function.span);
+        self.emit(Op::Return, function.span);
+
+        let mut proto = self.fns.pop().unwrap().proto;
+        // The parameters are in scope in the whole function
+        let end = proto.code.len() as u32;
+        for local in proto.locals.iter_mut().flatten().filter(|local| local.end == u32::MAX) {
+            local.end = end;
+        }
+        self.module.protos[index] = proto;
+        self.emit(Op::Closure(index as u32), function.span);
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        let span = expr.span;
+        match expr.kind {
+            ExprKind::Int(i) => {
+                let index = self.constant(Value::Int(i));
+                self.emit(Op::Const(index), span);
+            }
+            ExprKind::Real(r) => {
+                let index = self.constant(Value::Real(r));
+                self.emit(Op::Const(index), span);
+            }
+            ExprKind::Str(ref s) => {
+                let index = self.constant(Value::Str(s.clone()));
+                self.emit(Op::Const(index), span);
+            }
+            ExprKind::Bool(b) => {
+                self.emit(if b { Op::True } else { Op::False }, span);
+            }
+            ExprKind::Nil => {
+                self.emit(Op::Nil, span);
+            }
+            ExprKind::Identity(ref name) => {
+                let var = self.var(name, span);
+                self.emit(Op::GetVar(var), span);
+            }
+            ExprKind::Device(ref name) => {
+                let name = self.name(name);
+                self.emit(Op::Device(name), span);
+            }
+            ExprKind::Unary(op, ref operand) => {
+                self.expr(operand);
+                self.emit(Op::Unary(op), span);
+            }
+            ExprKind::Binary(op @ BinaryOp::And, ref lhs, ref rhs) |
+            ExprKind::Binary(op @ BinaryOp::Or, ref lhs, ref rhs) => {
+                // Jumps to the end as soon as the result is known
+                let and = op == BinaryOp::And;
+                let short = if and { Op::JumpIfFalse(0) } else { Op::JumpIfTrue(0) };
+                self.expr(lhs);
+                let first = self.emit(short, span);
+                self.expr(rhs);
+                let second = self.emit(short, span);
+                self.emit(if and { Op::True } else { Op::False }, span);
+                let to_end = self.emit(Op::Jump(0), span);
+                self.patch(first);
+                self.patch(second);
+                self.emit(if and { Op::False } else { Op::True }, span);
+                self.patch(to_end);
+            }
+            ExprKind::Binary(op, ref lhs, ref rhs) => {
+                self.expr(lhs);
+                self.expr(rhs);
+                self.emit(Op::Binary(op), span);
+            }
+            ExprKind::Ternary(ref cond, ref then, ref otherwise) => {
+                self.expr(cond);
+                let to_else = self.emit(Op::JumpIfFalse(0), span);
+                self.expr(then);
+                let to_end = self.emit(Op::Jump(0), span);
+                self.patch(to_else);
+                self.expr(otherwise);
+                self.patch(to_end);
+            }
+            ExprKind::List(ref items) => {
+                for item in items {
+                    self.expr(item);
+                }
+                self.emit(Op::List(items.len() as u32), span);
+            }
+            ExprKind::Index(ref list, ref index) => {
+                self.expr(list);
+                self.expr(index);
+                self.emit(Op::Index, span);
+            }
+            ExprKind::Map(ref entries) => {
+                for (key, value) in entries {
+                    let key = self.constant(Value::Str(key.clone()));
+                    self.emit(Op::Const(key), value.span);
+                    self.expr(value);
+                }
+                self.emit(Op::Map(entries.len() as u32), span);
+            }
+            ExprKind::Field(ref object, ref name) => {
+                self.expr(object);
+                let name = self.name(name);
+                self.emit(Op::Field(name), span);
+            }
+            ExprKind::Call(ref callee, ref args) => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(arg);
+                }
+                let count = self.slot(args.len(), "arguments", span);
+                self.emit(Op::Call(count), span);
+            }
+            ExprKind::Function(ref function) => self.function(function),
+            ExprKind::Range(ref start, ref end, ref step, inclusive) => {
+                self.expr(start);
+                self.expr(end);
+                if let Some(ref step) = *step {
+                    self.expr(step);
+                }
+                self.emit(Op::Range { inclusive, step: step.is_some() }, span);
+            }
+        }
+    }
+}
+
+/// The names declared by the statements of a block, by assigning
+/// to them or declaring a function.
+fn declared_names(block: &[Stmt]) -> Vec<String> {
+    let mut names = Vec::new();
+    for stmt in block {
+        match stmt.kind {
+            StmtKind::Assign(Expr { kind: ExprKind::Identity(ref name), .. }, None, _) => {
+                names.push(name.clone())
+            }
+            StmtKind::Function(ref function) => {
+                names.extend(function.name.clone());
+            }
+            _ => {}
+        }
+    }
+    names
+}
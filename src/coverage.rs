@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use ast::*;
+use span::Span;
+
+/// The way a branch goes when the condition of an `if` or a ternary
+/// holds, when a `while` or `for` loop runs its body again, and when `&`
+/// or `|` evaluates its right-hand side.
+pub const TAKEN: usize = 0;
+/// The other way a branch goes, like leaving a loop.
+pub const NOT_TAKEN: usize = 1;
+
+/// Counts how many times each statement of a program ran, and which way
+/// each of its branches went, see `Interpreter::start_coverage`.
+///
+/// The statements and branches which never ran are known from the
+/// program, so they are reported with a count of zero.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    statements: BTreeMap<Span, u64>,
+    /// The counts of the two ways of each branch, by where it is.
+    branches: BTreeMap<Span, [u64; 2]>,
+}
+
+impl Coverage {
+    /// Prepares to count the statements and branches of `program`,
+    /// including those of its functions.
+    pub fn new(program: &[Stmt]) -> Self {
+        let mut coverage = Coverage::default();
+        coverage.block(program);
+        coverage
+    }
+
+    fn block(&mut self, block: &[Stmt]) {
+        for stmt in block {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        self.statements.insert(stmt.span, 0);
+        match stmt.kind {
+            StmtKind::Expr(ref expr) => self.expr(expr),
+            StmtKind::Assign(ref target, _, ref value) => {
+                self.expr(target);
+                self.expr(value);
+            }
+            StmtKind::If(ref cond, ref then, ref otherwise) => {
+                self.branches.insert(stmt.span, [0; 2]);
+                self.expr(cond);
+                self.block(then);
+                if let Some(ref otherwise) = *otherwise {
+                    self.block(otherwise);
+                }
+            }
+            StmtKind::While(ref cond, ref body) | StmtKind::For(_, ref cond, ref body) => {
+                self.branches.insert(stmt.span, [0; 2]);
+                self.expr(cond);
+                self.block(body);
+            }
+            StmtKind::Break | StmtKind::Return(None) => {}
+            StmtKind::Return(Some(ref value)) => self.expr(value),
+            StmtKind::Function(ref function) | StmtKind::Every(_, ref function) |
+            StmtKind::On(_, ref function) => self.block(&function.body),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr.kind {
+            ExprKind::Int(_) | ExprKind::Real(_) | ExprKind::Str(_) | ExprKind::Bool(_) |
+            ExprKind::Nil | ExprKind::Identity(_) | ExprKind::Device(_) => {}
+            ExprKind::Unary(_, ref operand) | ExprKind::Field(ref operand, _) => self.expr(operand),
+            ExprKind::Binary(op, ref lhs, ref rhs) => {
+                if op == BinaryOp::And || op == BinaryOp::Or {
+                    self.branches.insert(expr.span, [0; 2]);
+                }
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            ExprKind::Ternary(ref cond, ref then, ref otherwise) => {
+                self.branches.insert(expr.span, [0; 2]);
+                self.expr(cond);
+                self.expr(then);
+                self.expr(otherwise);
+            }
+            ExprKind::List(ref items) => {
+                for item in items {
+                    self.expr(item);
+                }
+            }
+            ExprKind::Index(ref list, ref index) => {
+                self.expr(list);
+                self.expr(index);
+            }
+            ExprKind::Map(ref entries) => {
+                for entry in entries {
+                    self.expr(&entry.1);
+                }
+            }
+            ExprKind::Call(ref callee, ref args) => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            ExprKind::Function(ref function) => self.block(&function.body),
+            ExprKind::Range(ref start, ref end, ref step, _) => {
+                self.expr(start);
+                self.expr(end);
+                if let Some(ref step) = *step {
+                    self.expr(step);
+                }
+            }
+        }
+    }
+
+    /// Counts a run of the statement at `span`.
+    pub fn statement(&mut self, span: Span) {
+        *self.statements.entry(span).or_insert(0) += 1;
+    }
+
+    /// Counts the branch at `span` going `way`, `TAKEN` or `NOT_TAKEN`.
+    pub fn branch(&mut self, span: Span, way: usize) {
+        self.branches.entry(span).or_insert([0; 2])[way] += 1;
+    }
+
+    /// The lines with statements and how many times they ran, in order.
+    /// A line with several statements counts the one which ran the most.
+    pub fn lines(&self) -> Vec<(u32, u64)> {
+        let mut lines: BTreeMap<u32, u64> = BTreeMap::new();
+        for (span, &count) in &self.statements {
+            let line = lines.entry(span.line).or_insert(0);
+            *line = (*line).max(count);
+        }
+        lines.into_iter().collect()
+    }
+
+    /// The branches and how many times they went each way, in order.
+    pub fn branches(&self) -> Vec<(Span, [u64; 2])> {
+        self.branches.iter().map(|(&span, &counts)| (span, counts)).collect()
+    }
+
+    /// The coverage in the LCOV format read by coverage tools, as a record
+    /// for the source file at `path`.
+    pub fn lcov(&self, path: &str) -> String {
+        let mut lcov = String::new();
+        let _ = writeln!(lcov, "TN:");
+        let _ = writeln!(lcov, "SF:{}", path);
+        let (mut found, mut hit) = (0, 0);
+        // The branches on a line are told apart by their order on it
+        let mut block = 0;
+        let mut last_line = 0;
+        for (span, counts) in self.branches() {
+            block = if span.line == last_line { block + 1 } else { 0 };
+            last_line = span.line;
+            for (way, &count) in counts.iter().enumerate() {
+                if counts == [0; 2] {
+                    // The branch itself never ran
+                    let _ = writeln!(lcov, "BRDA:{},{},{},-", span.line, block, way);
+                } else {
+                    let _ = writeln!(lcov, "BRDA:{},{},{},{}", span.line, block, way, count);
+                }
+                found += 1;
+                hit += (count > 0) as u32;
+            }
+        }
+        let _ = writeln!(lcov, "BRF:{}", found);
+        let _ = writeln!(lcov, "BRH:{}", hit);
+        let lines = self.lines();
+        for &(line, count) in &lines {
+            let _ = writeln!(lcov, "DA:{},{}", line, count);
+        }
+        let _ = writeln!(lcov, "LF:{}", lines.len());
+        let _ = writeln!(lcov, "LH:{}", lines.iter().filter(|line| line.1 > 0).count());
+        let _ = writeln!(lcov, "end_of_record");
+        lcov
+    }
+}
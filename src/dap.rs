@@ -0,0 +1,279 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+use compiler::{self, Module};
+use debugger::Step;
+use diagnostic::Diagnostic;
+use json::Json;
+use lsp::{read_message, write_message};
+use parser::Parser;
+use value::Value;
+use vm::Vm;
+use error;
+use error::Error::*;
+
+/// The `variablesReference` of the globals. The locals of the frame at
+/// index `i` of the stack are `i + FIRST_FRAME`.
+const GLOBALS: usize = 1;
+const FIRST_FRAME: usize = 2;
+
+/// A Debug Adapter Protocol server for editors, debugging one script
+/// on a `Vm`.
+///
+/// The script is given by the `program` path of `launch` and starts
+/// running on `configurationDone`, stopping before its first statement
+/// if `stopOnEntry` is set. It has a single thread. What it prints is
+/// sent as `output` events.
+#[derive(Default)]
+pub struct Adapter {
+    vm: Vm,
+    module: Option<Rc<Module>>,
+    /// The path of the script.
+    program: String,
+    stop_on_entry: bool,
+    /// The number of the last message sent.
+    seq: usize,
+    exited: bool,
+}
+
+impl Adapter {
+    pub fn new() -> Self {
+        let mut adapter = Adapter::default();
+        adapter.vm.capture_output();
+        adapter
+    }
+
+    /// Whether the client disconnected.
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// Serves the requests of `input` and writes the responses and events
+    /// to `output` until the client disconnects or closes the input.
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        while !self.exited {
+            let content = match read_message(&mut input)? {
+                Some(content) => content,
+                None => break,
+            };
+            let replies = match Json::parse(&content) {
+                Ok(request) => self.handle(&request),
+                Err(e) => {
+                    let response = self.response(&Json::Null, Err(e));
+                    vec![response]
+                }
+            };
+            for reply in replies {
+                write_message(&mut output, &reply.to_string())?;
+            }
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Handles a request, and returns the response followed by the
+    /// events to send.
+    pub fn handle(&mut self, request: &Json) -> Vec<Json> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let args = request.get("arguments").unwrap_or(&Json::Null);
+        let mut events = Vec::new();
+        let body = match command {
+            "initialize" => {
+                events.push(("initialized", Json::Null));
+                Ok(Json::object(vec![("supportsConfigurationDoneRequest", Json::from(true))]))
+            }
+            "launch" => self.launch(args),
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.vm.break_next();
+                }
+                let module = self.module.clone();
+                match module {
+                    Some(module) => {
+                        let result = self.vm.run(module);
+                        self.events(result, "entry", &mut events);
+                        Ok(Json::Null)
+                    }
+                    None => Err("nothing was launched".to_string()),
+                }
+            }
+            "threads" => {
+                let thread = Json::object(vec![("id", Json::from(1)), ("name", Json::from("main"))]);
+                Ok(Json::object(vec![("threads", Json::Array(vec![thread]))]))
+            }
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(scopes(args)),
+            "variables" => Ok(self.variables(args)),
+            "continue" | "next" | "stepIn" | "stepOut" if !self.vm.is_paused() => {
+                Err("the script is not stopped".to_string())
+            }
+            "continue" => {
+                let result = self.vm.resume();
+                self.events(result, "breakpoint", &mut events);
+                Ok(Json::object(vec![("allThreadsContinued", Json::from(true))]))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let step = match command {
+                    "next" => Step::Over,
+                    "stepIn" => Step::In,
+                    _ => Step::Out,
+                };
+                let result = self.vm.step(step);
+                self.events(result, "step", &mut events);
+                Ok(Json::Null)
+            }
+            "disconnect" => {
+                self.exited = true;
+                Ok(Json::Null)
+            }
+            _ => Err(format!("unknown command {}", command)),
+        };
+        let mut replies = vec![self.response(request, body)];
+        for (event, body) in events {
+            replies.push(self.event(event, body));
+        }
+        replies
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let program = args.get("program").and_then(Json::as_str).ok_or("no program to launch")?;
+        let source = fs::read_to_string(program).map_err(|e| format!("{}: {}", program, e))?;
+        let program_ast = Parser::new(&source).parse_program()
+            .map_err(|e| format!("{}:{}", program, Diagnostic::error(e)))?;
+        // Compiled as written, so it stops where the source says
+        let module = compiler::compile(&program_ast)
+            .map_err(|e| format!("{}:{}", program, Diagnostic::error(e)))?;
+        self.module = Some(Rc::new(module));
+        self.program = program.to_string();
+        self.stop_on_entry = args.get("stopOnEntry") == Some(&Json::Bool(true));
+        Ok(Json::Null)
+    }
+
+    /// Replaces the breakpoints. Those on lines without a statement are
+    /// not verified, as the script never stops there.
+    fn set_breakpoints(&mut self, args: &Json) -> Json {
+        for line in self.vm.breakpoints() {
+            self.vm.remove_breakpoint(line);
+        }
+        let lines: Vec<u32> = match args.get("breakpoints") {
+            Some(Json::Array(breakpoints)) => breakpoints.iter()
+                .filter_map(|b| b.get("line").and_then(Json::as_f64))
+                .map(|line| line as u32)
+                .collect(),
+            _ => Vec::new(),
+        };
+        let breakpoints = lines.into_iter().map(|line| {
+            self.vm.set_breakpoint(line);
+            let verified = self.module.as_ref().is_some_and(|module| {
+                module.protos.iter().any(|proto| proto.statements.iter().any(|s| s.1.line == line))
+            });
+            Json::object(vec![("verified", Json::from(verified)), ("line", Json::from(line as usize))])
+        }).collect();
+        Json::object(vec![("breakpoints", Json::Array(breakpoints))])
+    }
+
+    fn stack_trace(&self) -> Json {
+        let source = Json::object(vec![("path", Json::from(self.program.as_str()))]);
+        let frames: Vec<Json> = self.vm.stack().into_iter().enumerate().map(|(i, frame)| {
+            Json::object(vec![("id", Json::from(i)),
+                              ("name", Json::from(frame.name)),
+                              ("line", Json::from(frame.span.line as usize)),
+                              ("column", Json::from(frame.span.column as usize)),
+                              ("source", source.clone())])
+        }).collect();
+        let total = frames.len();
+        Json::object(vec![("stackFrames", Json::Array(frames)), ("totalFrames", Json::from(total))])
+    }
+
+    fn variables(&self, args: &Json) -> Json {
+        let reference = args.get("variablesReference").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+        let vars = match reference {
+            GLOBALS => self.vm.globals(),
+            _ => self.vm.stack().into_iter().nth(reference.wrapping_sub(FIRST_FRAME))
+                .map_or_else(Vec::new, |frame| frame.locals),
+        };
+        let vars = vars.into_iter().map(|(name, value)| {
+            Json::object(vec![("name", Json::from(name)),
+                              ("value", Json::from(show(&value))),
+                              ("type", Json::from(value.type_name())),
+                              ("variablesReference", Json::from(0))])
+        }).collect();
+        Json::object(vec![("variables", Json::Array(vars))])
+    }
+
+    /// The events telling how far the script ran: stopped for `reason`,
+    /// or exited and terminated, after what it printed.
+    fn events(&mut self, result: error::Result<Value>, reason: &str, events: &mut Vec<(&str, Json)>) {
+        let printed = self.vm.take_output();
+        if !printed.is_empty() {
+            events.push(("output", output("stdout", printed)));
+        }
+        let code = match result {
+            Err(Stopped(_)) => {
+                let body = Json::object(vec![("reason", Json::from(reason)),
+                                             ("threadId", Json::from(1)),
+                                             ("allThreadsStopped", Json::from(true))]);
+                events.push(("stopped", body));
+                return;
+            }
+            Ok(_) => 0,
+            Err(e) => {
+                events.push(("output", output("stderr", format!("{}:{}\n", self.program, Diagnostic::error(e)))));
+                1
+            }
+        };
+        events.push(("exited", Json::object(vec![("exitCode", Json::from(code))])));
+        events.push(("terminated", Json::Null));
+    }
+
+    fn response(&mut self, request: &Json, body: Result<Json, String>) -> Json {
+        self.seq += 1;
+        let mut fields = vec![("seq", Json::from(self.seq)),
+                              ("type", Json::from("response")),
+                              ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+                              ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+                              ("success", Json::from(body.is_ok()))];
+        match body {
+            Ok(Json::Null) => {}
+            Ok(body) => fields.push(("body", body)),
+            Err(message) => fields.push(("message", Json::from(message))),
+        }
+        Json::object(fields)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Json {
+        self.seq += 1;
+        let mut fields = vec![("seq", Json::from(self.seq)),
+                              ("type", Json::from("event")),
+                              ("event", Json::from(event))];
+        if body != Json::Null {
+            fields.push(("body", body));
+        }
+        Json::object(fields)
+    }
+}
+
+/// The scopes of a stack frame: its locals and the globals.
+fn scopes(args: &Json) -> Json {
+    let frame = args.get("frameId").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+    let scope = |name: &str, reference: usize| {
+        Json::object(vec![("name", Json::from(name)),
+                          ("variablesReference", Json::from(reference)),
+                          ("expensive", Json::from(false))])
+    };
+    Json::object(vec![("scopes", Json::Array(vec![scope("Locals", frame + FIRST_FRAME), scope("Globals", GLOBALS)]))])
+}
+
+fn output(category: &str, output: String) -> Json {
+    Json::object(vec![("category", Json::from(category)), ("output", Json::from(output))])
+}
+
+/// A value as shown by a debugger, with strings quoted.
+fn show(value: &Value) -> String {
+    match *value {
+        Value::Str(ref s) => format!("{:?}", s),
+        ref value => value.to_string(),
+    }
+}
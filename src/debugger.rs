@@ -0,0 +1,105 @@
+use std::collections::BTreeSet;
+
+use span::Span;
+use value::Value;
+
+/// How far `Vm::step` runs a stopped script.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// To the next statement, which may be in a function it calls.
+    In,
+    /// To the next statement of the same function, or of its caller
+    /// once it returns.
+    Over,
+    /// To the next statement of the caller.
+    Out,
+}
+
+/// A call being run, as seen by a debugger.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    /// The name of the function, `<main>` for the script itself and
+    /// `<anonymous>` for a function without one.
+    pub name: String,
+    /// The statement the script stopped at for the innermost call, and
+    /// the call being made for the others.
+    pub span: Span,
+    /// The variables of the function in scope, by name. The globals are
+    /// not included, see `Vm::globals`.
+    pub locals: Vec<(String, Value)>,
+}
+
+/// Decides where a `Vm` stops: before the statements on the lines with
+/// a breakpoint and after steps. It does nothing when there are no
+/// breakpoints and no step is being taken.
+#[derive(Debug, Clone, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u32>,
+    /// The step being taken and how many calls deep it started.
+    step: Option<(Step, usize)>,
+    /// How many calls deep the script stopped and at which instruction,
+    /// so it does not stop there again when it continues.
+    stopped_at: Option<(usize, usize)>,
+}
+
+impl Debugger {
+    pub fn set_breakpoint(&mut self, line: u32) {
+        self.breakpoints.insert(line);
+    }
+
+    pub fn remove_breakpoint(&mut self, line: u32) {
+        self.breakpoints.remove(&line);
+    }
+
+    /// The lines with a breakpoint, in order.
+    pub fn breakpoints(&self) -> Vec<u32> {
+        self.breakpoints.iter().cloned().collect()
+    }
+
+    /// Whether the script may have to stop, so the statements it runs
+    /// need to be checked with `stops`.
+    pub fn is_active(&self) -> bool {
+        !self.breakpoints.is_empty() || self.step.is_some() || self.stopped_at.is_some()
+    }
+
+    /// Takes `step` from a script stopped `depth` calls deep.
+    pub fn step(&mut self, step: Step, depth: usize) {
+        self.step = Some((step, depth));
+    }
+
+    /// Stops before the next statement, wherever it is.
+    pub fn break_next(&mut self) {
+        self.step = Some((Step::In, 0));
+    }
+
+    /// Forgets where the script stopped, as another one is starting.
+    pub fn forget_stop(&mut self) {
+        self.stopped_at = None;
+    }
+
+    /// Forgets the step being taken, as the script ended.
+    pub fn finish(&mut self) {
+        self.step = None;
+        self.stopped_at = None;
+    }
+
+    /// Whether to stop before the statement starting at instruction `ip`
+    /// on `line`, `depth` calls deep.
+    pub fn stops(&mut self, depth: usize, ip: usize, line: u32) -> bool {
+        if self.stopped_at.take() == Some((depth, ip)) {
+            return false;
+        }
+        let stepped = match self.step {
+            Some((Step::In, _)) => true,
+            Some((Step::Over, from)) => depth <= from,
+            Some((Step::Out, from)) => depth < from,
+            None => false,
+        };
+        if stepped || self.breakpoints.contains(&line) {
+            self.step = None;
+            self.stopped_at = Some((depth, ip));
+            return true;
+        }
+        false
+    }
+}
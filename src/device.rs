@@ -0,0 +1,71 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use span::Span;
+use value::Value;
+use error;
+use error::Error::*;
+
+/// The devices and channels a script can reference with `@name`.
+/// It is implemented by the program embedding the interpreter,
+/// e.g. to give the scripts access to the sensors of the device.
+///
+/// A name is the dotted path written after the `@`,
+/// so `@accel.x` is looked up as `"accel.x"`.
+pub trait DeviceRegistry {
+    /// Returns `true` if `name` is a registered device or channel.
+    fn contains(&self, name: &str) -> bool;
+
+    /// The names of the devices and channels, so scripts can be checked
+    /// before they run, see `Resolver::declare_device`.
+    fn names(&self) -> Vec<String>;
+
+    /// Reads the current value of the device or channel `name`.
+    /// Returns `None` if the device could not be read.
+    fn read(&mut self, name: &str) -> Option<Value>;
+}
+
+/// Resolves the device reference `@name` in `devices` and reads its value.
+pub fn read_device(devices: Option<&Rc<RefCell<dyn DeviceRegistry>>>, name: &str, span: Span)
+                   -> error::Result<Value> {
+    let devices = match devices {
+        Some(devices) if devices.borrow().contains(name) => devices,
+        _ => return Err(UnknownDevice(name.to_string(), span)),
+    };
+    let value = devices.borrow_mut().read(name);
+    value.ok_or_else(|| DeviceUnavailable(name.to_string(), span))
+}
+
+/// A `DeviceRegistry` holding a fixed value for each device.
+/// Useful for testing scripts and for values set by the host.
+#[derive(Debug, Default, Clone)]
+pub struct DeviceMap {
+    devices: HashMap<String, Value>,
+}
+
+impl DeviceMap {
+    pub fn new() -> Self {
+        DeviceMap::default()
+    }
+
+    /// Registers the device `name`, or updates its value if it is
+    /// already registered.
+    pub fn insert<V: Into<Value>>(&mut self, name: &str, value: V) {
+        self.devices.insert(name.to_string(), value.into());
+    }
+}
+
+impl DeviceRegistry for DeviceMap {
+    fn contains(&self, name: &str) -> bool {
+        self.devices.contains_key(name)
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.devices.keys().cloned().collect()
+    }
+
+    fn read(&mut self, name: &str) -> Option<Value> {
+        self.devices.get(name).cloned()
+    }
+}
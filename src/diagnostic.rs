@@ -0,0 +1,151 @@
+use std::fmt;
+
+use span::Span;
+use error::Error;
+
+/// Something suspicious in a script which does not stop it from running.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// A variable, parameter or function which is never used.
+    UnusedVariable(String, Span),
+    /// A declaration hiding a variable declared at the second span,
+    /// or a builtin function or a name declared by the host if there
+    /// is none.
+    Shadowing(String, Span, Option<Span>),
+}
+
+impl Warning {
+    /// A short description of the warning.
+    pub fn description(&self) -> &str {
+        match *self {
+            Warning::UnusedVariable(..) => "unused variable",
+            Warning::Shadowing(..) => "shadowed variable",
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match *self {
+            Warning::UnusedVariable(_, span) |
+            Warning::Shadowing(_, span, _) => span,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::UnusedVariable(ref name, _) => write!(f, "{} {}", self.description(), name),
+            Warning::Shadowing(ref name, _, Some(previous)) => {
+                write!(f, "{} hides the variable declared at {}", name, previous)
+            }
+            Warning::Shadowing(ref name, _, None) => {
+                write!(f, "{} hides a builtin or host name", name)
+            }
+        }
+    }
+}
+
+/// What a `Diagnostic` reports.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticKind {
+    Error(Error),
+    Warning(Warning),
+}
+
+/// An error or a warning found by checking a script without running it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    /// A note on how to fix the problem, like a name it may be a typo of.
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(error: Error) -> Self {
+        Diagnostic {
+            kind: DiagnosticKind::Error(error),
+            help: None,
+        }
+    }
+
+    pub fn warning(warning: Warning) -> Self {
+        Diagnostic {
+            kind: DiagnosticKind::Warning(warning),
+            help: None,
+        }
+    }
+
+    /// Adds the help note suggesting `name` if there is one.
+    pub fn suggest(mut self, name: Option<&str>) -> Self {
+        self.help = name.map(|name| format!("did you mean `{}`?", name));
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self.kind, DiagnosticKind::Error(_))
+    }
+
+    /// Where in the source the problem is. Errors from the lexer
+    /// found while parsing are reported with their position.
+    pub fn span(&self) -> Span {
+        match self.kind {
+            DiagnosticKind::Error(ref e) => e.span().unwrap_or_default(),
+            DiagnosticKind::Warning(ref w) => w.span(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            DiagnosticKind::Error(ref e) => write!(f, "{}: error: {}", self.span(), e)?,
+            DiagnosticKind::Warning(ref w) => write!(f, "{}: warning: {}", self.span(), w)?,
+        }
+        match self.help {
+            Some(ref help) => write!(f, "\n  help: {}", help),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Finds the candidate `name` is most likely a typo of: the closest one
+/// by the number of characters inserted, removed, replaced or swapped
+/// with their neighbour, if it is close enough. The first one wins a tie.
+pub fn suggest<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+    where I: IntoIterator<Item = &'a str>
+{
+    // One edit for every three characters, so short names don't match everything
+    let limit = (name.chars().count() / 3).max(1);
+    let mut best: Option<(usize, &str)> = None;
+    for candidate in candidates {
+        if candidate == name {
+            continue;
+        }
+        let distance = edit_distance(name, candidate);
+        if distance <= limit && best.is_none_or(|b| distance < b.0) {
+            best = Some((distance, candidate));
+        }
+    }
+    best.map(|b| b.1)
+}
+
+/// The optimal string alignment distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // Three rows of the table: the two before the current one
+    let mut before: Vec<usize> = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            current[j] = (previous[j] + 1).min(current[j - 1] + 1).min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        before = ::std::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
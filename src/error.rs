@@ -2,6 +2,8 @@ use std::fmt;
 use std::num;
 use std::error::Error as StdError;
 
+use tokens::Token;
+use span::Span;
 use error::Error::*;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -9,6 +11,7 @@ pub type Result<T> = ::std::result::Result<T, Error>;
 #[derive(Debug, Clone, PartialEq)]
 /// The error types of the interpreter.
 pub enum Error {
+    // Lexer errors
     InfiniteString,
     StringEOL,
     LargeInt,
@@ -16,6 +19,165 @@ pub enum Error {
     Illegal(char),
     UnknownEscape(char),
     ParseIntError(num::ParseIntError),
+
+    // Parser errors
+    /// A lexer error found while parsing.
+    Lexical(Box<Error>, Span),
+    UnexpectedToken(Token, Span),
+    /// The first `Token` is the expected one, the second the one found.
+    ExpectedToken(Token, Token, Span),
+    InvalidAssignment(Span),
+    /// An `every` handler with a period of zero.
+    ZeroPeriod(Span),
+    /// A type annotation naming no type.
+    UnknownType(String, Span),
+    /// Expressions or blocks nested deeper than `parser::MAX_DEPTH`.
+    NestedTooDeep(Span),
+
+    // Resolver errors
+    /// A parameter with the same name as an earlier one.
+    DuplicateParameter(String, Span),
+
+    // Compiler errors
+    /// A function with more locals or captures, or a call with more
+    /// arguments, than the bytecode can number. The `String` says which.
+    TooMany(String, Span),
+
+    // Runtime errors
+    UndefinedVariable(String, Span),
+    /// The `String` describes the operation and the types involved.
+    TypeMismatch(String, Span),
+    DivisionByZero(Span),
+    Overflow(Span),
+    BreakOutsideLoop(Span),
+    ZeroStep(Span),
+    IndexOutOfBounds(i32, Span),
+    MissingField(String, Span),
+    /// The `String` is the type of the value.
+    NotIterable(String, Span),
+    UnknownDevice(String, Span),
+    DeviceUnavailable(String, Span),
+    ReturnOutsideFunction(Span),
+    /// The `String` is the type of the value called.
+    NotCallable(String, Span),
+    /// The number of arguments expected and the number given.
+    ArityMismatch(usize, usize, Span),
+    /// The script used up its fuel, the span is the loop it was running
+    /// or what it was running if it was not in a loop.
+    OutOfFuel(Span),
+    /// The script ran past its deadline, the span is like for `OutOfFuel`.
+    DeadlineExceeded(Span),
+    /// The `Vm` stopped before the statement at the span, at a
+    /// breakpoint or after a step, see `Vm::step`.
+    Stopped(Span),
+    CallDepthExceeded(Span),
+    HeapLimitExceeded(Span),
+    /// The length of the string.
+    StringTooLong(usize, Span),
+    /// The length of the list.
+    ListTooLong(usize, Span),
+    /// A native function failed, the `String` is its name and message.
+    NativeError(String, Span),
+
+    // Host errors
+    /// An input/output error, the `String` holds the message.
+    Io(String),
+    /// A bad line in a recorded sensor trace.
+    BadTrace(u32, String),
+    /// A sample rate of a sensor trace which is not above zero.
+    BadSampleRate(u32),
+    /// A snapshot that could not be restored, the `String` tells why.
+    BadSnapshot(String),
+}
+
+impl Error {
+    /// A short description of the error.
+    pub fn description(&self) -> &str {
+        match *self {
+            InfiniteString => "infinite string literal",
+            StringEOL => "found newline in string literal",
+            LargeInt => "int literal too big",
+            BadRealLiteral => "could not parse real literal",
+            Illegal(_) => "found illegal character",
+            UnknownEscape(_) => "found unknow escape code",
+            ParseIntError(_) => "could not parse int literal",
+            Lexical(ref e, _) => Error::description(e),
+            UnexpectedToken(..) => "unexpected token",
+            ExpectedToken(..) => "expected token",
+            InvalidAssignment(_) => "invalid assignment target",
+            ZeroPeriod(_) => "period is zero",
+            UnknownType(..) => "unknown type",
+            NestedTooDeep(_) => "nested too deep",
+            DuplicateParameter(..) => "duplicate parameter",
+            TooMany(..) => "too large to compile",
+            UndefinedVariable(..) => "undefined variable",
+            TypeMismatch(..) => "type mismatch",
+            DivisionByZero(_) => "division by zero",
+            Overflow(_) => "arithmetic overflow",
+            BreakOutsideLoop(_) => "break outside of a loop",
+            ZeroStep(_) => "range step is zero",
+            IndexOutOfBounds(..) => "index out of bounds",
+            MissingField(..) => "no such field",
+            NotIterable(..) => "cannot iterate over",
+            UnknownDevice(..) => "unknown device",
+            DeviceUnavailable(..) => "could not read device",
+            ReturnOutsideFunction(_) => "return outside of a function",
+            NotCallable(..) => "cannot call",
+            ArityMismatch(..) => "wrong number of arguments",
+            OutOfFuel(_) => "ran out of fuel",
+            DeadlineExceeded(_) => "deadline exceeded",
+            Stopped(_) => "stopped by the debugger",
+            CallDepthExceeded(_) => "calls nested too deep",
+            HeapLimitExceeded(_) => "out of memory",
+            StringTooLong(..) => "string too long",
+            ListTooLong(..) => "list too long",
+            NativeError(..) => "native function failed",
+            Io(_) => "input/output error",
+            BadTrace(..) => "bad sensor trace",
+            BadSampleRate(_) => "bad sample rate",
+            BadSnapshot(_) => "bad snapshot",
+        }
+    }
+
+    /// Returns where in the source the error happened.
+    /// Errors from the lexer do not know their position, it is
+    /// reported by the `Lexer` together with the error instead.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            Lexical(_, span) |
+            UnexpectedToken(_, span) |
+            ExpectedToken(_, _, span) |
+            InvalidAssignment(span) |
+            ZeroPeriod(span) |
+            UnknownType(_, span) |
+            NestedTooDeep(span) |
+            DuplicateParameter(_, span) |
+            TooMany(_, span) |
+            UndefinedVariable(_, span) |
+            TypeMismatch(_, span) |
+            DivisionByZero(span) |
+            Overflow(span) |
+            BreakOutsideLoop(span) |
+            ZeroStep(span) |
+            IndexOutOfBounds(_, span) |
+            MissingField(_, span) |
+            NotIterable(_, span) |
+            UnknownDevice(_, span) |
+            DeviceUnavailable(_, span) |
+            ReturnOutsideFunction(span) |
+            NotCallable(_, span) |
+            ArityMismatch(_, _, span) |
+            OutOfFuel(span) |
+            DeadlineExceeded(span) |
+            Stopped(span) |
+            CallDepthExceeded(span) |
+            HeapLimitExceeded(span) |
+            StringTooLong(_, span) |
+            ListTooLong(_, span) |
+            NativeError(_, span) => Some(span),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -29,27 +191,94 @@ impl fmt::Display for Error {
                        c.escape_default().collect::<String>())
             }
             ParseIntError(ref e) => fmt::Display::fmt(e, f),
+            Lexical(ref e, _) => fmt::Display::fmt(e, f),
+            UnexpectedToken(ref t, _) => write!(f, "{} {:?}", self.description(), t),
+            ExpectedToken(ref expected, ref found, _) => {
+                write!(f, "expected {:?}, found {:?}", expected, found)
+            }
+            UndefinedVariable(ref name, _) |
+            DuplicateParameter(ref name, _) |
+            UnknownType(ref name, _) |
+            MissingField(ref name, _) |
+            NotIterable(ref name, _) |
+            NotCallable(ref name, _) => write!(f, "{} {}", self.description(), name),
+            UnknownDevice(ref name, _) |
+            DeviceUnavailable(ref name, _) => write!(f, "{} @{}", self.description(), name),
+            ArityMismatch(expected, found, _) => {
+                write!(f, "{}: expected {}, found {}", self.description(), expected, found)
+            }
+            StringTooLong(len, _) => write!(f, "{}: {} bytes", self.description(), len),
+            ListTooLong(len, _) => write!(f, "{}: {} items", self.description(), len),
+            IndexOutOfBounds(index, _) => write!(f, "{}: {}", self.description(), index),
+            TooMany(ref what, _) => write!(f, "{}: too many {}", self.description(), what),
+            TypeMismatch(ref msg, _) |
+            NativeError(ref msg, _) |
+            BadSnapshot(ref msg) |
+            Io(ref msg) => write!(f, "{}: {}", self.description(), msg),
+            BadTrace(line, ref msg) => write!(f, "{} at line {}: {}", self.description(), line, msg),
+            BadSampleRate(rate) => write!(f, "{}: {}", self.description(), rate),
             _ => f.write_str(self.description()),
         }
     }
 }
 
-impl StdError for Error {
-    fn description(&self) -> &str {
+impl StdError for Error {}
+
+impl From<num::ParseIntError> for Error {
+    fn from(err: num::ParseIntError) -> Self {
+        ParseIntError(err)
+    }
+}
+
+/// An error from calling a script function from the host, see
+/// `Interpreter::call`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallError {
+    /// There is no global variable or function with the name.
+    UndefinedFunction(String),
+    /// The global is not a function, the second `String` is its type.
+    NotAFunction(String, String),
+    /// The `Vm` is paused in the middle of a script, which must be
+    /// resumed or dropped by running another script first.
+    Paused,
+    /// The function failed while running.
+    Runtime(Error),
+}
+
+impl CallError {
+    /// The runtime error, if the function failed while running.
+    pub fn runtime(&self) -> Option<&Error> {
         match *self {
-            InfiniteString => "infinite string literal",
-            StringEOL => "found newline in string literal",
-            LargeInt => "int literal too big",
-            BadRealLiteral => "could not parse real literal",
-            Illegal(_) => "found illegal character",
-            UnknownEscape(_) => "found unknow escape code",
-            ParseIntError(ref e) => e.description(),
+            CallError::Runtime(ref e) => Some(e),
+            _ => None,
         }
     }
 }
 
-impl From<num::ParseIntError> for Error {
-    fn from(err: num::ParseIntError) -> Self {
-        ParseIntError(err)
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CallError::UndefinedFunction(ref name) => write!(f, "undefined function {}", name),
+            CallError::NotAFunction(ref name, ref type_name) => {
+                write!(f, "cannot call {}: it is a {}", name, type_name)
+            }
+            CallError::Paused => f.write_str("a paused script is waiting to be resumed"),
+            CallError::Runtime(ref e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl StdError for CallError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            CallError::Runtime(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for CallError {
+    fn from(err: Error) -> Self {
+        CallError::Runtime(err)
     }
 }
@@ -0,0 +1,854 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use ast::*;
+use budget::Budget;
+use coverage::{Coverage, NOT_TAKEN, TAKEN};
+use builtins::{Builtin, Host, Output};
+use native::{IntoNative, Native, Natives};
+use device::{self, DeviceRegistry};
+use limits::{Heap, Limits, Measure};
+use parser::Parser;
+use optimizer;
+use resolver::Resolver;
+use scheduler::Scheduler;
+use snapshot::{Kind, Reader, Shared, Writer};
+use span::Span;
+use value::Value;
+use ops;
+use error;
+use error::Error::*;
+use error::CallError;
+
+/// A scope holding variables. Every block creates a new scope
+/// whose parent is the enclosing scope.
+#[derive(Debug, Default)]
+struct Env {
+    vars: RefCell<HashMap<String, Value>>,
+    parent: Option<Rc<Env>>,
+}
+
+impl Env {
+    /// Creates a new scope inside `parent`.
+    fn child(parent: &Rc<Env>) -> Rc<Env> {
+        Rc::new(Env {
+            vars: RefCell::new(HashMap::new()),
+            parent: Some(parent.clone()),
+        })
+    }
+
+    /// Looks up the variable `name` in this scope or any enclosing scope.
+    /// A declared function is given a strong reference to its scope, as
+    /// it may outlive it once read.
+    fn get(&self, name: &str) -> Option<Value> {
+        match self.vars.borrow().get(name) {
+            Some(Value::Function(closure)) if closure.is_declared() => {
+                Some(Value::Function(Rc::new(Closure {
+                    function: closure.function.clone(),
+                    env: Scope::Strong(closure.env()),
+                })))
+            }
+            Some(value) => Some(value.clone()),
+            None => self.parent.as_ref().and_then(|parent| parent.get(name)),
+        }
+    }
+
+    /// Assigns to the variable `name` in the closest scope it is declared in.
+    /// If it is not declared, it is declared in this scope.
+    fn assign(&self, name: &str, value: Value) {
+        if let Err(value) = self.assign_existing(name, value) {
+            self.vars.borrow_mut().insert(name.to_string(), value);
+        }
+    }
+
+    /// Assigns to an already declared variable, or gives back
+    /// `value` if there is none.
+    fn assign_existing(&self, name: &str, value: Value) -> Result<(), Value> {
+        if let Some(slot) = self.vars.borrow_mut().get_mut(name) {
+            *slot = value;
+            return Ok(());
+        }
+        match self.parent {
+            Some(ref parent) => parent.assign_existing(name, value),
+            None => Err(value),
+        }
+    }
+
+    /// Measures the variables of this scope and the enclosing scopes.
+    fn measure(env: &Rc<Env>, measure: &mut Measure) {
+        if !measure.first_visit(env) {
+            return;
+        }
+        for (name, value) in env.vars.borrow().iter() {
+            measure.add(name.len());
+            measure.value(value);
+        }
+        if let Some(ref parent) = env.parent {
+            Env::measure(parent, measure);
+        }
+    }
+
+    /// Writes this scope and the enclosing scopes to a snapshot.
+    /// The parent is written first, as it cannot change.
+    fn write(env: &Rc<Env>, writer: &mut Writer) {
+        writer.bool(env.parent.is_some());
+        if let Some(ref parent) = env.parent {
+            Env::write(parent, writer);
+        }
+        if writer.shared(env) {
+            writer.vars(env.vars.borrow().iter());
+        }
+    }
+
+    /// Reads a scope written by `write`.
+    fn read(reader: &mut Reader) -> error::Result<Rc<Env>> {
+        let parent = if reader.bool()? { Some(reader.nested(Env::read)?) } else { None };
+        match reader.shared()? {
+            Shared::Old(env) => Ok(env),
+            Shared::New(id) => {
+                let env = Rc::new(Env {
+                    vars: RefCell::new(HashMap::new()),
+                    parent,
+                });
+                reader.define(id, env.clone());
+                for (name, value) in reader.vars()? {
+                    env.vars.borrow_mut().insert(name, value);
+                }
+                Ok(env)
+            }
+        }
+    }
+}
+
+/// The scope a closure was created in.
+enum Scope {
+    Strong(Rc<Env>),
+    /// The scope of a declared function, which holds the function. The
+    /// reference is weak so the two do not keep each other alive, and
+    /// the function is only reached through the scope, see `Env::get`.
+    Declared(Weak<Env>),
+}
+
+/// A function value. It keeps the scope the function was created in
+/// alive, so the function sees the variables of that scope as they are
+/// when it is called, not as they were when it was created.
+pub struct Closure {
+    pub function: Rc<Function>,
+    env: Scope,
+}
+
+impl Closure {
+    /// The scope the closure was created in.
+    fn env(&self) -> Rc<Env> {
+        match self.env {
+            Scope::Strong(ref env) => env.clone(),
+            Scope::Declared(ref env) => env.upgrade().expect("a declared function outlived its scope"),
+        }
+    }
+
+    fn is_declared(&self) -> bool {
+        matches!(self.env, Scope::Declared(_))
+    }
+
+    /// Measures the variables the closure can see. Those of a declared
+    /// function are measured with the scope holding it.
+    pub fn measure(&self, measure: &mut Measure) {
+        if let Scope::Strong(ref env) = self.env {
+            Env::measure(env, measure);
+        }
+    }
+
+    /// Writes a closure to a snapshot, see `snapshot`.
+    pub fn write(closure: &Rc<Closure>, writer: &mut Writer) {
+        writer.function(&closure.function);
+        writer.bool(closure.is_declared());
+        Env::write(&closure.env(), writer);
+        writer.shared(closure);
+    }
+
+    /// Reads a closure written by `write`.
+    ///
+    /// A declared function is only held by its scope, under its name, and
+    /// refers back to it weakly. So it is refused if it is read twice, or
+    /// if its scope does not hold it once the snapshot is read, as it could
+    /// then outlive its scope.
+    pub fn read(reader: &mut Reader) -> error::Result<Rc<Closure>> {
+        let function = reader.function()?;
+        let declared = reader.bool()?;
+        let env = Env::read(reader)?;
+        match reader.shared::<Closure>()? {
+            Shared::Old(ref closure) if closure.is_declared() => {
+                Err(BadSnapshot("a declared function is referred to twice".to_string()))
+            }
+            Shared::Old(closure) => Ok(closure),
+            Shared::New(id) if declared => {
+                let closure = Rc::new(Closure {
+                    function,
+                    env: Scope::Declared(Rc::downgrade(&env)),
+                });
+                reader.define(id, closure.clone());
+                let held = closure.clone();
+                reader.check(move || {
+                    let name = held.function.name.clone().unwrap_or_default();
+                    match env.vars.borrow().get(&name) {
+                        Some(Value::Function(ref closure)) if Rc::ptr_eq(closure, &held) => Ok(()),
+                        _ => Err(BadSnapshot(format!("the declared function {} is not held by its scope", name))),
+                    }
+                });
+                Ok(closure)
+            }
+            Shared::New(id) => {
+                let closure = Rc::new(Closure { function, env: Scope::Strong(env) });
+                reader.define(id, closure.clone());
+                Ok(closure)
+            }
+        }
+    }
+}
+
+/// Two closures are equal only if they are the same function created in
+/// the same scope, as a declared function is read as a new closure.
+impl PartialEq for Closure {
+    fn eq(&self, other: &Closure) -> bool {
+        Rc::ptr_eq(&self.function, &other.function) && Rc::ptr_eq(&self.env(), &other.env())
+    }
+}
+
+/// The scope is left out, as it may hold the closure itself.
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Closure").field("function", &self.function.name).finish()
+    }
+}
+
+/// What to do after a statement has been executed.
+enum Flow {
+    Next,
+    Break(Span),
+    Return(Value, Span),
+}
+
+/// A tree-walking evaluator for the scripts.
+#[derive(Default)]
+pub struct Interpreter {
+    globals: Rc<Env>,
+    devices: Option<Rc<RefCell<dyn DeviceRegistry>>>,
+    natives: Natives,
+    scheduler: Scheduler,
+    output: Output,
+    budget: Budget,
+    /// The spans of the loops being executed, innermost last.
+    loops: Vec<Span>,
+    heap: Heap,
+    /// The number of script functions being called.
+    depth: usize,
+    /// The scopes of the blocks being executed, innermost last.
+    scopes: Vec<Rc<Env>>,
+    coverage: Option<Coverage>,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter::default()
+    }
+
+    /// Gives the scripts access to the devices in `devices` through `@name`.
+    /// The host keeps its own handle to update the devices between runs.
+    pub fn set_devices(&mut self, devices: Rc<RefCell<dyn DeviceRegistry>>) {
+        self.devices = Some(devices);
+    }
+
+    /// Makes the Rust closure `f` callable from scripts as `name`.
+    /// Like the builtin functions, it can be hidden by a variable, and
+    /// it replaces a builtin function or native function of the same
+    /// name. See `native::Native` for the types it can take and return.
+    pub fn register_fn<Args, F: IntoNative<Args>>(&mut self, name: &str, f: F) {
+        self.natives.insert(name.to_string(), Rc::new(Native::new(name, f)));
+    }
+
+    /// Collects the output of `print` instead of writing it to the
+    /// standard output, see `take_output`.
+    pub fn capture_output(&mut self) {
+        self.output = Output::Buffer(String::new());
+    }
+
+    /// Returns the output collected since the last call, if it is
+    /// being collected.
+    pub fn take_output(&mut self) -> String {
+        match self.output {
+            Output::Buffer(ref mut buf) => ::std::mem::take(buf),
+            Output::Stdout => String::new(),
+        }
+    }
+
+    /// Limits the number of statements and expressions evaluated to `fuel`,
+    /// or removes the limit. Running out of fuel stops the script with
+    /// `OutOfFuel`, and the fuel left carries over to the next run. A
+    /// stopped script cannot be continued, the `Vm` is needed for that.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.budget.set_fuel(fuel);
+    }
+
+    /// The fuel left, or `None` if there is no limit.
+    pub fn fuel(&self) -> Option<u64> {
+        self.budget.fuel()
+    }
+
+    /// Stops scripts running past `deadline` with `DeadlineExceeded`,
+    /// or removes the deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.budget.set_deadline(deadline);
+    }
+
+    /// Sets the limits on memory and recursion.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.heap.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.heap.limits
+    }
+
+    /// Starts counting which statements and branches of `program` run,
+    /// see `coverage`. The program is run with `run`, as `eval` runs
+    /// the program given by `optimizer::optimize`, which may move or
+    /// drop statements.
+    pub fn start_coverage(&mut self, program: &[Stmt]) {
+        self.coverage = Some(Coverage::new(program));
+    }
+
+    /// The coverage being counted, if any.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Stops counting the coverage, and returns it.
+    pub fn stop_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    /// Parses, optimizes and runs `source`, see `optimizer::optimize`
+    /// and `run`.
+    pub fn eval(&mut self, source: &str) -> error::Result<Value> {
+        let program = Parser::new(source).parse_program()?;
+        self.run(&optimizer::optimize(&program).0)
+    }
+
+    /// Runs a program in the global scope. Variables assigned at the top level
+    /// are kept for the next run. Returns the value of the last statement
+    /// if it is an expression, or `nil` otherwise. A program reading a
+    /// device the host does not have fails with `UnknownDevice` before
+    /// it runs.
+    pub fn run(&mut self, program: &[Stmt]) -> error::Result<Value> {
+        self.resolver().check_devices(program)?;
+        self.loops.clear();
+        self.scopes.clear();
+        self.depth = 0;
+        self.heap.reset();
+        let globals = self.globals.clone();
+        let mut last = Value::Nil;
+        for stmt in program {
+            last = Value::Nil;
+            if let StmtKind::Expr(ref expr) = stmt.kind {
+                self.cover(stmt.span);
+                last = self.eval_expr(expr, &globals)?;
+            } else {
+                match self.exec(stmt, &globals)? {
+                    Flow::Next => {}
+                    Flow::Break(span) => return Err(BreakOutsideLoop(span)),
+                    Flow::Return(_, span) => return Err(ReturnOutsideFunction(span)),
+                }
+            }
+        }
+        Ok(last)
+    }
+
+    /// Calls the global function `name` with `args`, like a script
+    /// calling `name(args)`. Errors in the call itself, like a wrong
+    /// number of arguments, are reported at the function's definition.
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, CallError> {
+        let globals = self.globals.clone();
+        let callee = self.lookup(name, &globals)
+            .ok_or_else(|| CallError::UndefinedFunction(name.to_string()))?;
+        if callee.type_name() != "fn" {
+            return Err(CallError::NotAFunction(name.to_string(), callee.type_name().to_string()));
+        }
+        Ok(self.call_from_host(callee, args)?)
+    }
+
+    /// Advances the virtual clock by `by`, running the `every` handlers
+    /// due in order. If a handler fails, the clock stays at the time it
+    /// was due and the handlers due after it are left for the next call.
+    pub fn advance(&mut self, by: Duration) -> Result<(), CallError> {
+        let until = self.scheduler.now() + by;
+        while let Some(handler) = self.scheduler.next_due(until) {
+            self.call_from_host(handler, Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// Runs the `on @device` handlers of `device` in the order they
+    /// were registered, for example when the device has a new reading.
+    pub fn signal(&mut self, device: &str) -> Result<(), CallError> {
+        for handler in self.scheduler.handlers(device) {
+            self.call_from_host(handler, Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// The time on the virtual clock, see `advance`.
+    pub fn now(&self) -> Duration {
+        self.scheduler.now()
+    }
+
+    /// Calls a function from outside of any script. Errors in the call
+    /// itself are reported at the function's definition.
+    fn call_from_host(&mut self, callee: Value, args: Vec<Value>) -> error::Result<Value> {
+        let span = match callee {
+            Value::Function(ref closure) => closure.function.span,
+            _ => Span::default(),
+        };
+        self.loops.clear();
+        self.scopes.clear();
+        self.depth = 0;
+        self.heap.reset();
+        self.call_value(callee, args, span)
+    }
+
+    /// Saves the global variables, the handlers and every value they
+    /// refer to, so they can be restored later with `restore`, see
+    /// `snapshot`.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut writer = Writer::new(Kind::Interpreter);
+        Env::write(&self.globals, &mut writer);
+        self.scheduler.write(&mut writer);
+        writer.finish()
+    }
+
+    /// Replaces the global variables and the handlers with those saved
+    /// in `snapshot`.
+    /// The native functions the values refer to are looked up by name,
+    /// so they must be registered before.
+    pub fn restore(&mut self, snapshot: &[u8]) -> error::Result<()> {
+        let mut reader = Reader::new(snapshot, Kind::Interpreter, &self.natives)?;
+        let globals = Env::read(&mut reader)?;
+        let scheduler = Scheduler::read(&mut reader)?;
+        reader.finish()?;
+        if globals.parent.is_some() {
+            return Err(BadSnapshot("the globals are not a global scope".to_string()));
+        }
+        self.globals = globals;
+        self.scheduler = scheduler;
+        Ok(())
+    }
+
+    /// A resolver which knows the native functions, the global variables
+    /// and the devices of the host, to check a script before running it,
+    /// see `Resolver::check`.
+    pub fn resolver(&self) -> Resolver {
+        let mut resolver = Resolver::new();
+        for name in self.globals.vars.borrow().keys() {
+            resolver.declare_host(name);
+        }
+        for native in self.natives.values() {
+            resolver.declare_native(native.name(), native.arity());
+        }
+        if let Some(ref devices) = self.devices {
+            for name in devices.borrow().names() {
+                resolver.declare_device(&name);
+            }
+        }
+        resolver
+    }
+
+    /// The value of the global variable `name`.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name)
+    }
+
+    /// Assigns `value` to the global variable `name`, declaring it if needed.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.vars.borrow_mut().insert(name.to_string(), value);
+    }
+
+    /// Looks up a variable, then the native and builtin functions.
+    fn lookup(&self, name: &str, env: &Env) -> Option<Value> {
+        env.get(name)
+            .or_else(|| self.natives.get(name).cloned().map(Value::Native))
+            .or_else(|| Builtin::lookup(name).map(Value::Builtin))
+    }
+
+    /// Executes the statements of a block in a new scope.
+    fn exec_block(&mut self, block: &[Stmt], env: &Rc<Env>) -> error::Result<Flow> {
+        let env = Env::child(env);
+        self.scopes.push(env.clone());
+        let flow = self.exec_stmts(block, &env);
+        self.scopes.pop();
+        flow
+    }
+
+    fn exec_stmts(&mut self, block: &[Stmt], env: &Rc<Env>) -> error::Result<Flow> {
+        for stmt in block {
+            match self.exec(stmt, env)? {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Counts the memory of a value just created at `span`, see `Heap`.
+    fn track(&mut self, value: &Value, span: Span) -> error::Result<()> {
+        if self.heap.alloc(value, span)? {
+            self.collect(Some(value), span)?;
+        }
+        Ok(())
+    }
+
+    /// Counts the memory of a value just created at `span` and returns it.
+    fn alloc(&mut self, value: Value, span: Span) -> error::Result<Value> {
+        self.track(&value, span)?;
+        Ok(value)
+    }
+
+    /// Measures the memory of the values the script can reach,
+    /// together with `value` which may not be stored yet.
+    fn collect(&mut self, value: Option<&Value>, span: Span) -> error::Result<()> {
+        let mut measure = Measure::new();
+        Env::measure(&self.globals, &mut measure);
+        for env in &self.scopes {
+            Env::measure(env, &mut measure);
+        }
+        self.scheduler.measure(&mut measure);
+        if let Some(value) = value {
+            measure.value(value);
+        }
+        self.heap.measured(measure.bytes(), span)
+    }
+
+    /// Takes a step of the budget. The span is used if it ran out
+    /// outside of a loop.
+    fn step(&mut self, span: Span) -> error::Result<()> {
+        let loops = &self.loops;
+        self.budget.step().map_err(|e| e.error(loops.last().cloned().unwrap_or(span)))
+    }
+
+    /// Counts a run of the statement at `span`, if counting the coverage.
+    fn cover(&mut self, span: Span) {
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.statement(span);
+        }
+    }
+
+    /// Counts the branch at `span` going `way`, if counting the coverage.
+    fn cover_branch(&mut self, span: Span, way: usize) {
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.branch(span, way);
+        }
+    }
+
+    fn exec(&mut self, stmt: &Stmt, env: &Rc<Env>) -> error::Result<Flow> {
+        self.step(stmt.span)?;
+        self.cover(stmt.span);
+        match stmt.kind {
+            StmtKind::Expr(ref expr) => {
+                self.eval_expr(expr, env)?;
+            }
+            StmtKind::Assign(ref target, None, ref value) => {
+                let value = self.eval_expr(value, env)?;
+                self.assign(target, value, env)?;
+            }
+            StmtKind::Assign(ref target, Some(op), ref value) => {
+                self.compound_assign(target, op, value, env)?;
+            }
+            StmtKind::If(ref cond, ref then, ref otherwise) => {
+                if self.eval_expr(cond, env)?.is_truthy() {
+                    self.cover_branch(stmt.span, TAKEN);
+                    return self.exec_block(then, env);
+                }
+                self.cover_branch(stmt.span, NOT_TAKEN);
+                if let Some(ref otherwise) = *otherwise {
+                    return self.exec_block(otherwise, env);
+                }
+            }
+            StmtKind::While(..) | StmtKind::For(..) => {
+                self.loops.push(stmt.span);
+                let flow = self.exec_loop(stmt, env);
+                self.loops.pop();
+                return flow;
+            }
+            StmtKind::Break => return Ok(Flow::Break(stmt.span)),
+            StmtKind::Return(ref value) => {
+                let value = match *value {
+                    Some(ref value) => self.eval_expr(value, env)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(value, stmt.span));
+            }
+            StmtKind::Function(ref function) => {
+                // Declared in the current scope, so a function can call itself
+                let closure = Closure {
+                    function: function.clone(),
+                    env: Scope::Declared(Rc::downgrade(env)),
+                };
+                let name = function.name.clone().unwrap_or_default();
+                env.vars.borrow_mut().insert(name, Value::Function(Rc::new(closure)));
+            }
+            StmtKind::Every(period, ref function) => {
+                let handler = Closure {
+                    function: function.clone(),
+                    env: Scope::Strong(env.clone()),
+                };
+                self.scheduler.every(period, Value::Function(Rc::new(handler)));
+            }
+            StmtKind::On(ref device, ref function) => {
+                let handler = Closure {
+                    function: function.clone(),
+                    env: Scope::Strong(env.clone()),
+                };
+                self.scheduler.on(device, Value::Function(Rc::new(handler)));
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Executes a `while` or `for` loop.
+    fn exec_loop(&mut self, stmt: &Stmt, env: &Rc<Env>) -> error::Result<Flow> {
+        match stmt.kind {
+            StmtKind::While(ref cond, ref body) => {
+                while self.eval_expr(cond, env)?.is_truthy() {
+                    self.cover_branch(stmt.span, TAKEN);
+                    match self.exec_block(body, env)? {
+                        Flow::Next => {}
+                        Flow::Break(_) => return Ok(Flow::Next),
+                        flow => return Ok(flow),
+                    }
+                }
+                self.cover_branch(stmt.span, NOT_TAKEN);
+            }
+            StmtKind::For(ref name, ref iterable, ref body) => {
+                let value = self.eval_expr(iterable, env)?;
+                let iter = match value.iter() {
+                    Some(iter) => iter,
+                    None => return Err(NotIterable(value.type_name().to_string(), iterable.span)),
+                };
+                for value in iter {
+                    self.cover_branch(stmt.span, TAKEN);
+                    // Every iteration gets its own scope holding the loop variable
+                    let scope = Env::child(env);
+                    scope.vars.borrow_mut().insert(name.clone(), value);
+                    match self.exec_block(body, &scope)? {
+                        Flow::Next => {}
+                        Flow::Break(_) => return Ok(Flow::Next),
+                        flow => return Ok(flow),
+                    }
+                }
+                self.cover_branch(stmt.span, NOT_TAKEN);
+            }
+            _ => unreachable!("not a loop"),
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Stores `value` in the place denoted by `target`.
+    fn assign(&mut self, target: &Expr, value: Value, env: &Rc<Env>) -> error::Result<()> {
+        match target.kind {
+            ExprKind::Identity(ref name) => {
+                env.assign(name, value);
+                Ok(())
+            }
+            ExprKind::Index(ref list, ref index) => {
+                let list = self.eval_expr(list, env)?;
+                let index = self.eval_expr(index, env)?;
+                ops::set_index(&list, &index, value, target.span)
+            }
+            ExprKind::Field(ref object, ref name) => {
+                let object = self.eval_expr(object, env)?;
+                ops::set_field(&object, name, value, target.span)
+            }
+            _ => Err(InvalidAssignment(target.span)),
+        }
+    }
+
+    /// Performs `target op= value`. The parts of the target are evaluated
+    /// only once, before `value`, so `xs[f()] += 1` calls `f` once.
+    /// The variable must already exist.
+    fn compound_assign(&mut self, target: &Expr, op: BinaryOp, value: &Expr, env: &Rc<Env>)
+                       -> error::Result<()> {
+        match target.kind {
+            ExprKind::Identity(ref name) => {
+                let current = env.get(name)
+                    .ok_or_else(|| UndefinedVariable(name.clone(), target.span))?;
+                let value = self.eval_expr(value, env)?;
+                let value = ops::binary(op, current, value, target.span)?;
+                self.track(&value, target.span)?;
+                env.assign(name, value);
+                Ok(())
+            }
+            ExprKind::Index(ref list, ref index) => {
+                let list = self.eval_expr(list, env)?;
+                let index = self.eval_expr(index, env)?;
+                let current = ops::index(&list, &index, target.span)?;
+                let value = self.eval_expr(value, env)?;
+                let value = ops::binary(op, current, value, target.span)?;
+                self.track(&value, target.span)?;
+                ops::set_index(&list, &index, value, target.span)
+            }
+            ExprKind::Field(ref object, ref name) => {
+                let object = self.eval_expr(object, env)?;
+                let current = ops::field(&object, name, target.span)?;
+                let value = self.eval_expr(value, env)?;
+                let value = ops::binary(op, current, value, target.span)?;
+                self.track(&value, target.span)?;
+                ops::set_field(&object, name, value, target.span)
+            }
+            _ => Err(InvalidAssignment(target.span)),
+        }
+    }
+
+    fn eval_expr(&mut self, expr: &Expr, env: &Rc<Env>) -> error::Result<Value> {
+        self.step(expr.span)?;
+        match expr.kind {
+            ExprKind::Int(i) => Ok(Value::Int(i)),
+            ExprKind::Real(r) => Ok(Value::Real(r)),
+            ExprKind::Str(ref s) => self.alloc(Value::Str(s.clone()), expr.span),
+            ExprKind::Bool(b) => Ok(Value::Bool(b)),
+            ExprKind::Nil => Ok(Value::Nil),
+            ExprKind::Identity(ref name) => {
+                self.lookup(name, env).ok_or_else(|| UndefinedVariable(name.clone(), expr.span))
+            }
+            ExprKind::Device(ref name) => {
+                device::read_device(self.devices.as_ref(), name, expr.span)
+            }
+            ExprKind::Unary(op, ref operand) => {
+                let value = self.eval_expr(operand, env)?;
+                ops::unary(op, value, expr.span)
+            }
+            ExprKind::Binary(BinaryOp::And, ref lhs, ref rhs) => {
+                let lhs = self.eval_expr(lhs, env)?.is_truthy();
+                self.cover_branch(expr.span, if lhs { TAKEN } else { NOT_TAKEN });
+                let value = lhs && self.eval_expr(rhs, env)?.is_truthy();
+                Ok(Value::Bool(value))
+            }
+            ExprKind::Binary(BinaryOp::Or, ref lhs, ref rhs) => {
+                let lhs = self.eval_expr(lhs, env)?.is_truthy();
+                self.cover_branch(expr.span, if lhs { NOT_TAKEN } else { TAKEN });
+                let value = lhs || self.eval_expr(rhs, env)?.is_truthy();
+                Ok(Value::Bool(value))
+            }
+            ExprKind::Binary(op, ref lhs, ref rhs) => {
+                let lhs = self.eval_expr(lhs, env)?;
+                let rhs = self.eval_expr(rhs, env)?;
+                let value = ops::binary(op, lhs, rhs, expr.span)?;
+                self.alloc(value, expr.span)
+            }
+            ExprKind::List(ref items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    values.push(self.eval_expr(item, env)?);
+                }
+                self.alloc(Value::list(values), expr.span)
+            }
+            ExprKind::Index(ref list, ref index) => {
+                let list = self.eval_expr(list, env)?;
+                let index = self.eval_expr(index, env)?;
+                let value = ops::index(&list, &index, expr.span)?;
+                self.alloc(value, expr.span)
+            }
+            ExprKind::Map(ref entries) => {
+                let mut map = BTreeMap::new();
+                for (key, value) in entries {
+                    map.insert(key.clone(), self.eval_expr(value, env)?);
+                }
+                self.alloc(Value::map(map), expr.span)
+            }
+            ExprKind::Field(ref object, ref name) => {
+                let object = self.eval_expr(object, env)?;
+                ops::field(&object, name, expr.span)
+            }
+            ExprKind::Call(ref callee, ref args) => {
+                let callee = self.eval_expr(callee, env)?;
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(self.eval_expr(arg, env)?);
+                }
+                self.call_value(callee, values, expr.span)
+            }
+            ExprKind::Function(ref function) => {
+                let closure = Closure {
+                    function: function.clone(),
+                    env: Scope::Strong(env.clone()),
+                };
+                Ok(Value::Function(Rc::new(closure)))
+            }
+            ExprKind::Range(ref start, ref end, ref step, inclusive) => {
+                let start = self.eval_expr(start, env)?;
+                let end = self.eval_expr(end, env)?;
+                let step = match *step {
+                    Some(ref step) => Some(self.eval_expr(step, env)?),
+                    None => None,
+                };
+                ops::range(start, end, step, inclusive, expr.span)
+            }
+            ExprKind::Ternary(ref cond, ref then, ref otherwise) => {
+                if self.eval_expr(cond, env)?.is_truthy() {
+                    self.cover_branch(expr.span, TAKEN);
+                    self.eval_expr(then, env)
+                } else {
+                    self.cover_branch(expr.span, NOT_TAKEN);
+                    self.eval_expr(otherwise, env)
+                }
+            }
+        }
+    }
+
+    /// Calls a function value with `args`. The `span` is where it is called from.
+    fn call_value(&mut self, callee: Value, args: Vec<Value>, span: Span)
+                  -> error::Result<Value> {
+        let closure = match callee {
+            Value::Function(ref closure) => closure.clone(),
+            Value::Builtin(builtin) => {
+                let value = builtin.call(args, self, span)?;
+                return self.alloc(value, span);
+            }
+            Value::Native(ref native) => {
+                let value = native.call(args, span)?;
+                return self.alloc(value, span);
+            }
+            _ => return Err(NotCallable(callee.type_name().to_string(), span)),
+        };
+        let params = &closure.function.params;
+        if params.len() != args.len() {
+            return Err(ArityMismatch(params.len(), args.len(), span));
+        }
+        self.heap.check_depth(self.depth + 1, span)?;
+        let scope = Env::child(&closure.env());
+        for (param, arg) in params.iter().zip(args) {
+            scope.vars.borrow_mut().insert(param.name.clone(), arg);
+        }
+        self.depth += 1;
+        let flow = self.exec_block(&closure.function.body, &scope);
+        self.depth -= 1;
+        match flow? {
+            Flow::Next => Ok(Value::Nil),
+            Flow::Break(span) => Err(BreakOutsideLoop(span)),
+            Flow::Return(value, _) => Ok(value),
+        }
+    }
+}
+
+impl Host for Interpreter {
+    fn print(&mut self, line: &str) {
+        self.output.write_line(line);
+    }
+
+    fn grow_list(&mut self, len: usize, span: Span) -> error::Result<()> {
+        if self.heap.grow_list(len, span)? {
+            self.collect(None, span)?;
+        }
+        Ok(())
+    }
+}
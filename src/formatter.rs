@@ -0,0 +1,343 @@
+use lexer::Lexer;
+use parser::Parser;
+use tokens::Token;
+use error;
+
+/// The indentation of one level of nesting.
+const INDENT: &str = "    ";
+
+/// Formats a script the one canonical way, which formatting again
+/// leaves as it is.
+///
+/// Every token and comment is kept as written and so are the line
+/// breaks, as they matter to the parser, except that a `{` or `else`
+/// starting a line is moved to the end of the previous one. Lines are
+/// indented by four spaces for each level of brackets opened on the
+/// lines before and not closed, and tokens are spaced the same way
+/// everywhere, with spaces around binary operators. Runs of blank
+/// lines become a single one. Lists, maps and calls have a trailing
+/// comma only when the closing bracket is on its own line, and
+/// parameter lists never have one.
+///
+/// Fails if the script does not parse.
+pub fn format(source: &str) -> error::Result<String> {
+    Parser::new(source).parse_program()?;
+    let mut tokens = Vec::new();
+    let mut lexer = Lexer::with_comments(source);
+    loop {
+        let token = lexer.next_token()?;
+        if token == Token::EndOfFile {
+            break;
+        }
+        let (start, end) = lexer.range();
+        tokens.push(Tok {
+            token,
+            text: source[start..end].to_string(),
+            line: lexer.span().line,
+            end_line: lexer.line(),
+            map: false,
+        });
+    }
+    find_maps(&mut tokens);
+    let tokens = fix_commas(tokens);
+    Ok(render(&join_lines(tokens)))
+}
+
+/// A token with its text and the lines it starts and ends on.
+#[derive(Debug, Clone)]
+struct Tok {
+    token: Token,
+    text: String,
+    line: u32,
+    end_line: u32,
+    /// `true` for the braces of a map rather than a block.
+    map: bool,
+}
+
+/// A line of output, with whether a blank line comes before it.
+struct Line {
+    tokens: Vec<Tok>,
+    blank_before: bool,
+}
+
+/// The kind of bracket a part of a line is nested in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Nesting {
+    Paren,
+    /// The parameters of a function, where `:` starts a type.
+    Params,
+    Square,
+    Block,
+    Map,
+}
+
+/// Marks the braces of map literals, which are followed by a key and a
+/// `:` or are empty, as blocks can only be empty after a statement.
+fn find_maps(tokens: &mut [Tok]) {
+    let mut open = Vec::new();
+    for i in 0..tokens.len() {
+        match tokens[i].token {
+            Token::LeftCurlyParam => {
+                let mut next = tokens[i + 1..].iter().filter(|t| !is_comment(&t.token));
+                let map = match (next.next().map(|t| &t.token), next.next().map(|t| &t.token)) {
+                    (Some(&Token::Identity(_)), Some(&Token::Colon)) |
+                    (Some(&Token::Str(_)), Some(&Token::Colon)) |
+                    (Some(&Token::RawStr(_)), Some(&Token::Colon)) => true,
+                    (Some(&Token::RightCurlyParam), _) => !ends_block_head(tokens[..i].last()),
+                    _ => false,
+                };
+                tokens[i].map = map;
+                open.push(map);
+            }
+            Token::RightCurlyParam => tokens[i].map = open.pop().unwrap_or(false),
+            _ => {}
+        }
+    }
+}
+
+/// Whether a `{` after `token` starts a block rather than a map: after
+/// the condition of an `if` or `while`, the parameters of a function,
+/// `else` or the device of an `on`.
+fn ends_block_head(token: Option<&Tok>) -> bool {
+    // An empty `{}` in an expression like `x = {}` or `f({})` is a map
+    match token.map(|t| &t.token) {
+        None => false,
+        Some(token) => !matches!(*token,
+                                 Token::Assignment | Token::PlusAssignment | Token::Return |
+                                 Token::LeftParam | Token::LeftSquareParam | Token::Comma |
+                                 Token::Colon | Token::QuestionMark) && !is_operator(token),
+    }
+}
+
+/// Drops trailing commas before a `)`, and before a `]` or the `}` of
+/// a map on the same line, and adds one before a `]` or the `}` of a
+/// map starting a line.
+fn fix_commas(tokens: Vec<Tok>) -> Vec<Tok> {
+    let mut fixed: Vec<Tok> = Vec::with_capacity(tokens.len());
+    for tok in tokens {
+        let closes_list = tok.token == Token::RightSquareParam ||
+                          tok.token == Token::RightCurlyParam && tok.map;
+        if tok.token == Token::RightParam || closes_list {
+            // The last token which is not a comment
+            let last = fixed.iter().rposition(|t| !is_comment(&t.token));
+            let own_line = fixed.last().is_some_and(|t| tok.line > t.end_line);
+            if let Some(last) = last {
+                match fixed[last].token {
+                    Token::Comma if tok.token == Token::RightParam || !own_line => {
+                        fixed.remove(last);
+                    }
+                    Token::Comma | Token::LeftSquareParam | Token::LeftCurlyParam => {}
+                    _ if closes_list && own_line => {
+                        let comma = Tok {
+                            token: Token::Comma,
+                            text: ",".to_string(),
+                            line: fixed[last].end_line,
+                            end_line: fixed[last].end_line,
+                            map: false,
+                        };
+                        fixed.insert(last + 1, comma);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        fixed.push(tok);
+    }
+    fixed
+}
+
+/// Splits the tokens into lines as in the source, moving a `{` or
+/// `else` which starts a line to the end of the previous one.
+fn join_lines(tokens: Vec<Tok>) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut end_line = 0;
+    for tok in tokens {
+        let starts_line = lines.is_empty() || tok.line > end_line;
+        let blank_before = tok.line > end_line + 1 && !lines.is_empty();
+        end_line = tok.end_line;
+        if starts_line && !can_join(lines.last(), &tok) {
+            lines.push(Line { tokens: vec![tok], blank_before });
+        } else {
+            lines.last_mut().unwrap().tokens.push(tok);
+        }
+    }
+    lines
+}
+
+/// Whether `tok` starting a line can be moved to the end of `previous`.
+fn can_join(previous: Option<&Line>, tok: &Tok) -> bool {
+    let last = match previous.and_then(|line| line.tokens.last()) {
+        Some(last) => &last.token,
+        None => return false,
+    };
+    match tok.token {
+        Token::Else => *last == Token::RightCurlyParam,
+        // The value of a `return` must start on its line
+        Token::LeftCurlyParam if !tok.map => {
+            !matches!(*last,
+                      Token::Return | Token::Comment(_) | Token::Comma | Token::LeftParam |
+                      Token::LeftSquareParam | Token::LeftCurlyParam)
+        }
+        _ => false,
+    }
+}
+
+/// Writes the lines with their indentation and the spaces between tokens.
+fn render(lines: &[Line]) -> String {
+    let mut out = String::new();
+    // The open brackets with the indentation of the line they are on
+    let mut open: Vec<(Nesting, usize)> = Vec::new();
+    let mut state = State::default();
+    let mut previous: Option<&Tok> = None;
+    for line in lines {
+        let first = &line.tokens[0].token;
+        let indent = match open.last() {
+            Some(&(_, indent)) if is_closing(first) => indent,
+            Some(&(_, indent)) => indent + 1,
+            None => 0,
+        };
+        let after_opening = previous.is_some_and(|t| is_opening(&t.token));
+        if line.blank_before && !after_opening && !is_closing(first) {
+            out.push('\n');
+        }
+        for _ in 0..indent {
+            out.push_str(INDENT);
+        }
+        for (i, tok) in line.tokens.iter().enumerate() {
+            if i > 0 && state.space_before(previous.unwrap(), tok, open.last().map(|o| o.0)) {
+                out.push(' ');
+            }
+            out.push_str(&tok.text);
+            state.update(previous, tok, &mut open, indent);
+            previous = Some(tok);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// What the spacing of a token depends on besides its neighbours.
+#[derive(Default)]
+struct State {
+    /// The number of `?` of a ternary waiting for their `:`, for each
+    /// level of brackets.
+    ternaries: Vec<usize>,
+    /// The next token is a type.
+    expect_type: bool,
+    /// The last token was a type, so a `?` makes it optional.
+    after_type: bool,
+    /// The last token was a unary operator.
+    after_unary: bool,
+    /// The last token was `fn`.
+    after_fn: bool,
+    /// The last token was the name after `fn`.
+    after_fn_name: bool,
+}
+
+impl State {
+    fn ternaries(&mut self) -> &mut usize {
+        if self.ternaries.is_empty() {
+            self.ternaries.push(0);
+        }
+        self.ternaries.last_mut().unwrap()
+    }
+
+    /// Whether there is a space between `prev` and `tok` on a line.
+    fn space_before(&mut self, prev: &Tok, tok: &Tok, nesting: Option<Nesting>) -> bool {
+        match tok.token {
+            Token::Comment(_) => return true,
+            Token::Comma | Token::Semicolon | Token::RightParam | Token::RightSquareParam | Token::Dot => {
+                return false
+            }
+            Token::Colon => return *self.ternaries() > 0 && nesting != Some(Nesting::Params),
+            Token::QuestionMark => return !self.after_type,
+            Token::RightCurlyParam => return !tok.map && prev.token != Token::LeftCurlyParam,
+            _ => {}
+        }
+        match prev.token {
+            Token::LeftParam | Token::LeftSquareParam | Token::Dot | Token::At => return false,
+            Token::LeftCurlyParam => return !prev.map,
+            _ if self.after_unary => return false,
+            _ => {}
+        }
+        if is_range(&tok.token) || is_range(&prev.token) {
+            return false;
+        }
+        match tok.token {
+            Token::LeftParam => !ends_operand(&prev.token) || self.after_fn,
+            Token::LeftSquareParam => !ends_operand(&prev.token),
+            _ => true,
+        }
+    }
+
+    /// Moves past `tok` on a line indented by `indent`.
+    fn update(&mut self, prev: Option<&Tok>, tok: &Tok, open: &mut Vec<(Nesting, usize)>, indent: usize) {
+        let nesting = open.last().map(|o| o.0);
+        let was_type = self.expect_type;
+        self.after_type = was_type;
+        self.expect_type = match tok.token {
+            Token::Arrow => true,
+            Token::Colon => nesting == Some(Nesting::Params),
+            _ => false,
+        };
+        self.after_unary = match tok.token {
+            Token::Not => true,
+            Token::Minus => prev.is_none_or(|p| !ends_operand(&p.token)),
+            _ => false,
+        };
+        let params = tok.token == Token::LeftParam && (self.after_fn || self.after_fn_name);
+        self.after_fn_name = self.after_fn && matches!(tok.token, Token::Identity(_));
+        self.after_fn = tok.token == Token::Function;
+        let opened = match tok.token {
+            Token::LeftParam if params => Some(Nesting::Params),
+            Token::LeftParam => Some(Nesting::Paren),
+            Token::LeftSquareParam => Some(Nesting::Square),
+            Token::LeftCurlyParam if tok.map => Some(Nesting::Map),
+            Token::LeftCurlyParam => Some(Nesting::Block),
+            _ => None,
+        };
+        if let Some(nesting) = opened {
+            open.push((nesting, indent));
+            self.ternaries.push(0);
+        } else if is_closing(&tok.token) {
+            open.pop();
+            self.ternaries.pop();
+        } else if tok.token == Token::QuestionMark && !was_type {
+            *self.ternaries() += 1;
+        } else if tok.token == Token::Colon && *self.ternaries() > 0 && nesting != Some(Nesting::Params) {
+            *self.ternaries() -= 1;
+        }
+    }
+}
+
+fn is_comment(token: &Token) -> bool {
+    matches!(*token, Token::Comment(_))
+}
+
+fn is_opening(token: &Token) -> bool {
+    matches!(*token, Token::LeftParam | Token::LeftSquareParam | Token::LeftCurlyParam)
+}
+
+fn is_closing(token: &Token) -> bool {
+    matches!(*token, Token::RightParam | Token::RightSquareParam | Token::RightCurlyParam)
+}
+
+fn is_range(token: &Token) -> bool {
+    matches!(*token, Token::ExclusiveRange | Token::InclusiveRange)
+}
+
+fn is_operator(token: &Token) -> bool {
+    token.is_arithmetic() || token.is_assignment() ||
+    matches!(*token,
+             Token::Equal | Token::NotEqual | Token::GreaterThan | Token::LessThan |
+             Token::GreaterEqual | Token::LessEqual | Token::And | Token::Or | Token::Not)
+}
+
+/// Whether an expression can end with `token`, so a `-` after it is
+/// binary and a `(` or `[` after it calls or indexes it.
+fn ends_operand(token: &Token) -> bool {
+    matches!(*token,
+             Token::Identity(_) | Token::Int(_) | Token::Real(_) | Token::Str(_) |
+             Token::RawStr(_) | Token::Duration(_) | Token::True | Token::False | Token::Nil |
+             Token::RightParam | Token::RightSquareParam | Token::RightCurlyParam)
+}
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use device::DeviceRegistry;
+use json::Json;
+use real::Real;
+use value::Value;
+use error;
+use error::Error::*;
+
+/// The names of the columns in a trace, in the order used by
+/// traces without a header. They follow RTIMULib: `a` is the
+/// accelerometer, `g` the gyroscope and `m` the magnetometer (compass).
+const COLUMNS: [&str; 9] = ["ax", "ay", "az", "gx", "gy", "gz", "mx", "my", "mz"];
+
+/// One reading of the accelerometer, gyroscope and magnetometer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Sample {
+    pub accel: [Real; 3],
+    pub gyro: [Real; 3],
+    pub compass: [Real; 3],
+}
+
+impl Sample {
+    /// Returns the reading in the column `column` of `COLUMNS`.
+    fn column_mut(&mut self, column: usize) -> &mut Real {
+        let axis = column % 3;
+        match column / 3 {
+            0 => &mut self.accel[axis],
+            1 => &mut self.gyro[axis],
+            _ => &mut self.compass[axis],
+        }
+    }
+
+    /// Returns the reading of a sensor channel such as `"gyro.z"`,
+    /// or all three axes of a sensor such as `"gyro"` as a map.
+    fn channel(&self, name: &str) -> Option<Value> {
+        let mut parts = name.splitn(2, '.');
+        let sensor = match parts.next() {
+            Some("accel") => &self.accel,
+            Some("gyro") => &self.gyro,
+            Some("compass") => &self.compass,
+            _ => return None,
+        };
+        match parts.next() {
+            Some("x") => Some(Value::Real(sensor[0])),
+            Some("y") => Some(Value::Real(sensor[1])),
+            Some("z") => Some(Value::Real(sensor[2])),
+            Some(_) => None,
+            None => {
+                let axes = ["x", "y", "z"].iter().zip(sensor.iter());
+                Some(Value::map(axes.map(|(axis, r)| (axis.to_string(), Value::Real(*r)))
+                    .collect::<BTreeMap<_, _>>()))
+            }
+        }
+    }
+}
+
+/// A simulated IMU replaying recorded samples, so scripts reading the
+/// sensors can be run and tested without the hardware.
+///
+/// The replay is driven by the host: the sample returned is the one
+/// recorded at the time passed to `advance`, given the sample rate of
+/// the trace. When the trace runs out the last sample is held, or the
+/// trace starts over if looping is enabled.
+///
+/// The channels are `accel.x`, `accel.y`, `accel.z` and likewise for
+/// `gyro` and `compass`. Reading `accel`, `gyro` or `compass` gives a map
+/// holding all three axes, e.g. `{x: 0, y: 0, z: 1}`.
+#[derive(Debug, Clone)]
+pub struct ImuReplay {
+    samples: Vec<Sample>,
+    rate: u32,
+    elapsed: Duration,
+    looping: bool,
+}
+
+impl ImuReplay {
+    /// Creates a replay of `samples` recorded at `rate` samples per second.
+    /// The traces read from text fail with `BadSampleRate` instead.
+    ///
+    /// # Panics
+    /// Panics if `rate` is zero.
+    pub fn new(samples: Vec<Sample>, rate: u32) -> Self {
+        assert!(rate > 0, "the sample rate must be positive");
+        ImuReplay {
+            samples,
+            rate,
+            elapsed: Duration::from_secs(0),
+            looping: false,
+        }
+    }
+
+    /// Reads a trace of comma separated values, one sample per line.
+    /// The first line may be a header naming the columns, see `COLUMNS`,
+    /// if it names at least one of them. Unknown columns, like a
+    /// timestamp, are ignored and missing columns read as zero. Without
+    /// a header the columns are `ax, ay, az, gx, gy, gz, mx, my, mz`.
+    /// Empty lines and lines starting with `#` are skipped.
+    pub fn from_csv(input: &str, rate: u32) -> error::Result<Self> {
+        check_rate(rate)?;
+        let mut columns: Vec<Option<usize>> = (0..COLUMNS.len()).map(Some).collect();
+        let mut samples = Vec::new();
+        let mut first = true;
+
+        for (n, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let header = first && fields.iter().any(|f| COLUMNS.contains(f));
+            first = false;
+            if header {
+                columns = fields.iter().map(|f| COLUMNS.iter().position(|c| c == f)).collect();
+                continue;
+            }
+
+            let mut sample = Sample::default();
+            for (field, column) in fields.iter().zip(&columns) {
+                if let Some(column) = *column {
+                    let value = field.parse::<f32>()
+                        .map_err(|_| BadTrace(n as u32 + 1, format!("bad number `{}`", field)))?;
+                    *sample.column_mut(column) = to_real(value, n)?;
+                }
+            }
+            samples.push(sample);
+        }
+        Ok(ImuReplay::new(samples, rate))
+    }
+
+    /// Reads a trace in the JSON Lines format, one JSON object per line
+    /// with the column names of `COLUMNS` as keys, e.g.
+    /// `{"ax": 0.01, "ay": -0.02, "az": 0.98}`. Missing keys read as zero.
+    pub fn from_json_lines(input: &str, rate: u32) -> error::Result<Self> {
+        check_rate(rate)?;
+        let mut samples = Vec::new();
+
+        for (n, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let json = Json::parse(line).map_err(|e| BadTrace(n as u32 + 1, e))?;
+            if !matches!(json, Json::Object(_)) {
+                return Err(BadTrace(n as u32 + 1, "expected an object".to_string()));
+            }
+
+            let mut sample = Sample::default();
+            for (column, name) in COLUMNS.iter().enumerate() {
+                match json.get(name) {
+                    Some(&Json::Number(value)) => *sample.column_mut(column) = to_real(value as f32, n)?,
+                    Some(_) => {
+                        return Err(BadTrace(n as u32 + 1, format!("`{}` is not a number", name)))
+                    }
+                    None => {}
+                }
+            }
+            samples.push(sample);
+        }
+        Ok(ImuReplay::new(samples, rate))
+    }
+
+    /// Reads a trace from a file. Files ending in `.jsonl` or `.json` are
+    /// read as JSON Lines, all other files as comma separated values.
+    pub fn load<P: AsRef<Path>>(path: P, rate: u32) -> error::Result<Self> {
+        let path = path.as_ref();
+        let mut buf = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut buf))
+            .map_err(|e| Io(format!("{}: {}", path.display(), e)))?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("jsonl") | Some("json") => ImuReplay::from_json_lines(&buf, rate),
+            _ => ImuReplay::from_csv(&buf, rate),
+        }
+    }
+
+    /// Starts the trace over when it runs out instead of holding the last sample.
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    /// Moves the time of the replay forward.
+    pub fn advance(&mut self, time: Duration) {
+        self.elapsed += time;
+    }
+
+    /// Moves the replay back to the first sample.
+    pub fn rewind(&mut self) {
+        self.elapsed = Duration::from_secs(0);
+    }
+
+    /// The time elapsed since the start of the replay.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns `true` if the time has moved past the last sample.
+    /// A looping replay never finishes.
+    pub fn finished(&self) -> bool {
+        !self.looping && self.index() >= self.samples.len()
+    }
+
+    /// Returns the sample at the current time, or `None` if the trace is empty.
+    pub fn sample(&self) -> Option<&Sample> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let index = self.index();
+        if self.looping {
+            self.samples.get(index % self.samples.len())
+        } else {
+            self.samples.get(index).or_else(|| self.samples.last())
+        }
+    }
+
+    /// The index of the sample recorded at the current time.
+    fn index(&self) -> usize {
+        let micros = self.elapsed.as_secs() * 1_000_000 + self.elapsed.subsec_micros() as u64;
+        (micros * self.rate as u64 / 1_000_000) as usize
+    }
+}
+
+impl DeviceRegistry for ImuReplay {
+    fn contains(&self, name: &str) -> bool {
+        Sample::default().channel(name).is_some()
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for sensor in &["accel", "gyro", "compass"] {
+            names.push(sensor.to_string());
+            names.extend(["x", "y", "z"].iter().map(|axis| format!("{}.{}", sensor, axis)));
+        }
+        names
+    }
+
+    fn read(&mut self, name: &str) -> Option<Value> {
+        self.sample().and_then(|sample| sample.channel(name))
+    }
+}
+
+/// Fails if `rate` is zero, see `ImuReplay::new`.
+fn check_rate(rate: u32) -> error::Result<()> {
+    if rate == 0 {
+        return Err(BadSampleRate(rate));
+    }
+    Ok(())
+}
+
+/// Converts a reading to a `Real`, failing if it does not fit.
+fn to_real(value: f32, line: usize) -> error::Result<Real> {
+    if value.is_finite() && (-32768.0..32768.0).contains(&value) {
+        Ok(Real::from(value))
+    } else {
+        Err(BadTrace(line as u32 + 1, format!("{} does not fit in a real", value)))
+    }
+}
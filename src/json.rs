@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A JSON value. Objects keep their keys sorted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+impl Json {
+    /// Parses a JSON document. The error describes what went wrong.
+    pub fn parse(input: &str) -> Result<Json, String> {
+        let mut parser = JsonParser { input: input.chars().peekable() };
+        let json = parser.parse_value()?;
+        parser.skip_whitespace();
+        match parser.input.next() {
+            None => Ok(json),
+            Some(c) => Err(format!("unexpected `{}` after the value", c)),
+        }
+    }
+
+    /// An object with `fields`.
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    /// Looks up `key` if this is an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::Str(ref s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Json {
+    fn from(s: &'a str) -> Json {
+        Json::Str(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::Str(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+struct JsonParser<'a> {
+    input: Peekable<Chars<'a>>,
+}
+
+impl<'a> JsonParser<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.input.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.input.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.input.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected `{}`, found `{}`", expected, c)),
+            None => Err(format!("expected `{}`, found end of input", expected)),
+        }
+    }
+
+    /// Consumes `word` which must follow in the input.
+    fn expect_word(&mut self, word: &str) -> Result<(), String> {
+        for expected in word.chars() {
+            if self.input.next() != Some(expected) {
+                return Err(format!("expected `{}`", word));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.input.peek().cloned() {
+            Some('n') => self.expect_word("null").map(|_| Json::Null),
+            Some('t') => self.expect_word("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect_word("false").map(|_| Json::Bool(false)),
+            Some('"') => self.parse_string().map(Json::Str),
+            Some('[') => self.parse_array(),
+            Some('{') => self.parse_object(),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(c) => Err(format!("unexpected `{}`", c)),
+            None => Err("unexpected end of input".to_string()),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Json, String> {
+        let mut buf = String::new();
+        while let Some(&c) = self.input.peek() {
+            if !(c.is_ascii_digit() || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E') {
+                break;
+            }
+            buf.push(c);
+            self.input.next();
+        }
+        buf.parse().map(Json::Number).map_err(|_| format!("bad number `{}`", buf))
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut buf = String::new();
+        loop {
+            match self.input.next() {
+                Some('"') => return Ok(buf),
+                Some('\\') => {
+                    let c = match self.input.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => self.parse_unicode_escape()?,
+                        Some(c) => c,
+                        None => return Err("unterminated string".to_string()),
+                    };
+                    buf.push(c);
+                }
+                Some(c) => buf.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+
+    /// Parses the four hex digits of a `\u` escape, including surrogate pairs.
+    fn parse_unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.parse_hex()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            self.expect_word("\\u")?;
+            let low = self.parse_hex()?;
+            0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+        } else {
+            high
+        };
+        ::std::char::from_u32(code).ok_or_else(|| "bad unicode escape".to_string())
+    }
+
+    fn parse_hex(&mut self) -> Result<u32, String> {
+        let digits: String = self.input.by_ref().take(4).collect();
+        u32::from_str_radix(&digits, 16).map_err(|_| format!("bad unicode escape `{}`", digits))
+    }
+
+    fn parse_array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.input.peek() == Some(&']') {
+            self.input.next();
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.input.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err("expected `,` or `]`".to_string()),
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut map = BTreeMap::new();
+        self.skip_whitespace();
+        if self.input.peek() == Some(&'}') {
+            self.input.next();
+            return Ok(Json::Object(map));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.expect(':')?;
+            let value = self.parse_value()?;
+            map.insert(key, value);
+            self.skip_whitespace();
+            match self.input.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(map)),
+                _ => return Err("expected `,` or `}`".to_string()),
+            }
+        }
+    }
+}
+
+/// Writes `s` as a quoted JSON string.
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+/// Writes the value as compact JSON.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::Str(ref s) => write_string(f, s),
+            Json::Array(ref items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(ref map) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use tokens::*;
 use real::Real;
+use span::Span;
 use error;
 use error::Error::*;
 
@@ -8,11 +9,18 @@ use std::iter::Peekable;
 
 /// The Lexical scanner.
 /// It performs a lexical scanning of a string.
+#[derive(Clone)]
 pub struct Lexer<'a> {
     input: Peekable<Chars<'a>>,
     line: u32,
     column: u32,
-    start: u32,
+    span: Span,
+    /// The byte offset of the next `char` in the input.
+    offset: usize,
+    /// The byte offset where the last `Token` starts.
+    start: usize,
+    /// Returns comments as `Token::Comment` instead of skipping them.
+    comments: bool,
 }
 
 impl<'a> Lexer<'a> {
@@ -22,15 +30,40 @@ impl<'a> Lexer<'a> {
             input: input.chars().peekable(),
             line: 1,
             column: 1,
-            start: 1,
+            span: Span::new(1, 1),
+            offset: 0,
+            start: 0,
+            comments: false,
+        }
+    }
+
+    /// Create a new lexer which returns the comments of the input as
+    /// `Token::Comment`, for tools working on the source like a formatter.
+    pub fn with_comments(input: &'a str) -> Self {
+        Lexer {
+            comments: true,
+            ..Lexer::new(input)
         }
     }
 
     /// Andvances the scanner and returns the next `char`.
     /// If the input is empty, it returns `None`.
+    /// Reading a newline (`\n`) moves the scanner to the next line.
     fn read_char(&mut self) -> Option<char> {
-        self.column += 1;
-        self.input.next()
+        let c = self.input.next();
+        self.offset += c.map_or(0, char::len_utf8);
+        if c == Some('\n') {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        c
+    }
+
+    /// Returns the position of the next `char` in the input.
+    fn here(&self) -> Span {
+        Span::new(self.line, self.column)
     }
 
     /// Peeks at the next `char` from the input.
@@ -58,7 +91,10 @@ impl<'a> Lexer<'a> {
     /// Skips all `char`s until it finds a newline (`\n`)
     /// or until the end of file is reached.
     fn skip_line(&mut self) {
-        while !self.peek_char_eq('\n') {
+        while let Some(&c) = self.peek_char() {
+            if c == '\n' {
+                break;
+            }
             self.skip();
         }
     }
@@ -66,17 +102,14 @@ impl<'a> Lexer<'a> {
     /// Skips all whitespace `char`s and comment blocks.
     fn skip_whitespace(&mut self) {
         while let Some(&c) = self.peek_char() {
-            if c == '\n' {
-                self.line += 1;
-                self.column = 0;
-            } else if c == '#' {
+            if c == '#' && !self.comments {
                 self.skip_line();
                 continue;
             }
             if !c.is_whitespace() {
                 break;
             }
-            self.read_char();
+            self.skip();
         }
     }
 
@@ -107,7 +140,7 @@ impl<'a> Lexer<'a> {
 
     /// Reads a number from the input.
     /// Returns a `Result<Token, LexerError>` where `Token` is
-    /// either an `Int` or a `Real`. The error happens when
+    /// either an `Int`, a `Real` or a `Duration`. The error happens when
     /// the parsing of the number fails.
     fn read_number(&mut self, first: char) -> error::Result<Token> {
         let mut buf = String::new();
@@ -128,8 +161,19 @@ impl<'a> Lexer<'a> {
                 return Real::parse(&buf).map(Token::Real);
             }
         }
-        // else we just return the int
-        buf.parse().map(Token::Int).map_err(error::Error::from)
+        // An int directly followed by a unit is a duration
+        let unit: String = self.input.clone().take_while(|&c| is_alphanumeric(c)).collect();
+        let scale = match unit.as_str() {
+            "ms" => 1,
+            "s" => 1000,
+            // else we just return the int
+            _ => return buf.parse().map(Token::Int).map_err(error::Error::from),
+        };
+        for _ in 0..unit.len() {
+            self.skip();
+        }
+        let n: u32 = buf.parse()?;
+        n.checked_mul(scale).map(Token::Duration).ok_or(LargeInt)
     }
 
     /// Reads a string literal from the input.
@@ -140,16 +184,10 @@ impl<'a> Lexer<'a> {
         // Finding a newline or EOF results in an error.
         while let Some(&c) = self.peek_char() {
             if c == '\\' {
-                buf.push(self.read_char().unwrap());
-                match self.peek_char() {
-                    Some(&p) => {
-                        if !is_escape_char(p) {
-                            return Err(UnknownEscape(p));
-                        }
-                    }
-                    None => break,
-                }
+                self.read_escape(&mut buf)?;
+                continue;
             } else if c == '\n' {
+                self.span = self.here();
                 return Err(StringEOL);
             } else if c == '"' {
                 self.skip();
@@ -160,6 +198,93 @@ impl<'a> Lexer<'a> {
         Err(InfiniteString)
     }
 
+    /// Reads an escape sequence into `buf`. The escape sequence is kept as is,
+    /// but it is an error if the escaped `char` is unknown.
+    /// The unknown `char` is not consumed.
+    fn read_escape(&mut self, buf: &mut String) -> error::Result<()> {
+        let escape = self.here();
+        buf.push(self.read_char().unwrap());
+        match self.peek_char() {
+            Some(&p) if is_escape_char(p) => {
+                buf.push(self.read_char().unwrap());
+                Ok(())
+            }
+            Some(&p) => {
+                self.span = escape;
+                Err(UnknownEscape(p))
+            }
+            None => Err(InfiniteString),
+        }
+    }
+
+    /// Checks if the next two `char`s are `""`, which together with
+    /// the `"` already read opens a multi-line string literal.
+    fn is_multiline_string_start(&self) -> bool {
+        self.input.clone().take(2).filter(|&c| c == '"').count() == 2
+    }
+
+    /// Reads a multi-line string literal from the input.
+    /// The string is delimited by `"""` and may contain newlines.
+    /// Escape sequences are handled the same way as in `read_string`.
+    /// The common indentation of the lines is removed, see `strip_indentation`.
+    fn read_multiline_string(&mut self) -> error::Result<Token> {
+        self.skip();
+        self.skip();
+        let mut buf = String::new();
+
+        while let Some(&c) = self.peek_char() {
+            if c == '\\' {
+                self.read_escape(&mut buf)?;
+                continue;
+            } else if c == '"' && self.input.clone().take_while(|&x| x == '"').count() >= 3 {
+                self.skip();
+                self.skip();
+                self.skip();
+                return Ok(Token::Str(strip_indentation(&buf)));
+            }
+            buf.push(self.read_char().unwrap());
+        }
+        Err(InfiniteString)
+    }
+
+    /// Checks if the input following an `r` opens a raw string literal,
+    /// that is zero or more `#` followed by a `"`.
+    fn is_raw_string_start(&self) -> bool {
+        let mut input = self.input.clone();
+        loop {
+            match input.next() {
+                Some('#') => continue,
+                Some('"') => return true,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Reads a raw string literal from the input, e.g. `r"C:\path"`
+    /// or `r#"say "hi""#`. Backslashes are not treated as escapes and
+    /// the string may span multiple lines. The string is closed by a `"`
+    /// followed by the same number of `#` as the opening.
+    fn read_raw_string(&mut self) -> error::Result<Token> {
+        let mut hashes = 0;
+        while self.peek_char_eq('#') {
+            self.skip();
+            hashes += 1;
+        }
+        self.skip();
+
+        let mut buf = String::new();
+        while let Some(c) = self.read_char() {
+            if c == '"' && self.input.clone().take_while(|&x| x == '#').take(hashes).count() == hashes {
+                for _ in 0..hashes {
+                    self.skip();
+                }
+                return Ok(Token::RawStr(buf));
+            }
+            buf.push(c);
+        }
+        Err(InfiniteString)
+    }
+
     /// Generates a `Token` from the characters read from the input.
     /// It traverses the input one `char` at the time and generates `Token`s.
     /// When the whole input has been scanned, the lexer will yield
@@ -171,13 +296,20 @@ impl<'a> Lexer<'a> {
     /// The lexer traverses the input only once.
     pub fn next_token(&mut self) -> error::Result<Token> {
         self.skip_whitespace();
-        self.start = self.column;
+        self.span = self.here();
+        self.start = self.offset;
 
         if let Some(c) = self.read_char() {
             match c {
+                '#' => {
+                    let mut buf = String::from("#");
+                    self.read_while(&mut buf, |c| c != '\n');
+                    Ok(Token::Comment(buf.trim_end().to_string()))
+                }
                 '@' => Ok(Token::At),
                 ',' => Ok(Token::Comma),
                 ';' => Ok(Token::Semicolon),
+                ':' => Ok(Token::Colon),
                 '{' => Ok(Token::LeftCurlyParam),
                 '}' => Ok(Token::RightCurlyParam),
                 '[' => Ok(Token::LeftSquareParam),
@@ -215,6 +347,9 @@ impl<'a> Lexer<'a> {
                     if self.peek_char_eq('=') {
                         self.skip();
                         Ok(Token::MinusAssignment)
+                    } else if self.peek_char_eq('>') {
+                        self.skip();
+                        Ok(Token::Arrow)
                     } else {
                         Ok(Token::Minus)
                     }
@@ -264,8 +399,15 @@ impl<'a> Lexer<'a> {
                         Ok(Token::Dot)
                     }
                 }
-                '0'...'9' => self.read_number(c),
-                '"' => self.read_string(),
+                '0'..='9' => self.read_number(c),
+                '"' => {
+                    if self.is_multiline_string_start() {
+                        self.read_multiline_string()
+                    } else {
+                        self.read_string()
+                    }
+                }
+                'r' if self.is_raw_string_start() => self.read_raw_string(),
                 _ => {
                     if is_letter(c) {
                         Ok(self.read_identifier(c))
@@ -286,7 +428,20 @@ impl<'a> Lexer<'a> {
 
     /// Returns the starting position of the last `Token`.
     pub fn pos(&self) -> u32 {
-        self.start
+        self.span.column
+    }
+
+    /// Returns the byte offsets in the input where the last `Token`
+    /// starts and ends.
+    pub fn range(&self) -> (usize, usize) {
+        (self.start, self.offset)
+    }
+
+    /// Returns the `Span` of the last `Token`.
+    /// For an error inside a string literal it points at the
+    /// offending `char` instead, which might be on a later line.
+    pub fn span(&self) -> Span {
+        self.span
     }
 }
 
@@ -294,7 +449,7 @@ impl<'a> Iterator for Lexer<'a> {
     /// The type of the elements being iterated over.
     /// It returns a `Result`, where the `Ok` variant is a `Token`
     /// and the `Err`is an error encountered while scanning the input.
-    /// The first `u32` is the current line number.
+    /// The first `u32` is the line number where the `Token` starts.
     /// The second `u32` is the starting position of the `Token`.
     type Item = (error::Result<Token>, u32, u32);
 
@@ -303,7 +458,7 @@ impl<'a> Iterator for Lexer<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         match self.next_token() {
             Ok(Token::EndOfFile) => None,
-            token => Some((token, self.line(), self.pos())),
+            token => Some((token, self.span.line, self.span.column)),
         }
     }
 }
@@ -315,10 +470,7 @@ fn is_letter(c: char) -> bool {
 
 /// Checks if `c` is a number.
 fn is_numeric(c: char) -> bool {
-    match c {
-        '0'...'9' => true,
-        _ => false,
-    }
+    c.is_ascii_digit()
 }
 
 /// Chekcs is `c` is a number, letter or a underscore (`_`).
@@ -328,8 +480,37 @@ fn is_alphanumeric(c: char) -> bool {
 
 /// Returns true if `c` is an escape character
 fn is_escape_char(c: char) -> bool {
-    match c {
-        '"' | 'n' | 't' | 'r' | '\\' => true,
-        _ => false,
+    matches!(c, '"' | 'n' | 't' | 'r' | '\\')
+}
+
+/// Removes the common leading whitespace from every line of a
+/// multi-line string literal. A first line holding only whitespace
+/// (the rest of the line with the opening quotes) is dropped, and so is
+/// a last line holding only whitespace (the indentation of the closing quotes).
+/// The indentation of the closing quotes also counts towards the
+/// common indentation.
+fn strip_indentation(input: &str) -> String {
+    let mut lines: Vec<&str> = input.split('\n').collect();
+    if lines.len() == 1 {
+        return input.to_string();
+    }
+    if lines[0].trim().is_empty() {
+        lines.remove(0);
     }
+    let closing = match lines.last() {
+        Some(line) if line.trim().is_empty() => lines.pop(),
+        _ => None,
+    };
+
+    let indent = lines.iter()
+        .filter(|line| !line.trim().is_empty())
+        .chain(closing.as_ref())
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+
+    lines.iter()
+        .map(|line| if line.trim().is_empty() { "" } else { &line[indent..] })
+        .collect::<Vec<_>>()
+        .join("\n")
 }
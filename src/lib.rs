@@ -3,3 +3,30 @@ pub mod tokens;
 pub mod parser;
 pub mod real;
 pub mod error;
+pub mod span;
+pub mod ast;
+pub mod value;
+pub mod ops;
+pub mod eval;
+pub mod builtins;
+pub mod native;
+pub mod budget;
+pub mod debugger;
+pub mod profiler;
+pub mod coverage;
+pub mod limits;
+pub mod compiler;
+pub mod vm;
+pub mod snapshot;
+pub mod scheduler;
+pub mod diagnostic;
+pub mod resolver;
+pub mod types;
+pub mod checker;
+pub mod optimizer;
+pub mod formatter;
+pub mod lsp;
+pub mod dap;
+pub mod device;
+pub mod imu;
+pub mod json;
@@ -0,0 +1,186 @@
+use std::collections::HashSet;
+use std::mem;
+use std::rc::Rc;
+
+use span::Span;
+use value::Value;
+use error;
+use error::Error::*;
+
+/// Limits on the memory and recursion of scripts, so a runaway script
+/// stops with an error instead of taking down the host.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// How deep calls of script functions may nest. The `Interpreter`
+    /// uses the stack of the host for each call, so a deep limit needs
+    /// a big stack, especially in debug builds.
+    pub call_depth: usize,
+    /// The bytes of strings, lists and maps the values of a script
+    /// may hold at once.
+    pub heap: usize,
+    /// The length of the longest string, in bytes.
+    pub string_len: usize,
+    /// The number of items of the longest list.
+    pub list_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            call_depth: 64,
+            heap: 64 << 20,
+            string_len: 1 << 20,
+            list_len: 1 << 20,
+        }
+    }
+}
+
+/// The bytes taken by each item of a list or map.
+const ITEM_SIZE: usize = mem::size_of::<Value>();
+
+/// Keeps track of the memory held by the values of a script.
+///
+/// Memory is counted when values are created, but not when they are
+/// dropped. So when the count goes over the limit the engine measures
+/// the values it can still reach with a `Measure`, and only fails if
+/// those are still over the limit.
+#[derive(Debug, Clone, Default)]
+pub struct Heap {
+    pub limits: Limits,
+    used: usize,
+}
+
+impl Heap {
+    /// Forgets the memory counted so far.
+    pub fn reset(&mut self) {
+        self.used = 0;
+    }
+
+    /// The bytes counted since the last measure.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Counts a value just created at `span`, failing if it is too long.
+    /// Returns `true` if the count went over the limit and the engine
+    /// should measure its values, see `measured`.
+    pub fn alloc(&mut self, value: &Value, span: Span) -> error::Result<bool> {
+        let bytes = match *value {
+            Value::Str(ref s) => {
+                if s.len() > self.limits.string_len {
+                    return Err(StringTooLong(s.len(), span));
+                }
+                s.len()
+            }
+            Value::List(ref list) => {
+                let len = list.borrow().len();
+                if len > self.limits.list_len {
+                    return Err(ListTooLong(len, span));
+                }
+                len * ITEM_SIZE
+            }
+            Value::Map(ref map) => map.borrow().keys().map(|key| ITEM_SIZE + key.len()).sum(),
+            _ => 0,
+        };
+        Ok(self.count(bytes))
+    }
+
+    /// Counts an item added to a list, which is now `len` items long.
+    /// Returns `true` like `alloc`.
+    pub fn grow_list(&mut self, len: usize, span: Span) -> error::Result<bool> {
+        if len > self.limits.list_len {
+            return Err(ListTooLong(len, span));
+        }
+        Ok(self.count(ITEM_SIZE))
+    }
+
+    fn count(&mut self, bytes: usize) -> bool {
+        self.used = self.used.saturating_add(bytes);
+        self.used > self.limits.heap
+    }
+
+    /// Replaces the count with the bytes measured by the engine,
+    /// failing if they are over the limit.
+    pub fn measured(&mut self, bytes: usize, span: Span) -> error::Result<()> {
+        self.used = bytes;
+        if bytes > self.limits.heap {
+            return Err(HeapLimitExceeded(span));
+        }
+        Ok(())
+    }
+
+    /// Checks that a call at `span` would nest `depth` calls deep.
+    pub fn check_depth(&self, depth: usize, span: Span) -> error::Result<()> {
+        if depth > self.limits.call_depth {
+            return Err(CallDepthExceeded(span));
+        }
+        Ok(())
+    }
+}
+
+/// Measures the memory held by values, counting values shared by
+/// several others only once.
+#[derive(Debug, Default)]
+pub struct Measure {
+    seen: HashSet<usize>,
+    bytes: usize,
+}
+
+impl Measure {
+    pub fn new() -> Self {
+        Measure::default()
+    }
+
+    /// The bytes measured so far.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes;
+    }
+
+    /// Returns `true` the first time it is called with `rc`, so
+    /// what it points to is only measured once.
+    pub fn first_visit<T: ?Sized>(&mut self, rc: &Rc<T>) -> bool {
+        self.seen.insert(Rc::as_ptr(rc) as *const () as usize)
+    }
+
+    /// Measures `value` and everything it holds.
+    pub fn value(&mut self, value: &Value) {
+        let mut pending = Vec::new();
+        self.visit(value, &mut pending);
+        while let Some(value) = pending.pop() {
+            match value {
+                Value::List(ref list) => {
+                    let list = list.borrow();
+                    self.add(list.len() * ITEM_SIZE);
+                    for item in list.iter() {
+                        self.visit(item, &mut pending);
+                    }
+                }
+                Value::Map(ref map) => {
+                    for (key, item) in map.borrow().iter() {
+                        self.add(ITEM_SIZE + key.len());
+                        self.visit(item, &mut pending);
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Measures `value`, but adds a list or map seen for the first time to
+    /// `pending` to measure its items later, so nesting does not take up
+    /// the stack.
+    fn visit(&mut self, value: &Value, pending: &mut Vec<Value>) {
+        match *value {
+            Value::Str(ref s) => self.add(s.len()),
+            Value::List(ref list) if self.first_visit(list) => pending.push(value.clone()),
+            Value::Map(ref map) if self.first_visit(map) => pending.push(value.clone()),
+            Value::Function(ref closure) if self.first_visit(closure) => closure.measure(self),
+            Value::Compiled(ref closure) if self.first_visit(closure) => closure.measure(self),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use ast::*;
+use builtins::Builtin;
+use diagnostic::{Diagnostic, DiagnosticKind};
+use json::Json;
+use lexer::Lexer;
+use parser::Parser;
+use resolver::{Declaration, DeclarationKind, Resolution, Resolver};
+use span::Span;
+use tokens::Token;
+
+/// The kinds of semantic tokens, in the order of their index in the
+/// tokens sent to the editor.
+pub const TOKEN_TYPES: [&str; 8] =
+    ["keyword", "operator", "number", "string", "comment", "variable", "parameter", "function"];
+
+/// The LSP code of a method the server does not know.
+const METHOD_NOT_FOUND: f64 = -32601.0;
+/// The LSP code of a message which is not JSON.
+const PARSE_ERROR: f64 = -32700.0;
+
+/// A Language Server Protocol server for editors, which reports the
+/// diagnostics of `Resolver::check` as documents change and answers
+/// requests for semantic tokens, definitions, hovers and document
+/// symbols.
+///
+/// Documents are synced in full. Positions are converted between the
+/// lines and columns of `Span`, which count from 1 in `char`s, and the
+/// LSP ones, which count from 0 in UTF-16 code units.
+#[derive(Default)]
+pub struct Server {
+    /// The text of the open documents by their URI.
+    documents: HashMap<String, String>,
+    shutdown: bool,
+    exited: bool,
+}
+
+/// A token of a document and where it is.
+struct SourceToken {
+    token: Token,
+    span: Span,
+    /// The length in UTF-16 code units of the token's first line.
+    len: usize,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server::default()
+    }
+
+    /// Whether the client sent `exit`.
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// The exit code after `exit`, which is 1 if there was no `shutdown`.
+    pub fn exit_code(&self) -> i32 {
+        if self.shutdown { 0 } else { 1 }
+    }
+
+    /// Serves the messages of `input` and writes the replies to `output`
+    /// until the client exits or closes the input.
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        while !self.exited {
+            let content = match read_message(&mut input)? {
+                Some(content) => content,
+                None => break,
+            };
+            let replies = match Json::parse(&content) {
+                Ok(message) => self.handle(&message),
+                Err(e) => vec![error_response(Json::Null, PARSE_ERROR, &e)],
+            };
+            for reply in replies {
+                write_message(&mut output, &reply.to_string())?;
+            }
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Handles a request or notification, and returns the response and
+    /// the notifications to send back.
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notification(method, params),
+        };
+        let result = match method {
+            "initialize" => capabilities(),
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "textDocument/semanticTokens/full" => self.document(params).map_or(Json::Null, |d| semantic_tokens(&d)),
+            "textDocument/definition" => self.at_position(params, definition),
+            "textDocument/hover" => self.at_position(params, hover),
+            "textDocument/documentSymbol" => self.document(params).map_or(Json::Null, |d| document_symbols(&d)),
+            _ => return vec![error_response(id, METHOD_NOT_FOUND, &format!("unknown method {}", method))],
+        };
+        vec![Json::object(vec![("jsonrpc", Json::from("2.0")), ("id", id), ("result", result)])]
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = params.get("textDocument").and_then(|doc| doc.get("uri")).and_then(Json::as_str);
+        let uri = match (method, uri) {
+            ("exit", _) => {
+                self.exited = true;
+                return Vec::new();
+            }
+            (_, Some(uri)) => uri.to_string(),
+            _ => return Vec::new(),
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").and_then(|doc| doc.get("text"));
+                self.documents.insert(uri.clone(), text.and_then(Json::as_str).unwrap_or("").to_string());
+            }
+            "textDocument/didChange" => {
+                // The last change has the whole text
+                let text = match params.get("contentChanges") {
+                    Some(Json::Array(changes)) => changes.last().and_then(|c| c.get("text")).and_then(Json::as_str),
+                    _ => None,
+                };
+                match text {
+                    Some(text) => self.documents.insert(uri.clone(), text.to_string()),
+                    None => return Vec::new(),
+                };
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+            }
+            _ => return Vec::new(),
+        }
+        let diagnostics = match self.documents.get(&uri) {
+            Some(text) => {
+                let document = Document::new(&uri, text);
+                resolver().check(text).iter().map(|d| diagnostic(&document, d)).collect()
+            }
+            None => Vec::new(),
+        };
+        let params = Json::object(vec![("uri", Json::from(uri.as_str())), ("diagnostics", Json::Array(diagnostics))]);
+        vec![Json::object(vec![("jsonrpc", Json::from("2.0")),
+                         ("method", Json::from("textDocument/publishDiagnostics")),
+                         ("params", params)])]
+    }
+
+    /// The open document a request is about.
+    fn document<'a>(&'a self, params: &Json) -> Option<Document<'a>> {
+        let uri = params.get("textDocument").and_then(|doc| doc.get("uri")).and_then(Json::as_str)?;
+        self.documents.get_key_value(uri).map(|(uri, text)| Document::new(uri, text))
+    }
+
+    /// Answers a request about the token at a position in a document with
+    /// `f`, or with null if there is no token there.
+    fn at_position(&self, params: &Json, f: fn(&Document, &SourceToken) -> Json) -> Json {
+        let (document, position) = match (self.document(params), params.get("position")) {
+            (Some(document), Some(position)) => (document, position),
+            _ => return Json::Null,
+        };
+        let line = position.get("line").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+        let character = position.get("character").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+        match document.token_at(line, character) {
+            Some(token) => f(&document, token),
+            None => Json::Null,
+        }
+    }
+}
+
+/// An open document with its tokens, and its resolved names if it parses.
+struct Document<'a> {
+    uri: &'a str,
+    lines: Vec<&'a str>,
+    tokens: Vec<SourceToken>,
+    program: Option<Program>,
+    resolution: Option<Resolution>,
+    /// The names after `fn` and `for` with the span of the keyword,
+    /// where the resolver declares them.
+    names: Vec<(Span, Span)>,
+}
+
+impl<'a> Document<'a> {
+    fn new(uri: &'a str, text: &'a str) -> Self {
+        let lines: Vec<&str> = text.split('\n').collect();
+        let mut tokens = Vec::new();
+        let mut lexer = Lexer::with_comments(text);
+        // The tokens up to the first error
+        while let Ok(token) = lexer.next_token() {
+            if token == Token::EndOfFile {
+                break;
+            }
+            let (start, end) = lexer.range();
+            let first_line = text[start..end].split('\n').next().unwrap_or("");
+            tokens.push(SourceToken {
+                token,
+                span: lexer.span(),
+                len: first_line.encode_utf16().count(),
+            });
+        }
+        let program = Parser::new(text).parse_program().ok();
+        let resolution = program.as_ref().map(|program| resolver().resolve(program));
+        let names = tokens.windows(2).filter_map(|pair| match (&pair[0].token, &pair[1].token) {
+            (&Token::Function, &Token::Identity(_)) | (&Token::For, &Token::Identity(_)) => {
+                Some((pair[1].span, pair[0].span))
+            }
+            _ => None,
+        }).collect();
+        Document { uri, lines, tokens, program, resolution, names }
+    }
+
+    /// The LSP line and character of `span`.
+    fn location(&self, span: Span) -> (usize, usize) {
+        let line = span.line.saturating_sub(1) as usize;
+        let text = self.lines.get(line).cloned().unwrap_or("");
+        let column = span.column.saturating_sub(1) as usize;
+        (line, text.chars().take(column).map(char::len_utf16).sum())
+    }
+
+    fn position(&self, span: Span) -> Json {
+        let (line, character) = self.location(span);
+        Json::object(vec![("line", Json::from(line)), ("character", Json::from(character))])
+    }
+
+    /// The LSP range of the token at `span`, or an empty range at `span`
+    /// if no token starts there.
+    fn range(&self, span: Span) -> Json {
+        let (line, character) = self.location(span);
+        let len = self.tokens.iter().find(|t| t.span == span).map_or(0, |t| t.len);
+        let end = Json::object(vec![("line", Json::from(line)), ("character", Json::from(character + len))]);
+        Json::object(vec![("start", self.position(span)), ("end", end)])
+    }
+
+    /// The token covering the LSP position.
+    fn token_at(&self, line: usize, character: usize) -> Option<&SourceToken> {
+        let text = self.lines.get(line)?;
+        // The column of the `char` at the position
+        let mut units = 0;
+        let mut column = 1;
+        for c in text.chars() {
+            if units + c.len_utf16() > character {
+                break;
+            }
+            units += c.len_utf16();
+            column += 1;
+        }
+        let span = Span::new(line as u32 + 1, column);
+        self.tokens.iter().rev().find(|t| t.span.line == span.line && t.span <= span)
+            .filter(|t| character < self.location(t.span).1 + t.len.max(1))
+    }
+
+    /// The declaration of the name used or declared at `span`.
+    fn declaration_of(&self, span: Span) -> Option<&Declaration> {
+        let resolution = self.resolution.as_ref()?;
+        let declared = self.names.iter().find(|n| n.0 == span).map_or(span, |n| n.1);
+        resolution.declaration_of(span).or_else(|| resolution.declarations.iter().find(|d| d.span == declared))
+    }
+
+    /// Where the name of a declaration is.
+    fn name_of(&self, declaration: &Declaration) -> Span {
+        self.names.iter().find(|n| n.1 == declaration.span).map_or(declaration.span, |n| n.0)
+    }
+}
+
+/// A resolver for the documents. The editor does not know the host
+/// running them, so any device may be used.
+fn resolver() -> Resolver {
+    let mut resolver = Resolver::new();
+    resolver.allow_any_device();
+    resolver
+}
+
+/// What the server can do, the result of `initialize`.
+fn capabilities() -> Json {
+    let types = TOKEN_TYPES.iter().map(|&t| Json::from(t)).collect();
+    let legend = Json::object(vec![("tokenTypes", Json::Array(types)), ("tokenModifiers", Json::Array(Vec::new()))]);
+    let semantic_tokens = Json::object(vec![("legend", legend), ("full", Json::from(true))]);
+    let capabilities = Json::object(vec![
+        // Full sync
+        ("textDocumentSync", Json::from(1)),
+        ("semanticTokensProvider", semantic_tokens),
+        ("definitionProvider", Json::from(true)),
+        ("hoverProvider", Json::from(true)),
+        ("documentSymbolProvider", Json::from(true)),
+    ]);
+    let info = Json::object(vec![("name", Json::from("interpreter_lsp")), ("version", Json::from(env!("CARGO_PKG_VERSION")))]);
+    Json::object(vec![("capabilities", capabilities), ("serverInfo", info)])
+}
+
+/// The LSP diagnostic of a problem found in a document.
+fn diagnostic(document: &Document, diagnostic: &Diagnostic) -> Json {
+    let (severity, mut message) = match diagnostic.kind {
+        DiagnosticKind::Error(ref e) => (1, e.to_string()),
+        DiagnosticKind::Warning(ref w) => (2, w.to_string()),
+    };
+    if let Some(ref help) = diagnostic.help {
+        message = format!("{}\nhelp: {}", message, help);
+    }
+    Json::object(vec![("range", document.range(diagnostic.span())),
+                ("severity", Json::from(severity)),
+                ("source", Json::from("interpreter")),
+                ("message", Json::from(message))])
+}
+
+/// The semantic tokens of a document, each as the line and start
+/// relative to the previous token, its length, type and no modifiers.
+fn semantic_tokens(document: &Document) -> Json {
+    let mut data = Vec::new();
+    let (mut line, mut start) = (0, 0);
+    for token in &document.tokens {
+        let kind = match token_type(document, token) {
+            Some(kind) => kind,
+            None => continue,
+        };
+        let (token_line, token_start) = document.location(token.span);
+        if token_line != line {
+            start = 0;
+        }
+        data.extend(vec![token_line - line, token_start - start, token.len, kind, 0]);
+        line = token_line;
+        start = token_start;
+    }
+    Json::object(vec![("data", Json::Array(data.into_iter().map(Json::from).collect()))])
+}
+
+/// The index in `TOKEN_TYPES` of the kind of `token`, if it has one.
+fn token_type(document: &Document, token: &SourceToken) -> Option<usize> {
+    let name = |kind| TOKEN_TYPES.iter().position(|&t| t == kind);
+    match token.token {
+        ref t if t.is_keyword() => name("keyword"),
+        Token::Nil => name("keyword"),
+        ref t if t.is_arithmetic() || t.is_assignment() => name("operator"),
+        Token::Equal | Token::NotEqual | Token::GreaterThan | Token::LessThan | Token::GreaterEqual |
+        Token::LessEqual | Token::And | Token::Or | Token::Not | Token::Arrow |
+        Token::ExclusiveRange | Token::InclusiveRange => name("operator"),
+        Token::Int(_) | Token::Real(_) | Token::Duration(_) => name("number"),
+        Token::Str(_) | Token::RawStr(_) => name("string"),
+        Token::Comment(_) => name("comment"),
+        Token::Identity(ref id) => {
+            match document.declaration_of(token.span).map(|d| d.kind) {
+                Some(DeclarationKind::Parameter) => name("parameter"),
+                Some(DeclarationKind::Function) => name("function"),
+                Some(_) => name("variable"),
+                None if Builtin::lookup(id).is_some() => name("function"),
+                None => name("variable"),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The location of the declaration of the name at a position.
+fn definition(document: &Document, token: &SourceToken) -> Json {
+    match document.declaration_of(token.span) {
+        Some(declaration) => {
+            Json::object(vec![("uri", Json::from(document.uri)), ("range", document.range(document.name_of(declaration)))])
+        }
+        None => Json::Null,
+    }
+}
+
+/// What the builtin function at a position does.
+fn hover(document: &Document, token: &SourceToken) -> Json {
+    let builtin = match token.token {
+        Token::Identity(ref id) if document.declaration_of(token.span).is_none() => Builtin::lookup(id),
+        _ => None,
+    };
+    match builtin {
+        Some(builtin) => {
+            let contents = Json::object(vec![("kind", Json::from("markdown")), ("value", Json::from(builtin.help()))]);
+            Json::object(vec![("contents", contents), ("range", document.range(token.span))])
+        }
+        None => Json::Null,
+    }
+}
+
+/// The `fn` declarations of a document, with the ones declared in
+/// their bodies as children.
+fn document_symbols(document: &Document) -> Json {
+    match document.program {
+        Some(ref program) => Json::Array(symbols(document, program)),
+        None => Json::Null,
+    }
+}
+
+fn symbols(document: &Document, block: &[Stmt]) -> Vec<Json> {
+    let mut symbols = Vec::new();
+    for stmt in block {
+        match stmt.kind {
+            StmtKind::Function(ref function) => symbols.push(symbol(document, function)),
+            StmtKind::If(_, ref then, ref otherwise) => {
+                symbols.extend(self::symbols(document, then));
+                if let Some(ref otherwise) = *otherwise {
+                    symbols.extend(self::symbols(document, otherwise));
+                }
+            }
+            StmtKind::While(_, ref body) | StmtKind::For(_, _, ref body) => {
+                symbols.extend(self::symbols(document, body))
+            }
+            StmtKind::Every(_, ref function) | StmtKind::On(_, ref function) => {
+                symbols.extend(self::symbols(document, &function.body))
+            }
+            _ => {}
+        }
+    }
+    symbols
+}
+
+/// The symbol of a function declaration, which covers it from the `fn`
+/// to the `}` closing its body.
+fn symbol(document: &Document, function: &Function) -> Json {
+    let tokens = &document.tokens;
+    let start = tokens.iter().position(|t| t.span == function.span).unwrap_or(0);
+    // The body is the first block after the parameters
+    let params = closing(tokens, start + 2);
+    let body = tokens[params..].iter().position(|t| t.token == Token::LeftCurlyParam).map(|i| params + i);
+    let end = body.map(|body| closing(tokens, body)).unwrap_or(params);
+    let end_span = tokens.get(end).map_or(function.span, |t| t.span);
+    let end = document.range(end_span).get("end").cloned().unwrap_or(Json::Null);
+    let range = Json::object(vec![("start", document.position(function.span)), ("end", end)]);
+    let name = tokens.get(start + 1).map_or(function.span, |t| t.span);
+    let params: Vec<String> = function.params.iter().map(|param| match param.ty {
+        Some(ref ty) => format!("{}: {}", param.name, ty),
+        None => param.name.clone(),
+    }).collect();
+    let mut detail = format!("fn ({})", params.join(", "));
+    if let Some(ref ret) = function.ret {
+        detail = format!("{} -> {}", detail, ret);
+    }
+    Json::object(vec![("name", Json::from(function.name.as_deref().unwrap_or(""))),
+                ("detail", Json::from(detail)),
+                // Function
+                ("kind", Json::from(12)),
+                ("range", range),
+                ("selectionRange", document.range(name)),
+                ("children", Json::Array(symbols(document, &function.body)))])
+}
+
+/// The index of the bracket closing the one at `open`.
+fn closing(tokens: &[SourceToken], open: usize) -> usize {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token.token {
+            Token::LeftParam | Token::LeftSquareParam | Token::LeftCurlyParam => depth += 1,
+            Token::RightParam | Token::RightSquareParam | Token::RightCurlyParam => {
+                depth -= 1;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => {}
+        }
+    }
+    tokens.len().saturating_sub(1)
+}
+
+/// Reads the content of the next message, which follows its headers,
+/// or returns `None` at the end of the input.
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut content = vec![0; length];
+    input.read_exact(&mut content)?;
+    String::from_utf8(content).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes a message with its headers.
+pub fn write_message<W: Write>(output: &mut W, content: &str) -> io::Result<()> {
+    write!(output, "Content-Length: {}\r\n\r\n{}", content.len(), content)
+}
+
+fn error_response(id: Json, code: f64, message: &str) -> Json {
+    let error = Json::object(vec![("code", Json::Number(code)), ("message", Json::from(message))]);
+    Json::object(vec![("jsonrpc", Json::from("2.0")), ("id", id), ("error", error)])
+}
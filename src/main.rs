@@ -1,21 +1,51 @@
 extern crate interpreter;
 
 use interpreter::lexer;
+use interpreter::formatter;
+use interpreter::compiler;
+use interpreter::diagnostic::Diagnostic;
+use interpreter::debugger::Step;
+use interpreter::dap::Adapter;
+use interpreter::profiler::Metric;
+use interpreter::error::Error;
+use interpreter::eval::Interpreter;
+use interpreter::parser::Parser;
+use interpreter::vm::Vm;
 
-use std::io::Read;
-use std::fs::File;
+use std::env;
+use std::io::{self, BufRead, Read, Write};
+use std::fs::{self, File};
+use std::process;
+use std::rc::Rc;
 
-const FILE_NAME: &'static str = "tests/random.txt";
+const FILE_NAME: &str = "tests/random.txt";
 
-fn read_file() -> Result<String, std::io::Error> {
+const USAGE: &str = "usage: interpreter_bin [fmt [--check] [files..] | debug [--dap | file] |
+                                   profile [--instructions] [--collapsed out] file |
+                                   test [--coverage] files..]";
+
+const DEBUG_HELP: &str = "commands:
+  c, continue     run to the next breakpoint
+  s, step         run to the next statement
+  n, next         run to the next statement of this function
+  f, finish       run until this function returns
+  b, break LINE   stop before the statements on LINE
+  d, delete LINE  remove the breakpoint on LINE
+  bt, stack       list the calls
+  l, locals       list the variables of this function
+  p, print NAME   print a variable
+  q, quit         stop debugging";
+
+fn read_file(name: &str) -> Result<String, std::io::Error> {
     let mut buf = String::new();
-    let mut file = File::open(FILE_NAME)?;
+    let mut file = File::open(name)?;
     file.read_to_string(&mut buf)?;
     Ok(buf)
 }
 
-fn main() {
-    let buf = read_file().unwrap();
+/// Prints the tokens of `tests/random.txt`.
+fn dump_tokens() {
+    let buf = read_file(FILE_NAME).unwrap();
     let lexer = lexer::Lexer::new(&buf);
 
     for (item, line, pos) in lexer {
@@ -26,3 +56,306 @@ fn main() {
         }
     }
 }
+
+/// Formats the files in place, or stdin to stdout if there are none.
+/// With `--check` the files are left as they are and the ones which
+/// would change are listed. Returns the exit code.
+fn fmt(args: &[String]) -> i32 {
+    let check = args.iter().any(|arg| arg == "--check");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if files.is_empty() {
+        let mut source = String::new();
+        if let Err(e) = io::stdin().read_to_string(&mut source) {
+            eprintln!("<stdin>: {}", e);
+            return 2;
+        }
+        return match formatter::format(&source) {
+            Ok(ref formatted) if check => (*formatted != source) as i32,
+            Ok(formatted) => {
+                print!("{}", formatted);
+                0
+            }
+            Err(e) => {
+                eprintln!("<stdin>:{}", Diagnostic::error(e));
+                2
+            }
+        };
+    }
+    let mut code = 0;
+    for file in files {
+        let source = match read_file(file) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                code = 2;
+                continue;
+            }
+        };
+        let formatted = match formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!("{}:{}", file, Diagnostic::error(e));
+                code = 2;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{}", file);
+            code = code.max(1);
+        } else if let Err(e) = File::create(file).and_then(|mut out| out.write_all(formatted.as_bytes())) {
+            eprintln!("{}: {}", file, e);
+            code = 2;
+        }
+    }
+    code
+}
+
+/// Runs a script in the debugger, reading commands from stdin. It
+/// stops before the first statement. Returns the exit code, which is 1
+/// if the script fails. With `--dap` it serves the Debug Adapter
+/// Protocol over stdin and stdout instead.
+fn debug(args: &[String]) -> i32 {
+    let name = match *args {
+        [ref flag] if flag == "--dap" => {
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            if let Err(e) = Adapter::new().run(stdin.lock(), stdout.lock()) {
+                eprintln!("interpreter_bin: {}", e);
+                return 2;
+            }
+            return 0;
+        }
+        [ref name] => name,
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let source = match read_file(name) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", name, e);
+            return 2;
+        }
+    };
+    let program = match Parser::new(&source).parse_program() {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}:{}", name, Diagnostic::error(e));
+            return 2;
+        }
+    };
+    let lines: Vec<&str> = source.lines().collect();
+    let stdin = io::stdin();
+    let mut commands = stdin.lock().lines();
+    let mut vm = Vm::new();
+    vm.break_next();
+    // Compiled as written, so it stops where the source says
+    let module = match compiler::compile(&program) {
+        Ok(module) => module,
+        Err(e) => {
+            eprintln!("{}:{}", name, Diagnostic::error(e));
+            return 2;
+        }
+    };
+    let mut result = vm.run(Rc::new(module));
+    loop {
+        let span = match result {
+            Err(Error::Stopped(span)) => span,
+            Ok(value) => {
+                println!("finished with {}", value);
+                return 0;
+            }
+            Err(e) => {
+                println!("{}:{}", name, Diagnostic::error(e));
+                return 1;
+            }
+        };
+        println!("stopped at {}", span);
+        println!("{:>4} | {}", span.line, lines.get(span.line as usize - 1).unwrap_or(&""));
+        result = loop {
+            print!("(debug) ");
+            let _ = io::stdout().flush();
+            let command = match commands.next() {
+                Some(Ok(command)) => command,
+                _ => return 0,
+            };
+            let words: Vec<&str> = command.split_whitespace().collect();
+            match *words {
+                ["c"] | ["continue"] => break vm.resume(),
+                ["s"] | ["step"] => break vm.step(Step::In),
+                ["n"] | ["next"] => break vm.step(Step::Over),
+                ["f"] | ["finish"] => break vm.step(Step::Out),
+                ["b", line] | ["break", line] => match line.parse() {
+                    Ok(line) => {
+                        vm.set_breakpoint(line);
+                        println!("breakpoint at line {}", line);
+                    }
+                    Err(_) => println!("not a line: {}", line),
+                },
+                ["d", line] | ["delete", line] => match line.parse() {
+                    Ok(line) => {
+                        vm.remove_breakpoint(line);
+                        println!("removed the breakpoint at line {}", line);
+                    }
+                    Err(_) => println!("not a line: {}", line),
+                },
+                ["bt"] | ["stack"] => {
+                    for (i, frame) in vm.stack().iter().enumerate() {
+                        println!("#{} {} at {}", i, frame.name, frame.span);
+                    }
+                }
+                ["l"] | ["locals"] => {
+                    for (name, value) in &vm.stack()[0].locals {
+                        println!("{} = {}", name, value);
+                    }
+                }
+                ["p", name] | ["print", name] => {
+                    let value = vm.stack()[0].locals.iter().find(|local| local.0 == name).map(|local| local.1.clone())
+                        .or_else(|| vm.get_global(name));
+                    match value {
+                        Some(value) => println!("{} = {}", name, value),
+                        None => println!("no variable {}", name),
+                    }
+                }
+                ["q"] | ["quit"] => return 0,
+                [] => {}
+                _ => println!("{}", DEBUG_HELP),
+            }
+        };
+    }
+}
+
+/// Runs a script with the profiler and writes the report to stderr,
+/// sorted by time or, with `--instructions`, by instructions. With
+/// `--collapsed` the collapsed stacks are also written to a file for
+/// flame graph tools. Returns the exit code, which is 1 if the script
+/// fails.
+fn profile(args: &[String]) -> i32 {
+    let mut metric = Metric::Time;
+    let mut collapsed = None;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--instructions" => metric = Metric::Instructions,
+            "--collapsed" if args.len() > 0 => collapsed = args.next(),
+            _ => files.push(arg),
+        }
+    }
+    let name = match *files {
+        [name] if !name.starts_with("--") => name,
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let source = match read_file(name) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", name, e);
+            return 2;
+        }
+    };
+    let mut vm = Vm::new();
+    vm.start_profiling();
+    let code = match vm.eval(&source) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}:{}", name, Diagnostic::error(e));
+            1
+        }
+    };
+    let profile = vm.stop_profiling().expect("the script was profiled");
+    eprint!("{}", profile.report(metric));
+    if let Some(out) = collapsed {
+        if let Err(e) = File::create(out).and_then(|mut out| out.write_all(profile.collapsed(metric).as_bytes())) {
+            eprintln!("{}: {}", out, e);
+            return 2;
+        }
+    }
+    code
+}
+
+/// Runs the scripts, and those in the directories given, and reports
+/// which fail. A script fails if it stops with an error. With
+/// `--coverage` the statements and branches the scripts ran are
+/// written to `lcov.info` in the LCOV format. Returns the exit code,
+/// which is 1 if a script fails.
+fn test(args: &[String]) -> i32 {
+    let coverage = args.iter().any(|arg| arg == "--coverage");
+    let mut files = Vec::new();
+    for arg in args.iter().filter(|arg| *arg != "--coverage") {
+        match fs::read_dir(arg) {
+            Ok(entries) => {
+                let mut scripts: Vec<String> = entries.filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_file() && path.extension().is_some_and(|e| e == "txt"))
+                    .map(|path| path.display().to_string())
+                    .collect();
+                scripts.sort();
+                files.extend(scripts);
+            }
+            Err(_) => files.push(arg.clone()),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+    let (mut passed, mut failed) = (0, 0);
+    let mut lcov = String::new();
+    for file in &files {
+        let result = read_file(file).map_err(|e| format!("{}: {}", file, e)).and_then(|source| {
+            let program = Parser::new(&source).parse_program()
+                .map_err(|e| format!("{}:{}", file, Diagnostic::error(e)))?;
+            let mut interpreter = Interpreter::new();
+            interpreter.capture_output();
+            if coverage {
+                interpreter.start_coverage(&program);
+            }
+            let result = interpreter.run(&program);
+            if let Some(coverage) = interpreter.stop_coverage() {
+                lcov.push_str(&coverage.lcov(file));
+            }
+            let output = interpreter.take_output();
+            result.map_err(|e| format!("{}{}:{}", output, file, Diagnostic::error(e)))
+        });
+        match result {
+            Ok(_) => {
+                println!("test {} ... ok", file);
+                passed += 1;
+            }
+            Err(e) => {
+                println!("test {} ... FAILED\n{}", file, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} passed; {} failed", passed, failed);
+    if coverage {
+        if let Err(e) = File::create("lcov.info").and_then(|mut out| out.write_all(lcov.as_bytes())) {
+            eprintln!("lcov.info: {}", e);
+            return 2;
+        }
+    }
+    (failed > 0) as i32
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => dump_tokens(),
+        Some("fmt") => process::exit(fmt(&args[1..])),
+        Some("debug") => process::exit(debug(&args[1..])),
+        Some("profile") => process::exit(profile(&args[1..])),
+        Some("test") => process::exit(test(&args[1..])),
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use span::Span;
+use real::Real;
+use value::Value;
+use error;
+use error::Error::*;
+
+/// A function written in Rust that scripts can call, registered by the
+/// host with `register_fn` on the `Interpreter` or the `Vm`.
+pub struct Native {
+    name: String,
+    arity: usize,
+    func: RefCell<Box<NativeFn>>,
+}
+
+/// A native function taking its name, the arguments and where it is
+/// called from. The number of arguments has already been checked.
+type NativeFn = dyn FnMut(&str, Vec<Value>, Span) -> error::Result<Value>;
+
+impl Native {
+    /// Wraps `f`, a Rust closure taking arguments that can be converted
+    /// from values with `FromValue` and returning a `NativeResult`.
+    pub fn new<Args, F: IntoNative<Args>>(name: &str, f: F) -> Native {
+        Native {
+            name: name.to_string(),
+            arity: F::arity(),
+            func: RefCell::new(f.into_native()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// Calls the function with `args`. The `span` is where it is called
+    /// from, and is used for every error.
+    pub fn call(&self, args: Vec<Value>, span: Span) -> error::Result<Value> {
+        if args.len() != self.arity {
+            return Err(ArityMismatch(self.arity, args.len(), span));
+        }
+        // A native function cannot run scripts, so it is never called
+        // while it is already running.
+        let mut func = self.func.borrow_mut();
+        (*func)(&self.name, args, span)
+    }
+}
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Native) -> bool {
+        ::std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
+/// The native functions registered by the host, by name.
+pub type Natives = HashMap<String, Rc<Native>>;
+
+/// A Rust type that can be converted from a script value.
+pub trait FromValue: Sized {
+    /// The name of the type in error messages.
+    fn type_name() -> String;
+
+    /// Converts `value`, or returns `None` if it has the wrong type.
+    fn from_value(value: Value) -> Option<Self>;
+}
+
+/// A Rust type that can be converted to a script value.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+impl FromValue for Value {
+    fn type_name() -> String {
+        "any value".to_string()
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl FromValue for i32 {
+    fn type_name() -> String {
+        "int".to_string()
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+}
+
+/// Ints are converted to reals like in arithmetic.
+impl FromValue for Real {
+    fn type_name() -> String {
+        "real".to_string()
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Real(r) => Some(r),
+            Value::Int(i) => Real::checked_from_int(i),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for String {
+    fn type_name() -> String {
+        "str".to_string()
+    }
+
+    fn from_value(mut value: Value) -> Option<Self> {
+        match value {
+            Value::Str(ref mut s) => Some(::std::mem::take(s)),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn type_name() -> String {
+        "bool".to_string()
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+/// A list whose items all convert to `T`. The items are copied.
+impl<T: FromValue> FromValue for Vec<T> {
+    fn type_name() -> String {
+        format!("list of {}", T::type_name())
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::List(ref list) => list.borrow().iter().cloned().map(T::from_value).collect(),
+            _ => None,
+        }
+    }
+}
+
+/// `nil` is `None`, any other value must convert to `T`.
+impl<T: FromValue> FromValue for Option<T> {
+    fn type_name() -> String {
+        format!("{} or nil", T::type_name())
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Nil => Some(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+
+impl IntoValue for i32 {
+    fn into_value(self) -> Value {
+        Value::Int(self)
+    }
+}
+
+impl IntoValue for Real {
+    fn into_value(self) -> Value {
+        Value::Real(self)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::Str(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::Str(self.to_string())
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::list(self.into_iter().map(IntoValue::into_value).collect())
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        self.map_or(Value::Nil, IntoValue::into_value)
+    }
+}
+
+/// What a native function can return: a value, or a `Result` whose
+/// error is reported to the script as a `NativeError`.
+pub trait NativeResult {
+    fn into_result(self, name: &str, span: Span) -> error::Result<Value>;
+}
+
+impl<T: IntoValue> NativeResult for T {
+    fn into_result(self, _name: &str, _span: Span) -> error::Result<Value> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue, E: fmt::Display> NativeResult for Result<T, E> {
+    fn into_result(self, name: &str, span: Span) -> error::Result<Value> {
+        self.map(IntoValue::into_value)
+            .map_err(|e| NativeError(format!("{}: {}", name, e), span))
+    }
+}
+
+/// A Rust closure that can be registered as a native function. It is
+/// implemented for closures of up to six arguments, `Args` being the
+/// tuple of their types.
+pub trait IntoNative<Args> {
+    fn arity() -> usize;
+    fn into_native(self) -> Box<NativeFn>;
+}
+
+/// Converts the argument at `index` (counting from 0) of `name`.
+fn argument<T: FromValue>(name: &str, index: usize, value: Value, span: Span)
+                          -> error::Result<T> {
+    let found = value.type_name();
+    T::from_value(value).ok_or_else(|| {
+        let msg = format!("argument {} of {} must be {}, found {}",
+                          index + 1, name, T::type_name(), found);
+        TypeMismatch(msg, span)
+    })
+}
+
+macro_rules! into_native {
+    ($arity:expr; $($arg:ident $var:ident),*) => {
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+            where F: FnMut($($arg),*) -> R + 'static,
+                  R: NativeResult,
+                  $($arg: FromValue),*
+        {
+            fn arity() -> usize {
+                $arity
+            }
+
+            #[allow(unused_variables, unused_mut)]
+            fn into_native(mut self) -> Box<NativeFn> {
+                Box::new(move |name: &str, args: Vec<Value>, span: Span| {
+                    let mut args = args.into_iter().enumerate();
+                    $(
+                        let (index, value) = args.next().expect("arity was checked");
+                        let $var = argument::<$arg>(name, index, value, span)?;
+                    )*
+                    self($($var),*).into_result(name, span)
+                })
+            }
+        }
+    }
+}
+
+into_native!(0;);
+into_native!(1; A a);
+into_native!(2; A a, B b);
+into_native!(3; A a, B b, C c);
+into_native!(4; A a, B b, C c, D d);
+into_native!(5; A a, B b, C c, D d, E e);
+into_native!(6; A a, B b, C c, D d, E e, G g);
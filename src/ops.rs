@@ -0,0 +1,300 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use ast::{BinaryOp, UnaryOp};
+use real::Real;
+use span::Span;
+use value::{Value, Range, address};
+use error;
+use error::Error::*;
+
+/// Two numbers of the same type. An `Int` paired with a `Real`
+/// is converted to a `Real`.
+enum Numbers {
+    Ints(i32, i32),
+    Reals(Real, Real),
+}
+
+/// Applies the prefix operator `op` to `value`.
+pub fn unary(op: UnaryOp, value: Value, span: Span) -> error::Result<Value> {
+    match (op, value) {
+        (UnaryOp::Not, value) => Ok(Value::Bool(!value.is_truthy())),
+        (UnaryOp::Neg, Value::Int(i)) => i.checked_neg().map(Value::Int).ok_or(Overflow(span)),
+        (UnaryOp::Neg, Value::Real(r)) => r.checked_neg().map(Value::Real).ok_or(Overflow(span)),
+        (op, value) => {
+            Err(TypeMismatch(format!("cannot apply `{}` to {}", op.symbol(), value.type_name()),
+                             span))
+        }
+    }
+}
+
+/// Applies the binary operator `op` to `lhs` and `rhs`.
+/// `&` and `|` evaluate both operands here, short-circuiting
+/// is left to the caller.
+pub fn binary(op: BinaryOp, lhs: Value, rhs: Value, span: Span) -> error::Result<Value> {
+    match op {
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => {
+            arithmetic(op, lhs, rhs, span)
+        }
+        BinaryOp::Equal => Ok(Value::Bool(equals(&lhs, &rhs))),
+        BinaryOp::NotEqual => Ok(Value::Bool(!equals(&lhs, &rhs))),
+        BinaryOp::GreaterThan => compare(op, &lhs, &rhs, span).map(|o| Value::Bool(o == Ordering::Greater)),
+        BinaryOp::LessThan => compare(op, &lhs, &rhs, span).map(|o| Value::Bool(o == Ordering::Less)),
+        BinaryOp::GreaterEqual => compare(op, &lhs, &rhs, span).map(|o| Value::Bool(o != Ordering::Less)),
+        BinaryOp::LessEqual => compare(op, &lhs, &rhs, span).map(|o| Value::Bool(o != Ordering::Greater)),
+        BinaryOp::And => Ok(Value::Bool(lhs.is_truthy() && rhs.is_truthy())),
+        BinaryOp::Or => Ok(Value::Bool(lhs.is_truthy() || rhs.is_truthy())),
+    }
+}
+
+/// Creates a range from its bounds. Without a step the step is one.
+/// Fails if the bounds are not numbers or if the step is zero.
+pub fn range(start: Value, end: Value, step: Option<Value>, inclusive: bool, span: Span)
+             -> error::Result<Value> {
+    let step = step.unwrap_or(Value::Int(1));
+    let to_real = |value: &Value| match *value {
+        Value::Int(i) => Real::checked_from_int(i).ok_or(Overflow(span)),
+        Value::Real(r) => Ok(r),
+        _ => Err(TypeMismatch(format!("cannot make a range of {}", value.type_name()), span)),
+    };
+    let range = match (&start, &end, &step) {
+        (&Value::Int(start), &Value::Int(end), &Value::Int(step)) => {
+            if step == 0 {
+                return Err(ZeroStep(span));
+            }
+            Range::Int { start, end, step, inclusive }
+        }
+        _ => {
+            let (start, end, step) = (to_real(&start)?, to_real(&end)?, to_real(&step)?);
+            if step.is_zero() {
+                return Err(ZeroStep(span));
+            }
+            Range::Real { start, end, step, inclusive }
+        }
+    };
+    Ok(Value::Range(range))
+}
+
+/// Reads `target[index]`. An int index picks one item, a negative index
+/// counts from the end. A range index takes a slice. Lists and
+/// strings can be indexed.
+pub fn index(target: &Value, index: &Value, span: Span) -> error::Result<Value> {
+    match (target, index) {
+        (Value::List(list), &Value::Int(i)) => {
+            let list = list.borrow();
+            Ok(list[position(i, list.len(), span)?].clone())
+        }
+        (Value::List(list), Value::Range(range)) => {
+            let list = list.borrow();
+            let items = slice_positions(range, list.len(), span)?;
+            Ok(Value::list(items.into_iter().map(|i| list[i].clone()).collect()))
+        }
+        (Value::Map(_), Value::Str(key)) => field(target, key, span),
+        (Value::Str(s), &Value::Int(i)) => {
+            let chars: Vec<char> = s.chars().collect();
+            Ok(Value::Str(chars[position(i, chars.len(), span)?].to_string()))
+        }
+        (Value::Str(s), Value::Range(range)) => {
+            let chars: Vec<char> = s.chars().collect();
+            let items = slice_positions(range, chars.len(), span)?;
+            Ok(Value::Str(items.into_iter().map(|i| chars[i]).collect()))
+        }
+        _ => {
+            Err(TypeMismatch(format!("cannot index {} with {}",
+                                     target.type_name(),
+                                     index.type_name()),
+                             span))
+        }
+    }
+}
+
+/// Performs `target[index] = value`. Only single items of lists
+/// and entries of maps can be assigned.
+pub fn set_index(target: &Value, index: &Value, value: Value, span: Span) -> error::Result<()> {
+    match (target, index) {
+        (Value::Map(_), Value::Str(key)) => set_field(target, key, value, span),
+        (Value::List(list), &Value::Int(i)) => {
+            let mut list = list.borrow_mut();
+            let i = position(i, list.len(), span)?;
+            list[i] = value;
+            Ok(())
+        }
+        _ => {
+            Err(TypeMismatch(format!("cannot assign to {} indexed with {}",
+                                     target.type_name(),
+                                     index.type_name()),
+                             span))
+        }
+    }
+}
+
+/// Reads `target.name`.
+pub fn field(target: &Value, name: &str, span: Span) -> error::Result<Value> {
+    match *target {
+        Value::Map(ref map) => {
+            map.borrow().get(name).cloned().ok_or_else(|| MissingField(name.to_string(), span))
+        }
+        _ => Err(TypeMismatch(format!("{} has no fields", target.type_name()), span)),
+    }
+}
+
+/// Performs `target.name = value`. Assigning to a missing field adds it.
+pub fn set_field(target: &Value, name: &str, value: Value, span: Span) -> error::Result<()> {
+    match *target {
+        Value::Map(ref map) => {
+            map.borrow_mut().insert(name.to_string(), value);
+            Ok(())
+        }
+        _ => Err(TypeMismatch(format!("{} has no fields", target.type_name()), span)),
+    }
+}
+
+/// Converts a possibly negative index to a position in a sequence of `len` items.
+fn position(index: i32, len: usize, span: Span) -> error::Result<usize> {
+    let i = if index < 0 {
+        len as i64 + index as i64
+    } else {
+        index as i64
+    };
+    if i < 0 || i >= len as i64 {
+        Err(IndexOutOfBounds(index, span))
+    } else {
+        Ok(i as usize)
+    }
+}
+
+/// Returns the positions picked by slicing a sequence of `len` items with `range`.
+fn slice_positions(range: &Range, len: usize, span: Span) -> error::Result<Vec<usize>> {
+    if let Range::Real { .. } = *range {
+        return Err(TypeMismatch("cannot index with a range of reals".to_string(), span));
+    }
+    range.iter()
+        .map(|i| match i {
+            Value::Int(i) => position(i, len, span),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Checks if two values are equal. An `Int` is equal to a `Real`
+/// with the same value. Lists and maps are equal if their items are equal,
+/// so a list holding itself is equal to another holding itself.
+pub fn equals(lhs: &Value, rhs: &Value) -> bool {
+    let mut compared = HashSet::new();
+    let mut pending = Vec::new();
+    if !equals_in(lhs, rhs, &mut compared, &mut pending) {
+        return false;
+    }
+    while let Some((lhs, rhs)) = pending.pop() {
+        let equal = match (&lhs, &rhs) {
+            (Value::List(a), Value::List(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() &&
+                a.iter().zip(b.iter()).all(|(a, b)| equals_in(a, b, &mut compared, &mut pending))
+            }
+            (Value::Map(a), Value::Map(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() &&
+                a.iter().zip(b.iter()).all(|((k1, v1), (k2, v2))| {
+                    k1 == k2 && equals_in(v1, v2, &mut compared, &mut pending)
+                })
+            }
+            _ => unreachable!(),
+        };
+        if !equal {
+            return false;
+        }
+    }
+    true
+}
+
+/// Checks if two values are equal, leaving the items of two lists or
+/// maps to compare later: the pair is added to `pending` and counts as
+/// equal for now. The pairs in `compared` were added already, so they
+/// are not compared again.
+fn equals_in(lhs: &Value, rhs: &Value, compared: &mut HashSet<(usize, usize)>,
+             pending: &mut Vec<(Value, Value)>) -> bool {
+    match (lhs, rhs) {
+        (&Value::Int(i), &Value::Real(r)) | (&Value::Real(r), &Value::Int(i)) => {
+            Real::checked_from_int(i) == Some(r)
+        }
+        (Value::List(a), Value::List(b)) => {
+            if !Rc::ptr_eq(a, b) && compared.insert((address(a), address(b))) {
+                pending.push((lhs.clone(), rhs.clone()));
+            }
+            true
+        }
+        (Value::Map(a), Value::Map(b)) => {
+            if !Rc::ptr_eq(a, b) && compared.insert((address(a), address(b))) {
+                pending.push((lhs.clone(), rhs.clone()));
+            }
+            true
+        }
+        _ => lhs == rhs,
+    }
+}
+
+/// Orders two numbers or two strings.
+fn compare(op: BinaryOp, lhs: &Value, rhs: &Value, span: Span) -> error::Result<Ordering> {
+    if let (Value::Str(a), Value::Str(b)) = (lhs, rhs) {
+        return Ok(a.cmp(b));
+    }
+    match numbers(op, lhs, rhs, span)? {
+        Numbers::Ints(a, b) => Ok(a.cmp(&b)),
+        Numbers::Reals(a, b) => Ok(a.cmp(&b)),
+    }
+}
+
+/// Performs `+`, `-`, `*` and `/`. `+` also concatenates strings.
+fn arithmetic(op: BinaryOp, lhs: Value, rhs: Value, span: Span) -> error::Result<Value> {
+    if let (BinaryOp::Add, Value::Str(a), Value::Str(b)) = (op, &lhs, &rhs) {
+        return Ok(Value::Str(format!("{}{}", a, b)));
+    }
+    let result = match numbers(op, &lhs, &rhs, span)? {
+        Numbers::Ints(a, b) => {
+            if op == BinaryOp::Div && b == 0 {
+                return Err(DivisionByZero(span));
+            }
+            match op {
+                BinaryOp::Add => a.checked_add(b),
+                BinaryOp::Sub => a.checked_sub(b),
+                BinaryOp::Mul => a.checked_mul(b),
+                _ => a.checked_div(b),
+            }.map(Value::Int)
+        }
+        Numbers::Reals(a, b) => {
+            if op == BinaryOp::Div && b.is_zero() {
+                return Err(DivisionByZero(span));
+            }
+            match op {
+                BinaryOp::Add => a.checked_add(b),
+                BinaryOp::Sub => a.checked_sub(b),
+                BinaryOp::Mul => a.checked_mul(b),
+                _ => a.checked_div(b),
+            }.map(Value::Real)
+        }
+    };
+    result.ok_or(Overflow(span))
+}
+
+/// Converts the operands of a numeric operator to the same type.
+fn numbers(op: BinaryOp, lhs: &Value, rhs: &Value, span: Span) -> error::Result<Numbers> {
+    let to_real = |i| Real::checked_from_int(i).ok_or(Overflow(span));
+    match (lhs, rhs) {
+        (&Value::Int(a), &Value::Int(b)) => Ok(Numbers::Ints(a, b)),
+        (&Value::Real(a), &Value::Real(b)) => Ok(Numbers::Reals(a, b)),
+        (&Value::Int(a), &Value::Real(b)) => Ok(Numbers::Reals(to_real(a)?, b)),
+        (&Value::Real(a), &Value::Int(b)) => Ok(Numbers::Reals(a, to_real(b)?)),
+        _ => Err(mismatch(op, lhs, rhs, span)),
+    }
+}
+
+/// Creates the error for a binary operator applied to the wrong types.
+pub fn mismatch(op: BinaryOp, lhs: &Value, rhs: &Value, span: Span) -> error::Error {
+    TypeMismatch(format!("cannot apply `{}` to {} and {}",
+                         op.symbol(),
+                         lhs.type_name(),
+                         rhs.type_name()),
+                 span)
+}
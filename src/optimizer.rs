@@ -0,0 +1,229 @@
+use std::rc::Rc;
+
+use ast::*;
+use diagnostic::Diagnostic;
+use span::Span;
+use value::Value;
+use ops;
+
+/// Simplifies a program before it runs, without changing what it does.
+///
+/// Operators applied to constants are computed once here with the same
+/// semantics as at runtime, see `ops`: arithmetic and comparisons of
+/// numbers, `!`, `==` and `!=` of constants, and `&` and `|` when their
+/// left side decides the result. An operation which fails, like one
+/// which overflows, is left for the runtime and reported as a
+/// diagnostic. Branches of an `if` or `?:` with a constant condition
+/// are taken or dropped, as are `while` loops which never run and
+/// statements after a `return` or `break`.
+///
+/// Returns the simplified program and the operations which will fail
+/// if they run.
+pub fn optimize(program: &[Stmt]) -> (Program, Vec<Diagnostic>) {
+    let mut optimizer = Optimizer { diagnostics: Vec::new() };
+    let mut optimized = optimizer.block(program);
+    // The program returns the value of its last statement if it is an
+    // expression, so one which is not must not be dropped to reveal one
+    let ends_with_expr = |block: &[Stmt]| matches!(block.last().map(|stmt| &stmt.kind), Some(&StmtKind::Expr(_)));
+    if ends_with_expr(&optimized) && !ends_with_expr(program) {
+        let span = program.last().unwrap().span;
+        let cond = Expr::new(ExprKind::Bool(false), span);
+        optimized.push(Stmt::new(StmtKind::If(cond, Vec::new(), None), span));
+    }
+    (optimized, optimizer.diagnostics)
+}
+
+struct Optimizer {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Optimizer {
+    fn block(&mut self, block: &[Stmt]) -> Block {
+        let mut optimized = Vec::with_capacity(block.len());
+        for stmt in block {
+            if let Some(stmt) = self.stmt(stmt) {
+                let ends = matches!(stmt.kind, StmtKind::Return(_) | StmtKind::Break);
+                optimized.push(stmt);
+                // Nothing after it runs
+                if ends {
+                    break;
+                }
+            }
+        }
+        optimized
+    }
+
+    /// Simplifies a statement, or returns `None` if it does nothing.
+    fn stmt(&mut self, stmt: &Stmt) -> Option<Stmt> {
+        let kind = match stmt.kind {
+            StmtKind::Expr(ref expr) => StmtKind::Expr(self.expr(expr)),
+            StmtKind::Assign(ref target, op, ref value) => {
+                StmtKind::Assign(self.expr(target), op, self.expr(value))
+            }
+            StmtKind::If(ref cond, ref then, ref otherwise) => {
+                let cond = self.expr(cond);
+                match constant(&cond) {
+                    // The block is kept for its scope
+                    Some(value) => {
+                        let taken = match *otherwise {
+                            _ if value.is_truthy() => then,
+                            Some(ref otherwise) => otherwise,
+                            None => return None,
+                        };
+                        let cond = Expr::new(ExprKind::Bool(true), cond.span);
+                        StmtKind::If(cond, self.block(taken), None)
+                    }
+                    None => {
+                        let otherwise = otherwise.as_ref().map(|block| self.block(block));
+                        StmtKind::If(cond, self.block(then), otherwise)
+                    }
+                }
+            }
+            StmtKind::While(ref cond, ref body) => {
+                let cond = self.expr(cond);
+                if constant(&cond).is_some_and(|value| !value.is_truthy()) {
+                    return None;
+                }
+                StmtKind::While(cond, self.block(body))
+            }
+            StmtKind::For(ref name, ref iterable, ref body) => {
+                StmtKind::For(name.clone(), self.expr(iterable), self.block(body))
+            }
+            StmtKind::Break => StmtKind::Break,
+            StmtKind::Return(ref value) => StmtKind::Return(value.as_ref().map(|value| self.expr(value))),
+            StmtKind::Function(ref function) => StmtKind::Function(self.function(function)),
+            StmtKind::Every(period, ref function) => StmtKind::Every(period, self.function(function)),
+            StmtKind::On(ref device, ref function) => StmtKind::On(device.clone(), self.function(function)),
+        };
+        Some(Stmt::new(kind, stmt.span))
+    }
+
+    fn function(&mut self, function: &Function) -> Rc<Function> {
+        Rc::new(Function {
+            name: function.name.clone(),
+            params: function.params.clone(),
+            ret: function.ret.clone(),
+            body: self.block(&function.body),
+            span: function.span,
+        })
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Expr {
+        let boxed = |optimizer: &mut Optimizer, expr: &Expr| Box::new(optimizer.expr(expr));
+        let kind = match expr.kind {
+            ExprKind::Unary(op, ref operand) => {
+                let operand = self.expr(operand);
+                let folded = match constant(&operand) {
+                    Some(ref value) if op == UnaryOp::Not || is_number(value) => {
+                        self.fold(ops::unary(op, value.clone(), expr.span))
+                    }
+                    _ => None,
+                };
+                match folded {
+                    Some(kind) => kind,
+                    None => ExprKind::Unary(op, Box::new(operand)),
+                }
+            }
+            ExprKind::Binary(op, ref left, ref right) => {
+                let left = self.expr(left);
+                let right = self.expr(right);
+                match self.binary(op, &left, &right, expr.span) {
+                    Some(kind) => kind,
+                    None => ExprKind::Binary(op, Box::new(left), Box::new(right)),
+                }
+            }
+            ExprKind::Ternary(ref cond, ref then, ref otherwise) => {
+                let cond = self.expr(cond);
+                match constant(&cond) {
+                    Some(ref value) if value.is_truthy() => return self.expr(then),
+                    Some(_) => return self.expr(otherwise),
+                    None => ExprKind::Ternary(Box::new(cond), boxed(self, then), boxed(self, otherwise)),
+                }
+            }
+            ExprKind::List(ref items) => ExprKind::List(items.iter().map(|item| self.expr(item)).collect()),
+            ExprKind::Index(ref target, ref index) => ExprKind::Index(boxed(self, target), boxed(self, index)),
+            ExprKind::Map(ref entries) => {
+                ExprKind::Map(entries.iter().map(|entry| (entry.0.clone(), self.expr(&entry.1))).collect())
+            }
+            ExprKind::Field(ref object, ref name) => ExprKind::Field(boxed(self, object), name.clone()),
+            ExprKind::Call(ref callee, ref args) => {
+                ExprKind::Call(boxed(self, callee), args.iter().map(|arg| self.expr(arg)).collect())
+            }
+            ExprKind::Function(ref function) => ExprKind::Function(self.function(function)),
+            ExprKind::Range(ref start, ref end, ref step, inclusive) => {
+                let step = step.as_ref().map(|step| boxed(self, step));
+                ExprKind::Range(boxed(self, start), boxed(self, end), step, inclusive)
+            }
+            _ => expr.kind.clone(),
+        };
+        Expr::new(kind, expr.span)
+    }
+
+    /// Folds `left op right` if the operands are constants it can be
+    /// computed for.
+    fn binary(&mut self, op: BinaryOp, left: &Expr, right: &Expr, span: Span) -> Option<ExprKind> {
+        let lhs = constant(left)?;
+        // The right side is not evaluated if the left one decides
+        match op {
+            BinaryOp::And if !lhs.is_truthy() => return Some(ExprKind::Bool(false)),
+            BinaryOp::Or if lhs.is_truthy() => return Some(ExprKind::Bool(true)),
+            _ => {}
+        }
+        let rhs = constant(right)?;
+        let foldable = match op {
+            BinaryOp::Equal | BinaryOp::NotEqual | BinaryOp::And | BinaryOp::Or => true,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => {
+                is_number(&lhs) && is_number(&rhs)
+            }
+            _ => is_number(&lhs) && is_number(&rhs) || is_str(&lhs) && is_str(&rhs),
+        };
+        if !foldable {
+            return None;
+        }
+        self.fold(ops::binary(op, lhs, rhs, span))
+    }
+
+    /// The literal for a computed value, or `None` if it failed, which
+    /// is reported.
+    fn fold(&mut self, result: ::error::Result<Value>) -> Option<ExprKind> {
+        match result {
+            Ok(value) => literal(value),
+            Err(e) => {
+                self.diagnostics.push(Diagnostic::error(e));
+                None
+            }
+        }
+    }
+}
+
+/// The value of a literal.
+fn constant(expr: &Expr) -> Option<Value> {
+    Some(match expr.kind {
+        ExprKind::Int(i) => Value::Int(i),
+        ExprKind::Real(r) => Value::Real(r),
+        ExprKind::Str(ref s) => Value::Str(s.clone()),
+        ExprKind::Bool(b) => Value::Bool(b),
+        ExprKind::Nil => Value::Nil,
+        _ => return None,
+    })
+}
+
+/// The literal of a value, if it can be written as one.
+fn literal(mut value: Value) -> Option<ExprKind> {
+    Some(match value {
+        Value::Int(i) => ExprKind::Int(i),
+        Value::Real(r) => ExprKind::Real(r),
+        Value::Str(ref mut s) => ExprKind::Str(::std::mem::take(s)),
+        Value::Bool(b) => ExprKind::Bool(b),
+        Value::Nil => ExprKind::Nil,
+        _ => return None,
+    })
+}
+
+fn is_number(value: &Value) -> bool {
+    matches!(*value, Value::Int(_) | Value::Real(_))
+}
+
+fn is_str(value: &Value) -> bool {
+    matches!(*value, Value::Str(_))
+}
@@ -1,13 +1,611 @@
 use lexer::Lexer;
+use tokens::Token;
+use ast::*;
+use span::Span;
+use types::Type;
+use diagnostic;
+use error;
+use error::Error::*;
 
 use std::iter::Peekable;
+use std::rc::Rc;
 
+/// Returned by `Parser::peek` when the lexer has no more tokens.
+static END_OF_FILE: Token = Token::EndOfFile;
+
+/// The keywords starting a statement, which a misspelled one is
+/// likely to be.
+const STATEMENT_KEYWORDS: [&str; 8] = ["if", "while", "for", "break", "return", "fn", "every", "on"];
+
+/// How deep expressions and blocks may be nested in each other, so the
+/// passes walking the tree do not overflow the stack. A chain of binary
+/// operators or calls nests as deep as it is long.
+pub const MAX_DEPTH: usize = 100;
+
+/// A recursive descent parser which builds the AST from the
+/// tokens produced by the `Lexer`.
 pub struct Parser<'a> {
     lexer: Peekable<Lexer<'a>>,
+    /// The span of the last consumed `Token`.
+    span: Span,
+    /// The keyword which a lone identifier starting a statement on the
+    /// current line may be a typo of, and the line.
+    typo: Option<(&'static str, u32)>,
+    /// How deep the expression or block being parsed is nested.
+    depth: usize,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(input: &'a str) -> Self {
-        Parser { lexer: Lexer::new(input).peekable() }
+        Parser {
+            lexer: Lexer::new(input).peekable(),
+            span: Span::new(1, 1),
+            typo: None,
+            depth: 0,
+        }
+    }
+
+    /// A help note for the error returned by the parser: the keyword
+    /// a word on the line where the statement failing to parse starts
+    /// may be a typo of, like `whiel` in `whiel x < 3 {`.
+    pub fn help(&self) -> Option<&'static str> {
+        self.typo.map(|typo| typo.0)
+    }
+
+    /// Parses the whole input as a list of statements.
+    pub fn parse_program(&mut self) -> error::Result<Program> {
+        let mut program = Vec::new();
+        while *self.peek()? != Token::EndOfFile {
+            program.push(self.parse_statement()?);
+        }
+        Ok(program)
+    }
+
+    /// Parses the whole input as a single expression.
+    pub fn parse_expression(&mut self) -> error::Result<Expr> {
+        let expr = self.parse_expr()?;
+        self.expect(Token::EndOfFile)?;
+        Ok(expr)
+    }
+
+    /// Returns the next `Token` without consuming it.
+    fn peek(&mut self) -> error::Result<&Token> {
+        match self.lexer.peek() {
+            Some(&(Ok(ref token), _, _)) => Ok(token),
+            Some(&(Err(ref e), line, column)) => {
+                Err(Lexical(Box::new(e.clone()), Span::new(line, column)))
+            }
+            None => Ok(&END_OF_FILE),
+        }
+    }
+
+    /// Returns the span of the next `Token`.
+    fn peek_span(&mut self) -> Span {
+        match self.lexer.peek() {
+            Some(&(_, line, column)) => Span::new(line, column),
+            None => self.span,
+        }
+    }
+
+    /// Returns the `Token` after the next one without consuming anything.
+    fn peek_second(&self) -> Option<Token> {
+        let mut lexer = self.lexer.clone();
+        lexer.next();
+        match lexer.next() {
+            Some((Ok(token), _, _)) => Some(token),
+            _ => None,
+        }
+    }
+
+    /// Consumes the next `Token` and returns it together with its span.
+    fn next(&mut self) -> error::Result<(Token, Span)> {
+        match self.lexer.next() {
+            Some((Ok(token), line, column)) => {
+                self.span = Span::new(line, column);
+                Ok((token, self.span))
+            }
+            Some((Err(e), line, column)) => Err(Lexical(Box::new(e), Span::new(line, column))),
+            None => Ok((Token::EndOfFile, self.span)),
+        }
+    }
+
+    /// Consumes the next `Token` if it is equal to `token`.
+    fn eat(&mut self, token: &Token) -> error::Result<bool> {
+        if self.peek()? == token {
+            self.next()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Consumes the next `Token` and returns its span,
+    /// or an error if it is not `expected`.
+    fn expect(&mut self, expected: Token) -> error::Result<Span> {
+        let span = self.peek_span();
+        let found = self.peek()?.clone();
+        if found == expected {
+            self.next()?;
+            Ok(span)
+        } else {
+            Err(ExpectedToken(expected, found, span))
+        }
+    }
+
+    /// Consumes an identifier and returns its name.
+    fn expect_identity(&mut self) -> error::Result<(String, Span)> {
+        match self.next()? {
+            (Token::Identity(name), span) => Ok((name, span)),
+            (found, span) => Err(ExpectedToken(Token::Identity(String::new()), found, span)),
+        }
+    }
+
+    /// Parses a statement. A statement can be followed by an optional `;`.
+    fn parse_statement(&mut self) -> error::Result<Stmt> {
+        let span = self.peek_span();
+        if self.typo.is_some_and(|typo| typo.1 < span.line) {
+            self.typo = None;
+        }
+        // `fn name(..)` declares a function, `fn (..)` starts an expression
+        let declaration = matches!(*self.peek()?, Token::Function) &&
+                          matches!(self.peek_second(), Some(Token::Identity(_)));
+        let kind = match *self.peek()? {
+            Token::If => self.parse_if()?,
+            Token::While => {
+                self.next()?;
+                let cond = self.parse_expr()?;
+                StmtKind::While(cond, self.parse_block()?)
+            }
+            Token::For => {
+                self.next()?;
+                let (name, _) = self.expect_identity()?;
+                self.expect(Token::In)?;
+                let iterable = self.parse_expr()?;
+                StmtKind::For(name, iterable, self.parse_block()?)
+            }
+            Token::Break => {
+                self.next()?;
+                StmtKind::Break
+            }
+            Token::Return => {
+                self.next()?;
+                // The value must start on the same line as the `return`
+                let same_line = self.peek_span().line == span.line;
+                match *self.peek()? {
+                    Token::RightCurlyParam | Token::Semicolon | Token::EndOfFile => {
+                        StmtKind::Return(None)
+                    }
+                    _ if !same_line => StmtKind::Return(None),
+                    _ => StmtKind::Return(Some(self.parse_expr()?)),
+                }
+            }
+            Token::Every => {
+                self.next()?;
+                let period = match self.next()? {
+                    (Token::Duration(0), span) => return Err(ZeroPeriod(span)),
+                    (Token::Duration(ms), _) => ms,
+                    (token, span) => return Err(UnexpectedToken(token, span)),
+                };
+                StmtKind::Every(period, Rc::new(self.parse_handler(span)?))
+            }
+            Token::On => {
+                self.next()?;
+                self.expect(Token::At)?;
+                let device = self.parse_device_path()?;
+                StmtKind::On(device, Rc::new(self.parse_handler(span)?))
+            }
+            Token::Function if declaration => {
+                self.next()?;
+                let (name, _) = self.expect_identity()?;
+                StmtKind::Function(Rc::new(self.parse_function(Some(name), span)?))
+            }
+            _ => {
+                let expr = self.parse_expr()?;
+                if !self.peek()?.is_assignment() {
+                    self.find_typo(&expr)?;
+                    StmtKind::Expr(expr)
+                } else {
+                    let op = assign_operator(&self.next()?.0);
+                    let target = assign_target(expr)?;
+                    StmtKind::Assign(target, op, self.parse_expr()?)
+                }
+            }
+        };
+        self.eat(&Token::Semicolon)?;
+        Ok(Stmt::new(kind, span))
+    }
+
+    /// Remembers the keyword an identifier used as a statement may be a
+    /// typo of, if something else follows it on the same line. The first
+    /// one on the line is kept, as the words after it are misparsed.
+    fn find_typo(&mut self, expr: &Expr) -> error::Result<()> {
+        if self.typo.is_some() {
+            return Ok(());
+        }
+        if let ExprKind::Identity(ref name) = expr.kind {
+            let followed = self.peek_span().line == expr.span.line &&
+                           !matches!(*self.peek()?, Token::Semicolon | Token::RightCurlyParam | Token::EndOfFile);
+            if let Some(keyword) = diagnostic::suggest(name, STATEMENT_KEYWORDS.iter().cloned()) {
+                if followed {
+                    self.typo = Some((keyword, expr.span.line));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the block of an `every` or `on` handler as a function
+    /// without parameters.
+    fn parse_handler(&mut self, span: Span) -> error::Result<Function> {
+        Ok(Function {
+            name: None,
+            params: Vec::new(),
+            ret: None,
+            body: self.parse_block()?,
+            span,
+        })
+    }
+
+    /// Parses `if cond { .. }` with an optional `else { .. }` or `else if ..`.
+    fn parse_if(&mut self) -> error::Result<StmtKind> {
+        self.expect(Token::If)?;
+        let cond = self.parse_expr()?;
+        let then = self.parse_block()?;
+        let otherwise = if self.eat(&Token::Else)? {
+            if *self.peek()? == Token::If {
+                let span = self.peek_span();
+                Some(vec![Stmt::new(self.nested(Parser::parse_if)?, span)])
+            } else {
+                Some(self.parse_block()?)
+            }
+        } else {
+            None
+        };
+        Ok(StmtKind::If(cond, then, otherwise))
+    }
+
+    /// Parses the parameters and body of a function after
+    /// the `fn` keyword and the name.
+    fn parse_function(&mut self, name: Option<String>, span: Span) -> error::Result<Function> {
+        self.expect(Token::LeftParam)?;
+        let mut params = Vec::new();
+        while !self.eat(&Token::RightParam)? {
+            let (name, span) = self.expect_identity()?;
+            let ty = if self.eat(&Token::Colon)? { Some(self.parse_type()?) } else { None };
+            params.push(Param { name, ty, span });
+            if !self.eat(&Token::Comma)? {
+                self.expect(Token::RightParam)?;
+                break;
+            }
+        }
+        let ret = if self.eat(&Token::Arrow)? { Some(self.parse_type()?) } else { None };
+        let body = self.parse_block()?;
+        Ok(Function {
+            name,
+            params,
+            ret,
+            body,
+            span,
+        })
+    }
+
+    /// Parses a type annotation, a type name optionally followed by `?`
+    /// if the value can also be nil.
+    fn parse_type(&mut self) -> error::Result<Type> {
+        let ty = match self.next()? {
+            (Token::Nil, _) => Type::Nil,
+            (Token::Function, _) => Type::Fn(None),
+            (Token::Identity(name), span) => match Type::from_name(&name) {
+                Some(ty) => ty,
+                None => return Err(UnknownType(name, span)),
+            },
+            (found, span) => return Err(ExpectedToken(Token::Identity(String::new()), found, span)),
+        };
+        if self.eat(&Token::QuestionMark)? {
+            Ok(ty.optional())
+        } else {
+            Ok(ty)
+        }
+    }
+
+    /// Goes one level deeper, failing with `NestedTooDeep` past
+    /// `MAX_DEPTH`. The caller restores `depth` when done with the level.
+    fn deeper(&mut self) -> error::Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(NestedTooDeep(self.peek_span()));
+        }
+        Ok(())
+    }
+
+    /// Parses with `parse` one level deeper.
+    fn nested<T, F>(&mut self, parse: F) -> error::Result<T>
+        where F: FnOnce(&mut Self) -> error::Result<T>
+    {
+        self.deeper()?;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Parses a list of statements enclosed in `{` and `}`.
+    fn parse_block(&mut self) -> error::Result<Block> {
+        self.nested(Parser::parse_statements)
+    }
+
+    fn parse_statements(&mut self) -> error::Result<Block> {
+        self.expect(Token::LeftCurlyParam)?;
+        let mut block = Vec::new();
+        while !self.eat(&Token::RightCurlyParam)? {
+            if *self.peek()? == Token::EndOfFile {
+                return Err(ExpectedToken(Token::RightCurlyParam, Token::EndOfFile, self.span));
+            }
+            block.push(self.parse_statement()?);
+        }
+        Ok(block)
+    }
+
+    fn parse_expr(&mut self) -> error::Result<Expr> {
+        self.nested(Parser::parse_ternary)
+    }
+
+    /// Parses `cond ? then : otherwise`. It binds looser than any binary
+    /// operator and is right associative, so `a ? b : c ? d : e`
+    /// is parsed as `a ? b : (c ? d : e)`.
+    fn parse_ternary(&mut self) -> error::Result<Expr> {
+        let cond = self.parse_binary(0)?;
+        if *self.peek()? != Token::QuestionMark {
+            return Ok(cond);
+        }
+        let (_, span) = self.next()?;
+        let then = self.nested(Parser::parse_ternary)?;
+        self.expect(Token::Colon)?;
+        let otherwise = self.nested(Parser::parse_ternary)?;
+        Ok(Expr::new(ExprKind::Ternary(Box::new(cond), Box::new(then), Box::new(otherwise)),
+                     span))
+    }
+
+    /// Parses binary operators using precedence climbing.
+    /// Only operators binding at least as hard as `min_precedence`
+    /// are consumed. All binary operators are left associative.
+    /// Ranges are parsed here as well, see `parse_range`.
+    fn parse_binary(&mut self, min_precedence: u8) -> error::Result<Expr> {
+        let depth = self.depth;
+        let mut lhs = self.parse_unary()?;
+        loop {
+            if let Some(inclusive) = range_operator(self.peek()?) {
+                if RANGE_PRECEDENCE < min_precedence {
+                    break;
+                }
+                self.deeper()?;
+                lhs = self.parse_range(lhs, inclusive)?;
+                continue;
+            }
+            let (op, precedence) = match binary_operator(self.peek()?) {
+                Some((op, precedence)) if precedence >= min_precedence => (op, precedence),
+                _ => break,
+            };
+            self.deeper()?;
+            let (_, span) = self.next()?;
+            let rhs = self.parse_binary(precedence + 1)?;
+            lhs = Expr::new(ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)), span);
+        }
+        self.depth = depth;
+        Ok(lhs)
+    }
+
+    /// Parses the rest of a range `start..end by step` after `start`.
+    /// A range binds looser than arithmetic but harder than comparisons.
+    fn parse_range(&mut self, start: Expr, inclusive: bool) -> error::Result<Expr> {
+        let (_, span) = self.next()?;
+        let end = self.parse_binary(RANGE_PRECEDENCE + 1)?;
+        let step = if self.eat(&Token::By)? {
+            Some(Box::new(self.parse_binary(RANGE_PRECEDENCE + 1)?))
+        } else {
+            None
+        };
+        Ok(Expr::new(ExprKind::Range(Box::new(start), Box::new(end), step, inclusive), span))
+    }
+
+    /// Parses the prefix operators `-` and `!`.
+    fn parse_unary(&mut self) -> error::Result<Expr> {
+        let op = match *self.peek()? {
+            Token::Minus => UnaryOp::Neg,
+            Token::Not => UnaryOp::Not,
+            _ => return self.parse_postfix(),
+        };
+        let (_, span) = self.next()?;
+        let expr = self.nested(Parser::parse_unary)?;
+        Ok(Expr::new(ExprKind::Unary(op, Box::new(expr)), span))
+    }
+
+    /// Parses an expression followed by any number of calls, indexing
+    /// and field access operations.
+    /// An opening bracket or parenthesis on a new line starts a new
+    /// statement instead of indexing or calling the previous expression.
+    fn parse_postfix(&mut self) -> error::Result<Expr> {
+        let depth = self.depth;
+        let mut expr = self.parse_primary()?;
+        loop {
+            let same_line = self.peek_span().line == self.span.line;
+            match *self.peek()? {
+                Token::LeftSquareParam if same_line => {
+                    self.deeper()?;
+                    let (_, span) = self.next()?;
+                    let index = self.parse_expr()?;
+                    self.expect(Token::RightSquareParam)?;
+                    expr = Expr::new(ExprKind::Index(Box::new(expr), Box::new(index)), span);
+                }
+                Token::LeftParam if same_line => {
+                    self.deeper()?;
+                    let (_, span) = self.next()?;
+                    let args = self.parse_list(Token::RightParam)?;
+                    expr = Expr::new(ExprKind::Call(Box::new(expr), args), span);
+                }
+                Token::Dot => {
+                    self.deeper()?;
+                    self.next()?;
+                    let (name, span) = self.expect_identity()?;
+                    expr = Expr::new(ExprKind::Field(Box::new(expr), name), span);
+                }
+                _ => {
+                    self.depth = depth;
+                    return Ok(expr);
+                }
+            }
+        }
+    }
+
+    /// Parses a comma separated list of expressions ending with `end`.
+    /// A trailing comma is allowed.
+    fn parse_list(&mut self, end: Token) -> error::Result<Vec<Expr>> {
+        let mut items = Vec::new();
+        while !self.eat(&end)? {
+            items.push(self.parse_expr()?);
+            if !self.eat(&Token::Comma)? {
+                self.expect(end)?;
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    /// Parses the entries of a map literal after the `{`.
+    /// The keys are names or strings.
+    fn parse_map(&mut self) -> error::Result<Vec<(String, Expr)>> {
+        let mut entries = Vec::new();
+        while !self.eat(&Token::RightCurlyParam)? {
+            let key = match self.next()? {
+                (Token::Identity(name), _) => name,
+                (Token::Str(s), _) => unescape(&s),
+                (Token::RawStr(s), _) => s,
+                (found, span) => {
+                    return Err(ExpectedToken(Token::Identity(String::new()), found, span))
+                }
+            };
+            self.expect(Token::Colon)?;
+            entries.push((key, self.parse_expr()?));
+            if !self.eat(&Token::Comma)? {
+                self.expect(Token::RightCurlyParam)?;
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Parses literals, names, device references and parenthesized expressions.
+    fn parse_primary(&mut self) -> error::Result<Expr> {
+        let (token, span) = self.next()?;
+        let kind = match token {
+            Token::Int(i) => ExprKind::Int(i),
+            Token::Real(r) => ExprKind::Real(r),
+            Token::Str(s) => ExprKind::Str(unescape(&s)),
+            Token::RawStr(s) => ExprKind::Str(s),
+            Token::True => ExprKind::Bool(true),
+            Token::False => ExprKind::Bool(false),
+            Token::Nil => ExprKind::Nil,
+            Token::Identity(name) => ExprKind::Identity(name),
+            Token::At => ExprKind::Device(self.parse_device_path()?),
+            Token::LeftSquareParam => ExprKind::List(self.parse_list(Token::RightSquareParam)?),
+            Token::LeftCurlyParam => ExprKind::Map(self.parse_map()?),
+            Token::Function => ExprKind::Function(Rc::new(self.parse_function(None, span)?)),
+            Token::LeftParam => {
+                let expr = self.parse_expr()?;
+                self.expect(Token::RightParam)?;
+                return Ok(expr);
+            }
+            Token::EndOfFile => {
+                return Err(UnexpectedToken(Token::EndOfFile, span));
+            }
+            token => return Err(UnexpectedToken(token, span)),
+        };
+        Ok(Expr::new(kind, span))
+    }
+
+    /// Parses the dotted name following an `@`, e.g. `accel.x`.
+    fn parse_device_path(&mut self) -> error::Result<String> {
+        let (mut path, _) = self.expect_identity()?;
+        while self.eat(&Token::Dot)? {
+            path.push('.');
+            path.push_str(&self.expect_identity()?.0);
+        }
+        Ok(path)
+    }
+}
+
+/// Checks that `expr` can be assigned to: a variable, an element or a field.
+fn assign_target(expr: Expr) -> error::Result<Expr> {
+    match expr.kind {
+        ExprKind::Identity(_) |
+        ExprKind::Index(..) |
+        ExprKind::Field(..) => Ok(expr),
+        _ => Err(InvalidAssignment(expr.span)),
+    }
+}
+
+/// Returns the operator of a compound assignment token,
+/// or `None` for a plain `=`.
+fn assign_operator(token: &Token) -> Option<BinaryOp> {
+    match *token {
+        Token::PlusAssignment => Some(BinaryOp::Add),
+        Token::MinusAssignment => Some(BinaryOp::Sub),
+        Token::MulAssignment => Some(BinaryOp::Mul),
+        Token::DivAssignment => Some(BinaryOp::Div),
+        _ => None,
+    }
+}
+
+/// How hard `..` and `...` bind, see `binary_operator`.
+const RANGE_PRECEDENCE: u8 = 5;
+
+/// Returns `Some(inclusive)` if `token` is a range operator.
+fn range_operator(token: &Token) -> Option<bool> {
+    match *token {
+        Token::ExclusiveRange => Some(false),
+        Token::InclusiveRange => Some(true),
+        _ => None,
+    }
+}
+
+/// Returns the binary operator for `token` and how hard it binds.
+/// A higher number binds harder.
+fn binary_operator(token: &Token) -> Option<(BinaryOp, u8)> {
+    let op = match *token {
+        Token::Or => (BinaryOp::Or, 1),
+        Token::And => (BinaryOp::And, 2),
+        Token::Equal => (BinaryOp::Equal, 3),
+        Token::NotEqual => (BinaryOp::NotEqual, 3),
+        Token::GreaterThan => (BinaryOp::GreaterThan, 4),
+        Token::LessThan => (BinaryOp::LessThan, 4),
+        Token::GreaterEqual => (BinaryOp::GreaterEqual, 4),
+        Token::LessEqual => (BinaryOp::LessEqual, 4),
+        Token::Plus => (BinaryOp::Add, 6),
+        Token::Minus => (BinaryOp::Sub, 6),
+        Token::Mul => (BinaryOp::Mul, 7),
+        Token::Div => (BinaryOp::Div, 7),
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Replaces the escape sequences in a string literal with the
+/// `char`s they represent. The lexer has already checked that
+/// every escape sequence is valid.
+fn unescape(s: &str) -> String {
+    let mut buf = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            buf.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => buf.push('\n'),
+            Some('t') => buf.push('\t'),
+            Some('r') => buf.push('\r'),
+            Some(c) => buf.push(c),
+            None => {}
+        }
     }
+    buf
 }
@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use span::Span;
+
+/// The node of the calls made from outside of any script, under which
+/// the outermost calls are.
+pub const ROOT: usize = 0;
+
+/// What a report is sorted by, and what the collapsed stacks count.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Metric {
+    /// The time spent, in microseconds for the collapsed stacks.
+    Time,
+    /// The number of instructions run, which does not change from one
+    /// run to the next.
+    Instructions,
+}
+
+/// What was run of a function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionStats {
+    /// The name of the function, as in `StackFrame::name`.
+    pub name: String,
+    /// Where the function is defined.
+    pub span: Span,
+    pub calls: u64,
+    /// The instructions run by the function itself.
+    pub instructions: u64,
+    /// The instructions run by the function and the functions it called.
+    pub total_instructions: u64,
+    /// The time spent in the function itself, which includes the time
+    /// spent in the builtin and native functions it called.
+    pub time: Duration,
+    /// The time spent in the function and the functions it called.
+    pub total_time: Duration,
+}
+
+/// What was run of a line of the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineStats {
+    pub line: u32,
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+/// A function called through a path of calls.
+#[derive(Debug, Clone)]
+struct Node {
+    caller: usize,
+    function: usize,
+    calls: u64,
+    instructions: u64,
+    time: Duration,
+}
+
+/// Counts the calls, instructions and time spent in each function and
+/// on each line of the scripts a `Vm` runs, see `Vm::start_profiling`.
+///
+/// The functions are told apart by their name and where they are
+/// defined. The counts are kept for each path of calls leading to a
+/// function, so they can be written as collapsed stacks for flame graph
+/// tools. The time of an instruction is measured until the next one
+/// starts, which makes scripts run a few times slower.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    functions: Vec<(String, Span)>,
+    function_ids: HashMap<(String, Span), usize>,
+    nodes: Vec<Node>,
+    /// The node of each function called from each node.
+    children: HashMap<(usize, usize), usize>,
+    lines: BTreeMap<u32, (u64, Duration)>,
+    /// The node and line of the instruction running, and when it started.
+    running: Option<(usize, u32, Instant)>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        let root = Node {
+            caller: ROOT,
+            function: usize::MAX,
+            calls: 0,
+            instructions: 0,
+            time: Duration::default(),
+        };
+        Profile {
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            nodes: vec![root],
+            children: HashMap::new(),
+            lines: BTreeMap::new(),
+            running: None,
+        }
+    }
+
+    /// The node of the function `name` defined at `span` when called
+    /// from the node `caller`, without counting a call.
+    pub fn node(&mut self, caller: usize, name: &str, span: Span) -> usize {
+        let key = (name.to_string(), span);
+        let function = match self.function_ids.get(&key) {
+            Some(&function) => function,
+            None => {
+                self.functions.push(key.clone());
+                self.function_ids.insert(key, self.functions.len() - 1);
+                self.functions.len() - 1
+            }
+        };
+        if let Some(&node) = self.children.get(&(caller, function)) {
+            return node;
+        }
+        self.nodes.push(Node {
+            caller,
+            function,
+            calls: 0,
+            instructions: 0,
+            time: Duration::default(),
+        });
+        self.children.insert((caller, function), self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    /// Counts a call to the function `name` defined at `span` from the
+    /// node `caller`, and returns the node of the call.
+    pub fn enter(&mut self, caller: usize, name: &str, span: Span) -> usize {
+        let node = self.node(caller, name, span);
+        self.nodes[node].calls += 1;
+        node
+    }
+
+    /// Counts an instruction on `line` run by the call at `node`, and
+    /// charges the time since the last instruction to it.
+    pub fn step(&mut self, node: usize, line: u32) {
+        let now = Instant::now();
+        self.charge(now);
+        self.nodes[node].instructions += 1;
+        self.lines.entry(line).or_insert((0, Duration::default())).0 += 1;
+        self.running = Some((node, line, now));
+    }
+
+    /// Charges the time since the last instruction to it, as the script
+    /// stopped running.
+    pub fn pause(&mut self) {
+        self.charge(Instant::now());
+        self.running = None;
+    }
+
+    fn charge(&mut self, now: Instant) {
+        if let Some((node, line, start)) = self.running {
+            let time = now.duration_since(start);
+            self.nodes[node].time += time;
+            self.lines.entry(line).or_insert((0, Duration::default())).1 += time;
+        }
+    }
+
+    /// The functions which were called, in the order of `metric`, the
+    /// costliest first.
+    pub fn functions(&self, metric: Metric) -> Vec<FunctionStats> {
+        let mut stats: Vec<FunctionStats> = self.functions.iter().map(|&(ref name, span)| FunctionStats {
+            name: name.clone(),
+            span,
+            calls: 0,
+            instructions: 0,
+            total_instructions: 0,
+            time: Duration::default(),
+            total_time: Duration::default(),
+        }).collect();
+        for (id, node) in self.nodes.iter().enumerate().skip(1) {
+            let function = &mut stats[node.function];
+            function.calls += node.calls;
+            function.instructions += node.instructions;
+            function.time += node.time;
+            // A recursive function is only charged once
+            let mut charged = Vec::new();
+            let mut at = id;
+            while at != ROOT {
+                let function = self.nodes[at].function;
+                if !charged.contains(&function) {
+                    charged.push(function);
+                    stats[function].total_instructions += node.instructions;
+                    stats[function].total_time += node.time;
+                }
+                at = self.nodes[at].caller;
+            }
+        }
+        stats.sort_by(|a, b| match metric {
+            Metric::Time => b.time.cmp(&a.time),
+            Metric::Instructions => b.instructions.cmp(&a.instructions),
+        }.then_with(|| a.span.cmp(&b.span)));
+        stats
+    }
+
+    /// The lines which were run, in the order of `metric`, the costliest
+    /// first.
+    pub fn lines(&self, metric: Metric) -> Vec<LineStats> {
+        let mut stats: Vec<LineStats> = self.lines.iter().map(|(&line, &(instructions, time))| LineStats {
+            line,
+            instructions,
+            time,
+        }).collect();
+        stats.sort_by(|a, b| match metric {
+            Metric::Time => b.time.cmp(&a.time),
+            Metric::Instructions => b.instructions.cmp(&a.instructions),
+        }.then_with(|| a.line.cmp(&b.line)));
+        stats
+    }
+
+    /// A table of the functions and one of the lines, sorted by `metric`.
+    pub fn report(&self, metric: Metric) -> String {
+        let mut report = String::new();
+        let _ = writeln!(report, "{:>8} {:>12} {:>18} {:>10} {:>10}  function",
+                         "calls", "instructions", "total instructions", "time", "total time");
+        for f in self.functions(metric) {
+            let _ = write!(report, "{:>8} {:>12} {:>18} {:>10} {:>10}  {}",
+                           f.calls, f.instructions, f.total_instructions,
+                           millis(f.time), millis(f.total_time), f.name);
+            // A script itself is not defined anywhere
+            if f.span != Span::default() {
+                let _ = write!(report, " ({})", f.span);
+            }
+            let _ = writeln!(report);
+        }
+        let _ = writeln!(report);
+        let _ = writeln!(report, "{:>12} {:>10}  line", "instructions", "time");
+        for line in self.lines(metric) {
+            let _ = writeln!(report, "{:>12} {:>10}  {}", line.instructions, millis(line.time), line.line);
+        }
+        report
+    }
+
+    /// The paths of calls as collapsed stacks: a line for each path, with
+    /// the names of the functions from the outermost separated by `;`,
+    /// followed by a space and what the innermost function cost by
+    /// `metric`. The paths costing nothing are left out.
+    pub fn collapsed(&self, metric: Metric) -> String {
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        for (id, node) in self.nodes.iter().enumerate().skip(1) {
+            let cost = match metric {
+                Metric::Time => node.time.as_micros() as u64,
+                Metric::Instructions => node.instructions,
+            };
+            if cost == 0 {
+                continue;
+            }
+            let mut names = Vec::new();
+            let mut at = id;
+            while at != ROOT {
+                names.push(self.functions[self.nodes[at].function].0.as_str());
+                at = self.nodes[at].caller;
+            }
+            names.reverse();
+            *stacks.entry(names.join(";")).or_insert(0) += cost;
+        }
+        stacks.into_iter().map(|(stack, cost)| format!("{} {}\n", stack, cost)).collect()
+    }
+}
+
+fn millis(time: Duration) -> String {
+    format!("{:.3}ms", time.as_secs_f64() * 1000.0)
+}
@@ -10,7 +10,6 @@ const FRACTION_VALUE: i32 = 1 << 16;
 /// Maximus size of the int part.
 /// Mask = 0111 1111 1111 1111
 const MAX_SIZE: i32 = 0x7FFF;
-const MIN_SIZE: i32 = 0xFFFF;
 /// The ammount of bits which the int part must be shifted.
 const SHIFT: i32 = 16;
 /// Maskign for the fraction part. It is used to remove the int part.
@@ -19,7 +18,7 @@ const FRACTION_MASK: i32 = 0xFFFF;
 /// A real with the format 16.16, meaning 16 bits for the integer
 /// and 16 bits for the fraction. The int is signed, meaning
 /// its range is `[-32768, 32767]`.
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
 pub struct Real {
     value: i32,
 }
@@ -29,7 +28,7 @@ impl Real {
     /// # Legal input examples
     /// `"3.14"`, `"3."`, `"3"`, `"."`, `".14"`
     pub fn parse(input: &str) -> error::Result<Real> {
-        let dot = input.find('.').unwrap_or_else(|| input.len());
+        let dot = input.find('.').unwrap_or(input.len());
         if dot == 0 {
             return Err(BadRealLiteral);
         }
@@ -47,6 +46,71 @@ impl Real {
         };
         Ok(Real { value: real })
     }
+
+    /// Converts an int to a `Real`.
+    /// Returns `None` if `n` does not fit in the int part.
+    pub fn checked_from_int(n: i32) -> Option<Real> {
+        if !(-MAX_SIZE - 1..=MAX_SIZE).contains(&n) {
+            None
+        } else {
+            Some(Real::from(n))
+        }
+    }
+
+    /// The raw 16.16 representation of the real.
+    pub fn to_bits(self) -> i32 {
+        self.value
+    }
+
+    /// Creates a real from its raw 16.16 representation, see `to_bits`.
+    pub fn from_bits(bits: i32) -> Real {
+        Real { value: bits }
+    }
+
+    /// Checked addition. Returns `None` if the result overflows.
+    pub fn checked_add(self, rhs: Real) -> Option<Real> {
+        self.value.checked_add(rhs.value).map(|value| Real { value })
+    }
+
+    /// Checked subtraction. Returns `None` if the result overflows.
+    pub fn checked_sub(self, rhs: Real) -> Option<Real> {
+        self.value.checked_sub(rhs.value).map(|value| Real { value })
+    }
+
+    /// Checked multiplication. Returns `None` if the result overflows.
+    pub fn checked_mul(self, rhs: Real) -> Option<Real> {
+        let value = (self.value as i64 * rhs.value as i64) >> SHIFT;
+        to_i32(value).map(|value| Real { value })
+    }
+
+    /// Checked division. Returns `None` if `rhs` is zero
+    /// or if the result overflows.
+    pub fn checked_div(self, rhs: Real) -> Option<Real> {
+        if rhs.value == 0 {
+            return None;
+        }
+        let value = ((self.value as i64) << SHIFT) / rhs.value as i64;
+        to_i32(value).map(|value| Real { value })
+    }
+
+    /// Checked negation. Returns `None` if the result overflows.
+    pub fn checked_neg(self) -> Option<Real> {
+        self.value.checked_neg().map(|value| Real { value })
+    }
+
+    /// Returns `true` if the real is zero.
+    pub fn is_zero(self) -> bool {
+        self.value == 0
+    }
+}
+
+/// Converts an i64 to an i32 if it fits.
+fn to_i32(n: i64) -> Option<i32> {
+    if n > i32::MAX as i64 || n < i32::MIN as i64 {
+        None
+    } else {
+        Some(n as i32)
+    }
 }
 
 // Arithmetic
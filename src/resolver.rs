@@ -0,0 +1,425 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use ast::*;
+use builtins::BUILTINS;
+use checker::Checker;
+use optimizer;
+use parser::Parser;
+use tokens::KEYWORDS;
+use diagnostic::{Diagnostic, DiagnosticKind, Warning};
+use span::Span;
+use error;
+use error::Error::*;
+
+/// What a name is declared as.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeclarationKind {
+    /// Declared by assigning to it.
+    Variable,
+    Parameter,
+    /// The variable of a `for` loop.
+    LoopVariable,
+    /// A named function declaration.
+    Function,
+}
+
+/// Where a name is declared. A function is declared at its `fn` and
+/// a loop variable at its `for`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Declaration {
+    pub name: String,
+    pub kind: DeclarationKind,
+    pub span: Span,
+    /// `true` for variables of the global scope.
+    pub global: bool,
+}
+
+/// The result of resolving the names of a script.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    pub declarations: Vec<Declaration>,
+    /// Every use of a declared name, with the index of its declaration.
+    /// Uses of builtin functions and names declared by the host are
+    /// left out.
+    pub bindings: Vec<(Span, usize)>,
+    /// The problems found, in the order they appear in the source.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Resolution {
+    /// The declaration of the name used at `span`.
+    pub fn declaration_of(&self, span: Span) -> Option<&Declaration> {
+        self.bindings.iter().find(|b| b.0 == span).map(|b| &self.declarations[b.1])
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(Diagnostic::is_error)
+    }
+}
+
+/// A scope of the script, see `eval::Interpreter`.
+#[derive(Default)]
+struct Scope {
+    /// The declarations of the names declared so far.
+    vars: HashMap<String, usize>,
+    /// The declarations which have been used.
+    used: HashSet<usize>,
+    /// Functions created in the scope, resolved when the scope ends.
+    functions: Vec<Rc<Function>>,
+}
+
+/// Binds the names of a script to where they are declared without
+/// running it, and finds names which are not declared, repeated
+/// parameters, `break` outside of loops and `return` outside of
+/// functions. It warns about unused variables and shadowing.
+///
+/// A variable is declared in a block by its first assignment, so it
+/// can only be used after that. Functions see the variables of the
+/// scope they are created in as they are when called, so a function's
+/// body is resolved at the end of that scope, and can use names
+/// declared after the function. Names starting with `_` are never
+/// reported as unused, and neither are global variables, as the host
+/// may use them.
+#[derive(Default)]
+pub struct Resolver {
+    /// Names declared by the host, like native functions.
+    host: HashSet<String>,
+    /// The number of arguments of the native functions of the host.
+    natives: HashMap<String, usize>,
+    /// The devices of the host, see `declare_device`.
+    devices: HashSet<String>,
+    /// Whether any device may be used, see `allow_any_device`.
+    any_device: bool,
+    scopes: Vec<Scope>,
+    /// The number of loops around the statement in the current function.
+    loops: usize,
+    /// The number of functions around the statement.
+    functions: usize,
+    result: Resolution,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver::default()
+    }
+
+    /// Tells the resolver the host declares `name`, for example a
+    /// native function or a global set with `set_global`.
+    pub fn declare_host(&mut self, name: &str) {
+        self.host.insert(name.to_string());
+    }
+
+    /// Tells the resolver the host has a native function `name` taking
+    /// `arity` arguments, which `check` passes on to the `Checker`.
+    pub fn declare_native(&mut self, name: &str, arity: usize) {
+        self.declare_host(name);
+        self.natives.insert(name.to_string(), arity);
+    }
+
+    /// Tells the resolver the host has a device or channel `name`, which
+    /// scripts can read with `@name`. Like the engines, the resolver
+    /// reports the use of any other device as `UnknownDevice`.
+    pub fn declare_device(&mut self, name: &str) {
+        self.devices.insert(name.to_string());
+    }
+
+    /// Lets scripts use any device, for tools like editors which do not
+    /// know the devices of the host.
+    pub fn allow_any_device(&mut self) {
+        self.any_device = true;
+    }
+
+    /// Parses, resolves and checks the types of `source`, and reports the
+    /// operations on constants which will fail, see `optimizer::optimize`.
+    /// A script which does not parse has only the parse error, with the
+    /// keyword it may come from a typo of as help.
+    pub fn check(self, source: &str) -> Vec<Diagnostic> {
+        let mut parser = Parser::new(source);
+        let program = match parser.parse_program() {
+            Ok(program) => program,
+            Err(e) => return vec![Diagnostic::error(e).suggest(parser.help())],
+        };
+        let mut checker = Checker::new();
+        for (name, &arity) in &self.natives {
+            checker.declare_native(name, arity);
+        }
+        let mut diagnostics = self.resolve(&program).diagnostics;
+        diagnostics.extend(checker.check(&program));
+        diagnostics.extend(optimizer::optimize(&program).1);
+        diagnostics.sort_by_key(Diagnostic::span);
+        diagnostics
+    }
+
+    pub fn resolve(mut self, program: &[Stmt]) -> Resolution {
+        self.block(program);
+        self.result.diagnostics.sort_by_key(Diagnostic::span);
+        self.result
+    }
+
+    /// Returns the first use of a device the host does not have in
+    /// `program`, so the engines can reject it before it runs.
+    pub fn check_devices(self, program: &[Stmt]) -> error::Result<()> {
+        let unknown = self.resolve(program).diagnostics.into_iter().find_map(|d| match d.kind {
+            DiagnosticKind::Error(e @ UnknownDevice(..)) => Some(e),
+            _ => None,
+        });
+        unknown.map_or(Ok(()), Err)
+    }
+
+    fn error(&mut self, error: ::error::Error) {
+        self.result.diagnostics.push(Diagnostic::error(error));
+    }
+
+    fn warn(&mut self, warning: Warning) {
+        self.result.diagnostics.push(Diagnostic::warning(warning));
+    }
+
+    /// Resolves a block in a new scope.
+    fn block(&mut self, block: &[Stmt]) {
+        self.scopes.push(Scope::default());
+        self.stmts(block);
+        self.end_scope();
+    }
+
+    fn stmts(&mut self, block: &[Stmt]) {
+        for stmt in block {
+            self.stmt(stmt);
+        }
+    }
+
+    /// Resolves the functions created in the innermost scope, then
+    /// drops the scope and reports its unused variables.
+    fn end_scope(&mut self) {
+        loop {
+            let functions = ::std::mem::take(&mut self.scopes.last_mut().unwrap().functions);
+            if functions.is_empty() {
+                break;
+            }
+            for function in functions {
+                self.function_body(&function);
+            }
+        }
+        let scope = self.scopes.pop().unwrap();
+        if self.scopes.is_empty() {
+            return;
+        }
+        let mut unused: Vec<usize> = scope.vars.values().filter(|d| !scope.used.contains(d)).cloned().collect();
+        unused.sort();
+        for index in unused {
+            let declaration = &self.result.declarations[index];
+            if !declaration.name.starts_with('_') {
+                let warning = Warning::UnusedVariable(declaration.name.clone(), declaration.span);
+                self.warn(warning);
+            }
+        }
+    }
+
+    /// Finds the scope and declaration of a visible name.
+    fn lookup(&self, name: &str) -> Option<(usize, usize)> {
+        self.scopes.iter().enumerate().rev()
+            .filter_map(|(depth, scope)| scope.vars.get(name).map(|&d| (depth, d)))
+            .next()
+    }
+
+    fn is_host_name(&self, name: &str) -> bool {
+        self.host.contains(name) || BUILTINS.iter().any(|b| b.name() == name)
+    }
+
+    /// The visible name, builtin function or keyword an undefined `name`
+    /// may be a typo of. Names of inner scopes come first.
+    fn suggestion(&self, name: &str) -> Option<String> {
+        let mut candidates: Vec<&str> = Vec::new();
+        let scopes = self.scopes.iter().rev().map(|scope| scope.vars.keys().collect::<Vec<_>>());
+        for names in scopes.chain(Some(self.host.iter().collect())) {
+            // Sorted so a tie doesn't depend on the order of the hash map
+            let start = candidates.len();
+            candidates.extend(names.into_iter().map(String::as_str));
+            candidates[start..].sort();
+        }
+        candidates.extend(BUILTINS.iter().map(|b| b.name()));
+        candidates.extend(KEYWORDS.iter().cloned());
+        ::diagnostic::suggest(name, candidates).map(str::to_string)
+    }
+
+    /// Records a use of `name` at `span`. Returns `false` if it is not declared.
+    fn use_name(&mut self, name: &str, span: Span) -> bool {
+        match self.lookup(name) {
+            Some((depth, declaration)) => {
+                self.scopes[depth].used.insert(declaration);
+                self.result.bindings.push((span, declaration));
+                true
+            }
+            None => self.is_host_name(name),
+        }
+    }
+
+    /// Declares `name` in the innermost scope. Unless it is a plain
+    /// variable, which would have been assigned to instead, it warns
+    /// if it hides a variable of an enclosing scope.
+    fn declare(&mut self, name: &str, kind: DeclarationKind, span: Span) {
+        let hidden = match self.lookup(name) {
+            Some((depth, _)) if depth + 1 == self.scopes.len() => None,
+            Some((_, declaration)) => Some(Some(self.result.declarations[declaration].span)),
+            None if self.is_host_name(name) => Some(None),
+            None => None,
+        };
+        if let Some(previous) = hidden {
+            self.warn(Warning::Shadowing(name.to_string(), span, previous));
+        }
+        let index = self.result.declarations.len();
+        self.result.declarations.push(Declaration {
+            name: name.to_string(),
+            kind,
+            span,
+            global: self.scopes.len() == 1,
+        });
+        self.scopes.last_mut().unwrap().vars.insert(name.to_string(), index);
+    }
+
+    /// Resolves the body of a function in a scope holding its parameters.
+    fn function_body(&mut self, function: &Function) {
+        let loops = ::std::mem::replace(&mut self.loops, 0);
+        self.functions += 1;
+        self.scopes.push(Scope::default());
+        for param in &function.params {
+            if self.scopes.last().unwrap().vars.contains_key(&param.name) {
+                self.error(DuplicateParameter(param.name.clone(), param.span));
+            } else {
+                self.declare(&param.name, DeclarationKind::Parameter, param.span);
+            }
+        }
+        self.block(&function.body);
+        self.end_scope();
+        self.functions -= 1;
+        self.loops = loops;
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt.kind {
+            StmtKind::Expr(ref expr) => self.expr(expr),
+            StmtKind::Assign(ref target, op, ref value) => {
+                self.expr(value);
+                match target.kind {
+                    ExprKind::Identity(ref name) if op.is_none() => {
+                        match self.lookup(name) {
+                            Some((_, declaration)) => self.result.bindings.push((target.span, declaration)),
+                            // Assigns to a global of the host
+                            None if self.host.contains(name) => {}
+                            None => self.declare(name, DeclarationKind::Variable, target.span),
+                        }
+                    }
+                    _ => self.expr(target),
+                }
+            }
+            StmtKind::If(ref cond, ref then, ref otherwise) => {
+                self.expr(cond);
+                self.block(then);
+                if let Some(ref otherwise) = *otherwise {
+                    self.block(otherwise);
+                }
+            }
+            StmtKind::While(ref cond, ref body) => {
+                self.expr(cond);
+                self.loops += 1;
+                self.block(body);
+                self.loops -= 1;
+            }
+            StmtKind::For(ref name, ref iterable, ref body) => {
+                self.expr(iterable);
+                self.scopes.push(Scope::default());
+                self.declare(name, DeclarationKind::LoopVariable, stmt.span);
+                self.loops += 1;
+                self.block(body);
+                self.loops -= 1;
+                self.end_scope();
+            }
+            StmtKind::Break => {
+                if self.loops == 0 {
+                    self.error(BreakOutsideLoop(stmt.span));
+                }
+            }
+            StmtKind::Return(ref value) => {
+                if self.functions == 0 {
+                    self.error(ReturnOutsideFunction(stmt.span));
+                }
+                if let Some(ref value) = *value {
+                    self.expr(value);
+                }
+            }
+            StmtKind::Function(ref function) => {
+                let name = function.name.clone().unwrap_or_default();
+                self.declare(&name, DeclarationKind::Function, function.span);
+                self.scopes.last_mut().unwrap().functions.push(function.clone());
+            }
+            StmtKind::Every(_, ref function) |
+            StmtKind::On(_, ref function) => {
+                self.scopes.last_mut().unwrap().functions.push(function.clone());
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr.kind {
+            ExprKind::Int(_) |
+            ExprKind::Real(_) |
+            ExprKind::Str(_) |
+            ExprKind::Bool(_) |
+            ExprKind::Nil => {}
+            ExprKind::Device(ref name) => {
+                if !self.any_device && !self.devices.contains(name) {
+                    let mut devices: Vec<&str> = self.devices.iter().map(String::as_str).collect();
+                    devices.sort();
+                    let suggestion = ::diagnostic::suggest(name, devices).map(|d| format!("@{}", d));
+                    let diagnostic = Diagnostic::error(UnknownDevice(name.clone(), expr.span));
+                    self.result.diagnostics.push(diagnostic.suggest(suggestion.as_deref()));
+                }
+            }
+            ExprKind::Identity(ref name) => {
+                if !self.use_name(name, expr.span) {
+                    let suggestion = self.suggestion(name);
+                    let diagnostic = Diagnostic::error(UndefinedVariable(name.clone(), expr.span));
+                    self.result.diagnostics.push(diagnostic.suggest(suggestion.as_deref()));
+                }
+            }
+            ExprKind::Unary(_, ref operand) => self.expr(operand),
+            ExprKind::Binary(_, ref left, ref right) |
+            ExprKind::Index(ref left, ref right) => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Ternary(ref cond, ref then, ref otherwise) => {
+                self.expr(cond);
+                self.expr(then);
+                self.expr(otherwise);
+            }
+            ExprKind::List(ref items) => {
+                for item in items {
+                    self.expr(item);
+                }
+            }
+            ExprKind::Map(ref entries) => {
+                for entry in entries {
+                    self.expr(&entry.1);
+                }
+            }
+            ExprKind::Field(ref object, _) => self.expr(object),
+            ExprKind::Call(ref callee, ref args) => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            ExprKind::Function(ref function) => {
+                self.scopes.last_mut().unwrap().functions.push(function.clone());
+            }
+            ExprKind::Range(ref start, ref end, ref step, _) => {
+                self.expr(start);
+                self.expr(end);
+                if let Some(ref step) = *step {
+                    self.expr(step);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use limits::Measure;
+use snapshot::{Reader, Writer};
+use value::Value;
+use error;
+
+/// Keeps the handlers registered by `every` and `on` statements, and
+/// a virtual clock the host advances to run them.
+///
+/// The clock starts at zero. A handler registered with `every 10ms`
+/// runs 10ms after it was registered and then every 10ms. Handlers
+/// due at the same time run in the order they were registered.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    now: Duration,
+    timers: Vec<Timer>,
+    handlers: Vec<(String, Value)>,
+}
+
+#[derive(Debug)]
+struct Timer {
+    period: Duration,
+    /// When the handler runs next.
+    next: Duration,
+    handler: Value,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler::default()
+    }
+
+    /// The time on the virtual clock.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Registers a handler to run every `period` milliseconds.
+    pub fn every(&mut self, period: u32, handler: Value) {
+        // The parser refuses a zero period, which would never let the clock move
+        let period = Duration::from_millis(u64::from(period.max(1)));
+        self.timers.push(Timer {
+            period,
+            next: self.now + period,
+            handler,
+        });
+    }
+
+    /// Registers a handler to run when the host signals `device`.
+    pub fn on(&mut self, device: &str, handler: Value) {
+        self.handlers.push((device.to_string(), handler));
+    }
+
+    /// Returns the next handler due at or before `until` and moves the
+    /// clock to when it is due, or moves the clock to `until` and returns
+    /// `None` if there are none.
+    pub fn next_due(&mut self, until: Duration) -> Option<Value> {
+        // The first registered timer wins a tie
+        let timer = self.timers.iter_mut()
+            .filter(|timer| timer.next <= until)
+            .min_by_key(|timer| timer.next);
+        match timer {
+            Some(timer) => {
+                self.now = timer.next;
+                timer.next += timer.period;
+                Some(timer.handler.clone())
+            }
+            None => {
+                self.now = until;
+                None
+            }
+        }
+    }
+
+    /// The handlers for `device`, in the order they were registered.
+    pub fn handlers(&self, device: &str) -> Vec<Value> {
+        self.handlers.iter().filter(|h| h.0 == device).map(|h| h.1.clone()).collect()
+    }
+
+    /// Measures the handlers and the variables they can see.
+    pub fn measure(&self, measure: &mut Measure) {
+        for timer in &self.timers {
+            measure.value(&timer.handler);
+        }
+        for handler in &self.handlers {
+            measure.value(&handler.1);
+        }
+    }
+
+    /// Writes the clock and the handlers to a snapshot.
+    pub fn write(&self, writer: &mut Writer) {
+        writer.duration(self.now);
+        writer.usize(self.timers.len());
+        for timer in &self.timers {
+            writer.duration(timer.period);
+            writer.duration(timer.next);
+            writer.value(&timer.handler);
+        }
+        writer.usize(self.handlers.len());
+        for (device, handler) in &self.handlers {
+            writer.str(device);
+            writer.value(handler);
+        }
+    }
+
+    /// Reads a scheduler written by `write`.
+    pub fn read(reader: &mut Reader) -> error::Result<Scheduler> {
+        let now = reader.duration()?;
+        let mut timers = Vec::new();
+        for _ in 0..reader.usize()? {
+            timers.push(Timer {
+                period: reader.duration()?,
+                next: reader.duration()?,
+                handler: reader.value()?,
+            });
+        }
+        let mut handlers = Vec::new();
+        for _ in 0..reader.usize()? {
+            handlers.push((reader.str()?, reader.value()?));
+        }
+        Ok(Scheduler {
+            now,
+            timers,
+            handlers,
+        })
+    }
+}
@@ -0,0 +1,1249 @@
+//! Snapshots of the state of the `Interpreter` or the `Vm`, so a script
+//! can be saved and continued later, for example after losing power.
+//!
+//! A snapshot starts with `MAGIC`, the format `VERSION` and the kind of
+//! engine it was taken from, and ends with a checksum of everything
+//! before it. Objects which may be shared, like lists, closures and
+//! compiled modules, are written once and given an id; later references
+//! to them only write the id. Ids are given in the order the objects are
+//! first written, so the reader can tell a new object from a reference.
+//! Mutable objects are given their id before their contents are written,
+//! and other objects after, which lets a snapshot hold cycles.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::time::Duration;
+
+use ast::*;
+use builtins::Builtin;
+use compiler::{Capture, Local, Loop, Module, Op, Place, Proto, Var};
+use native::Natives;
+use real::Real;
+use span::Span;
+use types::Type;
+use value::{Items, Range, Value};
+use eval;
+use vm;
+use error;
+use error::Error::*;
+
+/// The first bytes of every snapshot.
+pub const MAGIC: &[u8; 4] = b"SNAP";
+
+/// The version of the format written. Snapshots of other versions
+/// are refused.
+pub const VERSION: u16 = 5;
+
+/// How deep closures, scopes, statements, expressions and types may be
+/// nested in a snapshot, as they are read by recursing, so a forged
+/// snapshot cannot overflow the stack. It leaves room above the nesting
+/// `parser::MAX_DEPTH` allows in functions. Lists and maps are read in a
+/// loop, so they may be nested deeper.
+pub const MAX_DEPTH: usize = 128;
+
+/// The engine a snapshot was taken from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Interpreter,
+    Vm,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Interpreter => "interpreter",
+            Kind::Vm => "vm",
+        }
+    }
+}
+
+const UNARY_OPS: [UnaryOp; 2] = [UnaryOp::Neg, UnaryOp::Not];
+
+#[rustfmt::skip]
+const BINARY_OPS: [BinaryOp; 12] = [
+    BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul, BinaryOp::Div,
+    BinaryOp::Equal, BinaryOp::NotEqual, BinaryOp::GreaterThan, BinaryOp::LessThan,
+    BinaryOp::GreaterEqual, BinaryOp::LessEqual, BinaryOp::And, BinaryOp::Or,
+];
+
+/// The 64 bit FNV-1a hash of `data`.
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn bad(msg: &str) -> error::Error {
+    BadSnapshot(msg.to_string())
+}
+
+/// Writes a snapshot.
+pub struct Writer {
+    buf: Vec<u8>,
+    /// The ids of the shared objects written, by address.
+    ids: HashMap<usize, u32>,
+}
+
+impl Writer {
+    pub fn new(kind: Kind) -> Writer {
+        let mut writer = Writer {
+            buf: MAGIC.to_vec(),
+            ids: HashMap::new(),
+        };
+        writer.u16(VERSION);
+        writer.u8(kind as u8);
+        writer
+    }
+
+    /// Ends the snapshot with its checksum.
+    pub fn finish(mut self) -> Vec<u8> {
+        let sum = checksum(&self.buf);
+        self.buf.extend_from_slice(&sum.to_le_bytes());
+        self.buf
+    }
+
+    pub fn u8(&mut self, n: u8) {
+        self.buf.push(n);
+    }
+
+    pub fn u16(&mut self, n: u16) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, n: u32) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, n: i32) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, n: u64) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    /// Writes a length or an index.
+    pub fn usize(&mut self, n: usize) {
+        self.u32(n as u32);
+    }
+
+    pub fn bool(&mut self, b: bool) {
+        self.u8(b as u8);
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.usize(s.len());
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    pub fn span(&mut self, span: Span) {
+        self.u32(span.line);
+        self.u32(span.column);
+    }
+
+    pub fn duration(&mut self, duration: Duration) {
+        self.u64(duration.as_secs());
+        self.u32(duration.subsec_nanos());
+    }
+
+    fn option_str(&mut self, s: Option<&String>) {
+        self.bool(s.is_some());
+        if let Some(s) = s {
+            self.str(s);
+        }
+    }
+
+    /// Writes the id of a shared object. Returns `true` if it is the
+    /// first time the object is written, in which case the caller
+    /// writes its contents.
+    pub fn shared<T: ?Sized>(&mut self, rc: &Rc<T>) -> bool {
+        let next = self.ids.len() as u32;
+        let address = Rc::as_ptr(rc) as *const () as usize;
+        let id = *self.ids.entry(address).or_insert(next);
+        self.u32(id);
+        id == next
+    }
+
+    /// Writes variables sorted by name, so the same variables always
+    /// give the same snapshot.
+    pub fn vars<'v, I>(&mut self, vars: I)
+        where I: IntoIterator<Item = (&'v String, &'v Value)>
+    {
+        let mut vars: Vec<_> = vars.into_iter().collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        self.usize(vars.len());
+        for (name, value) in vars {
+            self.str(name);
+            self.value(value);
+        }
+    }
+
+    /// Writes a value. The items of lists and maps are written in a loop
+    /// rather than by recursing, so values nested deeply can be written.
+    pub fn value(&mut self, value: &Value) {
+        let mut open: Vec<Items> = self.value_head(value).into_iter().collect();
+        while let Some(mut items) = open.pop() {
+            let inner = items.next(|key, item, _| {
+                if let Some(key) = key {
+                    self.str(key);
+                }
+                self.value_head(item)
+            });
+            if let Some(inner) = inner {
+                open.push(items);
+                open.extend(inner);
+            }
+        }
+    }
+
+    /// Writes a value, but only the length of a list or map written for
+    /// the first time. Its items are returned, to be written next.
+    fn value_head(&mut self, value: &Value) -> Option<Items> {
+        match *value {
+            Value::Nil => self.u8(0),
+            Value::Bool(false) => self.u8(1),
+            Value::Bool(true) => self.u8(2),
+            Value::Int(i) => {
+                self.u8(3);
+                self.i32(i);
+            }
+            Value::Real(r) => {
+                self.u8(4);
+                self.i32(r.to_bits());
+            }
+            Value::Str(ref s) => {
+                self.u8(5);
+                self.str(s);
+            }
+            Value::Range(Range::Int { start, end, step, inclusive }) => {
+                self.u8(6);
+                self.i32(start);
+                self.i32(end);
+                self.i32(step);
+                self.bool(inclusive);
+            }
+            Value::Range(Range::Real { start, end, step, inclusive }) => {
+                self.u8(7);
+                self.i32(start.to_bits());
+                self.i32(end.to_bits());
+                self.i32(step.to_bits());
+                self.bool(inclusive);
+            }
+            Value::List(ref list) => {
+                self.u8(8);
+                if self.shared(list) {
+                    self.usize(list.borrow().len());
+                    return Some(Items::List(list.clone(), 0));
+                }
+            }
+            Value::Map(ref map) => {
+                self.u8(9);
+                if self.shared(map) {
+                    self.usize(map.borrow().len());
+                    return Some(Items::Map(map.clone(), None));
+                }
+            }
+            Value::Function(ref closure) => {
+                self.u8(10);
+                eval::Closure::write(closure, self);
+            }
+            Value::Compiled(ref closure) => {
+                self.u8(11);
+                vm::Closure::write(closure, self);
+            }
+            Value::Builtin(builtin) => {
+                self.u8(12);
+                self.str(builtin.name());
+            }
+            Value::Native(ref native) => {
+                self.u8(13);
+                self.str(native.name());
+            }
+        }
+        None
+    }
+
+    /// Writes a function defined in a script.
+    pub fn function(&mut self, function: &Rc<Function>) {
+        if !self.shared(function) {
+            return;
+        }
+        self.option_str(function.name.as_ref());
+        self.usize(function.params.len());
+        for param in &function.params {
+            self.str(&param.name);
+            self.option_type(param.ty.as_ref());
+            self.span(param.span);
+        }
+        self.option_type(function.ret.as_ref());
+        self.block(&function.body);
+        self.span(function.span);
+    }
+
+    fn block(&mut self, block: &[Stmt]) {
+        self.usize(block.len());
+        for stmt in block {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        self.span(stmt.span);
+        match stmt.kind {
+            StmtKind::Expr(ref expr) => {
+                self.u8(0);
+                self.expr(expr);
+            }
+            StmtKind::Assign(ref target, op, ref value) => {
+                self.u8(1);
+                self.expr(target);
+                self.bool(op.is_some());
+                if let Some(op) = op {
+                    self.binary_op(op);
+                }
+                self.expr(value);
+            }
+            StmtKind::If(ref cond, ref then, ref otherwise) => {
+                self.u8(2);
+                self.expr(cond);
+                self.block(then);
+                self.bool(otherwise.is_some());
+                if let Some(ref otherwise) = *otherwise {
+                    self.block(otherwise);
+                }
+            }
+            StmtKind::While(ref cond, ref body) => {
+                self.u8(3);
+                self.expr(cond);
+                self.block(body);
+            }
+            StmtKind::For(ref name, ref iterable, ref body) => {
+                self.u8(4);
+                self.str(name);
+                self.expr(iterable);
+                self.block(body);
+            }
+            StmtKind::Break => self.u8(5),
+            StmtKind::Return(ref value) => {
+                self.u8(6);
+                self.option_expr(value.as_ref());
+            }
+            StmtKind::Function(ref function) => {
+                self.u8(7);
+                self.function(function);
+            }
+            StmtKind::Every(period, ref function) => {
+                self.u8(8);
+                self.u32(period);
+                self.function(function);
+            }
+            StmtKind::On(ref device, ref function) => {
+                self.u8(9);
+                self.str(device);
+                self.function(function);
+            }
+        }
+    }
+
+    fn option_expr(&mut self, expr: Option<&Expr>) {
+        self.bool(expr.is_some());
+        if let Some(expr) = expr {
+            self.expr(expr);
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) {
+        self.usize(exprs.len());
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        self.span(expr.span);
+        match expr.kind {
+            ExprKind::Int(i) => {
+                self.u8(0);
+                self.i32(i);
+            }
+            ExprKind::Real(r) => {
+                self.u8(1);
+                self.i32(r.to_bits());
+            }
+            ExprKind::Str(ref s) => {
+                self.u8(2);
+                self.str(s);
+            }
+            ExprKind::Bool(b) => {
+                self.u8(3);
+                self.bool(b);
+            }
+            ExprKind::Nil => self.u8(4),
+            ExprKind::Identity(ref name) => {
+                self.u8(5);
+                self.str(name);
+            }
+            ExprKind::Device(ref name) => {
+                self.u8(6);
+                self.str(name);
+            }
+            ExprKind::Unary(op, ref operand) => {
+                self.u8(7);
+                self.unary_op(op);
+                self.expr(operand);
+            }
+            ExprKind::Binary(op, ref left, ref right) => {
+                self.u8(8);
+                self.binary_op(op);
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Ternary(ref cond, ref then, ref otherwise) => {
+                self.u8(9);
+                self.expr(cond);
+                self.expr(then);
+                self.expr(otherwise);
+            }
+            ExprKind::List(ref items) => {
+                self.u8(10);
+                self.exprs(items);
+            }
+            ExprKind::Index(ref target, ref index) => {
+                self.u8(11);
+                self.expr(target);
+                self.expr(index);
+            }
+            ExprKind::Map(ref entries) => {
+                self.u8(12);
+                self.usize(entries.len());
+                for (key, value) in entries {
+                    self.str(key);
+                    self.expr(value);
+                }
+            }
+            ExprKind::Field(ref target, ref name) => {
+                self.u8(13);
+                self.expr(target);
+                self.str(name);
+            }
+            ExprKind::Call(ref callee, ref args) => {
+                self.u8(14);
+                self.expr(callee);
+                self.exprs(args);
+            }
+            ExprKind::Function(ref function) => {
+                self.u8(15);
+                self.function(function);
+            }
+            ExprKind::Range(ref start, ref end, ref step, inclusive) => {
+                self.u8(16);
+                self.expr(start);
+                self.expr(end);
+                self.option_expr(step.as_ref().map(|step| &**step));
+                self.bool(inclusive);
+            }
+        }
+    }
+
+    /// Writes a type annotation. Annotations don't have signatures.
+    fn option_type(&mut self, ty: Option<&Type>) {
+        let ty = match ty {
+            Some(ty) => ty,
+            None => return self.u8(0),
+        };
+        match *ty {
+            Type::Any => self.u8(1),
+            Type::Nil => self.u8(2),
+            Type::Bool => self.u8(3),
+            Type::Int => self.u8(4),
+            Type::Real => self.u8(5),
+            Type::Str => self.u8(6),
+            Type::Range => self.u8(7),
+            Type::List => self.u8(8),
+            Type::Map => self.u8(9),
+            Type::Fn(_) => self.u8(10),
+            Type::Optional(ref ty) => {
+                self.u8(11);
+                self.option_type(Some(ty));
+            }
+        }
+    }
+
+    fn unary_op(&mut self, op: UnaryOp) {
+        let index = UNARY_OPS.iter().position(|&o| o == op).expect("unknown operator");
+        self.u8(index as u8);
+    }
+
+    fn binary_op(&mut self, op: BinaryOp) {
+        let index = BINARY_OPS.iter().position(|&o| o == op).expect("unknown operator");
+        self.u8(index as u8);
+    }
+
+    /// Writes a compiled module.
+    pub fn module(&mut self, module: &Rc<Module>) {
+        if !self.shared(module) {
+            return;
+        }
+        self.usize(module.constants.len());
+        for constant in &module.constants {
+            self.value(constant);
+        }
+        self.usize(module.names.len());
+        for name in &module.names {
+            self.str(name);
+        }
+        self.usize(module.protos.len());
+        for proto in &module.protos {
+            self.proto(proto);
+        }
+    }
+
+    fn proto(&mut self, proto: &Proto) {
+        self.option_str(proto.name.as_ref());
+        self.usize(proto.arity);
+        self.span(proto.span);
+        self.usize(proto.code.len());
+        for (&op, &span) in proto.code.iter().zip(&proto.spans) {
+            self.op(op);
+            self.span(span);
+        }
+        self.usize(proto.captured.len());
+        for &captured in &proto.captured {
+            self.bool(captured);
+        }
+        self.usize(proto.blocks.len());
+        for block in &proto.blocks {
+            self.usize(block.len());
+            for &slot in block {
+                self.u16(slot);
+            }
+        }
+        self.usize(proto.vars.len());
+        for var in &proto.vars {
+            self.u32(var.name);
+            self.usize(var.places.len());
+            for place in &var.places {
+                match *place {
+                    Place::Local(slot) => {
+                        self.u8(0);
+                        self.u16(slot);
+                    }
+                    Place::Capture(index) => {
+                        self.u8(1);
+                        self.u16(index);
+                    }
+                    Place::Global(name) => {
+                        self.u8(2);
+                        self.u32(name);
+                    }
+                }
+            }
+        }
+        self.usize(proto.captures.len());
+        for capture in &proto.captures {
+            match *capture {
+                Capture::Local(slot) => {
+                    self.u8(0);
+                    self.u16(slot);
+                }
+                Capture::Capture(index) => {
+                    self.u8(1);
+                    self.u16(index);
+                }
+            }
+        }
+        self.usize(proto.loops.len());
+        for l in &proto.loops {
+            self.u32(l.start);
+            self.u32(l.end);
+            self.span(l.span);
+        }
+        self.usize(proto.statements.len());
+        for &(start, span) in &proto.statements {
+            self.u32(start);
+            self.span(span);
+        }
+        self.usize(proto.locals.len());
+        for local in &proto.locals {
+            self.bool(local.is_some());
+            if let Some(ref local) = *local {
+                self.str(&local.name);
+                self.u32(local.start);
+                self.u32(local.end);
+            }
+        }
+    }
+
+    fn op(&mut self, op: Op) {
+        match op {
+            Op::Const(index) => {
+                self.u8(0);
+                self.u32(index);
+            }
+            Op::Nil => self.u8(1),
+            Op::True => self.u8(2),
+            Op::False => self.u8(3),
+            Op::Pop => self.u8(4),
+            Op::Dup => self.u8(5),
+            Op::Dup2 => self.u8(6),
+            Op::Swap => self.u8(7),
+            Op::Rotate => self.u8(8),
+            Op::GetVar(index) => {
+                self.u8(9);
+                self.u32(index);
+            }
+            Op::SetVar(index) => {
+                self.u8(10);
+                self.u32(index);
+            }
+            Op::Declare(index) => {
+                self.u8(11);
+                self.u32(index);
+            }
+            Op::EnterBlock(index) => {
+                self.u8(12);
+                self.u32(index);
+            }
+            Op::Unary(op) => {
+                self.u8(13);
+                self.unary_op(op);
+            }
+            Op::Binary(op) => {
+                self.u8(14);
+                self.binary_op(op);
+            }
+            Op::Jump(target) => {
+                self.u8(15);
+                self.u32(target);
+            }
+            Op::JumpIfFalse(target) => {
+                self.u8(16);
+                self.u32(target);
+            }
+            Op::JumpIfTrue(target) => {
+                self.u8(17);
+                self.u32(target);
+            }
+            Op::List(len) => {
+                self.u8(18);
+                self.u32(len);
+            }
+            Op::Map(len) => {
+                self.u8(19);
+                self.u32(len);
+            }
+            Op::Index => self.u8(20),
+            Op::SetIndex => self.u8(21),
+            Op::Field(name) => {
+                self.u8(22);
+                self.u32(name);
+            }
+            Op::SetField(name) => {
+                self.u8(23);
+                self.u32(name);
+            }
+            Op::Range { inclusive, step } => {
+                self.u8(24);
+                self.bool(inclusive);
+                self.bool(step);
+            }
+            Op::Device(name) => {
+                self.u8(25);
+                self.u32(name);
+            }
+            Op::Call(argc) => {
+                self.u8(26);
+                self.u16(argc);
+            }
+            Op::Closure(index) => {
+                self.u8(27);
+                self.u32(index);
+            }
+            Op::Return => self.u8(28),
+            Op::IterStart(slot) => {
+                self.u8(29);
+                self.u16(slot);
+            }
+            Op::IterNext(slot, target) => {
+                self.u8(30);
+                self.u16(slot);
+                self.u32(target);
+            }
+            Op::BreakOutsideLoop => self.u8(31),
+            Op::ReturnOutsideFunction => self.u8(32),
+            Op::Every(period) => {
+                self.u8(33);
+                self.u32(period);
+            }
+            Op::On(device) => {
+                self.u8(34);
+                self.u32(device);
+            }
+        }
+    }
+}
+
+/// A shared object read from a snapshot.
+pub enum Shared<T> {
+    /// An object read before.
+    Old(Rc<T>),
+    /// A new object with the id, which the caller reads and then
+    /// gives to the reader with `define`.
+    New(u32),
+}
+
+/// Reads a snapshot.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// The shared objects read so far, by id. An object is `None`
+    /// while it is being read.
+    objects: Vec<Option<Rc<dyn Any>>>,
+    /// The native functions of the engine restoring the snapshot.
+    natives: &'a Natives,
+    /// How deep the reader is in objects read by recursing.
+    depth: usize,
+    /// The checks to make once the whole snapshot is read, see `check`.
+    checks: Vec<Box<dyn Fn() -> error::Result<()>>>,
+}
+
+/// A list or map read from a snapshot, whose items are being read.
+struct Partial {
+    value: Value,
+    /// The number of items left to read.
+    left: usize,
+    /// The key of the item being read, in a map.
+    key: String,
+}
+
+impl Partial {
+    fn add(&mut self, item: Value) {
+        match self.value {
+            Value::List(ref list) => list.borrow_mut().push(item),
+            Value::Map(ref map) => {
+                map.borrow_mut().insert(::std::mem::take(&mut self.key), item);
+            }
+            _ => unreachable!(),
+        }
+        self.left -= 1;
+    }
+}
+
+impl<'a> Reader<'a> {
+    /// Checks the header and the checksum of a snapshot taken from
+    /// an engine of the given kind.
+    pub fn new(snapshot: &'a [u8], kind: Kind, natives: &'a Natives) -> error::Result<Reader<'a>> {
+        let header = MAGIC.len() + 3;
+        if snapshot.len() < header + 8 || !snapshot.starts_with(MAGIC) {
+            return Err(bad("not a snapshot"));
+        }
+        let (data, sum) = snapshot.split_at(snapshot.len() - 8);
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != VERSION {
+            return Err(BadSnapshot(format!("unsupported version {}", version)));
+        }
+        let mut sum_bytes = [0; 8];
+        sum_bytes.copy_from_slice(sum);
+        if checksum(data) != u64::from_le_bytes(sum_bytes) {
+            return Err(bad("checksum mismatch"));
+        }
+        let found = match data[6] {
+            0 => Kind::Interpreter,
+            1 => Kind::Vm,
+            _ => return Err(bad("unknown engine")),
+        };
+        if found != kind {
+            return Err(BadSnapshot(format!("expected a snapshot of the {}, found one of the {}",
+                                           kind.name(),
+                                           found.name())));
+        }
+        Ok(Reader {
+            data,
+            pos: header,
+            objects: Vec::new(),
+            natives,
+            depth: 0,
+            checks: Vec::new(),
+        })
+    }
+
+    /// Checks that the whole snapshot was read, and makes the checks
+    /// given to `check`.
+    pub fn finish(self) -> error::Result<()> {
+        if self.pos != self.data.len() {
+            return Err(bad("unexpected data at the end"));
+        }
+        self.checks.iter().try_for_each(|check| check())
+    }
+
+    /// Gives a check to make once the whole snapshot is read, for an
+    /// object which can only be checked once the objects it refers to
+    /// are read completely.
+    pub fn check<F>(&mut self, check: F)
+        where F: Fn() -> error::Result<()> + 'static
+    {
+        self.checks.push(Box::new(check));
+    }
+
+    /// Reads with `read` one level deeper, failing if the snapshot is
+    /// nested deeper than `MAX_DEPTH`.
+    pub fn nested<T, F>(&mut self, read: F) -> error::Result<T>
+        where F: FnOnce(&mut Self) -> error::Result<T>
+    {
+        if self.depth == MAX_DEPTH {
+            return Err(bad("nested too deep"));
+        }
+        self.depth += 1;
+        let result = read(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn bytes(&mut self, n: usize) -> error::Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(bad("unexpected end"));
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> error::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> error::Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> error::Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> error::Result<u64> {
+        let b = self.bytes(8)?;
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(b);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn i32(&mut self) -> error::Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    pub fn usize(&mut self) -> error::Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    pub fn bool(&mut self) -> error::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(bad("bad bool")),
+        }
+    }
+
+    pub fn str(&mut self) -> error::Result<String> {
+        let len = self.usize()?;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| bad("bad string"))
+    }
+
+    pub fn span(&mut self) -> error::Result<Span> {
+        Ok(Span::new(self.u32()?, self.u32()?))
+    }
+
+    pub fn duration(&mut self) -> error::Result<Duration> {
+        let secs = self.u64()?;
+        let nanos = self.u32()?;
+        if nanos >= 1_000_000_000 {
+            return Err(bad("bad duration"));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+
+    fn option_str(&mut self) -> error::Result<Option<String>> {
+        Ok(if self.bool()? { Some(self.str()?) } else { None })
+    }
+
+    /// Reads `len` items with `read`.
+    fn many<T, F>(&mut self, read: F) -> error::Result<Vec<T>>
+        where F: Fn(&mut Self) -> error::Result<T>
+    {
+        let len = self.usize()?;
+        (0..len).map(|_| read(self)).collect()
+    }
+
+    /// Reads the id of a shared object, see `Writer::shared`.
+    pub fn shared<T: Any>(&mut self) -> error::Result<Shared<T>> {
+        let id = self.u32()?;
+        if id as usize == self.objects.len() {
+            self.objects.push(None);
+            return Ok(Shared::New(id));
+        }
+        match self.objects.get(id as usize) {
+            Some(Some(object)) => {
+                object.clone().downcast().map(Shared::Old).map_err(|_| bad("wrong type of object"))
+            }
+            _ => Err(BadSnapshot(format!("bad reference to object {}", id))),
+        }
+    }
+
+    /// Gives the object with a new id, see `shared`.
+    pub fn define<T: Any>(&mut self, id: u32, object: Rc<T>) {
+        self.objects[id as usize] = Some(object);
+    }
+
+    /// Reads variables written by `Writer::vars`.
+    pub fn vars(&mut self) -> error::Result<Vec<(String, Value)>> {
+        self.many(|r| Ok((r.str()?, r.value()?)))
+    }
+
+    /// Reads a value written by `Writer::value`.
+    pub fn value(&mut self) -> error::Result<Value> {
+        match self.value_head()? {
+            (value, 0) => Ok(value),
+            (value, len) => self.items(value, len),
+        }
+    }
+
+    /// Reads the `len` items of a list or map read by `value_head`, in a
+    /// loop rather than by recursing, as well as the items of the lists
+    /// and maps among them.
+    fn items(&mut self, value: Value, len: usize) -> error::Result<Value> {
+        let mut open = vec![Partial { value, left: len, key: String::new() }];
+        loop {
+            if let Some(&mut Partial { value: Value::Map(_), ref mut key, .. }) = open.last_mut() {
+                *key = self.str()?;
+            }
+            let (mut value, len) = self.value_head()?;
+            if len > 0 {
+                open.push(Partial { value, left: len, key: String::new() });
+                continue;
+            }
+            // Add the value to the lists and maps it completes
+            loop {
+                let partial = match open.last_mut() {
+                    Some(partial) => partial,
+                    None => return Ok(value),
+                };
+                partial.add(value);
+                if partial.left > 0 {
+                    break;
+                }
+                value = open.pop().unwrap().value;
+            }
+        }
+    }
+
+    /// Reads a value, but only the length of a list or map read for the
+    /// first time, which is returned with it to read its items next.
+    fn value_head(&mut self) -> error::Result<(Value, usize)> {
+        Ok((match self.u8()? {
+            8 => match self.shared()? {
+                Shared::Old(list) => Value::List(list),
+                Shared::New(id) => {
+                    let list = Rc::new(RefCell::new(Vec::new()));
+                    self.define(id, list.clone());
+                    return Ok((Value::List(list), self.usize()?));
+                }
+            },
+            9 => match self.shared()? {
+                Shared::Old(map) => Value::Map(map),
+                Shared::New(id) => {
+                    let map = Rc::new(RefCell::new(BTreeMap::new()));
+                    self.define(id, map.clone());
+                    return Ok((Value::Map(map), self.usize()?));
+                }
+            },
+            10 => Value::Function(self.nested(eval::Closure::read)?),
+            11 => Value::Compiled(self.nested(vm::Closure::read)?),
+            tag => self.plain_value(tag)?,
+        }, 0))
+    }
+
+    /// Reads a value holding no other values, after its tag. It is kept
+    /// apart from `value_head`, which is on the stack once for each
+    /// closure nested in another.
+    fn plain_value(&mut self, tag: u8) -> error::Result<Value> {
+        Ok(match tag {
+            0 => Value::Nil,
+            1 => Value::Bool(false),
+            2 => Value::Bool(true),
+            3 => Value::Int(self.i32()?),
+            4 => Value::Real(Real::from_bits(self.i32()?)),
+            5 => Value::Str(self.str()?),
+            6 => {
+                Value::Range(Range::Int {
+                    start: self.i32()?,
+                    end: self.i32()?,
+                    step: self.i32()?,
+                    inclusive: self.bool()?,
+                })
+            }
+            7 => {
+                Value::Range(Range::Real {
+                    start: Real::from_bits(self.i32()?),
+                    end: Real::from_bits(self.i32()?),
+                    step: Real::from_bits(self.i32()?),
+                    inclusive: self.bool()?,
+                })
+            }
+            12 => {
+                let name = self.str()?;
+                let builtin = Builtin::lookup(&name)
+                    .ok_or_else(|| BadSnapshot(format!("unknown builtin function {}", name)))?;
+                Value::Builtin(builtin)
+            }
+            13 => {
+                let name = self.str()?;
+                let native = self.natives.get(&name)
+                    .ok_or_else(|| BadSnapshot(format!("unknown native function {}", name)))?;
+                Value::Native(native.clone())
+            }
+            tag => return Err(BadSnapshot(format!("bad value tag {}", tag))),
+        })
+    }
+
+    /// Reads a function written by `Writer::function`.
+    pub fn function(&mut self) -> error::Result<Rc<Function>> {
+        let id = match self.shared()? {
+            Shared::Old(function) => return Ok(function),
+            Shared::New(id) => id,
+        };
+        let name = self.option_str()?;
+        let params = self.many(|r| Ok(Param { name: r.str()?, ty: r.option_type()?, span: r.span()? }))?;
+        let ret = self.option_type()?;
+        let body = self.block()?;
+        let span = self.span()?;
+        let function = Rc::new(Function { name, params, ret, body, span });
+        self.define(id, function.clone());
+        Ok(function)
+    }
+
+    fn block(&mut self) -> error::Result<Block> {
+        self.many(Reader::stmt)
+    }
+
+    fn stmt(&mut self) -> error::Result<Stmt> {
+        let span = self.span()?;
+        let kind = self.nested(Reader::stmt_kind)?;
+        Ok(Stmt::new(kind, span))
+    }
+
+    fn stmt_kind(&mut self) -> error::Result<StmtKind> {
+        Ok(match self.u8()? {
+            0 => StmtKind::Expr(self.expr()?),
+            1 => {
+                let target = self.expr()?;
+                let op = if self.bool()? { Some(self.binary_op()?) } else { None };
+                StmtKind::Assign(target, op, self.expr()?)
+            }
+            2 => {
+                let cond = self.expr()?;
+                let then = self.block()?;
+                let otherwise = if self.bool()? { Some(self.block()?) } else { None };
+                StmtKind::If(cond, then, otherwise)
+            }
+            3 => StmtKind::While(self.expr()?, self.block()?),
+            4 => StmtKind::For(self.str()?, self.expr()?, self.block()?),
+            5 => StmtKind::Break,
+            6 => StmtKind::Return(self.option_expr()?),
+            7 => StmtKind::Function(self.function()?),
+            8 => StmtKind::Every(self.u32()?, self.function()?),
+            9 => StmtKind::On(self.str()?, self.function()?),
+            tag => return Err(BadSnapshot(format!("bad statement tag {}", tag))),
+        })
+    }
+
+    fn option_expr(&mut self) -> error::Result<Option<Expr>> {
+        Ok(if self.bool()? { Some(self.expr()?) } else { None })
+    }
+
+    fn boxed(&mut self) -> error::Result<Box<Expr>> {
+        self.expr().map(Box::new)
+    }
+
+    fn expr(&mut self) -> error::Result<Expr> {
+        let span = self.span()?;
+        let kind = self.nested(Reader::expr_kind)?;
+        Ok(Expr::new(kind, span))
+    }
+
+    fn expr_kind(&mut self) -> error::Result<ExprKind> {
+        Ok(match self.u8()? {
+            0 => ExprKind::Int(self.i32()?),
+            1 => ExprKind::Real(Real::from_bits(self.i32()?)),
+            2 => ExprKind::Str(self.str()?),
+            3 => ExprKind::Bool(self.bool()?),
+            4 => ExprKind::Nil,
+            5 => ExprKind::Identity(self.str()?),
+            6 => ExprKind::Device(self.str()?),
+            7 => ExprKind::Unary(self.unary_op()?, self.boxed()?),
+            8 => ExprKind::Binary(self.binary_op()?, self.boxed()?, self.boxed()?),
+            9 => ExprKind::Ternary(self.boxed()?, self.boxed()?, self.boxed()?),
+            10 => ExprKind::List(self.many(Reader::expr)?),
+            11 => ExprKind::Index(self.boxed()?, self.boxed()?),
+            12 => ExprKind::Map(self.many(|r| Ok((r.str()?, r.expr()?)))?),
+            13 => ExprKind::Field(self.boxed()?, self.str()?),
+            14 => ExprKind::Call(self.boxed()?, self.many(Reader::expr)?),
+            15 => ExprKind::Function(self.function()?),
+            16 => {
+                let start = self.boxed()?;
+                let end = self.boxed()?;
+                let step = self.option_expr()?.map(Box::new);
+                ExprKind::Range(start, end, step, self.bool()?)
+            }
+            tag => return Err(BadSnapshot(format!("bad expression tag {}", tag))),
+        })
+    }
+
+    fn option_type(&mut self) -> error::Result<Option<Type>> {
+        Ok(Some(match self.u8()? {
+            0 => return Ok(None),
+            1 => Type::Any,
+            2 => Type::Nil,
+            3 => Type::Bool,
+            4 => Type::Int,
+            5 => Type::Real,
+            6 => Type::Str,
+            7 => Type::Range,
+            8 => Type::List,
+            9 => Type::Map,
+            10 => Type::Fn(None),
+            11 => match self.nested(Reader::option_type)? {
+                Some(ty) => Type::Optional(Box::new(ty)),
+                None => return Err(bad("bad type")),
+            },
+            tag => return Err(BadSnapshot(format!("bad type tag {}", tag))),
+        }))
+    }
+
+    fn unary_op(&mut self) -> error::Result<UnaryOp> {
+        let index = self.u8()? as usize;
+        UNARY_OPS.get(index).cloned().ok_or_else(|| bad("bad operator"))
+    }
+
+    fn binary_op(&mut self) -> error::Result<BinaryOp> {
+        let index = self.u8()? as usize;
+        BINARY_OPS.get(index).cloned().ok_or_else(|| bad("bad operator"))
+    }
+
+    /// Reads a module written by `Writer::module`.
+    pub fn module(&mut self) -> error::Result<Rc<Module>> {
+        let id = match self.shared()? {
+            Shared::Old(module) => return Ok(module),
+            Shared::New(id) => id,
+        };
+        let module = Module {
+            constants: self.many(Reader::value)?,
+            names: self.many(Reader::str)?,
+            protos: self.many(Reader::proto)?,
+        };
+        if module.protos.is_empty() {
+            return Err(bad("module without code"));
+        }
+        let module = Rc::new(module);
+        self.define(id, module.clone());
+        Ok(module)
+    }
+
+    fn proto(&mut self) -> error::Result<Proto> {
+        let name = self.option_str()?;
+        let arity = self.usize()?;
+        let span = self.span()?;
+        let (code, spans) = self.many(|r| Ok((r.op()?, r.span()?)))?.into_iter().unzip();
+        Ok(Proto {
+            name,
+            arity,
+            span,
+            code,
+            spans,
+            captured: self.many(Reader::bool)?,
+            blocks: self.many(|r| r.many(Reader::u16))?,
+            vars: self.many(|r| {
+                Ok(Var {
+                    name: r.u32()?,
+                    places: r.many(Reader::place)?,
+                })
+            })?,
+            captures: self.many(|r| match r.u8()? {
+                0 => Ok(Capture::Local(r.u16()?)),
+                1 => Ok(Capture::Capture(r.u16()?)),
+                _ => Err(bad("bad capture")),
+            })?,
+            loops: self.many(|r| {
+                Ok(Loop {
+                    start: r.u32()?,
+                    end: r.u32()?,
+                    span: r.span()?,
+                })
+            })?,
+            statements: self.many(|r| Ok((r.u32()?, r.span()?)))?,
+            locals: self.many(|r| {
+                if !r.bool()? {
+                    return Ok(None);
+                }
+                Ok(Some(Local {
+                    name: r.str()?,
+                    start: r.u32()?,
+                    end: r.u32()?,
+                }))
+            })?,
+        })
+    }
+
+    fn place(&mut self) -> error::Result<Place> {
+        match self.u8()? {
+            0 => Ok(Place::Local(self.u16()?)),
+            1 => Ok(Place::Capture(self.u16()?)),
+            2 => Ok(Place::Global(self.u32()?)),
+            _ => Err(bad("bad place")),
+        }
+    }
+
+    fn op(&mut self) -> error::Result<Op> {
+        Ok(match self.u8()? {
+            0 => Op::Const(self.u32()?),
+            1 => Op::Nil,
+            2 => Op::True,
+            3 => Op::False,
+            4 => Op::Pop,
+            5 => Op::Dup,
+            6 => Op::Dup2,
+            7 => Op::Swap,
+            8 => Op::Rotate,
+            9 => Op::GetVar(self.u32()?),
+            10 => Op::SetVar(self.u32()?),
+            11 => Op::Declare(self.u32()?),
+            12 => Op::EnterBlock(self.u32()?),
+            13 => Op::Unary(self.unary_op()?),
+            14 => Op::Binary(self.binary_op()?),
+            15 => Op::Jump(self.u32()?),
+            16 => Op::JumpIfFalse(self.u32()?),
+            17 => Op::JumpIfTrue(self.u32()?),
+            18 => Op::List(self.u32()?),
+            19 => Op::Map(self.u32()?),
+            20 => Op::Index,
+            21 => Op::SetIndex,
+            22 => Op::Field(self.u32()?),
+            23 => Op::SetField(self.u32()?),
+            24 => Op::Range { inclusive: self.bool()?, step: self.bool()? },
+            25 => Op::Device(self.u32()?),
+            26 => Op::Call(self.u16()?),
+            27 => Op::Closure(self.u32()?),
+            28 => Op::Return,
+            29 => Op::IterStart(self.u16()?),
+            30 => Op::IterNext(self.u16()?, self.u32()?),
+            31 => Op::BreakOutsideLoop,
+            32 => Op::ReturnOutsideFunction,
+            33 => Op::Every(self.u32()?),
+            34 => Op::On(self.u32()?),
+            tag => return Err(BadSnapshot(format!("bad instruction tag {}", tag))),
+        })
+    }
+}
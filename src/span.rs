@@ -0,0 +1,22 @@
+use std::fmt;
+
+/// A position in the source code.
+/// Both the line and the column starts at 1.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Span {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Span {
+    /// Creates a new `Span` from a line and a column.
+    pub fn new(line: u32, column: u32) -> Self {
+        Span { line, column }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
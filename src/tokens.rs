@@ -10,10 +10,15 @@ pub enum Token {
     Int(i32),
     Real(Real),
     Str(String),
+    RawStr(String),
     Nil,
+    /// A duration in milliseconds, written `10ms` or `2s`.
+    Duration(u32),
 
     // Identifier
     Identity(String),
+    /// A comment, `# ..`, only produced by `Lexer::with_comments`.
+    Comment(String),
 
     // Assignment
     Assignment,
@@ -59,14 +64,20 @@ pub enum Token {
     Else,
     While,
     For,
+    In,
     Break,
     Return,
+    Every,
+    On,
     QuestionMark,
 
     // Misc
     Semicolon,
+    Colon,
     Comma,
     Dot,
+    /// `->` before the return type of a function.
+    Arrow,
 
     // Ranges
     ExclusiveRange,
@@ -77,10 +88,11 @@ pub enum Token {
 
 impl Token {
     /// Is this a keyword token?
-    #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[rustfmt::skip]
     pub fn is_keyword(&self) -> bool {
-        match *self {
+        matches!(*self,
               At
+            | By
             | Function
             | True
             | False
@@ -88,36 +100,33 @@ impl Token {
             | Else
             | While
             | For
+            | In
             | Break
             | Return
-            | QuestionMark => true,
-            _ => false,
-        }
+            | Every
+            | On
+            | QuestionMark)
     }
 
     /// Is this an assignment token?
-    #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[rustfmt::skip]
     pub fn is_assignment(&self) -> bool {
-        match *self {
+        matches!(*self,
               Assignment
             | PlusAssignment
             | MinusAssignment
             | MulAssignment
-            | DivAssignment => true,
-            _ => false,
-        }
+            | DivAssignment)
     }
 
     /// Is this an arithmetic  token?
-    #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[rustfmt::skip]
     pub fn is_arithmetic(&self) -> bool {
-        match *self {
+        matches!(*self,
               Plus
             | Minus
             | Mul
-            | Div => true,
-            _ => false,
-        }
+            | Div)
     }
 }
 
@@ -127,13 +136,21 @@ impl fmt::Display for Token {
         match *self {
             Int(i) => write!(f, "Int: {}", i),
             Real(r) => write!(f, "Real: {}", r),
+            Duration(ms) => write!(f, "Duration: {}ms", ms),
             Str(ref s) => write!(f, "Str: \"{}\"", s),
+            RawStr(ref s) => write!(f, "RawStr: \"{}\"", s),
             Identity(ref i) => write!(f, "Identity: \"{}\"", i),
             _ => write!(f, "{:?}", self),
         }
     }
 }
 
+/// The words `lookup_identity` turns into keyword tokens.
+pub const KEYWORDS: [&str; 14] = [
+    "fn", "true", "false", "if", "else", "while", "for", "in", "by", "break", "return", "every",
+    "on", "nil",
+];
+
 /// Performs a check on the input str `id` to see
 /// whenever it is a keyword token or a name token
 /// and then returns the coresponding `Token`.
@@ -146,8 +163,12 @@ pub fn lookup_identity(id: String) -> Token {
         "else" => Else,
         "while" => While,
         "for" => For,
+        "in" => In,
+        "by" => By,
         "break" => Break,
         "return" => Return,
+        "every" => Every,
+        "on" => On,
         "nil" => Nil,
         _ => Identity(id),
     }
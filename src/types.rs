@@ -0,0 +1,117 @@
+use std::fmt;
+use std::rc::Rc;
+
+/// The type of a value as far as the `checker::Checker` knows it, or
+/// as written in an annotation like `x: real?`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    /// Any value, the type of everything which is not annotated
+    /// and cannot be inferred.
+    Any,
+    Nil,
+    Bool,
+    Int,
+    Real,
+    Str,
+    Range,
+    List,
+    Map,
+    /// A function, with its parameters and return type if they are known.
+    Fn(Option<Rc<Signature>>),
+    /// `T?`, a value of the type or nil.
+    Optional(Box<Type>),
+}
+
+/// The parameters and the return type of a function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub ret: Type,
+}
+
+impl Type {
+    /// The type named `name` in an annotation.
+    pub fn from_name(name: &str) -> Option<Type> {
+        Some(match name {
+            "any" => Type::Any,
+            "nil" => Type::Nil,
+            "bool" => Type::Bool,
+            "int" => Type::Int,
+            "real" => Type::Real,
+            "str" => Type::Str,
+            "range" => Type::Range,
+            "list" => Type::List,
+            "map" => Type::Map,
+            "fn" => Type::Fn(None),
+            _ => return None,
+        })
+    }
+
+    /// `T?` for `T`. Nil and any value are already optional.
+    pub fn optional(self) -> Type {
+        match self {
+            Type::Any | Type::Nil | Type::Optional(_) => self,
+            _ => Type::Optional(Box::new(self)),
+        }
+    }
+
+    /// Whether the value can be nil, if the type is known.
+    pub fn is_optional(&self) -> bool {
+        matches!(*self, Type::Nil | Type::Optional(_))
+    }
+
+    /// The type without nil, like after checking a value is not nil.
+    pub fn non_nil(&self) -> Type {
+        match *self {
+            Type::Optional(ref ty) => (**ty).clone(),
+            _ => self.clone(),
+        }
+    }
+
+    /// Whether a value of this type can be used where `expected` is.
+    /// An int is not a real, as nothing converts one to the other.
+    pub fn fits(&self, expected: &Type) -> bool {
+        match (self, expected) {
+            (&Type::Any, _) | (_, &Type::Any) => true,
+            (&Type::Fn(_), &Type::Fn(_)) => true,
+            (&Type::Nil, &Type::Optional(_)) => true,
+            (Type::Optional(ty), Type::Optional(expected)) => ty.fits(expected),
+            (ty, Type::Optional(expected)) => ty.fits(expected),
+            _ => self == expected,
+        }
+    }
+
+    /// The type of a value which is either of this type or `other`.
+    pub fn join(&self, other: &Type) -> Type {
+        match (self, other) {
+            _ if self == other => self.clone(),
+            (&Type::Fn(_), &Type::Fn(_)) => Type::Fn(None),
+            (&Type::Nil, ty) | (ty, &Type::Nil) => ty.clone().optional(),
+            (&Type::Optional(ref ty), other) | (other, &Type::Optional(ref ty)) => {
+                match ty.join(other) {
+                    Type::Any => Type::Any,
+                    joined => joined.optional(),
+                }
+            }
+            _ => Type::Any,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Type::Any => f.write_str("any"),
+            Type::Nil => f.write_str("nil"),
+            Type::Bool => f.write_str("bool"),
+            Type::Int => f.write_str("int"),
+            Type::Real => f.write_str("real"),
+            Type::Str => f.write_str("str"),
+            Type::Range => f.write_str("range"),
+            Type::List => f.write_str("list"),
+            Type::Map => f.write_str("map"),
+            Type::Fn(_) => f.write_str("fn"),
+            Type::Optional(ref ty) => write!(f, "{} or nil", ty),
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::rc::Rc;
+use std::vec;
+
+use builtins::Builtin;
+use eval::Closure;
+use native::Native;
+use real::Real;
+use vm;
+
+/// A value produced when evaluating a script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i32),
+    Real(Real),
+    Str(String),
+    Range(Range),
+    /// A list shared by every value referring to it,
+    /// so changes through one reference are seen by all.
+    List(Rc<RefCell<Vec<Value>>>),
+    /// A map from names to values, shared like a `List`.
+    /// The keys are kept sorted so they are always listed in the same order.
+    Map(Rc<RefCell<BTreeMap<String, Value>>>),
+    /// A function defined in a script together with the scope it was created in.
+    Function(Rc<Closure>),
+    /// A function compiled to bytecode, run by the `Vm`.
+    Compiled(Rc<vm::Closure>),
+    Builtin(Builtin),
+    /// A Rust function registered by the host.
+    Native(Rc<Native>),
+}
+
+impl Value {
+    /// The name of the type of the value, used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Real(_) => "real",
+            Value::Str(_) => "str",
+            Value::Range(_) => "range",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Function(_) | Value::Compiled(_) | Value::Builtin(_) | Value::Native(_) => "fn",
+        }
+    }
+
+    /// Creates a new list holding `items`.
+    pub fn list(items: Vec<Value>) -> Value {
+        Value::List(Rc::new(RefCell::new(items)))
+    }
+
+    /// Creates a new map holding `entries`.
+    pub fn map(entries: BTreeMap<String, Value>) -> Value {
+        Value::Map(Rc::new(RefCell::new(entries)))
+    }
+
+    /// Returns an iterator over the items of a range or list, or the
+    /// keys of a map. Returns `None` if the value cannot be iterated over.
+    /// A list or map is copied first, so changing it while iterating is safe.
+    pub fn iter(&self) -> Option<ValueIter> {
+        match *self {
+            Value::Range(ref range) => Some(ValueIter::Range(range.iter())),
+            Value::List(ref list) => Some(ValueIter::List(list.borrow().clone().into_iter())),
+            Value::Map(ref map) => {
+                let keys: Vec<Value> = map.borrow().keys().cloned().map(Value::Str).collect();
+                Some(ValueIter::List(keys.into_iter()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Is this value considered true in a condition?
+    /// Only `nil` and `false` are false, every other value is true.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(*self, Value::Nil | Value::Bool(false))
+    }
+}
+
+/// Dropping a list or map would drop the lists and maps inside it from
+/// within, one call deeper for each level of nesting. So the last
+/// reference to a list or map empties it first, and the items held only
+/// by it are emptied in turn here.
+impl Drop for Value {
+    fn drop(&mut self) {
+        let mut items = Vec::new();
+        take_items(self, &mut items);
+        while let Some(mut item) = items.pop() {
+            take_items(&mut item, &mut items);
+        }
+    }
+}
+
+/// Moves the items of `value` to `items` if it is the last reference
+/// to a list or map.
+fn take_items(value: &mut Value, items: &mut Vec<Value>) {
+    match *value {
+        Value::List(ref list) if Rc::strong_count(list) == 1 => {
+            items.append(&mut list.borrow_mut());
+        }
+        Value::Map(ref map) if Rc::strong_count(map) == 1 => {
+            let entries = ::std::mem::take(&mut *map.borrow_mut());
+            items.extend(entries.into_values());
+        }
+        _ => {}
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut path = HashSet::new();
+        let mut open: Vec<Items> = write_value(f, self, false, &mut path)?.into_iter().collect();
+        while let Some(mut items) = open.pop() {
+            let inner = items.next(|key, item, first| {
+                if !first {
+                    f.write_str(", ")?;
+                }
+                if let Some(key) = key {
+                    write!(f, "{}: ", key)?;
+                }
+                write_value(f, item, true, &mut path)
+            });
+            match inner {
+                Some(inner) => {
+                    let inner = inner?;
+                    open.push(items);
+                    open.extend(inner);
+                }
+                None => {
+                    let (close, address) = match items {
+                        Items::List(ref list, _) => ("]", address(list)),
+                        Items::Map(ref map, _) => ("}", address(map)),
+                    };
+                    path.remove(&address);
+                    f.write_str(close)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The items of a list or map, visited one at a time so values nested
+/// deeply can be walked without recursing. It knows the index of the
+/// next item of a list, or the key of the item of a map visited last.
+pub enum Items {
+    List(Rc<RefCell<Vec<Value>>>, usize),
+    Map(Rc<RefCell<BTreeMap<String, Value>>>, Option<String>),
+}
+
+impl Items {
+    /// Calls `visit` with the key of the next item if it is in a map,
+    /// the item, and whether it is the first. Returns `None` once every
+    /// item was visited.
+    pub fn next<T, F>(&mut self, visit: F) -> Option<T>
+        where F: FnOnce(Option<&str>, &Value, bool) -> T
+    {
+        match *self {
+            Items::List(ref list, ref mut i) => {
+                let list = list.borrow();
+                let item = list.get(*i)?;
+                *i += 1;
+                Some(visit(None, item, *i == 1))
+            }
+            Items::Map(ref map, ref mut last) => {
+                let map = map.borrow();
+                let (key, item) = match *last {
+                    Some(ref key) => map.range::<str, _>((Excluded(key.as_str()), Unbounded)).next(),
+                    None => map.iter().next(),
+                }?;
+                let first = last.is_none();
+                *last = Some(key.clone());
+                Some(visit(Some(key), item, first))
+            }
+        }
+    }
+}
+
+/// Writes a value, where `path` holds the lists and maps it is inside of.
+/// Strings inside a list or map are quoted. Only the opening bracket of a
+/// list or map is written, and it is returned to write the rest, so
+/// nesting does not take up the stack. A list or map inside of itself is
+/// written as `[...]` or `{...}`.
+fn write_value(f: &mut fmt::Formatter, value: &Value, inside: bool, path: &mut HashSet<usize>)
+               -> Result<Option<Items>, fmt::Error> {
+    match *value {
+        Value::List(ref list) => {
+            if !path.insert(address(list)) {
+                f.write_str("[...]")?;
+                return Ok(None);
+            }
+            f.write_str("[")?;
+            return Ok(Some(Items::List(list.clone(), 0)));
+        }
+        Value::Map(ref map) => {
+            if !path.insert(address(map)) {
+                f.write_str("{...}")?;
+                return Ok(None);
+            }
+            f.write_str("{")?;
+            return Ok(Some(Items::Map(map.clone(), None)));
+        }
+        Value::Nil => f.write_str("nil"),
+        Value::Bool(b) => write!(f, "{}", b),
+        Value::Int(i) => write!(f, "{}", i),
+        Value::Real(r) => write!(f, "{}", r),
+        Value::Str(ref s) if inside => write!(f, "{:?}", s),
+        Value::Str(ref s) => f.write_str(s),
+        Value::Range(ref r) => write!(f, "{}", r),
+        Value::Function(ref closure) => match closure.function.name {
+            Some(ref name) => write!(f, "<fn {}>", name),
+            None => f.write_str("<fn>"),
+        },
+        Value::Compiled(ref closure) => match closure.proto().name {
+            Some(ref name) => write!(f, "<fn {}>", name),
+            None => f.write_str("<fn>"),
+        },
+        Value::Builtin(builtin) => write!(f, "<builtin {}>", builtin.name()),
+        Value::Native(ref native) => write!(f, "<native {}>", native.name()),
+    }?;
+    Ok(None)
+}
+
+/// The address of what `rc` points to, which tells lists and maps apart.
+pub fn address<T: ?Sized>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+/// An iterator over the items of a `Value`, see `Value::iter`.
+pub enum ValueIter {
+    Range(RangeIter),
+    List(vec::IntoIter<Value>),
+}
+
+impl Iterator for ValueIter {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        match *self {
+            ValueIter::Range(ref mut iter) => iter.next(),
+            ValueIter::List(ref mut iter) => iter.next(),
+        }
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<Real> for Value {
+    fn from(r: Real) -> Self {
+        Value::Real(r)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Self {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+
+/// A range of numbers from `start` towards `end` taking steps of `step`.
+/// The step is never zero, a negative step counts downwards.
+/// If any of the bounds or the step is a `Real` the range is a range of reals.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Range {
+    Int {
+        start: i32,
+        end: i32,
+        step: i32,
+        inclusive: bool,
+    },
+    Real {
+        start: Real,
+        end: Real,
+        step: Real,
+        inclusive: bool,
+    },
+}
+
+impl Range {
+    /// Returns an iterator over the numbers in the range.
+    pub fn iter(&self) -> RangeIter {
+        RangeIter {
+            range: *self,
+            next: self.first(),
+        }
+    }
+
+    /// The first number in the range, or `None` if it is empty.
+    pub fn first(&self) -> Option<Value> {
+        let start = match *self {
+            Range::Int { start, .. } => Value::Int(start),
+            Range::Real { start, .. } => Value::Real(start),
+        };
+        if self.in_bounds(&start) {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// The number after `n` in the range, or `None` if `n` is the
+    /// last one or the next number would overflow.
+    pub fn step_from(&self, n: &Value) -> Option<Value> {
+        let next = match (*self, n) {
+            (Range::Int { step, .. }, &Value::Int(n)) => n.checked_add(step).map(Value::Int),
+            (Range::Real { step, .. }, &Value::Real(n)) => n.checked_add(step).map(Value::Real),
+            _ => None,
+        };
+        next.filter(|next| self.in_bounds(next))
+    }
+
+    /// Checks if `n` is before the end of the range.
+    fn in_bounds(&self, n: &Value) -> bool {
+        match (*self, n) {
+            (Range::Int { end, step, inclusive, .. }, &Value::Int(n)) => {
+                in_bounds(n, end, step > 0, inclusive)
+            }
+            (Range::Real { end, step, inclusive, .. }, &Value::Real(n)) => {
+                in_bounds(n, end, step > Real::default(), inclusive)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Checks if `n` has not yet passed `end`, counting upwards if `up` is true.
+fn in_bounds<T: Ord>(n: T, end: T, up: bool, inclusive: bool) -> bool {
+    match (up, inclusive) {
+        (true, true) => n <= end,
+        (true, false) => n < end,
+        (false, true) => n >= end,
+        (false, false) => n > end,
+    }
+}
+
+/// An iterator over the numbers of a `Range`.
+/// It stops early if the next number would overflow.
+pub struct RangeIter {
+    range: Range,
+    next: Option<Value>,
+}
+
+impl Iterator for RangeIter {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        let current = self.next.take()?;
+        self.next = self.range.step_from(&current);
+        Some(current)
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (start, end, step, inclusive, unit) = match *self {
+            Range::Int { start, end, step, inclusive } => {
+                (Value::Int(start), Value::Int(end), Value::Int(step), inclusive, step == 1)
+            }
+            Range::Real { start, end, step, inclusive } => {
+                (Value::Real(start),
+                 Value::Real(end),
+                 Value::Real(step),
+                 inclusive,
+                 step == Real::from(1))
+            }
+        };
+        write!(f, "{}{}{}", start, if inclusive { "..." } else { ".." }, end)?;
+        if !unit {
+            write!(f, " by {}", step)?;
+        }
+        Ok(())
+    }
+}
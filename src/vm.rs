@@ -0,0 +1,1031 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ptr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use budget::Budget;
+use debugger::{Debugger, StackFrame, Step};
+use profiler::{self, Profile};
+use builtins::{Builtin, Host, Output};
+use native::{IntoNative, Native, Natives};
+use compiler::{self, Capture, Module, Op, Place, Proto};
+use device::{self, DeviceRegistry};
+use limits::{Heap, Limits, Measure};
+use parser::Parser;
+use optimizer;
+use resolver::Resolver;
+use scheduler::Scheduler;
+use snapshot::{Kind, Reader, Shared, Writer};
+use span::Span;
+use value::Value;
+use ops;
+use error;
+use error::Error::*;
+use error::CallError;
+
+/// A variable shared between a frame and the closures capturing it.
+/// It is `None` until the variable is declared.
+type Cell = Rc<RefCell<Option<Value>>>;
+
+/// A compiled function value together with the variables it captured.
+pub struct Closure {
+    pub module: Rc<Module>,
+    /// The index of the function in the module.
+    pub index: usize,
+    captures: Vec<Cell>,
+}
+
+impl Closure {
+    pub fn proto(&self) -> &Proto {
+        &self.module.protos[self.index]
+    }
+
+    /// The name of the function, `<main>` for a script itself and
+    /// `<anonymous>` for a function without one.
+    pub fn name(&self) -> String {
+        match self.proto().name {
+            Some(ref name) => name.clone(),
+            None if self.index == 0 => "<main>".to_string(),
+            None => "<anonymous>".to_string(),
+        }
+    }
+
+    /// Measures the variables the closure captured.
+    pub fn measure(&self, measure: &mut Measure) {
+        for cell in &self.captures {
+            if measure.first_visit(cell) {
+                if let Some(ref value) = *cell.borrow() {
+                    measure.value(value);
+                }
+            }
+        }
+    }
+
+    /// Writes a closure to a snapshot, see `Vm::snapshot`.
+    pub fn write(closure: &Rc<Closure>, writer: &mut Writer) {
+        writer.module(&closure.module);
+        writer.usize(closure.index);
+        writer.usize(closure.captures.len());
+        for cell in &closure.captures {
+            write_cell(cell, writer);
+        }
+        writer.shared(closure);
+    }
+
+    /// Reads a closure written by `write`.
+    pub fn read(reader: &mut Reader) -> error::Result<Rc<Closure>> {
+        let module = reader.module()?;
+        let index = reader.usize()?;
+        let len = reader.usize()?;
+        let captures = (0..len).map(|_| read_cell(reader)).collect::<error::Result<Vec<_>>>()?;
+        match reader.shared()? {
+            Shared::Old(closure) => Ok(closure),
+            Shared::New(id) => {
+                let proto = module.protos.get(index)
+                    .ok_or_else(|| BadSnapshot(format!("no function #{}", index)))?;
+                if proto.captures.len() != captures.len() {
+                    return Err(BadSnapshot("wrong number of captured variables".to_string()));
+                }
+                let closure = Rc::new(Closure {
+                    module,
+                    index,
+                    captures,
+                });
+                reader.define(id, closure.clone());
+                Ok(closure)
+            }
+        }
+    }
+}
+
+/// Writes a cell to a snapshot. The cell gets its id before its
+/// value is written, as the value may be a closure capturing it.
+fn write_cell(cell: &Cell, writer: &mut Writer) {
+    if writer.shared(cell) {
+        let value = cell.borrow();
+        writer.bool(value.is_some());
+        if let Some(ref value) = *value {
+            writer.value(value);
+        }
+    }
+}
+
+fn read_cell(reader: &mut Reader) -> error::Result<Cell> {
+    match reader.shared()? {
+        Shared::Old(cell) => Ok(cell),
+        Shared::New(id) => {
+            let cell = Rc::new(RefCell::new(None));
+            reader.define(id, cell.clone());
+            if reader.bool()? {
+                *cell.borrow_mut() = Some(reader.value()?);
+            }
+            Ok(cell)
+        }
+    }
+}
+
+/// Two closures are equal only if they are the same closure.
+impl PartialEq for Closure {
+    fn eq(&self, other: &Closure) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+/// The captured variables are left out, as they may hold the closure itself.
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Closure").field("function", &self.proto().name).finish()
+    }
+}
+
+/// A slot of a frame holding a local variable.
+#[derive(Debug, Clone)]
+enum Slot {
+    /// The variable is not declared.
+    Empty,
+    Value(Value),
+    /// The variable is captured by a closure.
+    Cell(Cell),
+}
+
+impl Slot {
+    /// A slot for a variable which is not declared yet.
+    fn new(captured: bool) -> Self {
+        if captured {
+            Slot::Cell(Rc::new(RefCell::new(None)))
+        } else {
+            Slot::Empty
+        }
+    }
+
+    fn get(&self) -> Option<Value> {
+        match *self {
+            Slot::Empty => None,
+            Slot::Value(ref value) => Some(value.clone()),
+            Slot::Cell(ref cell) => cell.borrow().clone(),
+        }
+    }
+
+    fn set(&mut self, value: Value) {
+        match *self {
+            Slot::Cell(ref cell) => *cell.borrow_mut() = Some(value),
+            _ => *self = Slot::Value(value),
+        }
+    }
+
+    /// Moves the variable into a cell so it can be captured.
+    fn cell(&mut self) -> Cell {
+        if let Slot::Cell(ref cell) = *self {
+            return cell.clone();
+        }
+        let cell = Rc::new(RefCell::new(self.get()));
+        *self = Slot::Cell(cell.clone());
+        cell
+    }
+}
+
+/// The state of a function call.
+struct Frame {
+    closure: Rc<Closure>,
+    /// The next instruction.
+    ip: usize,
+    /// Where the values of the call start on the stack.
+    base: usize,
+    locals: Vec<Slot>,
+    /// The node of the call in the profile, if profiling.
+    node: usize,
+}
+
+/// A stack-based virtual machine running scripts compiled by the
+/// `compiler`. It gives the same results as the `Interpreter`.
+#[derive(Default)]
+pub struct Vm {
+    globals: HashMap<String, Value>,
+    devices: Option<Rc<RefCell<dyn DeviceRegistry>>>,
+    natives: Natives,
+    scheduler: Scheduler,
+    output: Output,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    budget: Budget,
+    heap: Heap,
+    debugger: Debugger,
+    profile: Option<Profile>,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm::default()
+    }
+
+    /// Gives the scripts access to the devices in `devices` through `@name`.
+    pub fn set_devices(&mut self, devices: Rc<RefCell<dyn DeviceRegistry>>) {
+        self.devices = Some(devices);
+    }
+
+    /// Makes the Rust closure `f` callable from scripts as `name`,
+    /// like `Interpreter::register_fn`.
+    pub fn register_fn<Args, F: IntoNative<Args>>(&mut self, name: &str, f: F) {
+        self.natives.insert(name.to_string(), Rc::new(Native::new(name, f)));
+    }
+
+    /// Collects the output of `print`, see `take_output`.
+    pub fn capture_output(&mut self) {
+        self.output = Output::Buffer(String::new());
+    }
+
+    /// Returns the output collected since the last call, if it is
+    /// being collected.
+    pub fn take_output(&mut self) -> String {
+        match self.output {
+            Output::Buffer(ref mut buf) => ::std::mem::take(buf),
+            Output::Stdout => String::new(),
+        }
+    }
+
+    /// Limits the number of instructions executed to `fuel`, or removes
+    /// the limit. Running out of fuel pauses the script with `OutOfFuel`,
+    /// and it can be continued with `resume` after adding fuel.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.budget.set_fuel(fuel);
+    }
+
+    /// Gives more fuel, see `set_fuel`.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.budget.add_fuel(fuel);
+    }
+
+    /// The fuel left, or `None` if there is no limit.
+    pub fn fuel(&self) -> Option<u64> {
+        self.budget.fuel()
+    }
+
+    /// Pauses scripts running past `deadline` with `DeadlineExceeded`,
+    /// or removes the deadline. Like running out of fuel, the script
+    /// can be continued with `resume`.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.budget.set_deadline(deadline);
+    }
+
+    /// Returns `true` if a script was paused by running out of fuel or
+    /// past its deadline, and can be continued with `resume`.
+    pub fn is_paused(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// Continues a paused script, see `is_paused`. Returns the result of
+    /// the script like `run`, or `nil` if there is no paused script.
+    pub fn resume(&mut self) -> error::Result<Value> {
+        if !self.is_paused() {
+            return Ok(Value::Nil);
+        }
+        self.execute_resumable()
+    }
+
+    /// Stops scripts before the statements on `line`, like running out of
+    /// fuel they stop with `Stopped` and can be continued with `resume`
+    /// or `step`. To stop where they are written, scripts must be
+    /// compiled without `optimizer::optimize`, which may move or drop
+    /// statements.
+    pub fn set_breakpoint(&mut self, line: u32) {
+        self.debugger.set_breakpoint(line);
+    }
+
+    pub fn remove_breakpoint(&mut self, line: u32) {
+        self.debugger.remove_breakpoint(line);
+    }
+
+    /// The lines with a breakpoint, in order.
+    pub fn breakpoints(&self) -> Vec<u32> {
+        self.debugger.breakpoints()
+    }
+
+    /// Stops the next script run before its first statement, see
+    /// `set_breakpoint`.
+    pub fn break_next(&mut self) {
+        self.debugger.break_next();
+    }
+
+    /// Continues a paused script until it is done with `step`, or stops
+    /// at a breakpoint first. Returns like `resume`.
+    pub fn step(&mut self, step: Step) -> error::Result<Value> {
+        if !self.is_paused() {
+            return Ok(Value::Nil);
+        }
+        self.debugger.step(step, self.frames.len());
+        self.execute_resumable()
+    }
+
+    /// The calls of a paused script, innermost first.
+    pub fn stack(&self) -> Vec<StackFrame> {
+        self.frames.iter().rev().enumerate().map(|(depth, frame)| {
+            // Frames below the top are in the middle of a call
+            let ip = if depth == 0 { frame.ip } else { frame.ip - 1 };
+            let proto = frame.closure.proto();
+            // The innermost variable of each name, which hides the others
+            let mut locals: BTreeMap<String, (u32, Value)> = BTreeMap::new();
+            for (local, slot) in proto.locals.iter().zip(&frame.locals) {
+                let (local, value) = match (local, slot.get()) {
+                    (Some(local), Some(value)) if local.start as usize <= ip && ip < local.end as usize => {
+                        (local, value)
+                    }
+                    _ => continue,
+                };
+                if locals.get(&local.name).is_none_or(|l| l.0 <= local.start) {
+                    locals.insert(local.name.clone(), (local.start, value));
+                }
+            }
+            let span = match proto.statements.binary_search_by_key(&(ip as u32), |s| s.0) {
+                Ok(i) => proto.statements[i].1,
+                Err(_) => proto.spans[ip],
+            };
+            StackFrame {
+                name: frame.closure.name(),
+                span,
+                locals: locals.into_iter().map(|(name, local)| (name, local.1)).collect(),
+            }
+        }).collect()
+    }
+
+    /// Starts counting the calls, instructions and time spent in each
+    /// function and on each line of the scripts run from now on, see
+    /// `profile`. The previous profile is dropped.
+    pub fn start_profiling(&mut self) {
+        self.profile = Some(Profile::new());
+        self.profile_frames();
+    }
+
+    /// The profile being collected, if profiling.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Stops profiling, and returns the profile collected.
+    pub fn stop_profiling(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    /// Gives the calls of a paused script their node in the profile.
+    fn profile_frames(&mut self) {
+        if let Some(ref mut profile) = self.profile {
+            let mut caller = profiler::ROOT;
+            for frame in &mut self.frames {
+                frame.node = profile.node(caller, &frame.closure.name(), frame.closure.proto().span);
+                caller = frame.node;
+            }
+        }
+    }
+
+    /// The global variables, by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let mut globals: Vec<(String, Value)> = self.globals.iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+        globals
+    }
+
+    /// Saves the globals, the handlers and, if a script is paused, its
+    /// stack of calls and where each call is, so it can be restored later with
+    /// `restore`, see `snapshot`.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut writer = Writer::new(Kind::Vm);
+        writer.vars(&self.globals);
+        self.scheduler.write(&mut writer);
+        writer.usize(self.stack.len());
+        for value in &self.stack {
+            writer.value(value);
+        }
+        writer.usize(self.frames.len());
+        for frame in &self.frames {
+            Closure::write(&frame.closure, &mut writer);
+            writer.usize(frame.ip);
+            writer.usize(frame.base);
+            writer.usize(frame.locals.len());
+            for slot in &frame.locals {
+                match *slot {
+                    Slot::Empty => writer.u8(0),
+                    Slot::Value(ref value) => {
+                        writer.u8(1);
+                        writer.value(value);
+                    }
+                    Slot::Cell(ref cell) => {
+                        writer.u8(2);
+                        write_cell(cell, &mut writer);
+                    }
+                }
+            }
+        }
+        writer.finish()
+    }
+
+    /// Replaces the globals, the handlers and the paused script, if any,
+    /// with those saved in `snapshot`. A paused script is continued with
+    /// `resume`.
+    /// The native functions the values refer to are looked up by name,
+    /// so they must be registered before.
+    pub fn restore(&mut self, snapshot: &[u8]) -> error::Result<()> {
+        let mut reader = Reader::new(snapshot, Kind::Vm, &self.natives)?;
+        let globals = reader.vars()?.into_iter().collect();
+        let scheduler = Scheduler::read(&mut reader)?;
+        let len = reader.usize()?;
+        let stack = (0..len).map(|_| reader.value()).collect::<error::Result<Vec<_>>>()?;
+        let len = reader.usize()?;
+        let mut frames = Vec::new();
+        for _ in 0..len {
+            frames.push(Vm::read_frame(&mut reader, stack.len())?);
+        }
+        reader.finish()?;
+        self.globals = globals;
+        self.scheduler = scheduler;
+        self.stack = stack;
+        self.frames = frames;
+        self.heap.reset();
+        self.profile_frames();
+        Ok(())
+    }
+
+    fn read_frame(reader: &mut Reader, stack_len: usize) -> error::Result<Frame> {
+        let closure = Closure::read(reader)?;
+        let ip = reader.usize()?;
+        let base = reader.usize()?;
+        let len = reader.usize()?;
+        let mut locals = Vec::new();
+        for _ in 0..len {
+            locals.push(match reader.u8()? {
+                0 => Slot::Empty,
+                1 => Slot::Value(reader.value()?),
+                2 => Slot::Cell(read_cell(reader)?),
+                _ => return Err(BadSnapshot("bad slot".to_string())),
+            });
+        }
+        let proto = closure.proto();
+        if ip >= proto.code.len() || base > stack_len || locals.len() != proto.captured.len() {
+            return Err(BadSnapshot("bad call frame".to_string()));
+        }
+        Ok(Frame {
+            closure,
+            ip,
+            base,
+            locals,
+            node: profiler::ROOT,
+        })
+    }
+
+    /// Sets the limits on memory and recursion.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.heap.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.heap.limits
+    }
+
+    /// Parses, optimizes, compiles and runs `source`, see
+    /// `optimizer::optimize` and `run`. A script reading a device the
+    /// host does not have fails with `UnknownDevice` before it runs.
+    pub fn eval(&mut self, source: &str) -> error::Result<Value> {
+        let program = Parser::new(source).parse_program()?;
+        self.resolver().check_devices(&program)?;
+        self.run(Rc::new(compiler::compile(&optimizer::optimize(&program).0)?))
+    }
+
+    /// Runs a compiled program. Globals are kept for the next run.
+    /// Returns the value of the last statement if it is an expression,
+    /// or `nil` otherwise. Unlike `eval`, it cannot tell an unknown
+    /// device before reading it, so hosts should check the program with
+    /// `resolver` first.
+    pub fn run(&mut self, module: Rc<Module>) -> error::Result<Value> {
+        let main = Rc::new(Closure {
+            module,
+            index: 0,
+            captures: Vec::new(),
+        });
+        self.stack.clear();
+        self.frames.clear();
+        self.heap.reset();
+        self.debugger.forget_stop();
+        self.push_frame(main, Vec::new());
+        self.execute_resumable()
+    }
+
+    /// Calls the global function `name` with `args`, like
+    /// `Interpreter::call`. If the function runs out of fuel, it can be
+    /// continued with `resume`, which returns its result.
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, CallError> {
+        if self.is_paused() {
+            return Err(CallError::Paused);
+        }
+        let callee = self.globals.get(name).cloned()
+            .or_else(|| self.natives.get(name).cloned().map(Value::Native))
+            .or_else(|| Builtin::lookup(name).map(Value::Builtin))
+            .ok_or_else(|| CallError::UndefinedFunction(name.to_string()))?;
+        if callee.type_name() != "fn" {
+            return Err(CallError::NotAFunction(name.to_string(), callee.type_name().to_string()));
+        }
+        self.call_from_host(callee, args)
+    }
+
+    /// Advances the virtual clock by `by`, running the `every` handlers
+    /// due, like `Interpreter::advance`. If a handler runs out of fuel,
+    /// it can be continued with `resume`, and the handlers due after it
+    /// run on the next call.
+    pub fn advance(&mut self, by: Duration) -> Result<(), CallError> {
+        if self.is_paused() {
+            return Err(CallError::Paused);
+        }
+        let until = self.scheduler.now() + by;
+        while let Some(handler) = self.scheduler.next_due(until) {
+            self.call_from_host(handler, Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// Runs the `on @device` handlers of `device`, like `Interpreter::signal`.
+    pub fn signal(&mut self, device: &str) -> Result<(), CallError> {
+        for handler in self.scheduler.handlers(device) {
+            if self.is_paused() {
+                return Err(CallError::Paused);
+            }
+            self.call_from_host(handler, Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// The time on the virtual clock, see `advance`.
+    pub fn now(&self) -> Duration {
+        self.scheduler.now()
+    }
+
+    /// Calls a function from outside of any script.
+    fn call_from_host(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, CallError> {
+        let span = match callee {
+            Value::Compiled(ref closure) => closure.proto().span,
+            _ => Span::default(),
+        };
+        let argc = args.len();
+        self.stack.clear();
+        self.heap.reset();
+        self.debugger.forget_stop();
+        self.stack.push(callee);
+        self.stack.extend(args);
+        if let Err(e) = self.call_value(argc, span) {
+            self.stack.clear();
+            return Err(e.into());
+        }
+        if self.frames.is_empty() {
+            return Ok(self.pop());
+        }
+        Ok(self.execute_resumable()?)
+    }
+
+    /// A resolver which knows the native functions, the global variables
+    /// and the devices of the host, to check a script before running it,
+    /// see `Resolver::check`.
+    pub fn resolver(&self) -> Resolver {
+        let mut resolver = Resolver::new();
+        for name in self.globals.keys() {
+            resolver.declare_host(name);
+        }
+        for native in self.natives.values() {
+            resolver.declare_native(native.name(), native.arity());
+        }
+        if let Some(ref devices) = self.devices {
+            for name in devices.borrow().names() {
+                resolver.declare_device(&name);
+            }
+        }
+        resolver
+    }
+
+    /// The value of the global variable `name`.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name).cloned()
+    }
+
+    /// Assigns `value` to the global variable `name`, declaring it if needed.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    /// Runs the frames on the stack. The state is kept if the budget ran
+    /// out or the debugger stopped the script so it can be resumed, and
+    /// dropped on any other error.
+    fn execute_resumable(&mut self) -> error::Result<Value> {
+        let result = self.execute();
+        if let Some(ref mut profile) = self.profile {
+            profile.pause();
+        }
+        match result {
+            Err(OutOfFuel(_)) | Err(DeadlineExceeded(_)) | Err(Stopped(_)) => {}
+            Err(_) => {
+                self.stack.clear();
+                self.frames.clear();
+                self.debugger.finish();
+            }
+            Ok(_) => self.debugger.finish(),
+        }
+        result
+    }
+
+    fn push_frame(&mut self, closure: Rc<Closure>, args: Vec<Value>) {
+        let mut locals: Vec<Slot> = closure.proto().captured.iter().map(|&c| Slot::new(c)).collect();
+        for (slot, arg) in locals.iter_mut().zip(args) {
+            slot.set(arg);
+        }
+        let node = match self.profile {
+            Some(ref mut profile) => {
+                let caller = self.frames.last().map_or(profiler::ROOT, |frame| frame.node);
+                profile.enter(caller, &closure.name(), closure.proto().span)
+            }
+            None => profiler::ROOT,
+        };
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            base: self.stack.len(),
+            locals,
+            node,
+        });
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("no frame to run")
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("the stack is empty")
+    }
+
+    /// Pops `n` values, in the order they were pushed.
+    fn pop_n(&mut self, n: usize) -> Vec<Value> {
+        let at = self.stack.len() - n;
+        self.stack.split_off(at)
+    }
+
+    /// Runs until the outermost frame returns.
+    fn execute(&mut self) -> error::Result<Value> {
+        loop {
+            if self.debugger.is_active() {
+                let depth = self.frames.len();
+                let frame = self.frames.last().expect("no frame to run");
+                let proto = frame.closure.proto();
+                if let Ok(i) = proto.statements.binary_search_by_key(&(frame.ip as u32), |s| s.0) {
+                    let span = proto.statements[i].1;
+                    if self.debugger.stops(depth, frame.ip, span.line) {
+                        return Err(Stopped(span));
+                    }
+                }
+            }
+            if let Err(exhausted) = self.budget.step() {
+                return Err(exhausted.error(self.running_loop()));
+            }
+            let (op, span) = {
+                let frame = self.frame();
+                let proto = frame.closure.proto();
+                let next = (proto.code[frame.ip], proto.spans[frame.ip]);
+                frame.ip += 1;
+                next
+            };
+            if let Some(ref mut profile) = self.profile {
+                profile.step(self.frames.last().expect("no frame to run").node, span.line);
+            }
+            match op {
+                Op::Const(index) => {
+                    let value = self.frame().closure.module.constants[index as usize].clone();
+                    self.push_new(value, span)?;
+                }
+                Op::Nil => self.stack.push(Value::Nil),
+                Op::True => self.stack.push(Value::Bool(true)),
+                Op::False => self.stack.push(Value::Bool(false)),
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Dup => {
+                    let top = self.stack.last().cloned().expect("the stack is empty");
+                    self.stack.push(top);
+                }
+                Op::Dup2 => {
+                    let at = self.stack.len() - 2;
+                    let top: Vec<Value> = self.stack[at..].to_vec();
+                    self.stack.extend(top);
+                }
+                Op::Swap => {
+                    let len = self.stack.len();
+                    self.stack.swap(len - 1, len - 2);
+                }
+                Op::Rotate => {
+                    let value = self.stack.remove(self.stack.len() - 3);
+                    self.stack.push(value);
+                }
+                Op::GetVar(index) => {
+                    let value = self.get_var(index, span)?;
+                    self.stack.push(value);
+                }
+                Op::SetVar(index) => {
+                    let value = self.pop();
+                    self.set_var(index, value, false);
+                }
+                Op::Declare(index) => {
+                    let value = self.pop();
+                    self.set_var(index, value, true);
+                }
+                Op::EnterBlock(index) => {
+                    let frame = self.frame();
+                    let proto = frame.closure.proto();
+                    for &slot in &proto.blocks[index as usize] {
+                        frame.locals[slot as usize] = Slot::new(proto.captured[slot as usize]);
+                    }
+                }
+                Op::Unary(op) => {
+                    let value = self.pop();
+                    self.stack.push(ops::unary(op, value, span)?);
+                }
+                Op::Binary(op) => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    let value = ops::binary(op, lhs, rhs, span)?;
+                    self.push_new(value, span)?;
+                }
+                Op::Jump(to) => self.frame().ip = to as usize,
+                Op::JumpIfFalse(to) => {
+                    if !self.pop().is_truthy() {
+                        self.frame().ip = to as usize;
+                    }
+                }
+                Op::JumpIfTrue(to) => {
+                    if self.pop().is_truthy() {
+                        self.frame().ip = to as usize;
+                    }
+                }
+                Op::List(len) => {
+                    let items = self.pop_n(len as usize);
+                    self.push_new(Value::list(items), span)?;
+                }
+                Op::Map(len) => {
+                    let mut map = BTreeMap::new();
+                    let mut entries = self.pop_n(2 * len as usize).into_iter();
+                    while let (Some(mut key), Some(value)) = (entries.next(), entries.next()) {
+                        if let Value::Str(ref mut key) = key {
+                            map.insert(::std::mem::take(key), value);
+                        }
+                    }
+                    self.push_new(Value::map(map), span)?;
+                }
+                Op::Index => {
+                    let index = self.pop();
+                    let list = self.pop();
+                    let value = ops::index(&list, &index, span)?;
+                    self.push_new(value, span)?;
+                }
+                Op::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let list = self.pop();
+                    ops::set_index(&list, &index, value, span)?;
+                }
+                Op::Field(name) => {
+                    let object = self.pop();
+                    let value = ops::field(&object, self.name(name), span)?;
+                    self.stack.push(value);
+                }
+                Op::SetField(name) => {
+                    let value = self.pop();
+                    let object = self.pop();
+                    ops::set_field(&object, self.name(name), value, span)?;
+                }
+                Op::Range { inclusive, step } => {
+                    let step = if step { Some(self.pop()) } else { None };
+                    let end = self.pop();
+                    let start = self.pop();
+                    self.stack.push(ops::range(start, end, step, inclusive, span)?);
+                }
+                Op::Device(name) => {
+                    let value = device::read_device(self.devices.as_ref(), self.name(name), span)?;
+                    self.stack.push(value);
+                }
+                Op::Call(argc) => self.call_value(argc as usize, span)?,
+                Op::Closure(index) => {
+                    let closure = self.closure(index as usize);
+                    self.stack.push(Value::Compiled(Rc::new(closure)));
+                }
+                Op::Every(period) => {
+                    let handler = self.pop();
+                    self.scheduler.every(period, handler);
+                }
+                Op::On(name) => {
+                    let handler = self.pop();
+                    let device = self.name(name).to_string();
+                    self.scheduler.on(&device, handler);
+                }
+                Op::Return => {
+                    let value = self.pop();
+                    let frame = self.frames.pop().expect("no frame to return from");
+                    self.stack.truncate(frame.base);
+                    if self.frames.is_empty() {
+                        return Ok(value);
+                    }
+                    self.stack.push(value);
+                }
+                Op::IterStart(slot) => {
+                    let value = self.pop();
+                    let (source, position) = match value {
+                        Value::Range(range) => (Value::Range(range), range.first().unwrap_or(Value::Nil)),
+                        _ => match value.iter() {
+                            Some(iter) => (Value::list(iter.collect()), Value::Int(0)),
+                            None => return Err(NotIterable(value.type_name().to_string(), span)),
+                        },
+                    };
+                    let frame = self.frame();
+                    frame.locals[slot as usize].set(source);
+                    frame.locals[slot as usize + 1].set(position);
+                }
+                Op::IterNext(slot, to) => {
+                    match self.iter_next(slot as usize) {
+                        Some(item) => self.stack.push(item),
+                        None => self.frame().ip = to as usize,
+                    }
+                }
+                Op::BreakOutsideLoop => return Err(BreakOutsideLoop(span)),
+                Op::ReturnOutsideFunction => return Err(ReturnOutsideFunction(span)),
+            }
+        }
+    }
+
+    /// The span of the innermost loop being run, or of the next
+    /// instruction if no loop is running.
+    fn running_loop(&self) -> Span {
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            // Frames below the top are in the middle of a call
+            let ip = if depth == 0 { frame.ip } else { frame.ip - 1 } as u32;
+            let innermost = frame.closure.proto().loops.iter()
+                .filter(|l| l.start <= ip && ip < l.end)
+                .max_by_key(|l| l.start);
+            if let Some(l) = innermost {
+                return l.span;
+            }
+        }
+        let frame = self.frames.last().expect("no frame to run");
+        frame.closure.proto().spans[frame.ip]
+    }
+
+    /// Pushes a value just created at `span`, counting its memory.
+    fn push_new(&mut self, value: Value, span: Span) -> error::Result<()> {
+        if self.heap.alloc(&value, span)? {
+            self.collect(Some(&value), span)?;
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Measures the memory of the values the script can reach,
+    /// together with `value` which may not be stored yet.
+    fn collect(&mut self, value: Option<&Value>, span: Span) -> error::Result<()> {
+        let mut measure = Measure::new();
+        for (name, global) in &self.globals {
+            measure.add(name.len());
+            measure.value(global);
+        }
+        self.scheduler.measure(&mut measure);
+        for item in self.stack.iter().chain(value) {
+            measure.value(item);
+        }
+        for frame in &self.frames {
+            frame.closure.measure(&mut measure);
+            for slot in &frame.locals {
+                if let Some(ref local) = slot.get() {
+                    measure.value(local);
+                }
+            }
+        }
+        self.heap.measured(measure.bytes(), span)
+    }
+
+    /// Looks up a name in the module of the running function.
+    fn name(&self, index: u32) -> &str {
+        let frame = self.frames.last().expect("no frame to run");
+        &frame.closure.module.names[index as usize]
+    }
+
+    /// Reads a variable from the first place it is declared in.
+    fn get_var(&mut self, index: u32, span: Span) -> error::Result<Value> {
+        let closure = self.frame().closure.clone();
+        let var = &closure.proto().vars[index as usize];
+        for place in &var.places {
+            let value = match *place {
+                Place::Local(slot) => self.frame().locals[slot as usize].get(),
+                Place::Capture(index) => closure.captures[index as usize].borrow().clone(),
+                Place::Global(name) => self.globals.get(&closure.module.names[name as usize]).cloned(),
+            };
+            if let Some(value) = value {
+                return Ok(value);
+            }
+        }
+        let name = &closure.module.names[var.name as usize];
+        self.natives.get(name).cloned().map(Value::Native)
+            .or_else(|| Builtin::lookup(name).map(Value::Builtin))
+            .ok_or_else(|| UndefinedVariable(name.clone(), span))
+    }
+
+    /// Assigns to a variable in the first place it is declared in, or
+    /// declares it in the innermost scope. With `declare` the variable
+    /// is always declared in the innermost scope.
+    fn set_var(&mut self, index: u32, value: Value, declare: bool) {
+        let closure = self.frame().closure.clone();
+        let var = &closure.proto().vars[index as usize];
+        let declared = |vm: &mut Vm, place: &Place| match *place {
+            Place::Local(slot) => vm.frame().locals[slot as usize].get().is_some(),
+            Place::Capture(index) => closure.captures[index as usize].borrow().is_some(),
+            Place::Global(name) => vm.globals.contains_key(&closure.module.names[name as usize]),
+        };
+        let place = match var.places.iter().find(|place| !declare && declared(self, place)) {
+            Some(place) => *place,
+            None => var.places[0],
+        };
+        match place {
+            Place::Local(slot) => self.frame().locals[slot as usize].set(value),
+            Place::Capture(index) => *closure.captures[index as usize].borrow_mut() = Some(value),
+            Place::Global(name) => {
+                self.globals.insert(closure.module.names[name as usize].clone(), value);
+            }
+        }
+    }
+
+    /// Creates a closure of the function at `index`, capturing
+    /// variables of the running frame.
+    fn closure(&mut self, index: usize) -> Closure {
+        let frame = self.frame();
+        let module = frame.closure.module.clone();
+        let captures = module.protos[index].captures.iter().map(|capture| match *capture {
+            Capture::Local(slot) => frame.locals[slot as usize].cell(),
+            Capture::Capture(index) => frame.closure.captures[index as usize].clone(),
+        }).collect();
+        Closure {
+            module,
+            index,
+            captures,
+        }
+    }
+
+    /// Calls the value under the `argc` arguments on the stack.
+    fn call_value(&mut self, argc: usize, span: Span) -> error::Result<()> {
+        let args = self.pop_n(argc);
+        match self.pop() {
+            Value::Compiled(ref closure) => {
+                let arity = closure.proto().arity;
+                if arity != argc {
+                    return Err(ArityMismatch(arity, argc, span));
+                }
+                self.heap.check_depth(self.frames.len(), span)?;
+                self.push_frame(closure.clone(), args);
+            }
+            Value::Builtin(builtin) => {
+                let value = builtin.call(args, self, span)?;
+                self.push_new(value, span)?;
+            }
+            Value::Native(ref native) => {
+                let value = native.call(args, span)?;
+                self.push_new(value, span)?;
+            }
+            callee => return Err(NotCallable(callee.type_name().to_string(), span)),
+        }
+        Ok(())
+    }
+
+    /// Advances the iteration kept in the two slots at `slot`.
+    fn iter_next(&mut self, slot: usize) -> Option<Value> {
+        let locals = &mut self.frame().locals;
+        match (locals[slot].get(), locals[slot + 1].get()) {
+            (Some(Value::Range(range)), Some(current)) => {
+                if current == Value::Nil {
+                    return None;
+                }
+                locals[slot + 1].set(range.step_from(&current).unwrap_or(Value::Nil));
+                Some(current)
+            }
+            (Some(Value::List(ref list)), Some(Value::Int(i))) => {
+                let item = list.borrow().get(i as usize).cloned();
+                if item.is_some() {
+                    locals[slot + 1].set(Value::Int(i + 1));
+                }
+                item
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Host for Vm {
+    fn print(&mut self, line: &str) {
+        self.output.write_line(line);
+    }
+
+    fn grow_list(&mut self, len: usize, span: Span) -> error::Result<()> {
+        if self.heap.grow_list(len, span)? {
+            self.collect(None, span)?;
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,41 @@
+// The tests keep the rustfmt_skip attributes they were written with
+#![allow(clippy::deprecated_cfg_attr)]
+
 extern crate interpreter;
 
 use interpreter::lexer;
 use interpreter::tokens::Token;
 use interpreter::real::Real;
-use interpreter::error::Error;
+use interpreter::error::{CallError, Error};
+use interpreter::span::Span;
+use interpreter::parser::Parser;
+use interpreter::ast::{BinaryOp, Expr, ExprKind, Function, Stmt, StmtKind, UnaryOp};
+use interpreter::value::Value;
+use interpreter::eval::Interpreter;
+use interpreter::device::{DeviceMap, DeviceRegistry};
+use interpreter::imu::ImuReplay;
+use interpreter::compiler;
+use interpreter::vm::Vm;
+use interpreter::snapshot::{Kind, Writer};
+use interpreter::limits::{Limits, Measure};
+use interpreter::resolver::{DeclarationKind, Resolution, Resolver};
+use interpreter::types::Type;
+use interpreter::optimizer;
+use interpreter::formatter;
+use interpreter::json::Json;
+use interpreter::lsp::{self, Server};
+use interpreter::dap::Adapter;
+use interpreter::debugger::Step;
+use interpreter::profiler::Metric;
+use interpreter::coverage::{NOT_TAKEN, TAKEN};
+use interpreter::error;
+
+use std::cell::RefCell;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 #[test]
 #[cfg_attr(rustfmt, rustfmt_skip)]
@@ -73,6 +105,76 @@ fn test_string_illegal_newline() {
     assert_eq!(Err(Error::InfiniteString), lexer.next_token());
 }
 
+#[test]
+#[cfg_attr(rustfmt, rustfmt_skip)]
+fn test_comment_at_end_of_file() {
+    let mut lexer = lexer::Lexer::new("1 # no newline after this");
+    assert_eq!(Ok(Token::Int(1)), lexer.next_token());
+    assert_eq!(Ok(Token::EndOfFile), lexer.next_token());
+}
+
+#[test]
+#[cfg_attr(rustfmt, rustfmt_skip)]
+fn test_raw_string() {
+    let mut lexer = lexer::Lexer::new("r\"C:\\new\\x\" r#\"say \"hi\"\"# r##\"a\"#b\"## r \"x\"");
+    let tokens = vec![Ok(Token::RawStr("C:\\new\\x".to_string())),
+                      Ok(Token::RawStr("say \"hi\"".to_string())),
+                      Ok(Token::RawStr("a\"#b".to_string())),
+                      Ok(Token::Identity("r".to_string())),
+                      Ok(Token::Str("x".to_string())),
+                      Ok(Token::EndOfFile)];
+
+    for t in &tokens {
+        let token = lexer.next_token();
+        assert_eq!(token, *t);
+    }
+}
+
+#[test]
+#[cfg_attr(rustfmt, rustfmt_skip)]
+fn test_raw_string_spans_lines() {
+    let mut lexer = lexer::Lexer::new("r#\"one\ntwo\"#\nr\"never ends");
+    assert_eq!(lexer.next(), Some((Ok(Token::RawStr("one\ntwo".to_string())), 1, 1)));
+    assert_eq!(lexer.next(), Some((Err(Error::InfiniteString), 3, 1)));
+    assert_eq!(lexer.next(), None);
+}
+
+#[test]
+#[cfg_attr(rustfmt, rustfmt_skip)]
+fn test_multiline_string() {
+    let mut lexer = lexer::Lexer::new("x = \"\"\"
+        first
+          second\\t
+
+        third
+        \"\"\" y \"\"\"single\"\"\" \"\"");
+    let tokens = vec![Ok(Token::Identity("x".to_string())),
+                      Ok(Token::Assignment),
+                      Ok(Token::Str("first\n  second\\t\n\nthird".to_string())),
+                      Ok(Token::Identity("y".to_string())),
+                      Ok(Token::Str("single".to_string())),
+                      Ok(Token::Str("".to_string())),
+                      Ok(Token::EndOfFile)];
+
+    for t in &tokens {
+        let token = lexer.next_token();
+        assert_eq!(token, *t);
+    }
+}
+
+#[test]
+#[cfg_attr(rustfmt, rustfmt_skip)]
+fn test_multiline_string_spans() {
+    let mut lexer = lexer::Lexer::new("\"\"\"
+    ok
+    \"\"\"
+  \"\"\"
+    bad \\q
+    \"\"\"");
+    assert_eq!(lexer.next(), Some((Ok(Token::Str("ok".to_string())), 1, 1)));
+    assert_eq!(lexer.next(), Some((Err(Error::UnknownEscape('q')), 5, 9)));
+}
+
 #[test]
 #[cfg_attr(rustfmt, rustfmt_skip)]
 fn test_error_tokens() {
@@ -90,7 +192,7 @@ fn test_error_tokens() {
 }
 
 #[test]
-#[cfg_attr(rustfmt, rustfmp)]
+#[cfg_attr(rustfmt, rustfmt_skip)]
 fn test_error_messages() {
     let mut lexer = lexer::Lexer::new("\"\n\
     💡 \
@@ -120,6 +222,7 @@ fn test_real_int() {
 
 #[test]
 #[cfg_attr(rustfmt, rustfmt_skip)]
+#[allow(clippy::approx_constant)]
 fn test_real_fraction() {
     let a = Real::parse("3.14").unwrap();
     let b = Real::from(3.14);
@@ -169,3 +272,1709 @@ fn test_real_div() {
     let b = Real::from(5.0);
     assert!(a / b == Real::from(5));
 }
+
+#[test]
+fn test_eval_expressions() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(interpreter.eval("1 + 2 * 3"), Ok(Value::Int(7)));
+    assert_eq!(interpreter.eval("(1 + 2) * -3"), Ok(Value::Int(-9)));
+    assert_eq!(interpreter.eval("1 + 2.5"), Ok(Value::Real(Real::from(3.5))));
+    assert_eq!(interpreter.eval("\"a\\tb\" + r\"\\n\""), Ok(Value::from("a\tb\\n")));
+    assert_eq!(interpreter.eval("1 < 2 & !(2.0 != 2)"), Ok(Value::Bool(true)));
+    assert_eq!(interpreter.eval("7 / 0"), Err(Error::DivisionByZero(Span::new(1, 3))));
+    assert_eq!(interpreter.eval("1 + nil"),
+               Err(Error::TypeMismatch("cannot apply `+` to int and nil".to_string(),
+                                       Span::new(1, 3))));
+}
+
+#[test]
+fn test_eval_statements() {
+    let mut interpreter = Interpreter::new();
+    let source = "
+        i = 0
+        sum = 0
+        while true {
+            i = i + 1
+            if i > 10 { break } else if i == 5 { skipped = true } else { sum = sum + i }
+        }
+        sum";
+    assert_eq!(interpreter.eval(source), Ok(Value::Int(50)));
+    assert_eq!(interpreter.eval("skipped"),
+               Err(Error::UndefinedVariable("skipped".to_string(), Span::new(1, 1))));
+    assert_eq!(interpreter.eval("break"), Err(Error::BreakOutsideLoop(Span::new(1, 1))));
+}
+
+#[test]
+fn test_parse_errors() {
+    assert_eq!(Parser::new("1 + 2 = 3").parse_program(),
+               Err(Error::InvalidAssignment(Span::new(1, 3))));
+    assert_eq!(Parser::new("while x { y").parse_program(),
+               Err(Error::ExpectedToken(Token::RightCurlyParam, Token::EndOfFile, Span::new(1, 11))));
+    assert_eq!(Parser::new("x = $").parse_program(),
+               Err(Error::Lexical(Box::new(Error::Illegal('$')), Span::new(1, 5))));
+}
+
+#[test]
+fn test_nesting_limit() {
+    let parens = format!("x = {}1{}", "(".repeat(20000), ")".repeat(20000));
+    assert_eq!(Parser::new(&parens).parse_program(), Err(Error::NestedTooDeep(Span::new(1, 105))));
+    let negations = format!("x = {}1", "-".repeat(20000));
+    assert!(matches!(Parser::new(&negations).parse_program(), Err(Error::NestedTooDeep(_))));
+    let sum = format!("x = 1{}", " + 1".repeat(20000));
+    assert!(matches!(Parser::new(&sum).parse_program(), Err(Error::NestedTooDeep(_))));
+    let calls = format!("fn f() {{ return f }}\nf{}", "()".repeat(20000));
+    assert!(matches!(Parser::new(&calls).parse_program(), Err(Error::NestedTooDeep(_))));
+    let blocks = format!("{}x = 1{}", "if true { ".repeat(20000), " }".repeat(20000));
+    assert!(matches!(Parser::new(&blocks).parse_program(), Err(Error::NestedTooDeep(_))));
+
+    // What parses can be walked by every pass
+    let source = format!("x = 0\n{}x = {}1{}{}{}\nx",
+                         "if true {\n".repeat(20), "(".repeat(40), ")".repeat(40),
+                         " + 1".repeat(20), "\n}".repeat(20));
+    assert_eq!(Interpreter::new().eval(&source), Ok(Value::Int(21)));
+    assert_eq!(Vm::new().eval(&source), Ok(Value::Int(21)));
+    assert!(Resolver::new().check(&source).iter().all(|d| !d.is_error()));
+    assert!(formatter::format(&source).is_ok());
+}
+
+#[test]
+fn test_device_references() {
+    let mut devices = DeviceMap::new();
+    devices.insert("gyro", Real::from(1.5));
+    devices.insert("accel.x", 2);
+    let devices = Rc::new(RefCell::new(devices));
+
+    let mut interpreter = Interpreter::new();
+    interpreter.set_devices(devices.clone());
+    assert_eq!(interpreter.eval("@gyro * 2"), Ok(Value::Real(Real::from(3))));
+    assert_eq!(interpreter.eval("@accel.x"), Ok(Value::Int(2)));
+
+    devices.borrow_mut().insert("accel.x", 5);
+    assert_eq!(interpreter.eval("@accel.x"), Ok(Value::Int(5)));
+    // Unknown devices are found before the script does anything
+    assert_eq!(interpreter.eval("x = 1\nx = @accel.y"),
+               Err(Error::UnknownDevice("accel.y".to_string(), Span::new(2, 5))));
+    assert_eq!(interpreter.get_global("x"), None);
+    assert_eq!(Interpreter::new().eval("@gyro"),
+               Err(Error::UnknownDevice("gyro".to_string(), Span::new(1, 1))));
+
+    let mut vm = Vm::new();
+    vm.set_devices(devices.clone());
+    assert_eq!(vm.eval("x = 1\nx = @accel.y"),
+               Err(Error::UnknownDevice("accel.y".to_string(), Span::new(2, 5))));
+    assert_eq!(vm.get_global("x"), None);
+
+    let errors = |resolver: Resolver, source: &str| {
+        resolver.check(source).iter().filter(|d| d.is_error()).map(|d| d.to_string()).collect::<Vec<_>>()
+    };
+    assert_eq!(errors(Resolver::new(), "x = @nope"),
+               vec!["1:5: error: unknown device @nope"]);
+    assert!(errors(interpreter.resolver(), "x = @gyro + @accel.x").is_empty());
+    assert_eq!(errors(vm.resolver(), "x = @acel.x"),
+               vec!["1:5: error: unknown device @acel.x\n  help: did you mean `@accel.x`?"]);
+    let mut resolver = Resolver::new();
+    resolver.allow_any_device();
+    assert!(errors(resolver, "x = @nope").is_empty());
+}
+
+#[test]
+fn test_imu_replay_csv() {
+    let mut imu = ImuReplay::load("tests/imu_trace.csv", 10).unwrap();
+    assert_eq!(imu.read("accel.z"), Some(Value::Real(Real::from(1))));
+    assert_eq!(imu.read("gyro.x"), Some(Value::Real(Real::from(0.5))));
+
+    imu.advance(Duration::from_millis(150));
+    assert_eq!(imu.read("accel.y"), Some(Value::Real(Real::from(0.25))));
+    imu.advance(Duration::from_millis(100));
+    assert_eq!(imu.read("gyro.x"), Some(Value::Real(Real::from(-0.5))));
+    assert!(!imu.finished());
+
+    imu.advance(Duration::from_secs(1));
+    assert!(imu.finished());
+    assert_eq!(imu.read("accel.y"), Some(Value::Real(Real::from(0.5))));
+    assert!(imu.contains("compass.y"));
+    assert!(imu.contains("accel"));
+    assert!(!imu.contains("accel.xy"));
+    assert_eq!(imu.read("accel.w"), None);
+
+    imu.set_looping(true);
+    assert!(!imu.finished());
+    imu.advance(Duration::from_millis(50));
+    assert_eq!(imu.read("accel.y"), Some(Value::Real(Real::from(0.25))));
+}
+
+#[test]
+fn test_imu_replay_json_lines() {
+    let trace = "{\"ax\": 0.5, \"mz\": -2}\n\n{\"ay\": 1.25}\n";
+    let mut imu = ImuReplay::from_json_lines(trace, 100).unwrap();
+    assert_eq!(imu.read("compass.z"), Some(Value::Real(Real::from(-2))));
+    imu.advance(Duration::from_millis(10));
+    assert_eq!(imu.read("accel.y"), Some(Value::Real(Real::from(1.25))));
+    assert_eq!(imu.read("accel.x"), Some(Value::Real(Real::from(0))));
+
+    assert_eq!(ImuReplay::from_json_lines("{\"ax\": \"up\"}", 100).unwrap_err(),
+               Error::BadTrace(1, "`ax` is not a number".to_string()));
+    assert_eq!(ImuReplay::from_csv("ax,ay\n1,2\n3,x", 100).unwrap_err(),
+               Error::BadTrace(3, "bad number `x`".to_string()));
+    assert_eq!(ImuReplay::from_csv("40000", 100).unwrap_err(),
+               Error::BadTrace(1, "40000 does not fit in a real".to_string()));
+    assert_eq!(ImuReplay::from_csv("32768", 100).unwrap_err(),
+               Error::BadTrace(1, "32768 does not fit in a real".to_string()));
+    assert_eq!(ImuReplay::from_csv("-32768", 100).unwrap().read("accel.x"),
+               Some(Value::Real(Real::from(-32768))));
+    // A first line naming no column is a sample
+    assert_eq!(ImuReplay::from_csv("1,2,x\n3,4,5", 100).unwrap_err(),
+               Error::BadTrace(1, "bad number `x`".to_string()));
+    assert_eq!(ImuReplay::from_csv("t,ay\n0,2", 100).unwrap().read("accel.y"),
+               Some(Value::Real(Real::from(2))));
+    assert_eq!(ImuReplay::from_csv("1,2,3", 0).unwrap_err(), Error::BadSampleRate(0));
+    assert_eq!(ImuReplay::from_json_lines("{}", 0).unwrap_err(), Error::BadSampleRate(0));
+    assert_eq!(ImuReplay::load("tests/imu_trace.csv", 0).unwrap_err(), Error::BadSampleRate(0));
+}
+
+#[test]
+fn test_imu_replay_script() {
+    let imu = Rc::new(RefCell::new(ImuReplay::load("tests/imu_trace.csv", 10).unwrap()));
+    let mut interpreter = Interpreter::new();
+    interpreter.set_devices(imu.clone());
+
+    let mut tilts = Vec::new();
+    while !imu.borrow().finished() {
+        tilts.push(interpreter.eval("@accel.y * 4 + @accel.z").unwrap());
+        imu.borrow_mut().advance(Duration::from_millis(100));
+    }
+    assert_eq!(tilts, vec![Value::Real(Real::from(1)),
+                           Value::Real(Real::from(2)),
+                           Value::Real(Real::from(3))]);
+}
+
+#[test]
+fn test_ternary() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(interpreter.eval("1 < 2 ? \"yes\" : \"no\""), Ok(Value::from("yes")));
+    assert_eq!(interpreter.eval("x = 3\nx > 5 ? 1 : x > 2 ? 2 : 3"), Ok(Value::Int(2)));
+    assert_eq!(interpreter.eval("false | nil ? 1 : 2"), Ok(Value::Int(2)));
+    assert_eq!(interpreter.eval("(true ? false : true) ? 1 : 2"), Ok(Value::Int(2)));
+    // The untaken branch is never evaluated
+    assert_eq!(interpreter.eval("true ? 1 : 1 / 0"), Ok(Value::Int(1)));
+    assert_eq!(interpreter.eval("false ? missing : 2"), Ok(Value::Int(2)));
+
+    assert_eq!(Parser::new("a ? b c").parse_expression(),
+               Err(Error::ExpectedToken(Token::Colon, Token::Identity("c".to_string()), Span::new(1, 7))));
+}
+
+#[test]
+fn test_ternary_associativity() {
+    let ast = Parser::new("a ? b : c ? d : e").parse_expression().unwrap();
+    match ast.kind {
+        ExprKind::Ternary(_, _, ref otherwise) => {
+            assert!(matches!(otherwise.kind, ExprKind::Ternary(..)));
+        }
+        _ => panic!("expected a ternary, found {:?}", ast),
+    }
+}
+
+#[test]
+fn test_range_tokens() {
+    let mut lexer = lexer::Lexer::new("for i in 0..10 by 2");
+    let tokens = vec![Ok(Token::For),
+                      Ok(Token::Identity("i".to_string())),
+                      Ok(Token::In),
+                      Ok(Token::Int(0)),
+                      Ok(Token::ExclusiveRange),
+                      Ok(Token::Int(10)),
+                      Ok(Token::By),
+                      Ok(Token::Int(2)),
+                      Ok(Token::EndOfFile)];
+    for t in &tokens {
+        assert_eq!(lexer.next_token(), *t);
+    }
+}
+
+#[test]
+fn test_for_ranges() {
+    let mut interpreter = Interpreter::new();
+    let sum = |interpreter: &mut Interpreter, range: &str| {
+        interpreter.eval(&format!("s = 0 for i in {} {{ s = s + i }} s", range))
+    };
+    assert_eq!(sum(&mut interpreter, "0..10 by 2"), Ok(Value::Int(20)));
+    assert_eq!(sum(&mut interpreter, "0...10 by 2"), Ok(Value::Int(30)));
+    assert_eq!(sum(&mut interpreter, "1..1"), Ok(Value::Int(0)));
+    assert_eq!(sum(&mut interpreter, "10..0 by -3"), Ok(Value::Int(22)));
+    assert_eq!(sum(&mut interpreter, "0..1 by 0.25"), Ok(Value::Real(Real::from(1.5))));
+    assert_eq!(sum(&mut interpreter, "1.5...3"), Ok(Value::Real(Real::from(4))));
+    assert_eq!(sum(&mut interpreter, "2147483640...2147483647 by 4"), Err(Error::Overflow(Span::new(1, 53))));
+    assert_eq!(interpreter.eval("for i in 0..3 { if i == 1 { break } last = i }"), Ok(Value::Nil));
+    assert_eq!(interpreter.eval("i"), Err(Error::UndefinedVariable("i".to_string(), Span::new(1, 1))));
+}
+
+#[test]
+fn test_range_values() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(interpreter.eval("n = 4\nr = 1 + 1..n * 2 by n - 1\nr").map(|v| v.to_string()),
+               Ok("2..8 by 3".to_string()));
+    assert_eq!(interpreter.eval("c = 0 for x in r { c = c + x } c"), Ok(Value::Int(7)));
+    assert_eq!(interpreter.eval("0...1 == 0...1 by 1"), Ok(Value::Bool(true)));
+    assert_eq!(interpreter.eval("0..5 by 1 - 1"), Err(Error::ZeroStep(Span::new(1, 2))));
+    assert_eq!(interpreter.eval("for x in 3 { }"),
+               Err(Error::NotIterable("int".to_string(), Span::new(1, 10))));
+    assert_eq!(interpreter.eval("0..\"a\""),
+               Err(Error::TypeMismatch("cannot make a range of str".to_string(), Span::new(1, 2))));
+}
+
+#[test]
+fn test_lists() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(interpreter.eval("xs = [1, 2.5, \"x\", [],]\nxs").map(|v| v.to_string()),
+               Ok("[1, 2.5, \"x\", []]".to_string()));
+    assert_eq!(interpreter.eval("xs[0] + xs[-3]"), Ok(Value::Real(Real::from(3.5))));
+    assert_eq!(interpreter.eval("xs[-1] = [7, 8]\nxs[3][1] = 9\nxs[3]").map(|v| v.to_string()),
+               Ok("[7, 9]".to_string()));
+    // Lists are shared by reference
+    assert_eq!(interpreter.eval("ys = xs[3]\nys[0] = 0\nxs[3][0]"), Ok(Value::Int(0)));
+    assert_eq!(interpreter.eval("s = 0 for y in xs[3] { s = s + y } s"), Ok(Value::Int(9)));
+    assert_eq!(interpreter.eval("[1, 2] == [1, 2.0]"), Ok(Value::Bool(true)));
+    assert_eq!(interpreter.eval("\"hello\"[1] + \"hello\"[-2]"), Ok(Value::from("el")));
+}
+
+#[test]
+fn test_list_slices() {
+    let mut interpreter = Interpreter::new();
+    interpreter.eval("xs = [0, 1, 2, 3, 4, 5]").unwrap();
+    let slice = |interpreter: &mut Interpreter, source: &str| {
+        interpreter.eval(source).map(|v| v.to_string())
+    };
+    assert_eq!(slice(&mut interpreter, "xs[1..3]"), Ok("[1, 2]".to_string()));
+    assert_eq!(slice(&mut interpreter, "xs[1...5 by 2]"), Ok("[1, 3, 5]".to_string()));
+    assert_eq!(slice(&mut interpreter, "xs[5...0 by -1]"), Ok("[5, 4, 3, 2, 1, 0]".to_string()));
+    assert_eq!(slice(&mut interpreter, "xs[-2...-1]"), Ok("[4, 5]".to_string()));
+    assert_eq!(slice(&mut interpreter, "xs[2..2]"), Ok("[]".to_string()));
+    assert_eq!(slice(&mut interpreter, "\"sensor\"[0..3]"), Ok("sen".to_string()));
+    // A slice is a copy
+    assert_eq!(slice(&mut interpreter, "ys = xs[0..2]\nys[0] = 9\nxs[0]"), Ok("0".to_string()));
+}
+
+#[test]
+fn test_list_errors() {
+    let mut interpreter = Interpreter::new();
+    interpreter.eval("xs = [1, 2, 3]").unwrap();
+    assert_eq!(interpreter.eval("xs[3]"), Err(Error::IndexOutOfBounds(3, Span::new(1, 3))));
+    assert_eq!(interpreter.eval("\n  xs[-4] = 1"), Err(Error::IndexOutOfBounds(-4, Span::new(2, 5))));
+    assert_eq!(interpreter.eval("xs[1...3]"), Err(Error::IndexOutOfBounds(3, Span::new(1, 3))));
+    assert_eq!(interpreter.eval("xs[0.5..1]"),
+               Err(Error::TypeMismatch("cannot index with a range of reals".to_string(),
+                                       Span::new(1, 3))));
+    assert_eq!(interpreter.eval("xs[\"a\"]"),
+               Err(Error::TypeMismatch("cannot index list with str".to_string(), Span::new(1, 3))));
+    assert_eq!(interpreter.eval("xs[0..1] = [1]"),
+               Err(Error::TypeMismatch("cannot assign to list indexed with range".to_string(),
+                                       Span::new(1, 3))));
+    // A bracket on a new line starts a new statement
+    assert_eq!(interpreter.eval("x = xs\n[4, 5]").map(|v| v.to_string()), Ok("[4, 5]".to_string()));
+}
+
+#[test]
+fn test_maps() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(interpreter.eval("s = {z: 0.5, x: 1.0, \"y\": 2,}\ns").map(|v| v.to_string()),
+               Ok("{x: 1, y: 2, z: 0.5}".to_string()));
+    assert_eq!(interpreter.eval("s.x + s[\"y\"]"), Ok(Value::Real(Real::from(3))));
+    assert_eq!(interpreter.eval("s.x = \"left\"\ns.label = [s.x]\ns.x += \"!\"\ns.z *= 4\ns")
+                   .map(|v| v.to_string()),
+               Ok("{label: [\"left\"], x: \"left!\", y: 2, z: 2}".to_string()));
+    assert_eq!(interpreter.eval("keys = \"\" for k in s { keys = keys + k } keys"),
+               Ok(Value::from("labelxyz")));
+    assert_eq!(interpreter.eval("{a: {b: 1}}.a.b"), Ok(Value::Int(1)));
+    assert_eq!(interpreter.eval("{a: 1, b: 2.0} == {b: 2, a: 1.0}"), Ok(Value::Bool(true)));
+    assert_eq!(interpreter.eval("{} == {a: 1}"), Ok(Value::Bool(false)));
+}
+
+#[test]
+fn test_map_errors() {
+    let mut interpreter = Interpreter::new();
+    interpreter.eval("s = {x: 1}").unwrap();
+    assert_eq!(interpreter.eval("s.y"), Err(Error::MissingField("y".to_string(), Span::new(1, 3))));
+    assert_eq!(interpreter.eval("s.y += 1"), Err(Error::MissingField("y".to_string(), Span::new(1, 3))));
+    assert_eq!(interpreter.eval("s[\"y\"]"), Err(Error::MissingField("y".to_string(), Span::new(1, 2))));
+    assert_eq!(interpreter.eval("n = 1\nn.x"),
+               Err(Error::TypeMismatch("int has no fields".to_string(), Span::new(2, 3))));
+    assert_eq!(Parser::new("{1: 2}").parse_program(),
+               Err(Error::ExpectedToken(Token::Identity(String::new()), Token::Int(1), Span::new(1, 2))));
+}
+
+#[test]
+fn test_cyclic_values() {
+    let source = "xs = [1]\nxs[0] = xs\nm = {a: xs}\nm.b = m\nys = [xs, xs]\nprint(xs, m, ys)\n\
+                  zs = [1]\nzs[0] = zs\nprint(xs == xs, xs == zs, m == {a: zs, b: m}, xs == [[1]])";
+    let expected = "[[...]] {a: [[...]], b: {...}} [[[...]], [[...]]]\ntrue true true false\n";
+    let mut interpreter = Interpreter::new();
+    interpreter.capture_output();
+    assert_eq!(interpreter.eval(source), Ok(Value::Nil));
+    assert_eq!(interpreter.take_output(), expected);
+    let mut vm = Vm::new();
+    vm.capture_output();
+    assert_eq!(vm.eval(source), Ok(Value::Nil));
+    assert_eq!(vm.take_output(), expected);
+}
+
+#[test]
+fn test_deeply_nested_values() {
+    let source = "xs = []\nys = []\nm = {}\nfor i in 0..100000 { xs = [xs] ys = [ys] m = {a: m} }\n\
+                  print(xs == ys, xs == [ys], len(str(xs)), len(str(m)))";
+    let expected = "true false 200002 500002\n";
+    let mut interpreter = Interpreter::new();
+    interpreter.capture_output();
+    assert_eq!(interpreter.eval(source), Ok(Value::Nil));
+    assert_eq!(interpreter.take_output(), expected);
+    let mut measure = Measure::new();
+    measure.value(&interpreter.get_global("xs").unwrap());
+    assert_eq!(measure.bytes(), 100000 * mem::size_of::<Value>());
+    let mut vm = Vm::new();
+    vm.capture_output();
+    assert_eq!(vm.eval(source), Ok(Value::Nil));
+    assert_eq!(vm.take_output(), expected);
+    // Dropping the values does not overflow the stack either
+    drop(interpreter);
+    drop(vm);
+}
+
+#[test]
+fn test_imu_sample_record() {
+    let imu = Rc::new(RefCell::new(ImuReplay::load("tests/imu_trace.csv", 10).unwrap()));
+    let mut interpreter = Interpreter::new();
+    interpreter.set_devices(imu.clone());
+    imu.borrow_mut().advance(Duration::from_millis(100));
+    assert_eq!(interpreter.eval("a = @accel\na").map(|v| v.to_string()),
+               Ok("{x: 0, y: 0.25, z: 1}".to_string()));
+    assert_eq!(interpreter.eval("(@gyro).x + a.y"), Ok(Value::Real(Real::from(1.75))));
+}
+
+#[test]
+fn test_functions() {
+    let mut interpreter = Interpreter::new();
+    interpreter.eval("fn add(a, b,) {\n    return a + b\n}").unwrap();
+    assert_eq!(interpreter.eval("add(1, 2)"), Ok(Value::Int(3)));
+    assert_eq!(interpreter.eval("fn fib(n) { return n < 2 ? n : fib(n - 1) + fib(n - 2) } fib(15)"),
+               Ok(Value::Int(610)));
+    assert_eq!(interpreter.eval("fn apply(f, x) { return f(x) } apply(fn (x) { return x * 2 }, 21)"),
+               Ok(Value::Int(42)));
+    assert_eq!(interpreter.eval("fn nothing() { return }\nnothing()"), Ok(Value::Nil));
+    assert_eq!(interpreter.eval("fn first(xs) { for x in xs { return x } } first([7, 8])"),
+               Ok(Value::Int(7)));
+    assert_eq!(interpreter.eval("add").map(|v| v.to_string()), Ok("<fn add>".to_string()));
+    // A parenthesis on a new line starts a new statement
+    assert_eq!(interpreter.eval("x = add\n(1)"), Ok(Value::Int(1)));
+}
+
+#[test]
+fn test_closures() {
+    let mut interpreter = Interpreter::new();
+    interpreter.eval("
+        fn counter() {
+            n = 0
+            return fn () { n = n + 1 return n }
+        }
+        c = counter()
+        d = counter()
+        c() c() d()
+    ").unwrap();
+    assert_eq!(interpreter.eval("[c(), d()]").map(|v| v.to_string()), Ok("[3, 2]".to_string()));
+    // Captured by reference, changes after creating the closure are seen
+    assert_eq!(interpreter.eval("k = 1 f = fn (x) { return x * k } k = 10 f(2)"),
+               Ok(Value::Int(20)));
+    // Every iteration of a loop has its own loop variable
+    assert_eq!(interpreter.eval("fs = [] for i in 0..3 { push(fs, fn () { return i }) } [fs[0](), fs[2]()]")
+                   .map(|v| v.to_string()),
+               Ok("[0, 2]".to_string()));
+}
+
+#[test]
+fn test_declared_functions_are_freed() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_global("held", Value::list(vec![]));
+    let counts = |interpreter: &Interpreter| match interpreter.get_global("held") {
+        Some(Value::List(ref list)) => (Rc::strong_count(list), Rc::weak_count(list)),
+        _ => unreachable!(),
+    };
+    // The scope of the block holds the list until it is freed
+    interpreter.eval("if true {\n    xs = held\n    fn f() { return xs }\n    f()\n}").unwrap();
+    assert_eq!(counts(&interpreter), (2, 0));
+    // A declared function keeps its scope alive once it is read
+    interpreter.eval("fn make() {\n    xs = held\n    fn g(n) { return n < 1 ? xs : g(n - 1) }\n    return g\n}\ng = make()")
+        .unwrap();
+    assert_eq!(counts(&interpreter), (3, 0));
+    assert_eq!(interpreter.eval("len(g(3))"), Ok(Value::Int(0)));
+    assert_eq!(interpreter.eval("g = nil"), Ok(Value::Nil));
+    assert_eq!(counts(&interpreter), (2, 0));
+    assert_eq!(interpreter.eval("fn h() {}\nh == h"), Ok(Value::Bool(true)));
+}
+
+#[test]
+fn test_builtins() {
+    let mut interpreter = Interpreter::new();
+    interpreter.capture_output();
+    interpreter.eval("print(\"sum\", 1 + 2, [\"a\"])\nprint()\nxs = [1]\npush(xs, len(\"héllo\"))\nprint(xs)")
+        .unwrap();
+    assert_eq!(interpreter.take_output(), "sum 3 [\"a\"]\n\n[1, 5]\n");
+    assert_eq!(interpreter.eval("str(1.5) + \"!\""), Ok(Value::from("1.5!")));
+    assert_eq!(interpreter.eval("len = 2 len"), Ok(Value::Int(2)));
+}
+
+#[test]
+fn test_function_errors() {
+    let mut interpreter = Interpreter::new();
+    interpreter.eval("fn one(a) { return a }").unwrap();
+    assert_eq!(interpreter.eval("one(1, 2)"), Err(Error::ArityMismatch(1, 2, Span::new(1, 4))));
+    assert_eq!(interpreter.eval("str()"), Err(Error::ArityMismatch(1, 0, Span::new(1, 4))));
+    assert_eq!(interpreter.eval("x = 1 x()"), Err(Error::NotCallable("int".to_string(), Span::new(1, 8))));
+    assert_eq!(interpreter.eval("return 1"), Err(Error::ReturnOutsideFunction(Span::new(1, 1))));
+    assert_eq!(interpreter.eval("fn f() { break } for i in 0..2 { f() }"),
+               Err(Error::BreakOutsideLoop(Span::new(1, 10))));
+    assert_eq!(interpreter.eval("len(1)"),
+               Err(Error::TypeMismatch("int has no length".to_string(), Span::new(1, 4))));
+}
+
+#[test]
+fn test_compound_assignment() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(interpreter.eval("x = 1 x += 2 x *= 10 x -= 6 x /= 4 x"), Ok(Value::Int(6)));
+    assert_eq!(interpreter.eval("x += 0.5 x"), Ok(Value::Real(Real::from(6.5))));
+    assert_eq!(interpreter.eval("s = \"ab\" s += \"c\" s"), Ok(Value::from("abc")));
+    // Compound assignment changes the variable where it is declared
+    assert_eq!(interpreter.eval("n = 0 for i in 1...4 { n += i } n"), Ok(Value::Int(10)));
+    // The target is evaluated once
+    interpreter.capture_output();
+    assert_eq!(interpreter.eval("
+        xs = [1, 2, 3]
+        fn at(i) { print(\"at\", i) return i }
+        xs[at(1)] += 10
+        xs[at(-1)] *= 2
+        xs
+    ").map(|v| v.to_string()), Ok("[1, 12, 6]".to_string()));
+    assert_eq!(interpreter.take_output(), "at 1\nat -1\n");
+}
+
+#[test]
+fn test_compound_assignment_errors() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(interpreter.eval("y += 1"), Err(Error::UndefinedVariable("y".to_string(), Span::new(1, 1))));
+    assert_eq!(interpreter.eval("s = \"a\" s += 1"),
+               Err(Error::TypeMismatch("cannot apply `+` to str and int".to_string(), Span::new(1, 9))));
+    assert_eq!(interpreter.eval("n = 2147483647 n += 1"), Err(Error::Overflow(Span::new(1, 16))));
+    assert_eq!(interpreter.eval("xs = [1] xs[0] /= 0"), Err(Error::DivisionByZero(Span::new(1, 12))));
+    assert_eq!(Parser::new("f() += 1").parse_program(),
+               Err(Error::InvalidAssignment(Span::new(1, 2))));
+}
+
+/// Runs every script in `dir` and the directories inside of it with both
+/// the evaluator and the virtual machine and checks they agree on the
+/// output and the result. The scripts must parse, so they are not
+/// agreeing on a syntax error. The tokens of the lexer tests and the
+/// transcripts of the servers are not scripts.
+fn check_vm_agrees(dir: &Path) -> usize {
+    let mut checked = 0;
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            checked += check_vm_agrees(&path);
+            continue;
+        }
+        if path.extension().and_then(|e| e.to_str()) != Some("txt") ||
+           path == Path::new("tests/random.txt") ||
+           dir == Path::new("tests/lsp") || dir == Path::new("tests/dap") {
+            continue;
+        }
+        let source = fs::read_to_string(&path).unwrap();
+        if let Err(e) = Parser::new(&source).parse_program() {
+            panic!("{} does not parse: {}", path.display(), e);
+        }
+        let mut interpreter = Interpreter::new();
+        let mut vm = Vm::new();
+        interpreter.capture_output();
+        vm.capture_output();
+        let expected = interpreter.eval(&source).map(|v| v.to_string());
+        let found = vm.eval(&source).map(|v| v.to_string());
+        assert_eq!(found, expected, "result of {}", path.display());
+        assert_eq!(vm.take_output(), interpreter.take_output(), "output of {}", path.display());
+        checked += 1;
+    }
+    checked
+}
+
+#[test]
+fn test_vm_agrees_with_evaluator() {
+    assert_eq!(check_vm_agrees(Path::new("tests")), 9);
+}
+
+#[test]
+fn test_vm() {
+    let mut vm = Vm::new();
+    assert_eq!(vm.eval("x = 2 x * 21"), Ok(Value::Int(42)));
+    assert_eq!(vm.eval("x"), Ok(Value::Int(2)));
+    assert_eq!(vm.eval("fn f(n) { return n + x } f(1)"), Ok(Value::Int(3)));
+    assert_eq!(vm.eval("f(1, 2)"), Err(Error::ArityMismatch(1, 2, Span::new(1, 2))));
+    assert_eq!(vm.eval("for i in 0..3 { if i == 2 { return i } }"),
+               Err(Error::ReturnOutsideFunction(Span::new(1, 29))));
+    assert_eq!(vm.eval("y"), Err(Error::UndefinedVariable("y".to_string(), Span::new(1, 1))));
+}
+
+#[test]
+fn test_compile_limits() {
+    // The evaluator runs what is too large for the bytecode
+    let params: Vec<String> = (0..70000).map(|i| format!("p{}", i)).collect();
+    let args = vec!["1"; 70000].join(", ");
+    let source = format!("fn f({}) {{ return p69999 }}\nf({})", params.join(", "), args);
+    assert_eq!(Interpreter::new().eval(&source), Ok(Value::Int(1)));
+    let column = source.find("p65536").unwrap() as u32 + 1;
+    assert_eq!(Vm::new().eval(&source), Err(Error::TooMany("locals".to_string(), Span::new(1, column))));
+    assert_eq!(Vm::new().eval(&format!("fn f() {{}}\nf({})", args)),
+               Err(Error::TooMany("arguments".to_string(), Span::new(2, 2))));
+}
+
+#[test]
+fn test_disassemble() {
+    let program = Parser::new("x = 1\nprint(x + 2.5)").parse_program().unwrap();
+    let module = compiler::compile(&program).unwrap();
+    assert_eq!(module.disassemble(), "\
+fn #0 <main> (0 params, 0 slots)
+0000     1:5  Const(0)  ; 1
+0001     1:1  SetVar(0)  ; x [Global(0)]
+0002     2:1  GetVar(1)  ; print [Global(1)]
+0003     2:7  GetVar(0)  ; x [Global(0)]
+0004    2:11  Const(1)  ; 2.5
+0005     2:9  Binary(Add)
+0006     2:6  Call(1)
+0007     2:1  Return
+");
+}
+
+#[test]
+fn test_fuel() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_fuel(Some(1000));
+    assert_eq!(interpreter.eval("x = 0\nwhile true { x += 1 }"), Err(Error::OutOfFuel(Span::new(2, 1))));
+    assert_eq!(interpreter.fuel(), Some(0));
+    interpreter.set_fuel(Some(200));
+    // Without a loop the span is where it ran out
+    assert!(matches!(interpreter.eval("fn f(n) { return n + f(n) } f(1)"), Err(Error::OutOfFuel(_))));
+    interpreter.set_fuel(None);
+    interpreter.set_deadline(Some(Instant::now() + Duration::from_millis(10)));
+    assert_eq!(interpreter.eval("for i in 0..10 {\n    while true {}\n}"),
+               Err(Error::DeadlineExceeded(Span::new(2, 5))));
+}
+
+#[test]
+fn test_vm_resume_with_fuel() {
+    let source = "total = 0\nfor i in 0..100 {\n    total += i\n    if i / 10 * 10 == i { print(i) }\n}\ntotal";
+    let mut vm = Vm::new();
+    vm.capture_output();
+    vm.set_fuel(Some(100));
+    assert_eq!(vm.eval(source), Err(Error::OutOfFuel(Span::new(2, 1))));
+    assert!(vm.is_paused());
+    let mut result = Err(Error::OutOfFuel(Span::new(2, 1)));
+    while result == Err(Error::OutOfFuel(Span::new(2, 1))) {
+        vm.add_fuel(100);
+        result = vm.resume();
+    }
+    assert_eq!(result, Ok(Value::Int(4950)));
+    assert!(!vm.is_paused());
+    assert_eq!(vm.take_output(), "0\n10\n20\n30\n40\n50\n60\n70\n80\n90\n");
+
+    vm.set_fuel(None);
+    vm.set_deadline(Some(Instant::now() + Duration::from_millis(10)));
+    assert_eq!(vm.eval("fn spin() { while true {} }\nspin()"), Err(Error::DeadlineExceeded(Span::new(1, 13))));
+    vm.set_deadline(None);
+    assert!(vm.is_paused());
+}
+
+#[test]
+fn test_limits() {
+    let mut interpreter = Interpreter::new();
+    let mut vm = Vm::new();
+    let limits = Limits {
+        call_depth: 20,
+        heap: 4096,
+        string_len: 100,
+        list_len: 50,
+    };
+    interpreter.set_limits(limits);
+    vm.set_limits(limits);
+    let cases = [
+        ("fn f(n) { return f(n + 1) }\nf(0)", Error::CallDepthExceeded(Span::new(1, 19))),
+        ("s = \"ab\"\nwhile true { s += s }", Error::StringTooLong(128, Span::new(2, 14))),
+        ("xs = []\nwhile true { push(xs, 1) }", Error::ListTooLong(51, Span::new(2, 18))),
+        ("xs = [1, 2]\nfor i in 0..10 { xs = [xs, xs, xs] }\nys = []\nfor i in 0..100 { push(ys, [1, 2, 3, 4, 5]) }",
+         Error::HeapLimitExceeded(Span::new(4, 28))),
+    ];
+    for &(source, ref error) in cases.iter() {
+        assert_eq!(interpreter.eval(source).as_ref(), Err(error), "{}", source);
+        assert_eq!(vm.eval(source).as_ref(), Err(error), "{}", source);
+    }
+    // Memory which is no longer used does not count
+    let source = "for i in 0..1000 { xs = [i, i, i, i] s = \"text\" + str(i) }";
+    assert_eq!(interpreter.eval(source), Ok(Value::Nil));
+    assert_eq!(vm.eval(source), Ok(Value::Nil));
+    assert_eq!(Error::ListTooLong(51, Span::new(1, 1)).to_string(), "list too long: 51 items");
+}
+
+#[test]
+fn test_native_functions() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let speed = Rc::new(RefCell::new(0));
+    let mut interpreter = Interpreter::new();
+    let lines = log.clone();
+    interpreter.register_fn("log", move |line: String| lines.borrow_mut().push(line));
+    let motor = speed.clone();
+    interpreter.register_fn("set_motor", move |speed: i32| {
+        if speed.abs() > 100 {
+            return Err(format!("speed {} out of range", speed));
+        }
+        *motor.borrow_mut() = speed;
+        Ok(speed != 0)
+    });
+    interpreter.register_fn("now", || Real::from(1.5));
+    interpreter.register_fn("sum", |xs: Vec<i32>| xs.iter().sum::<i32>());
+    interpreter.register_fn("greet", |name: Option<String>| {
+        format!("hello {}", name.unwrap_or_else(|| "world".to_string()))
+    });
+    interpreter.register_fn("evens", |n: i32| (0..n).filter(|i| i % 2 == 0).collect::<Vec<_>>());
+    let mut count = 0;
+    interpreter.register_fn("tick", move || { count += 1; count });
+
+    assert_eq!(interpreter.eval("log(\"start\") set_motor(40)"), Ok(Value::Bool(true)));
+    assert_eq!(*log.borrow(), vec!["start".to_string()]);
+    assert_eq!(*speed.borrow(), 40);
+    assert_eq!(interpreter.eval("now() * 2"), Ok(Value::Real(Real::from(3.0))));
+    assert_eq!(interpreter.eval("sum([1, 2, 3]) + sum([])"), Ok(Value::Int(6)));
+    assert_eq!(interpreter.eval("greet(nil) + \", \" + greet(\"bot\")"),
+               Ok(Value::from("hello world, hello bot")));
+    assert_eq!(interpreter.eval("str(evens(5))"), Ok(Value::from("[0, 2, 4]")));
+    assert_eq!(interpreter.eval("tick() tick() tick()"), Ok(Value::Int(3)));
+    assert_eq!(interpreter.eval("str(set_motor)"), Ok(Value::from("<native set_motor>")));
+    // Natives are values and can be hidden by variables
+    assert_eq!(interpreter.eval("f = now f()"), Ok(Value::Real(Real::from(1.5))));
+    assert_eq!(interpreter.eval("now = 2 now"), Ok(Value::Int(2)));
+
+    // Errors are reported where the function is called
+    assert_eq!(interpreter.eval("\nset_motor(1, 2)"), Err(Error::ArityMismatch(1, 2, Span::new(2, 10))));
+    assert_eq!(interpreter.eval("set_motor(1.5)"),
+               Err(Error::TypeMismatch("argument 1 of set_motor must be int, found real".to_string(),
+                                       Span::new(1, 10))));
+    assert_eq!(interpreter.eval("sum([1, \"2\"])"),
+               Err(Error::TypeMismatch("argument 1 of sum must be list of int, found list".to_string(),
+                                       Span::new(1, 4))));
+    assert_eq!(interpreter.eval("set_motor(200)"),
+               Err(Error::NativeError("set_motor: speed 200 out of range".to_string(), Span::new(1, 10))));
+    assert_eq!(*speed.borrow(), 40);
+}
+
+#[test]
+fn test_vm_native_functions() {
+    let mut vm = Vm::new();
+    vm.capture_output();
+    vm.register_fn("scale", |x: Real, k: i32| x * k);
+    vm.register_fn("print", |line: String| format!("<{}>", line));
+    assert_eq!(vm.eval("fn f(x) { return scale(x, 3) } f(2)"), Ok(Value::Real(Real::from(6.0))));
+    assert_eq!(vm.eval("print(\"hi\")"), Ok(Value::from("<hi>")));
+    assert_eq!(vm.eval("scale(true, 1)"),
+               Err(Error::TypeMismatch("argument 1 of scale must be real, found bool".to_string(),
+                                       Span::new(1, 6))));
+    assert_eq!(vm.take_output(), "");
+}
+
+#[test]
+fn test_call_script_functions() {
+    let mut interpreter = Interpreter::new();
+    interpreter.register_fn("speed", |s: Real| s * 2);
+    interpreter.eval("
+        total = 0
+        fn on_sample(s) {
+            total += s
+            return speed(total)
+        }
+        threshold = 10
+    ").unwrap();
+    assert_eq!(interpreter.call("on_sample", vec![Value::Int(3)]), Ok(Value::Real(Real::from(6.0))));
+    assert_eq!(interpreter.call("on_sample", vec![Value::Int(1)]), Ok(Value::Real(Real::from(8.0))));
+    assert_eq!(interpreter.get_global("total"), Some(Value::Int(4)));
+    interpreter.set_global("total", Value::Int(-4));
+    interpreter.set_global("limit", Value::from("high"));
+    assert_eq!(interpreter.eval("on_sample(4) + 1"), Ok(Value::Real(Real::from(1.0))));
+    assert_eq!(interpreter.eval("limit"), Ok(Value::from("high")));
+    assert_eq!(interpreter.get_global("missing"), None);
+    // Natives and builtins can be called too
+    assert_eq!(interpreter.call("len", vec![Value::from("abc")]), Ok(Value::Int(3)));
+    assert_eq!(interpreter.call("speed", vec![Value::Int(1)]), Ok(Value::Real(Real::from(2.0))));
+
+    assert_eq!(interpreter.call("on_tick", vec![]), Err(CallError::UndefinedFunction("on_tick".to_string())));
+    assert_eq!(interpreter.call("threshold", vec![]),
+               Err(CallError::NotAFunction("threshold".to_string(), "int".to_string())));
+    assert_eq!(interpreter.call("on_sample", vec![]),
+               Err(CallError::Runtime(Error::ArityMismatch(1, 0, Span::new(3, 9)))));
+    let err = interpreter.call("on_sample", vec![Value::from("x")]).unwrap_err();
+    assert_eq!(err.runtime().and_then(Error::span), Some(Span::new(4, 13)));
+    assert_eq!(err.to_string(), "type mismatch: cannot apply `+` to int and str");
+}
+
+#[test]
+fn test_vm_call_script_functions() {
+    let mut vm = Vm::new();
+    vm.eval("
+        fn on_sample(s) {
+            n = 0
+            for i in 0..s { n += i }
+            return n
+        }
+    ").unwrap();
+    assert_eq!(vm.call("on_sample", vec![Value::Int(4)]), Ok(Value::Int(6)));
+    vm.set_global("k", Value::Int(7));
+    assert_eq!(vm.eval("k * 2"), Ok(Value::Int(14)));
+    assert_eq!(vm.get_global("on_sample").map(|f| f.to_string()), Some("<fn on_sample>".to_string()));
+    assert_eq!(vm.call("k", vec![]), Err(CallError::NotAFunction("k".to_string(), "int".to_string())));
+    assert_eq!(vm.call("on_sample", vec![]),
+               Err(CallError::Runtime(Error::ArityMismatch(1, 0, Span::new(2, 9)))));
+
+    // A call running out of fuel can be resumed
+    vm.set_fuel(Some(20));
+    assert!(matches!(vm.call("on_sample", vec![Value::Int(100)]), Err(CallError::Runtime(Error::OutOfFuel(_)))));
+    assert_eq!(vm.call("on_sample", vec![Value::Int(1)]), Err(CallError::Paused));
+    vm.set_fuel(None);
+    assert_eq!(vm.resume(), Ok(Value::Int(4950)));
+    assert_eq!(vm.call("on_sample", vec![Value::Int(1)]), Ok(Value::Int(0)));
+}
+
+const SNAPSHOT_SCRIPT: &str = "
+    fn counter() {
+        n = 0
+        return fn () { n += 1 return n }
+    }
+    next = counter()
+    samples = []
+    stats = {sum: 0, last: nil}
+    fn fib(n) { return n < 2 ? n : fib(n - 1) + fib(n - 2) }
+    for i in 0..12 {
+        id = next()
+        push(samples, i * 1.5)
+        stats.sum += i
+        stats.last = samples
+        print(id, fib(i), len(samples), stats.sum)
+    }
+    str(samples[11]) + \" \" + str(stats.last == samples)
+";
+
+#[test]
+fn test_vm_snapshot_resume() {
+    let mut vm = Vm::new();
+    vm.capture_output();
+    let expected = vm.eval(SNAPSHOT_SCRIPT).unwrap();
+    let expected_output = vm.take_output();
+
+    let mut vm = Vm::new();
+    vm.capture_output();
+    vm.set_fuel(Some(150));
+    let mut result = vm.eval(SNAPSHOT_SCRIPT);
+    let mut output = String::new();
+    let mut pauses = 0;
+    while let Err(Error::OutOfFuel(_)) = result {
+        output += &vm.take_output();
+        let snapshot = vm.snapshot();
+        // Continue on a new machine, as if the power was cut
+        vm = Vm::new();
+        vm.capture_output();
+        vm.set_fuel(Some(150));
+        vm.restore(&snapshot).unwrap();
+        assert!(vm.is_paused());
+        result = vm.resume();
+        pauses += 1;
+    }
+    output += &vm.take_output();
+    assert!(pauses > 10);
+    assert_eq!(result, Ok(expected));
+    assert_eq!(output, expected_output);
+
+    // The globals are restored with the values they share
+    let mut restored = Vm::new();
+    restored.restore(&vm.snapshot()).unwrap();
+    assert_eq!(restored.eval("push(samples, 0) len(stats.last)"), Ok(Value::Int(13)));
+    assert_eq!(restored.eval("next()"), Ok(Value::Int(13)));
+}
+
+#[test]
+fn test_interpreter_snapshot() {
+    let script = "
+        fn on_sample(s) {
+            total += s
+            history[len(history) - 1] = total
+            push(history, nil)
+            print(\"tick\", next(), total)
+            return total
+        }
+    ";
+    let setup = "
+        total = 0
+        history = [nil]
+        fn counter() {
+            n = 0
+            return fn () { n += 1 return n }
+        }
+        next = counter()
+        xs = [1] push(xs, xs)
+    ";
+    let run = |interpreter: &mut Interpreter, snapshots: bool| {
+        interpreter.capture_output();
+        interpreter.eval(setup).unwrap();
+        interpreter.eval(script).unwrap();
+        let mut output = String::new();
+        for i in 0..5 {
+            interpreter.call("on_sample", vec![Value::Int(i * 10)]).unwrap();
+            output += &interpreter.take_output();
+            if snapshots {
+                let snapshot = interpreter.snapshot();
+                *interpreter = Interpreter::new();
+                interpreter.capture_output();
+                interpreter.restore(&snapshot).unwrap();
+            }
+        }
+        output
+    };
+    let expected = run(&mut Interpreter::new(), false);
+    let mut interpreter = Interpreter::new();
+    assert_eq!(run(&mut interpreter, true), expected);
+    assert_eq!(interpreter.eval("str(history)"), Ok(Value::from("[0, 10, 30, 60, 100, nil]")));
+    // Cycles and shared values survive
+    assert_eq!(interpreter.eval("len(xs[1][1][1])"), Ok(Value::Int(2)));
+    // The same state always gives the same snapshot
+    assert_eq!(interpreter.snapshot(), interpreter.snapshot());
+}
+
+#[test]
+fn test_snapshot_errors() {
+    let mut interpreter = Interpreter::new();
+    interpreter.register_fn("now", || 1);
+    interpreter.eval("clock = now x = 1").unwrap();
+    let snapshot = interpreter.snapshot();
+
+    let bad = |msg: &str| Err(Error::BadSnapshot(msg.to_string()));
+    assert_eq!(Interpreter::new().restore(&snapshot), bad("unknown native function now"));
+    assert_eq!(Vm::new().restore(&snapshot),
+               bad("expected a snapshot of the vm, found one of the interpreter"));
+    assert_eq!(interpreter.restore(b"nonsense"), bad("not a snapshot"));
+    let mut corrupted = snapshot.clone();
+    corrupted[12] ^= 1;
+    assert_eq!(interpreter.restore(&corrupted), bad("checksum mismatch"));
+    assert_eq!(interpreter.restore(&snapshot[..snapshot.len() - 1]), bad("checksum mismatch"));
+    let mut version = snapshot.clone();
+    version[4] = 99;
+    assert_eq!(interpreter.restore(&version), bad("unsupported version 99"));
+
+    // A failed restore leaves the state as it was
+    assert_eq!(interpreter.eval("x"), Ok(Value::Int(1)));
+    let mut other = Interpreter::new();
+    other.register_fn("now", || 2);
+    other.restore(&snapshot).unwrap();
+    assert_eq!(other.eval("clock() + x"), Ok(Value::Int(3)));
+}
+
+#[test]
+fn test_snapshot_nesting() {
+    // Lists nested deeper than the stack allows are read and written in a loop
+    let source = "xs = []\nfor i in 0..100000 { xs = [xs] }";
+    let mut interpreter = Interpreter::new();
+    interpreter.eval(source).unwrap();
+    let snapshot = interpreter.snapshot();
+    interpreter.restore(&snapshot).unwrap();
+    assert_eq!(interpreter.eval("len(str(xs))"), Ok(Value::Int(200002)));
+    let mut vm = Vm::new();
+    vm.eval(source).unwrap();
+    let snapshot = vm.snapshot();
+    vm.restore(&snapshot).unwrap();
+    assert_eq!(vm.eval("len(str(xs))"), Ok(Value::Int(200002)));
+
+    // Snapshots of the interpreter holding the global variable `f`,
+    // forged with a `Writer` so the checksum is right
+    let (globals, scope, closure) = (Rc::new(()), Rc::new(()), Rc::new(()));
+    let forge = |function: &Rc<Function>, declared: bool, own_scope: bool, twice: bool| {
+        let write_closure = |writer: &mut Writer| {
+            writer.u8(10);
+            writer.function(function);
+            writer.bool(declared);
+            writer.bool(false);
+            if writer.shared(if own_scope { &scope } else { &globals }) {
+                writer.usize(0);
+            }
+            writer.shared(&closure);
+        };
+        let mut writer = Writer::new(Kind::Interpreter);
+        writer.bool(false);
+        writer.shared(&globals);
+        writer.usize(if twice { 2 } else { 1 });
+        writer.str("f");
+        write_closure(&mut writer);
+        if twice {
+            writer.str("g");
+            write_closure(&mut writer);
+        }
+        writer.duration(Duration::default());
+        writer.usize(0);
+        writer.usize(0);
+        writer.finish()
+    };
+    let span = Span::new(1, 1);
+    let f = Rc::new(Function { name: Some("f".to_string()), params: Vec::new(), ret: None, body: Vec::new(), span });
+    let mut interpreter = Interpreter::new();
+    interpreter.restore(&forge(&f, true, false, false)).unwrap();
+    assert_eq!(interpreter.eval("f()"), Ok(Value::Nil));
+
+    let bad = |msg: &str| Err(Error::BadSnapshot(msg.to_string()));
+    // A declared function which could outlive its scope
+    assert_eq!(interpreter.restore(&forge(&f, true, true, false)),
+               bad("the declared function f is not held by its scope"));
+    assert_eq!(interpreter.restore(&forge(&f, true, false, true)),
+               bad("a declared function is referred to twice"));
+    // A function nested deeper than the reader allows
+    let mut expr = Expr::new(ExprKind::Nil, span);
+    for _ in 0..1000 {
+        expr = Expr::new(ExprKind::Unary(UnaryOp::Neg, Box::new(expr)), span);
+    }
+    let body = vec![Stmt::new(StmtKind::Expr(expr), span)];
+    let deep = Rc::new(Function { name: None, params: Vec::new(), ret: None, body, span });
+    assert_eq!(interpreter.restore(&forge(&deep, false, false, false)), bad("nested too deep"));
+    assert_eq!(interpreter.eval("f()"), Ok(Value::Nil));
+}
+
+#[test]
+fn test_duration_tokens() {
+    let lexer = lexer::Lexer::new("every 10ms 2s 5 ms on");
+    let tokens: Vec<_> = lexer.map(|(token, _, _)| token).collect();
+    assert_eq!(tokens, vec![Ok(Token::Every),
+                            Ok(Token::Duration(10)),
+                            Ok(Token::Duration(2000)),
+                            Ok(Token::Int(5)),
+                            Ok(Token::Identity("ms".to_string())),
+                            Ok(Token::On)]);
+    assert_eq!(lexer::Lexer::new("5000000s").next_token(), Err(Error::LargeInt));
+    assert_eq!(Parser::new("every 0ms {}").parse_program(), Err(Error::ZeroPeriod(Span::new(1, 7))));
+    assert_eq!(Parser::new("every 10 {}").parse_program(),
+               Err(Error::UnexpectedToken(Token::Int(10), Span::new(1, 7))));
+}
+
+const HANDLERS_SCRIPT: &str = "
+    t = 0
+    every 10ms { t += 10 print(\"fast\", t) }
+    every 25ms { print(\"slow\") }
+    on @accel { print(\"accel\", t) }
+    on @gyro { print(\"gyro\") }
+";
+
+#[test]
+fn test_handlers() {
+    let mut interpreter = Interpreter::new();
+    interpreter.capture_output();
+    interpreter.eval(HANDLERS_SCRIPT).unwrap();
+    assert_eq!(interpreter.take_output(), "");
+    interpreter.advance(Duration::from_millis(30)).unwrap();
+    assert_eq!(interpreter.take_output(), "fast 10\nfast 20\nslow\nfast 30\n");
+    interpreter.signal("accel").unwrap();
+    interpreter.signal("unknown").unwrap();
+    assert_eq!(interpreter.take_output(), "accel 30\n");
+    // Handlers due at the same time run in the order they were registered
+    interpreter.advance(Duration::from_millis(20)).unwrap();
+    assert_eq!(interpreter.take_output(), "fast 40\nfast 50\nslow\n");
+    assert_eq!(interpreter.now(), Duration::from_millis(50));
+    // A handler registered later starts counting from then
+    interpreter.eval("every 5ms { print(\"late\") }").unwrap();
+    interpreter.advance(Duration::from_millis(9)).unwrap();
+    assert_eq!(interpreter.take_output(), "late\n");
+
+    interpreter.eval("on @gyro { x = nil + 1 }").unwrap();
+    let err = interpreter.signal("gyro");
+    assert_eq!(interpreter.take_output(), "gyro\n");
+    assert_eq!(err.unwrap_err().runtime().and_then(Error::span), Some(Span::new(1, 20)));
+}
+
+#[test]
+fn test_vm_handlers() {
+    let steps = |advance: &mut dyn FnMut(u64), signal: &mut dyn FnMut(&str)| {
+        advance(30);
+        signal("accel");
+        advance(20);
+        signal("gyro");
+        advance(1);
+        advance(24);
+    };
+    let mut interpreter = Interpreter::new();
+    interpreter.capture_output();
+    interpreter.eval(HANDLERS_SCRIPT).unwrap();
+    let interpreter = RefCell::new(interpreter);
+    steps(&mut |ms| interpreter.borrow_mut().advance(Duration::from_millis(ms)).unwrap(),
+          &mut |device| interpreter.borrow_mut().signal(device).unwrap());
+    let expected = interpreter.borrow_mut().take_output();
+
+    let mut vm = Vm::new();
+    vm.capture_output();
+    vm.eval(HANDLERS_SCRIPT).unwrap();
+    let vm = RefCell::new(vm);
+    steps(&mut |ms| vm.borrow_mut().advance(Duration::from_millis(ms)).unwrap(),
+          &mut |device| vm.borrow_mut().signal(device).unwrap());
+    assert_eq!(vm.borrow_mut().take_output(), expected);
+
+    // The clock and the handlers are part of a snapshot
+    let mut restored = Vm::new();
+    restored.capture_output();
+    restored.restore(&vm.borrow().snapshot()).unwrap();
+    assert_eq!(restored.now(), Duration::from_millis(75));
+    restored.advance(Duration::from_millis(5)).unwrap();
+    restored.signal("accel").unwrap();
+    assert_eq!(restored.take_output(), "fast 80\naccel 80\n");
+}
+
+fn resolve(source: &str) -> Resolution {
+    let program = Parser::new(source).parse_program().unwrap();
+    Resolver::new().resolve(&program)
+}
+
+fn diagnostics(resolution: &Resolution) -> Vec<String> {
+    resolution.diagnostics.iter().map(|d| d.to_string()).collect()
+}
+
+#[test]
+fn test_resolver_errors() {
+    let resolution = resolve("total = 0
+for i in 0..3 {
+    totl += i
+}
+fn foo(zomg, z, zomg,) {
+    return zomg + z
+}
+print(totel)
+break
+return 1
+");
+    assert!(resolution.has_errors());
+    assert_eq!(diagnostics(&resolution),
+               vec!["3:5: error: undefined variable totl\n  help: did you mean `total`?",
+                    "5:17: error: duplicate parameter zomg",
+                    "8:7: error: undefined variable totel\n  help: did you mean `total`?",
+                    "9:1: error: break outside of a loop",
+                    "10:1: error: return outside of a function"]);
+
+    // `break` in a function doesn't leave the loop around it
+    let resolution = resolve("while true {\n    f = fn() { break }\n    f()\n}");
+    assert_eq!(diagnostics(&resolution), vec!["2:16: error: break outside of a loop"]);
+
+    // A variable can only be used after its first assignment
+    let resolution = resolve("if true {\n    print(x)\n    x = 1\n    print(x)\n}");
+    assert_eq!(diagnostics(&resolution), vec!["2:11: error: undefined variable x"]);
+}
+
+#[test]
+fn test_resolver_warnings() {
+    let resolution = resolve("count = 1
+fn scale(x, _unused, count) {
+    y = 2
+    for count in 0..x {
+        print(count)
+    }
+    len = 3
+    return len
+}
+print(scale(1, 2, 3))
+");
+    assert!(!resolution.has_errors());
+    assert_eq!(diagnostics(&resolution),
+               vec!["2:22: warning: count hides the variable declared at 1:1",
+                    "2:22: warning: unused variable count",
+                    "3:5: warning: unused variable y",
+                    "4:5: warning: count hides the variable declared at 2:22",
+                    "7:5: warning: len hides a builtin or host name"]);
+}
+
+#[test]
+fn test_resolver_bindings() {
+    // Top level functions can call each other before they are declared
+    let resolution = resolve("fn even(n) { return n == 0 | odd(n - 1) }
+fn odd(n) { return n != 0 & even(n - 1) }
+x = 1
+x = even(10)
+print(x)
+");
+    assert!(resolution.diagnostics.is_empty());
+    let odd = resolution.declaration_of(Span::new(1, 30)).unwrap();
+    assert_eq!((odd.name.as_str(), odd.kind, odd.span, odd.global),
+               ("odd", DeclarationKind::Function, Span::new(2, 1), true));
+    let n = resolution.declaration_of(Span::new(2, 20)).unwrap();
+    assert_eq!((n.kind, n.span, n.global), (DeclarationKind::Parameter, Span::new(2, 8), false));
+    // Assigning again binds to the first assignment
+    assert_eq!(resolution.declaration_of(Span::new(4, 1)).unwrap().span, Span::new(3, 1));
+    assert_eq!(resolution.declaration_of(Span::new(5, 7)).unwrap().span, Span::new(3, 1));
+    // Builtin functions are not bound to a declaration
+    assert!(resolution.declaration_of(Span::new(5, 1)).is_none());
+
+    // The engines tell the resolver about their natives and globals
+    let program = Parser::new("limit = 2\nprint(clamp(speed, limit))").parse_program().unwrap();
+    assert!(Resolver::new().resolve(&program).has_errors());
+    let mut interpreter = Interpreter::new();
+    interpreter.register_fn("clamp", |x: i32, max: i32| x.min(max));
+    interpreter.set_global("speed", Value::Int(3));
+    assert!(interpreter.resolver().resolve(&program).diagnostics.is_empty());
+    let mut vm = Vm::new();
+    vm.register_fn("clamp", |x: i32, max: i32| x.min(max));
+    vm.set_global("speed", Value::Int(3));
+    assert!(vm.resolver().resolve(&program).diagnostics.is_empty());
+}
+
+#[test]
+fn test_suggestions() {
+    let help = |source: &str| {
+        Resolver::new().check(source).into_iter().map(|d| (d.span(), d.help)).collect::<Vec<_>>()
+    };
+    let suggest = |name: &str| Some(format!("did you mean `{}`?", name));
+
+    // Misspelled keywords failing to parse
+    assert_eq!(help("x = 0\nwhiel x < 3 {\n    x += 1\n}"), vec![(Span::new(3, 7), suggest("while"))]);
+    assert_eq!(help("fro i in 0..3 {\n    print(i)\n}"), vec![(Span::new(1, 7), suggest("for"))]);
+    // Only on the line of the typo
+    assert_eq!(help("fn f(x) {\n    retrn 1\n}\nx = {1}"), vec![(Span::new(4, 6), None)]);
+    // Misspelled keywords which parse as names
+    assert_eq!(help("fn f(x) {\n    retrun x\n}"), vec![(Span::new(2, 5), suggest("return"))]);
+
+    // Visible names come first, then names of the host, builtins and keywords
+    let mut vm = Vm::new();
+    vm.register_fn("clamp", |x: i32, max: i32| x.min(max));
+    vm.set_global("speed", Value::Int(3));
+    let diagnostics = vm.resolver().check("fn f(length) {
+    print(lenth, clmap(sped, 1), ture, prnit, speedometer)
+}
+f(1)
+");
+    let helps: Vec<_> = diagnostics.into_iter().filter(|d| d.is_error()).map(|d| d.help).collect();
+    assert_eq!(helps, vec![suggest("length"), suggest("clamp"), suggest("speed"), suggest("true"),
+                           suggest("print"), None]);
+}
+
+fn errors(source: &str) -> Vec<String> {
+    Resolver::new().check(source).iter().filter(|d| d.is_error()).map(|d| d.to_string()).collect()
+}
+
+#[test]
+fn test_type_annotations() {
+    let program = Parser::new("fn f(x: real?, n, f: fn) -> list { }").parse_program().unwrap();
+    let function = match program[0].kind {
+        StmtKind::Function(ref function) => function.clone(),
+        _ => panic!("expected a function"),
+    };
+    let types: Vec<_> = function.params.iter().map(|p| p.ty.clone()).collect();
+    assert_eq!(types, vec![Some(Type::Optional(Box::new(Type::Real))), None, Some(Type::Fn(None))]);
+    assert_eq!(function.ret, Some(Type::List));
+    assert_eq!(Parser::new("fn f(x: float) {}").parse_program(),
+               Err(Error::UnknownType("float".to_string(), Span::new(1, 9))));
+
+    // The scripts of the tests have no type errors
+    for entry in fs::read_dir("tests/scripts").unwrap() {
+        let path = entry.unwrap().path();
+        let source = fs::read_to_string(&path).unwrap();
+        let diagnostics = Resolver::new().check(&source);
+        let types: Vec<_> = diagnostics.iter().filter(|d| d.to_string().contains("type mismatch")).collect();
+        assert!(types.is_empty(), "{}: {:?}", path.display(), types);
+    }
+}
+
+#[test]
+fn test_type_checker() {
+    assert_eq!(errors("fn scale(x: real, k: int) -> real {
+    k = k * 2.0
+    return x * k
+}
+print(scale(2, 3))
+print(scale(2.0))
+print(scale(1.5, 2) + \"x\")
+reading = nil
+if len([]) > 0 {
+    reading = 1.5
+}
+print(scale(reading, 1))
+if reading != nil {
+    print(scale(reading, 1))
+}
+"),
+               vec!["2:5: error: type mismatch: k is declared as int, found real",
+                    "5:13: error: type mismatch: argument 1 of scale must be real, found int",
+                    "6:12: error: wrong number of arguments: expected 2, found 1",
+                    "7:21: error: type mismatch: cannot apply `+` to real and str",
+                    "12:13: error: type mismatch: argument 1 of scale must be real, found real or nil"]);
+
+    assert_eq!(errors("fn sign(n: int) -> int {
+    if n > 0 {
+        return 1
+    } else if n < 0 {
+        return \"-1\"
+    }
+}
+fn first(xs: list) -> any? {
+    for x in xs {
+        return x
+    }
+}
+fn apply(f: fn, x) { return f(x) }
+apply(sign, first([1]))
+apply(3, 4)
+n = 1
+n()
+for c in 10 {}
+"),
+               vec!["1:1: error: type mismatch: sign can end without a return, but must return int",
+                    "5:16: error: type mismatch: the return value of sign must be int, found str",
+                    "15:7: error: type mismatch: argument 1 of apply must be fn, found int",
+                    "17:2: error: cannot call int",
+                    "18:10: error: cannot iterate over int"]);
+
+    // A loop which cannot be left but by a return always returns
+    assert_eq!(errors("fn g() -> int { while true { return 1 } }
+fn h() -> int { while true { if g() > 0 { break } } }
+"),
+               vec!["2:1: error: type mismatch: h can end without a return, but must return int"]);
+
+    // Types flow through loops and closures
+    assert_eq!(errors("fn need(x: int) {}
+x = 0
+while len([]) < 10 {
+    need(x)
+    x = nil
+}
+f = fn () { need(count) }
+count = nil
+count = 1
+"),
+               vec!["4:10: error: type mismatch: argument 1 of need must be int, found int or nil",
+                    "7:18: error: type mismatch: argument 1 of need must be int, found int or nil"]);
+
+    // Natives of the host have their number of arguments checked
+    let mut vm = Vm::new();
+    vm.register_fn("clamp", |x: i32, max: i32| x.min(max));
+    let diagnostics = vm.resolver().check("clamp(1)");
+    assert_eq!(diagnostics[0].to_string(), "1:6: error: wrong number of arguments: expected 2, found 1");
+}
+
+/// The expressions of the expression and assignment statements of a program.
+fn values(program: &[Stmt]) -> Vec<ExprKind> {
+    program.iter().filter_map(|stmt| match stmt.kind {
+        StmtKind::Expr(ref expr) | StmtKind::Assign(_, _, ref expr) => Some(expr.kind.clone()),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn test_constant_folding() {
+    let optimized = |source: &str| optimizer::optimize(&Parser::new(source).parse_program().unwrap());
+
+    // Folded with the semantics of the runtime
+    let source = "3.14 * 2 / 180";
+    let expected = Interpreter::new().run(&Parser::new(source).parse_program().unwrap()).unwrap();
+    let (program, diagnostics) = optimized(source);
+    assert!(diagnostics.is_empty());
+    match values(&program)[0] {
+        ExprKind::Real(r) => assert_eq!(Value::Real(r), expected),
+        ref kind => panic!("expected a real, found {:?}", kind),
+    }
+    let (program, _) = optimized("x = -(2 + 3) * 4 < 1 & !nil\ny = 1 > 2 | x\nz = x + 2 * 3\nw = \"a\" < \"b\"");
+    let folded = values(&program);
+    assert_eq!(folded[0], ExprKind::Bool(true));
+    // Only the constant side is folded
+    match (&folded[1], &folded[2]) {
+        (ExprKind::Binary(BinaryOp::Or, left, _), ExprKind::Binary(BinaryOp::Add, _, right)) => {
+            assert_eq!((&left.kind, &right.kind), (&ExprKind::Bool(false), &ExprKind::Int(6)));
+        }
+        kinds => panic!("unexpected {:?}", kinds),
+    }
+    assert_eq!(folded[3], ExprKind::Bool(true));
+
+    // Operations which fail are left to the runtime and reported
+    let (program, diagnostics) = optimized("fn f() {\n    return 2147483647 + 1\n}\nx = 1.0 / 0");
+    let diagnostics: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+    assert_eq!(diagnostics, vec!["2:23: error: arithmetic overflow", "4:9: error: division by zero"]);
+    assert!(matches!(values(&program)[0], ExprKind::Binary(..)));
+    let mut vm = Vm::new();
+    assert_eq!(vm.eval("f = fn () { return 2147483647 + 1 }\nf()"), Err(Error::Overflow(Span::new(1, 31))));
+    let errors = Resolver::new().check("x = -2147483647 - 2");
+    assert_eq!(errors[0].to_string(), "1:17: error: arithmetic overflow");
+}
+
+#[test]
+fn test_dead_code_elimination() {
+    let optimized = |source: &str| optimizer::optimize(&Parser::new(source).parse_program().unwrap()).0;
+
+    // The taken branch keeps its block and so its scope
+    let program = optimized("if 1 < 2 {\n    a = 1\n} else {\n    b = 2\n}\nif false {\n    c = 3\n} else {\n    d = 4\n}");
+    assert_eq!(values(&program), vec![]);
+    match (&program[0].kind, &program[1].kind) {
+        (StmtKind::If(first, then, None), StmtKind::If(second, otherwise, None)) => {
+            assert_eq!((&first.kind, &second.kind), (&ExprKind::Bool(true), &ExprKind::Bool(true)));
+            assert_eq!((values(then), values(otherwise)), (vec![ExprKind::Int(1)], vec![ExprKind::Int(4)]));
+        }
+        kinds => panic!("unexpected {:?}", kinds),
+    }
+    assert!(matches!(Interpreter::new().eval("if true { y = 1 }\ny"), Err(Error::UndefinedVariable(..))));
+
+    // Branches and loops which never run, and code after leaving a block
+    let program = optimized("if false { a = 1 }\nwhile 1 > 2 { b = 2 }\nc = true ? 3 : 4\nwhile true {\n    break\n    d = 5\n}");
+    assert_eq!(values(&program), vec![ExprKind::Int(3)]);
+    match program[1].kind {
+        StmtKind::While(_, ref body) => assert_eq!(body.len(), 1),
+        ref kind => panic!("unexpected {:?}", kind),
+    }
+
+    // A program ending with a statement keeps returning nil
+    let source = "1 + 2\nif false { print(1) }";
+    assert_eq!(Interpreter::new().eval(source), Ok(Value::Nil));
+    assert_eq!(Vm::new().eval(source), Ok(Value::Nil));
+    assert_eq!(Vm::new().eval("x = 1\n1 + 2"), Ok(Value::Int(3)));
+}
+
+#[test]
+fn test_formatter() {
+    let source = "\n\n# scale things\nfn foo(zomg,z,xx,)\n{\n  x=zomg+z*-xx   # trailing\n      if x>1 & !z { return x } else\n  {\nreturn -x\n      }\n\n\n\n  ys=[1,2,3,]\n  cfg = {\n    a: 1, # one\n    b: 2 # two\n  }\n  f = fn(a:int?, b : real)->real? { a ? b : nil }\n  for i in 0..10 by 2 { ys [i]=i; print( ys[ i ] ) }\n  return m.a+@accel.x\n}\n";
+    let expected = "# scale things\nfn foo(zomg, z, xx) {\n    x = zomg + z * -xx # trailing\n    if x > 1 & !z { return x } else {\n        return -x\n    }\n\n    ys = [1, 2, 3]\n    cfg = {\n        a: 1, # one\n        b: 2, # two\n    }\n    f = fn (a: int?, b: real) -> real? { a ? b : nil }\n    for i in 0..10 by 2 { ys[i] = i; print(ys[i]) }\n    return m.a + @accel.x\n}\n";
+    assert_eq!(formatter::format(source), Ok(expected.to_string()));
+    assert_eq!(formatter::format(expected), Ok(expected.to_string()));
+
+    // A `{` after `return` or a comment is a map which must stay put
+    let source = "fn f() {\n    return\n    {a: 1}\n}\nx = c ? {k: 1} : {} # pick\n{}\n";
+    assert_eq!(formatter::format(source), Ok(source.to_string()));
+    assert!(formatter::format("x = (1").is_err());
+}
+
+#[test]
+fn test_formatter_keeps_scripts() {
+    for entry in fs::read_dir("tests/scripts").unwrap() {
+        let path = entry.unwrap().path();
+        let source = fs::read_to_string(&path).unwrap();
+        let formatted = formatter::format(&source).unwrap();
+        assert_eq!(formatter::format(&formatted).as_ref(), Ok(&formatted), "{}", path.display());
+
+        let mut original = Interpreter::new();
+        let mut interpreter = Interpreter::new();
+        original.capture_output();
+        interpreter.capture_output();
+        // Errors point at other columns in the formatted script
+        let expected = original.eval(&source).map(|v| v.to_string()).map_err(|e| e.description().to_string());
+        let found = interpreter.eval(&formatted).map(|v| v.to_string()).map_err(|e| e.description().to_string());
+        assert_eq!(found, expected, "result of {}", path.display());
+        assert_eq!(interpreter.take_output(), original.take_output(), "output of {}", path.display());
+    }
+}
+
+#[test]
+fn test_fmt_check() {
+    use std::process::Command;
+
+    let path = std::env::temp_dir().join("interpreter_fmt_check.txt");
+    let path = path.to_str().unwrap();
+    fs::write(path, "x=[1,2,]\n").unwrap();
+    let fmt = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_interpreter_bin")).arg("fmt").args(args).output().unwrap()
+    };
+
+    let output = fmt(&["--check", path]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), path);
+    assert_eq!(fs::read_to_string(path).unwrap(), "x=[1,2,]\n");
+
+    assert_eq!(fmt(&[path]).status.code(), Some(0));
+    assert_eq!(fs::read_to_string(path).unwrap(), "x = [1, 2]\n");
+    assert_eq!(fmt(&["--check", path]).status.code(), Some(0));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_debug_cli() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_interpreter_bin"))
+        .args(["debug", "tests/dap/programs/count.txt"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"b 3\nc\nbt\np sum\np total\nd 3\nc\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.split("(debug) ").flat_map(str::lines).collect();
+    assert_eq!(lines, vec!["stopped at 1:1",
+                           "   1 | fn add(a, b) {",
+                           "breakpoint at line 3",
+                           "stopped at 3:5",
+                           "   3 |     return sum",
+                           "#0 add at 3:5",
+                           "#1 <main> at 7:16",
+                           "sum = 1",
+                           "total = 0",
+                           "removed the breakpoint at line 3",
+                           "total 3",
+                           "finished with nil"]);
+}
+
+/// Plays the transcripts in `dir` through `serve`, where a line starting
+/// with `->` is a message sent to the server and one starting with `<-` a
+/// message it must reply with, in order. Returns how many were checked.
+fn check_transcripts<F>(dir: &str, mut serve: F) -> usize
+    where F: FnMut(&[u8], &mut Vec<u8>, &str)
+{
+    let mut checked = 0;
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if !path.is_file() {
+            continue;
+        }
+        let transcript = fs::read_to_string(&path).unwrap();
+        let mut input = Vec::new();
+        let mut expected = Vec::new();
+        for line in transcript.lines() {
+            if let Some(message) = line.strip_prefix("-> ") {
+                lsp::write_message(&mut input, message).unwrap();
+            } else if let Some(message) = line.strip_prefix("<- ") {
+                expected.push(Json::parse(message).unwrap());
+            }
+        }
+        let mut output = Vec::new();
+        serve(&input, &mut output, &path.display().to_string());
+
+        let mut output = &output[..];
+        let mut found = Vec::new();
+        while let Some(message) = lsp::read_message(&mut output).unwrap() {
+            found.push(Json::parse(&message).unwrap());
+        }
+        assert_eq!(found.len(), expected.len(), "replies in {}", path.display());
+        for (found, expected) in found.iter().zip(&expected) {
+            assert_eq!(found, expected, "in {}", path.display());
+        }
+        checked += 1;
+    }
+    checked
+}
+
+#[test]
+fn test_lsp_transcripts() {
+    let checked = check_transcripts("tests/lsp", |input, output, path| {
+        let mut server = Server::new();
+        server.run(input, output).unwrap();
+        assert!(server.exited(), "{} exits", path);
+        assert_eq!(server.exit_code(), 0, "{} shuts down", path);
+    });
+    assert!(checked >= 1);
+}
+
+#[test]
+fn test_lsp_errors() {
+    let mut server = Server::new();
+    let mut output = Vec::new();
+    let mut input = Vec::new();
+    lsp::write_message(&mut input, "{\"id\": 1,").unwrap();
+    lsp::write_message(&mut input, r#"{"jsonrpc":"2.0","method":"exit"}"#).unwrap();
+    server.run(&input[..], &mut output).unwrap();
+    // Exiting without a shutdown is an error
+    assert_eq!(server.exit_code(), 1);
+    let reply = Json::parse(&lsp::read_message(&mut &output[..]).unwrap().unwrap()).unwrap();
+    let code = reply.get("error").and_then(|e| e.get("code")).and_then(Json::as_f64);
+    assert_eq!(code, Some(-32700.0));
+
+    // Requests about documents which are not open have no result
+    let request = r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///b.txt"},"position":{"line":0,"character":0}}}"#;
+    let replies = server.handle(&Json::parse(request).unwrap());
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].get("result"), Some(&Json::Null));
+}
+
+/// Compiles `source` as written, so the debugger stops where it says.
+fn compile_unoptimized(source: &str) -> Rc<compiler::Module> {
+    Rc::new(compiler::compile(&Parser::new(source).parse_program().unwrap()).unwrap())
+}
+
+#[test]
+fn test_debugger_breakpoints() {
+    let source = "total = 0\nfn add(n) {\n    total += n\n    return total\n}\nfor i in 1...3 {\n    add(i)\n}\ntotal";
+    let mut vm = Vm::new();
+    vm.set_breakpoint(3);
+    assert_eq!(vm.run(compile_unoptimized(source)), Err(Error::Stopped(Span::new(3, 5))));
+    assert!(vm.is_paused());
+    assert_eq!(vm.call("add", vec![]), Err(CallError::Paused));
+
+    let stack = vm.stack();
+    let names: Vec<&str> = stack.iter().map(|frame| frame.name.as_str()).collect();
+    assert_eq!(names, vec!["add", "<main>"]);
+    assert_eq!(stack[0].locals, vec![("n".to_string(), Value::Int(1))]);
+    assert_eq!(stack[1].span, Span::new(7, 8));
+    assert_eq!(stack[1].locals, vec![("i".to_string(), Value::Int(1))]);
+    assert_eq!(vm.globals().len(), 2);
+
+    // Every time the line runs
+    assert_eq!(vm.resume(), Err(Error::Stopped(Span::new(3, 5))));
+    assert_eq!(vm.stack()[0].locals, vec![("n".to_string(), Value::Int(2))]);
+    vm.remove_breakpoint(3);
+    assert_eq!(vm.breakpoints(), vec![]);
+    assert_eq!(vm.resume(), Ok(Value::Int(6)));
+    assert!(!vm.is_paused());
+}
+
+#[test]
+fn test_debugger_steps() {
+    let source = "fn twice(x) {\n    y = x * 2\n    return y\n}\na = twice(1)\nb = twice(a)\nb";
+    let mut vm = Vm::new();
+    vm.break_next();
+    let line = |result: error::Result<Value>| match result {
+        Err(Error::Stopped(span)) => span.line,
+        result => panic!("did not stop: {:?}", result),
+    };
+    assert_eq!(line(vm.run(compile_unoptimized(source))), 1);
+    assert_eq!(line(vm.step(Step::Over)), 5);
+    assert_eq!(line(vm.step(Step::In)), 2);
+    assert_eq!(line(vm.step(Step::Over)), 3);
+    assert_eq!(vm.stack()[0].locals, vec![("x".to_string(), Value::Int(1)), ("y".to_string(), Value::Int(2))]);
+    assert_eq!(line(vm.step(Step::Over)), 6);
+    assert_eq!(line(vm.step(Step::In)), 2);
+    assert_eq!(line(vm.step(Step::Out)), 7);
+    assert_eq!(vm.get_global("b"), Some(Value::Int(4)));
+    assert_eq!(vm.step(Step::Over), Ok(Value::Int(4)));
+    // Nothing to step once the script is done
+    assert_eq!(vm.step(Step::In), Ok(Value::Nil));
+}
+
+#[test]
+fn test_dap_transcripts() {
+    let checked = check_transcripts("tests/dap", |input, output, path| {
+        let mut adapter = Adapter::new();
+        adapter.run(input, output).unwrap();
+        assert!(adapter.exited(), "{} disconnects", path);
+    });
+    assert!(checked >= 1);
+}
+
+#[test]
+fn test_profiler() {
+    let source = "fn fib(n) {\n    if n < 2 {\n        return n\n    }\n    return fib(n - 1) + fib(n - 2)\n}\n\
+                  fn run() {\n    total = 0\n    for i in 1...5 {\n        total += fib(i)\n    }\n    return total\n}\n\
+                  run()";
+    let mut vm = Vm::new();
+    assert!(vm.profile().is_none());
+    vm.start_profiling();
+    assert_eq!(vm.eval(source), Ok(Value::Int(12)));
+    // Calls from the host are counted too
+    assert_eq!(vm.call("fib", vec![Value::Int(1)]), Ok(Value::Int(1)));
+    let profile = vm.stop_profiling().unwrap();
+    assert!(vm.profile().is_none());
+
+    let functions = profile.functions(Metric::Instructions);
+    let names: Vec<&str> = functions.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, vec!["fib", "run", "<main>"]);
+    let calls: Vec<u64> = functions.iter().map(|f| f.calls).collect();
+    assert_eq!(calls, vec![34, 1, 1]);
+    assert_eq!(functions[0].span, Span::new(1, 1));
+    let collapsed = profile.collapsed(Metric::Instructions);
+    let stacks: Vec<(&str, u64)> = collapsed.lines().map(|line| {
+        let (stack, cost) = line.rsplit_once(' ').unwrap();
+        (stack, cost.parse().unwrap())
+    }).collect();
+    let names: Vec<&str> = stacks.iter().map(|stack| stack.0).collect();
+    assert_eq!(names, vec!["<main>", "<main>;run", "<main>;run;fib", "<main>;run;fib;fib",
+                           "<main>;run;fib;fib;fib", "<main>;run;fib;fib;fib;fib",
+                           "<main>;run;fib;fib;fib;fib;fib", "fib"]);
+    let from_host = stacks[7].1;
+
+    let (fib, run, main) = (&functions[0], &functions[1], &functions[2]);
+    assert_eq!(fib.total_instructions, fib.instructions);
+    assert_eq!(run.total_instructions, run.instructions + fib.instructions - from_host);
+    assert_eq!(main.total_instructions, main.instructions + run.total_instructions);
+    assert!(fib.total_time >= fib.time && main.total_time >= run.total_time);
+
+    // The recursive call is the busiest line
+    let lines = profile.lines(Metric::Instructions);
+    assert_eq!(lines[0].line, 5);
+    let instructions: u64 = lines.iter().map(|line| line.instructions).sum();
+    assert_eq!(instructions, main.total_instructions + from_host);
+    assert_eq!(stacks.iter().map(|stack| stack.1).sum::<u64>(), instructions);
+    assert!(profile.report(Metric::Time).contains("  run (7:1)\n"));
+}
+
+#[test]
+fn test_coverage() {
+    let source = "fn sign(n) {\n    if n < 0 {\n        return -1\n    }\n    return n > 0 ? 1 : 0\n}\n\
+                  fn unused() {\n    return 1\n}\n\
+                  i = 0\nwhile i < 3 & sign(i) >= 0 {\n    i += 1\n}\n\
+                  sign(5)";
+    let program = Parser::new(source).parse_program().unwrap();
+    let mut interpreter = Interpreter::new();
+    interpreter.start_coverage(&program);
+    assert_eq!(interpreter.run(&program), Ok(Value::Int(1)));
+    let coverage = interpreter.stop_coverage().unwrap();
+    assert!(interpreter.coverage().is_none());
+
+    assert_eq!(coverage.lines(), vec![(1, 1), (2, 4), (3, 0), (5, 4), (7, 1), (8, 0), (10, 1),
+                                      (11, 1), (12, 3), (14, 1)]);
+    let branches = coverage.branches();
+    assert_eq!(branches[0], (Span::new(2, 5), [0, 4]));
+    assert_eq!(branches[1].1, [3, 1]);
+    // The loop stops on `i < 3`, without calling `sign`
+    assert_eq!(branches[2], (Span::new(11, 1), [3, 1]));
+    assert_eq!(branches[3].1[TAKEN], 3);
+    assert_eq!(branches[3].1[NOT_TAKEN], 1);
+
+    assert_eq!(coverage.lcov("sign.txt"), "\
+TN:
+SF:sign.txt
+BRDA:2,0,0,0
+BRDA:2,0,1,4
+BRDA:5,0,0,3
+BRDA:5,0,1,1
+BRDA:11,0,0,3
+BRDA:11,0,1,1
+BRDA:11,1,0,3
+BRDA:11,1,1,1
+BRF:8
+BRH:7
+DA:1,1
+DA:2,4
+DA:3,0
+DA:5,4
+DA:7,1
+DA:8,0
+DA:10,1
+DA:11,1
+DA:12,3
+DA:14,1
+LF:10
+LH:8
+end_of_record
+");
+    // A function which never ran has no branch taken
+    interpreter.start_coverage(&Parser::new("fn f(x) { return x | 1 }").parse_program().unwrap());
+    assert!(interpreter.coverage().unwrap().lcov("f.txt").contains("BRDA:1,0,0,-\nBRDA:1,0,1,-\n"));
+}
+
+#[test]
+fn test_test_coverage_cli() {
+    use std::process::Command;
+
+    let dir = std::env::temp_dir().join("interpreter_test_coverage");
+    fs::create_dir_all(&dir).unwrap();
+    let script = fs::canonicalize("tests/scripts/control.txt").unwrap();
+    let failing = dir.join("failing.txt");
+    fs::write(&failing, "print(1)\nx = nil + 1\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_interpreter_bin"))
+        .arg("test").arg("--coverage").arg(&script).arg(&failing)
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("test {} ... ok\n", script.display())));
+    assert!(stdout.contains(&format!("test {} ... FAILED\n1\n{}:2:9: error:", failing.display(), failing.display())));
+    assert!(stdout.ends_with("1 passed; 1 failed\n"));
+
+    let lcov = fs::read_to_string(dir.join("lcov.info")).unwrap();
+    let records: Vec<&str> = lcov.lines().filter(|line| line.starts_with("SF:")).collect();
+    assert_eq!(records, vec![format!("SF:{}", script.display()), format!("SF:{}", failing.display())]);
+    // The statement which failed ran
+    assert!(lcov.ends_with("DA:1,1\nDA:2,1\nLF:2\nLH:2\nend_of_record\n"));
+    fs::remove_dir_all(&dir).unwrap();
+}